@@ -10,7 +10,7 @@ fn main() {
     const INSTRUCTIONS: &str =
         "a + 1.0\nb * 2.0\nc - 1.0\nalpha + 1.0\n45.0 -- beta -- 90.0\ngamma / 2.0";
 
-    let instructions: Instructions = INSTRUCTIONS.into();
+    let instructions: Instructions = INSTRUCTIONS.parse().unwrap();
 
     let new_lines = cif_modder::apply_instructions_to_cif_file(PATH, instructions).unwrap();
 
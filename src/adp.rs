@@ -0,0 +1,599 @@
+//! Atomic displacement parameters: scaling, conversion between U and B, and equivalent isotropic
+//! values from the anisotropic tensors.
+//!
+//! The isotropic `_atom_site_U_iso_or_equiv` and `_atom_site_B_iso_or_equiv` and the anisotropic
+//! `_atom_site_aniso_U_11` … `_atom_site_aniso_B_23` are edited together. Unchanged values keep their
+//! text.
+
+use std::{f64::consts::PI, fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    parse_number,
+    structure::{self, AtomSites, ANISO_INDICES},
+    utilities::{decimal_places, RemoveUncertaintyDigits},
+    DataBlock, Lattice, Loop,
+};
+
+const SCALE_KEYWORD: &str = "scale";
+const TO_KEYWORD: &str = "to";
+const UEQ_KEYWORD: &str = "ueq";
+
+const ADP_TYPE_TAG: &str = "_atom_site_adp_type";
+
+/// Converted values keep at least this many significant digits.
+const MIN_SIGNIFICANT_DIGITS: usize = 3;
+
+/// `B = 8π² U`.
+fn u_to_b_factor() -> f64 {
+    8.0 * PI * PI
+}
+
+/// Whether displacement parameters are given as mean-square displacements `U` or as `B = 8π² U`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum AdpKind {
+    U,
+    B,
+}
+
+impl AdpKind {
+    const ALL: [AdpKind; 2] = [AdpKind::U, AdpKind::B];
+
+    fn letter(&self) -> &'static str {
+        match self {
+            AdpKind::U => "U",
+            AdpKind::B => "B",
+        }
+    }
+
+    fn iso_tag(&self) -> String {
+        format!("_atom_site_{}_iso_or_equiv", self.letter())
+    }
+
+    fn aniso_tag(&self, (i, j): (usize, usize)) -> String {
+        format!("_atom_site_aniso_{}_{}{}", self.letter(), i + 1, j + 1)
+    }
+
+    /// The factor that converts a value of this kind to `kind`.
+    fn factor_to(&self, kind: AdpKind) -> f64 {
+        match (self, kind) {
+            (AdpKind::U, AdpKind::B) => u_to_b_factor(),
+            (AdpKind::B, AdpKind::U) => 1.0 / u_to_b_factor(),
+            _ => 1.0,
+        }
+    }
+}
+
+impl FromStr for AdpKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "U" => Ok(AdpKind::U),
+            "B" => Ok(AdpKind::B),
+            _ => Err(anyhow::anyhow!("{} is neither U nor B", s)),
+        }
+    }
+}
+
+impl Display for AdpKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.letter())
+    }
+}
+
+/// Edits the isotropic and anisotropic displacement parameters of the atom sites.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "AdpSpec", into = "AdpSpec")]
+pub enum Adp {
+    /// Multiplies every displacement parameter, e.g. to mimic a higher temperature.
+    Scale(f64),
+    /// Converts all displacement parameters to U or B and renames their tags.
+    Convert(AdpKind),
+    /// Writes the equivalent isotropic parameter `Ueq = ⅓ Σ Uij ai* aj* ai·aj` of every site with an
+    /// anisotropic tensor to `_atom_site_U_iso_or_equiv`.
+    Equivalent,
+}
+
+impl Adp {
+    /// Fails if the factor is not positive and finite.
+    pub fn scale(factor: f64) -> anyhow::Result<Self> {
+        if !(factor.is_finite() && factor > 0.0) {
+            return Err(anyhow::anyhow!(
+                "The displacement parameters cannot be scaled by {}",
+                factor
+            ));
+        }
+
+        Ok(Adp::Scale(factor))
+    }
+
+    /// Returns the number of modified values.
+    pub(crate) fn apply_to_block(&self, block: &mut DataBlock) -> anyhow::Result<usize> {
+        match self {
+            Adp::Scale(factor) => Ok(scale(block, *factor)),
+            Adp::Convert(kind) => Ok(convert(block, *kind)),
+            Adp::Equivalent => equivalent(block),
+        }
+    }
+}
+
+impl FromStr for Adp {
+    type Err = anyhow::Error;
+
+    /// Reads `scale 1.2`, `to B` or `ueq`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+
+        match words.as_slice() {
+            [SCALE_KEYWORD, factor] => Adp::scale(
+                parse_number(factor)
+                    .ok_or_else(|| anyhow::anyhow!("{} is not a scale factor", factor))?,
+            ),
+            [TO_KEYWORD, kind] => Ok(Adp::Convert(kind.parse()?)),
+            [UEQ_KEYWORD] => Ok(Adp::Equivalent),
+            _ => Err(anyhow::anyhow!(
+                "adp takes `{} <factor>`, `{} U`, `{} B` or `{}`, but got {}",
+                SCALE_KEYWORD,
+                TO_KEYWORD,
+                TO_KEYWORD,
+                UEQ_KEYWORD,
+                s
+            )),
+        }
+    }
+}
+
+impl Display for Adp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Adp::Scale(factor) => write!(f, "{} {}", SCALE_KEYWORD, factor),
+            Adp::Convert(kind) => write!(f, "{} {}", TO_KEYWORD, kind),
+            Adp::Equivalent => write!(f, "{}", UEQ_KEYWORD),
+        }
+    }
+}
+
+/// The structured form of an [`Adp`], e.g. `{ scale = 1.2 }`, `{ to = "B" }` or `{ ueq = true }`.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AdpSpec {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scale: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    to: Option<AdpKind>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    ueq: bool,
+}
+
+impl TryFrom<AdpSpec> for Adp {
+    type Error = anyhow::Error;
+
+    fn try_from(spec: AdpSpec) -> Result<Self, Self::Error> {
+        match (spec.scale, spec.to, spec.ueq) {
+            (Some(factor), None, false) => Adp::scale(factor),
+            (None, Some(kind), false) => Ok(Adp::Convert(kind)),
+            (None, None, true) => Ok(Adp::Equivalent),
+            _ => Err(anyhow::anyhow!("adp takes one of scale, to and ueq")),
+        }
+    }
+}
+
+impl From<Adp> for AdpSpec {
+    fn from(adp: Adp) -> Self {
+        let mut spec = AdpSpec {
+            scale: None,
+            to: None,
+            ueq: false,
+        };
+
+        match adp {
+            Adp::Scale(factor) => spec.scale = Some(factor),
+            Adp::Convert(kind) => spec.to = Some(kind),
+            Adp::Equivalent => spec.ueq = true,
+        }
+
+        spec
+    }
+}
+
+/// The isotropic displacement parameter U of a row from `_atom_site_U_iso_or_equiv` or
+/// `_atom_site_B_iso_or_equiv`, or `None` if it has neither.
+pub(crate) fn isotropic_u(sites: &AtomSites, row: usize) -> Option<f64> {
+    AdpKind::ALL.iter().find_map(|kind| {
+        sites
+            .value(row, &kind.iso_tag())
+            .and_then(parse_number)
+            .map(|value| value * kind.factor_to(AdpKind::U))
+    })
+}
+
+/// The columns of a loop that hold displacement parameters of `kind`.
+fn adp_columns(l: &Loop, kind: AdpKind) -> Vec<usize> {
+    std::iter::once(kind.iso_tag())
+        .chain(ANISO_INDICES.iter().map(|index| kind.aniso_tag(*index)))
+        .filter_map(|tag| l.column(&tag))
+        .collect()
+}
+
+/// The loops of the block with displacement parameters: the atom sites and the anisotropic sites.
+fn adp_loops(block: &mut DataBlock) -> impl Iterator<Item = &mut Loop> {
+    block.items_mut().iter_mut().filter_map(|item| match item {
+        crate::Item::Loop(l)
+            if l.column(structure::LABEL_TAG).is_some()
+                || l.column(structure::ANISO_LABEL_TAG).is_some() =>
+        {
+            Some(l)
+        }
+        _ => None,
+    })
+}
+
+/// Writes `value` with `decimals` decimal places, without a sign for zero.
+fn format_value(value: f64, decimals: usize) -> String {
+    let text = format!("{:.*}", decimals, value);
+
+    match text.strip_prefix('-') {
+        Some(abs) if abs.parse::<f64>() == Ok(0.0) => abs.to_string(),
+        _ => text,
+    }
+}
+
+/// The number of significant digits of a number as written, e.g. 2 for `0.0049(2)`.
+fn significant_digits(value: &str) -> usize {
+    value
+        .remove_uncertainty_digits()
+        .chars()
+        .filter(char::is_ascii_digit)
+        .skip_while(|c| *c == '0')
+        .count()
+}
+
+/// The number of decimal places that gives `value` the wanted number of significant digits.
+fn decimals_for(value: f64, significant_digits: usize) -> usize {
+    if value == 0.0 {
+        return significant_digits;
+    }
+
+    let magnitude = value.abs().log10().floor() as i64;
+
+    (significant_digits as i64 - 1 - magnitude).max(0) as usize
+}
+
+/// Multiplies every displacement parameter by `factor` and keeps the decimal places of every value.
+fn scale(block: &mut DataBlock, factor: f64) -> usize {
+    let mut modified_values_counter = 0;
+
+    for l in adp_loops(block) {
+        for kind in AdpKind::ALL {
+            for column in adp_columns(l, kind) {
+                for row in 0..l.len() {
+                    let text = l.value(row, column);
+
+                    let Some(value) = parse_number(&text) else {
+                        continue;
+                    };
+
+                    let decimals = decimal_places(&text.remove_uncertainty_digits());
+                    let new_text = format_value(value * factor, decimals);
+
+                    if new_text != text {
+                        l.set_value(row, column, &new_text);
+                        modified_values_counter += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    modified_values_counter
+}
+
+/// Converts the displacement parameters of the other kind to `kind`, renames their tags and the
+/// `Uani`, `Uiso`, … values of `_atom_site_adp_type`. Converted values keep their significant
+/// digits, but at least [`MIN_SIGNIFICANT_DIGITS`].
+fn convert(block: &mut DataBlock, kind: AdpKind) -> usize {
+    let other = match kind {
+        AdpKind::U => AdpKind::B,
+        AdpKind::B => AdpKind::U,
+    };
+
+    let factor = other.factor_to(kind);
+    let mut modified_values_counter = 0;
+
+    for l in adp_loops(block) {
+        let columns = adp_columns(l, other);
+
+        if columns.iter().any(|column| {
+            let tag = l.tags()[*column].clone();
+            l.column(&renamed_tag(&tag, other, kind)).is_some()
+        }) {
+            log::warn!(
+                "The loop with {} already has {} parameters and is not converted",
+                l.tags()[columns[0]],
+                kind
+            );
+            continue;
+        }
+
+        for &column in &columns {
+            for row in 0..l.len() {
+                let text = l.value(row, column);
+
+                let Some(value) = parse_number(&text) else {
+                    continue;
+                };
+
+                let new_value = value * factor;
+                let digits = significant_digits(&text).max(MIN_SIGNIFICANT_DIGITS);
+
+                l.set_value(
+                    row,
+                    column,
+                    &format_value(new_value, decimals_for(new_value, digits)),
+                );
+                modified_values_counter += 1;
+            }
+        }
+
+        if let Some(column) = l.column(ADP_TYPE_TAG) {
+            for row in 0..l.len() {
+                let adp_type = l.value(row, column);
+
+                if let Some(rest) = adp_type.strip_prefix(other.letter()) {
+                    l.set_value(row, column, &format!("{}{}", kind, rest));
+                    modified_values_counter += 1;
+                }
+            }
+        }
+
+        if !columns.is_empty() {
+            let tags = l
+                .tags()
+                .iter()
+                .enumerate()
+                .map(|(column, tag)| match columns.contains(&column) {
+                    true => renamed_tag(tag, other, kind),
+                    false => tag.clone(),
+                })
+                .collect();
+
+            l.set_tags(tags);
+        }
+    }
+
+    modified_values_counter
+}
+
+/// Replaces the letter of the kind in a tag, e.g. `_atom_site_aniso.U_11` becomes
+/// `_atom_site_aniso.B_11`.
+fn renamed_tag(tag: &str, from: AdpKind, to: AdpKind) -> String {
+    let pattern = format!("{}_", from);
+
+    match tag.rfind(&pattern) {
+        Some(index) => format!("{}{}_{}", &tag[..index], to, &tag[index + pattern.len()..]),
+        None => tag.to_string(),
+    }
+}
+
+/// Writes the equivalent isotropic parameter of every anisotropic site to the isotropic column of
+/// the atom sites. A missing isotropic column is added. Returns the number of modified values.
+fn equivalent(block: &mut DataBlock) -> anyhow::Result<usize> {
+    let Some(aniso) = block.find_loop(structure::ANISO_LABEL_TAG) else {
+        return Ok(0);
+    };
+
+    let Some(kind) = AdpKind::ALL
+        .into_iter()
+        .find(|kind| !adp_columns(aniso, *kind).is_empty())
+    else {
+        return Ok(0);
+    };
+
+    let lattice = Lattice::from_block(block)?;
+
+    let label_column = aniso
+        .column(structure::ANISO_LABEL_TAG)
+        .expect("The loop contains the tag");
+
+    let columns = ANISO_INDICES.map(|index| aniso.column(&kind.aniso_tag(index)));
+
+    // The label, the equivalent parameter and the decimal places of the tensor of every site.
+    let equivalents: Vec<(String, f64, usize)> = aniso
+        .rows()
+        .into_iter()
+        .filter_map(|row| {
+            let mut tensor = [[0.0; 3]; 3];
+            let mut decimals = 0;
+
+            for ((i, j), column) in ANISO_INDICES.into_iter().zip(columns) {
+                let Some(column) = column else {
+                    continue;
+                };
+
+                let value = parse_number(&row[column])?;
+
+                tensor[i][j] = value;
+                tensor[j][i] = value;
+                decimals = decimals.max(decimal_places(&row[column].remove_uncertainty_digits()));
+            }
+
+            Some((
+                row[label_column].clone(),
+                equivalent_isotropic(&lattice, tensor),
+                decimals,
+            ))
+        })
+        .collect();
+
+    let Some(sites) = block.find_loop_mut(structure::LABEL_TAG) else {
+        return Ok(0);
+    };
+
+    let iso = AdpKind::ALL
+        .into_iter()
+        .find_map(|iso_kind| Some((iso_kind, sites.column(&iso_kind.iso_tag())?)));
+
+    let (iso_kind, iso_column) = match iso {
+        Some(iso) => iso,
+        None => {
+            sites.push_column(&kind.iso_tag(), vec!["?".to_string(); sites.len()]);
+            (kind, sites.tags().len() - 1)
+        }
+    };
+
+    let label_column = sites
+        .column(structure::LABEL_TAG)
+        .expect("The loop contains the tag");
+    let adp_type_column = sites.column(ADP_TYPE_TAG);
+
+    let mut modified_values_counter = 0;
+
+    for row in 0..sites.len() {
+        let label = sites.value(row, label_column);
+
+        let Some((_, value, decimals)) = equivalents.iter().find(|(l, _, _)| *l == label) else {
+            continue;
+        };
+
+        let value = value * kind.factor_to(iso_kind);
+        let decimals = match iso_kind == kind {
+            true => *decimals,
+            false => decimals_for(value, MIN_SIGNIFICANT_DIGITS),
+        };
+
+        let text = format_value(value, decimals);
+
+        if sites.value(row, iso_column).remove_uncertainty_digits() != text {
+            sites.set_value(row, iso_column, &text);
+            modified_values_counter += 1;
+        }
+
+        if let Some(column) = adp_type_column {
+            let adp_type = format!("{}ani", iso_kind);
+
+            if sites.value(row, column) != adp_type {
+                sites.set_value(row, column, &adp_type);
+                modified_values_counter += 1;
+            }
+        }
+    }
+
+    Ok(modified_values_counter)
+}
+
+/// `Ueq = ⅓ Σ Uij ai* aj* ai·aj` for the tensor in the CIF convention.
+fn equivalent_isotropic(lattice: &Lattice, tensor: [[f64; 3]; 3]) -> f64 {
+    let reciprocal_lengths = lattice.reciprocal().lengths();
+    let metric_tensor = lattice.metric_tensor();
+
+    let mut sum = 0.0;
+
+    for i in 0..3 {
+        for j in 0..3 {
+            sum +=
+                tensor[i][j] * reciprocal_lengths[i] * reciprocal_lengths[j] * metric_tensor[i][j];
+        }
+    }
+
+    sum / 3.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::block;
+
+    fn aniso_values(block: &DataBlock) -> Vec<String> {
+        block.find_loop(structure::ANISO_LABEL_TAG).unwrap().row(0)
+    }
+
+    #[test]
+    fn test_scale() {
+        let mut block = block("tests/BaTiO3_P1.cif");
+
+        assert_eq!(
+            Adp::scale(2.0).unwrap().apply_to_block(&mut block).unwrap(),
+            8
+        );
+        assert_eq!(aniso_values(&block), ["Ti1", "0.0174", "0.0174", "0.0174"]);
+
+        let sites = block.find_loop(structure::LABEL_TAG).unwrap();
+        assert_eq!(sites.value(0, 8), "0.0098");
+        assert_eq!(sites.value(2, 8), "0.010");
+
+        assert!(Adp::scale(0.0).is_err());
+    }
+
+    #[test]
+    fn test_convert() {
+        let mut block = block("tests/BaTiO3_P1.cif");
+
+        Adp::Convert(AdpKind::B).apply_to_block(&mut block).unwrap();
+
+        let aniso = block.find_loop(structure::ANISO_LABEL_TAG).unwrap();
+        assert_eq!(aniso.tags()[1], "_atom_site_aniso_B_11");
+        assert_eq!(aniso.value(0, 1), "0.687");
+
+        let sites = block.find_loop(structure::LABEL_TAG).unwrap();
+        assert!(sites.column("_atom_site_B_iso_or_equiv").is_some());
+        assert_eq!(sites.value(0, 8), "0.387");
+        assert_eq!(sites.value(2, 8), "0.395");
+
+        Adp::Convert(AdpKind::U).apply_to_block(&mut block).unwrap();
+
+        let aniso = block.find_loop(structure::ANISO_LABEL_TAG).unwrap();
+        assert_eq!(aniso.tags()[1], "_atom_site_aniso_U_11");
+        assert_eq!(aniso.value(0, 1), "0.00870");
+
+        assert_eq!(
+            renamed_tag("_atom_site_aniso.U_23", AdpKind::U, AdpKind::B),
+            "_atom_site_aniso.B_23"
+        );
+    }
+
+    #[test]
+    fn test_equivalent() {
+        let mut block = block("tests/BaTiO3_P1.cif");
+
+        let aniso = block.find_loop_mut(structure::ANISO_LABEL_TAG).unwrap();
+        aniso.set_value(0, 3, "0.0120");
+
+        assert_eq!(Adp::Equivalent.apply_to_block(&mut block).unwrap(), 1);
+
+        let sites = block.find_loop(structure::LABEL_TAG).unwrap();
+        assert_eq!(sites.value(1, 8), "0.0098");
+
+        // An isotropic displacement in the CIF convention of an oblique cell.
+        let lattice = Lattice::new(5.0, 6.0, 7.0, 90.0, 100.0, 90.0).unwrap();
+        let reciprocal = lattice.reciprocal();
+        let lengths = reciprocal.lengths();
+        let metric_tensor = reciprocal.metric_tensor();
+
+        let mut tensor = [[0.0; 3]; 3];
+
+        for (i, row) in tensor.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = 0.01 * metric_tensor[i][j] / (lengths[i] * lengths[j]);
+            }
+        }
+
+        assert!((equivalent_isotropic(&lattice, tensor) - 0.01).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_adp_from_str() {
+        assert_eq!("scale 1.2".parse::<Adp>().unwrap(), Adp::Scale(1.2));
+        assert_eq!("to B".parse::<Adp>().unwrap().to_string(), "to B");
+        assert_eq!("ueq".parse::<Adp>().unwrap(), Adp::Equivalent);
+
+        assert!("scale".parse::<Adp>().is_err());
+        assert!("to C".parse::<Adp>().is_err());
+        assert!("scale -1".parse::<Adp>().is_err());
+
+        let adp: Adp = serde_json::from_str(r#"{"to": "U"}"#).unwrap();
+        assert_eq!(adp, Adp::Convert(AdpKind::U));
+        assert!(serde_json::from_str::<Adp>(r#"{"to": "U", "ueq": true}"#).is_err());
+    }
+}
@@ -0,0 +1,352 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, Write},
+};
+
+use anyhow::Context;
+
+use crate::{compression::Compression, derive_seed, seed_rng, Instructions};
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ArchiveFormat {
+    Tar(Compression),
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Determines the archive format from the file extension of `path`.
+    pub fn from_path(path: &str) -> Option<Self> {
+        if path.ends_with(".zip") {
+            Some(ArchiveFormat::Zip)
+        } else if path.ends_with(".tar") {
+            Some(ArchiveFormat::Tar(Compression::None))
+        } else if path.ends_with(".tar.gz") || path.ends_with(".tgz") {
+            Some(ArchiveFormat::Tar(Compression::Gzip))
+        } else if path.ends_with(".tar.xz") || path.ends_with(".txz") {
+            Some(ArchiveFormat::Tar(Compression::Xz))
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns whether an archive entry is a (possibly compressed) CIF that should be modified.
+pub fn is_cif_entry(name: &str) -> bool {
+    let name = name
+        .trim_end_matches(Compression::Gzip.extension())
+        .trim_end_matches(Compression::Xz.extension());
+
+    name.ends_with(".cif")
+}
+
+/// Calls `f` with the name and content of every CIF entry of the archive at `path`, in archive order.
+///
+/// Entries are streamed from the archive and never extracted to disk.
+pub fn for_each_cif_in_archive(
+    path: &str,
+    mut f: impl FnMut(&str, &mut dyn Read) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let format = ArchiveFormat::from_path(path)
+        .ok_or_else(|| anyhow::anyhow!("{} is not a known archive format", path))?;
+
+    let file = BufReader::new(File::open(path)?);
+
+    match format {
+        ArchiveFormat::Tar(compression) => {
+            let reader = crate::compression::decompress(file)?.1;
+
+            log::debug!("Reading {:?} compressed tar archive {}", compression, path);
+
+            let mut archive = tar::Archive::new(reader);
+
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                let name = entry.path()?.to_string_lossy().to_string();
+
+                if entry.header().entry_type().is_file() && is_cif_entry(&name) {
+                    f(&name, &mut entry).with_context(|| format!("In archive entry {}", name))?;
+                }
+            }
+        }
+        ArchiveFormat::Zip => {
+            let mut archive = zip::ZipArchive::new(file)?;
+
+            for index in 0..archive.len() {
+                let mut entry = archive.by_index(index)?;
+                let name = entry.name().to_string();
+
+                if entry.is_file() && is_cif_entry(&name) {
+                    f(&name, &mut entry).with_context(|| format!("In archive entry {}", name))?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Streams every entry of the archive at `input_path` into a new archive of the same format at
+/// `output_path`, applying the instructions to all CIF entries on the way.
+///
+/// Other entries are copied unchanged. The random number generator is reseeded for every CIF entry
+/// from `seed` and the entry name. Returns the number of modified entries.
+pub fn apply_instructions_to_archive(
+    input_path: &str,
+    output_path: &str,
+    instructions: &Instructions,
+    seed: u64,
+) -> anyhow::Result<usize> {
+    let format = ArchiveFormat::from_path(input_path)
+        .ok_or_else(|| anyhow::anyhow!("{} is not a known archive format", input_path))?;
+
+    let input = BufReader::new(File::open(input_path)?);
+    let output = File::create(output_path)?;
+
+    let modified_entries_counter = match format {
+        ArchiveFormat::Tar(compression) => {
+            let reader = crate::compression::decompress(input)?.1;
+            let writer = compression.compress(BufWriter::new(output));
+
+            let (writer, counter) = modify_tar(reader, writer, instructions, seed)?;
+
+            writer.finish()?.flush()?;
+
+            counter
+        }
+        ArchiveFormat::Zip => modify_zip(input, BufWriter::new(output), instructions, seed)?,
+    };
+
+    log::debug!(
+        "Modified {} entries in {}",
+        modified_entries_counter,
+        input_path
+    );
+
+    Ok(modified_entries_counter)
+}
+
+fn modify_entry(
+    name: &str,
+    reader: &mut dyn Read,
+    instructions: &Instructions,
+    seed: u64,
+) -> anyhow::Result<Vec<u8>> {
+    seed_rng(derive_seed(seed, name));
+
+    let mut buffer = Vec::new();
+
+    crate::apply_instructions_to_compressed(reader, &mut buffer, instructions)
+        .with_context(|| format!("In archive entry {}", name))?;
+
+    Ok(buffer)
+}
+
+fn modify_tar<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    instructions: &Instructions,
+    seed: u64,
+) -> anyhow::Result<(W, usize)> {
+    let mut archive = tar::Archive::new(reader);
+    let mut builder = tar::Builder::new(writer);
+
+    let mut modified_entries_counter = 0;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        // The full path and link target, which the header alone truncates to 100 bytes. The
+        // builder writes GNU long name entries for them again.
+        let path = entry.path()?.into_owned();
+        let link_name = entry.link_name()?.map(|link_name| link_name.into_owned());
+        let name = path.to_string_lossy().to_string();
+        let mut header = entry.header().clone();
+
+        if header.entry_type().is_file() && is_cif_entry(&name) {
+            let content = modify_entry(&name, &mut entry, instructions, seed)?;
+
+            header.set_size(content.len() as u64);
+
+            builder.append_data(&mut header, &path, content.as_slice())?;
+
+            modified_entries_counter += 1;
+        } else if let Some(link_name) = link_name {
+            builder.append_link(&mut header, &path, link_name)?;
+        } else {
+            builder.append_data(&mut header, &path, &mut entry)?;
+        }
+    }
+
+    Ok((builder.into_inner()?, modified_entries_counter))
+}
+
+fn modify_zip<R: Read + Seek, W: Write + Seek>(
+    reader: R,
+    writer: W,
+    instructions: &Instructions,
+    seed: u64,
+) -> anyhow::Result<usize> {
+    let mut archive = zip::ZipArchive::new(reader)?;
+    let mut zip_writer = zip::ZipWriter::new(writer);
+
+    let mut modified_entries_counter = 0;
+
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        let name = entry.name().to_string();
+
+        if entry.is_file() && is_cif_entry(&name) {
+            let mut options =
+                zip::write::SimpleFileOptions::default().compression_method(entry.compression());
+
+            if let Some(last_modified) = entry.last_modified() {
+                options = options.last_modified_time(last_modified);
+            }
+
+            if let Some(mode) = entry.unix_mode() {
+                options = options.unix_permissions(mode);
+            }
+
+            let content = modify_entry(&name, &mut entry, instructions, seed)?;
+
+            zip_writer.start_file(name, options)?;
+            zip_writer.write_all(&content)?;
+
+            modified_entries_counter += 1;
+        } else {
+            zip_writer.raw_copy_file(entry)?;
+        }
+    }
+
+    zip_writer.finish()?.flush()?;
+
+    Ok(modified_entries_counter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_archive_format_from_path() {
+        assert_eq!(ArchiveFormat::from_path("a.zip"), Some(ArchiveFormat::Zip));
+        assert_eq!(
+            ArchiveFormat::from_path("a.tar.gz"),
+            Some(ArchiveFormat::Tar(Compression::Gzip))
+        );
+        assert_eq!(
+            ArchiveFormat::from_path("a.txz"),
+            Some(ArchiveFormat::Tar(Compression::Xz))
+        );
+        assert_eq!(ArchiveFormat::from_path("a.cif.gz"), None);
+    }
+
+    #[test]
+    fn test_is_cif_entry() {
+        assert!(is_cif_entry("structures/BaTiO3.cif"));
+        assert!(is_cif_entry("structures/BaTiO3.cif.gz"));
+        assert!(!is_cif_entry("structures/README"));
+    }
+
+    #[test]
+    fn test_modify_tar() {
+        let content = std::fs::read("tests/BaTiO3.cif").unwrap();
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "BaTiO3.cif", content.as_slice())
+            .unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let instructions: Instructions = "a + 1.0".parse().unwrap();
+
+        let (modified, counter) =
+            modify_tar(archive.as_slice(), Vec::new(), &instructions, 0).unwrap();
+
+        assert_eq!(counter, 1);
+
+        let mut archive = tar::Archive::new(modified.as_slice());
+        let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+        let mut modified_content = String::new();
+        entry.read_to_string(&mut modified_content).unwrap();
+
+        assert!(modified_content.contains("_cell_length_a                     5.0094\n"));
+    }
+
+    #[test]
+    fn test_modify_tar_with_long_path() {
+        let content = std::fs::read("tests/BaTiO3.cif").unwrap();
+        let path = format!("{}/BaTiO3.cif", "structures/perovskites".repeat(6));
+        assert!(path.len() > 100);
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, &path, content.as_slice())
+            .unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let instructions: Instructions = "a + 1.0".parse().unwrap();
+
+        let (modified, counter) =
+            modify_tar(archive.as_slice(), Vec::new(), &instructions, 0).unwrap();
+
+        assert_eq!(counter, 1);
+
+        let mut archive = tar::Archive::new(modified.as_slice());
+        let entry = archive.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(entry.path().unwrap().to_string_lossy(), path);
+    }
+
+    #[test]
+    fn test_modify_zip() {
+        let content = std::fs::read("tests/BaTiO3.cif").unwrap();
+
+        let mut zip_writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default();
+        zip_writer
+            .start_file("structures/BaTiO3.cif", options)
+            .unwrap();
+        zip_writer.write_all(&content).unwrap();
+        zip_writer.start_file("structures/README", options).unwrap();
+        zip_writer.write_all(b"Not a CIF").unwrap();
+        let archive = zip_writer.finish().unwrap().into_inner();
+
+        let instructions: Instructions = "a + 1.0".parse().unwrap();
+
+        let mut modified = std::io::Cursor::new(Vec::new());
+        let counter = modify_zip(
+            std::io::Cursor::new(archive),
+            &mut modified,
+            &instructions,
+            0,
+        )
+        .unwrap();
+
+        assert_eq!(counter, 1);
+
+        let mut archive = zip::ZipArchive::new(modified).unwrap();
+        assert_eq!(archive.len(), 2);
+
+        let mut modified_content = String::new();
+        archive
+            .by_name("structures/BaTiO3.cif")
+            .unwrap()
+            .read_to_string(&mut modified_content)
+            .unwrap();
+        assert!(modified_content.contains("_cell_length_a                     5.0094\n"));
+
+        let mut readme = String::new();
+        archive
+            .by_name("structures/README")
+            .unwrap()
+            .read_to_string(&mut readme)
+            .unwrap();
+        assert_eq!(readme, "Not a CIF");
+    }
+}
@@ -1,18 +1,204 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+
+use crate::{Evaluation, Safeguard};
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum InstructionFormat {
+    Text,
+    Toml,
+    Json,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum ReportFormat {
+    Text,
+    Json,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum GeometryFormat {
+    Text,
+    Json,
+    /// `_geom_bond_` and `_geom_angle_` loops.
+    Cif,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum DiffractionFormat {
+    /// Two columns 2θ and intensity.
+    Text,
+    /// The peaks with their indices, d-spacing and multiplicity.
+    Peaks,
+    Json,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum ProfileArgument {
+    /// Peaks without width.
+    None,
+    Gaussian,
+    PseudoVoigt,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Check CIFs for structural problems and report them, like checkCIF.
+    Validate {
+        /// CIF files, archives or directories containing CIF files. Use `-` to read a CIF from
+        /// stdin.
+        #[arg(required = true)]
+        paths: Vec<String>,
+        /// The format of the report.
+        #[arg(long, value_enum, default_value_t = ReportFormat::Text)]
+        format: ReportFormat,
+    },
+    /// List the interatomic distances and bond angles of CIFs, including symmetry equivalent and
+    /// periodic images, or how instructions change them.
+    Geometry {
+        /// CIF files, archives or directories containing CIF files. Use `-` to read a CIF from
+        /// stdin.
+        #[arg(required = true)]
+        paths: Vec<String>,
+        /// Instructions as a string or a path to a file containing instructions. The distances and
+        /// angles of the modified structures are compared to the original ones.
+        #[arg(short, long)]
+        instructions: Option<String>,
+        /// The maximum distance in Å between two atoms to count as bonded.
+        #[arg(short, long, default_value_t = crate::DEFAULT_MAX_DISTANCE)]
+        max_distance: f64,
+        /// The format of the output.
+        #[arg(long, value_enum, default_value_t = GeometryFormat::Text)]
+        format: GeometryFormat,
+        /// Seed for the random number generator used by random instructions.
+        #[arg(short, long)]
+        seed: Option<u64>,
+    },
+    /// Simulate the powder X-ray diffraction pattern of CIFs and, with instructions, of the
+    /// modified structures.
+    Xrd {
+        /// CIF files, archives or directories containing CIF files. Use `-` to read a CIF from
+        /// stdin.
+        #[arg(required = true)]
+        paths: Vec<String>,
+        /// Instructions as a string or a path to a file containing instructions. The pattern of
+        /// the modified structures is written after the original one.
+        #[arg(short, long)]
+        instructions: Option<String>,
+        /// The wavelength in Å or an anode for its Kα1 wavelength: Cu, Mo, Co, Fe, Cr or Ag.
+        #[arg(short, long, default_value = "Cu", value_parser = crate::parse_wavelength)]
+        wavelength: f64,
+        /// The smallest diffraction angle 2θ in degrees.
+        #[arg(long, default_value_t = crate::DEFAULT_TWO_THETA_RANGE.0)]
+        min_two_theta: f64,
+        /// The largest diffraction angle 2θ in degrees.
+        #[arg(long, default_value_t = crate::DEFAULT_TWO_THETA_RANGE.1)]
+        max_two_theta: f64,
+        /// The shape of the peaks.
+        #[arg(long, value_enum, default_value_t = ProfileArgument::None)]
+        profile: ProfileArgument,
+        /// The full width at half maximum of the peaks in degrees 2θ.
+        #[arg(long, default_value_t = 0.1)]
+        fwhm: f64,
+        /// The Lorentzian fraction η of a pseudo-Voigt profile.
+        #[arg(long, default_value_t = 0.5)]
+        eta: f64,
+        /// The step of a broadened pattern in degrees 2θ.
+        #[arg(long, default_value_t = 0.02)]
+        step: f64,
+        /// The format of the output.
+        #[arg(long, value_enum, default_value_t = DiffractionFormat::Text)]
+        format: DiffractionFormat,
+        /// Seed for the random number generator used by random instructions.
+        #[arg(short, long)]
+        seed: Option<u64>,
+    },
+    /// List the reflections that the cell and the symmetry of CIFs allow with their d-spacing and
+    /// 2θ and, with instructions, how far they shift.
+    Reflections {
+        /// CIF files, archives or directories containing CIF files. Use `-` to read a CIF from
+        /// stdin.
+        #[arg(required = true)]
+        paths: Vec<String>,
+        /// Instructions as a string or a path to a file containing instructions. The reflections
+        /// of the modified structures are listed with their shift from the original cell.
+        #[arg(short, long)]
+        instructions: Option<String>,
+        /// The wavelength in Å or an anode for its Kα1 wavelength: Cu, Mo, Co, Fe, Cr or Ag.
+        #[arg(short, long, default_value = "Cu", value_parser = crate::parse_wavelength)]
+        wavelength: f64,
+        /// The smallest diffraction angle 2θ in degrees.
+        #[arg(long, default_value_t = crate::DEFAULT_TWO_THETA_RANGE.0)]
+        min_two_theta: f64,
+        /// The largest diffraction angle 2θ in degrees.
+        #[arg(long, default_value_t = crate::DEFAULT_TWO_THETA_RANGE.1)]
+        max_two_theta: f64,
+        /// The smallest d-spacing in Å. Replaces the largest diffraction angle.
+        #[arg(long, conflicts_with = "max_two_theta")]
+        min_d: Option<f64>,
+        /// The format of the output.
+        #[arg(long, value_enum, default_value_t = ReportFormat::Text)]
+        format: ReportFormat,
+        /// Seed for the random number generator used by random instructions.
+        #[arg(short, long)]
+        seed: Option<u64>,
+    },
+}
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Args {
-    /// The path to the CIF file or directory containing CIF files.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// The path to the CIF file or directory containing CIF files. Use `-` to read a CIF from stdin.
     #[arg(short, long, group = "execute")]
     pub cif: Option<String>,
-    /// Instructions as a string or a path to a file containing instructions.
-    #[arg(short, long, requires = "execute")]
+    /// Instructions as a string or a path to a file containing instructions. Files ending in
+    /// `.toml` or `.json` contain the structured form of the instructions.
+    #[arg(short, long)]
     pub instructions: Option<String>,
+    /// Print the instructions in the given format and exit without modifying files.
+    #[arg(long, value_name = "FORMAT")]
+    pub print_instructions: Option<InstructionFormat>,
+    /// How instructions read the values of other tags. Overrides the evaluation given in the
+    /// instructions.
+    #[arg(long, global = true)]
+    pub evaluation: Option<Evaluation>,
+    /// What to do with values outside the physical bounds of their tag, e.g. a negative cell
+    /// length. Overrides the safeguard given in the instructions.
+    #[arg(long, global = true)]
+    pub safeguard: Option<Safeguard>,
+    /// Path to a DDL1 or DDLm CIF dictionary, e.g. `cif_core.dic`. Modified tags have to be defined
+    /// in it and every modified value has to match its type, enumeration, range and loop membership.
+    #[arg(short, long, global = true)]
+    pub dictionary: Option<String>,
+    /// Apply the named preset of the instruction file. Can be given several times.
+    #[arg(short, long, global = true)]
+    pub preset: Vec<String>,
+    /// Write the modified CIFs to stdout instead of `_modified.cif` files.
+    #[arg(long)]
+    pub stdout: bool,
+    /// Number of files to process in parallel.
+    #[arg(short, long, default_value_t = 1)]
+    pub jobs: usize,
+    /// Continue with the remaining files if a file fails and report all failures at the end.
+    #[arg(short, long)]
+    pub keep_going: bool,
+    /// Seed for the random number generator. Every file gets its own stream derived from this seed.
+    #[arg(short, long)]
+    pub seed: Option<u64>,
+    /// Number of configurations to write for every file, e.g. of random defects. Every
+    /// configuration gets its own random stream and is written to `_modified_1.cif`,
+    /// `_modified_2.cif`, … or one after another to stdout.
+    #[arg(short = 'n', long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    pub configurations: u64,
+    /// List the known CIF tags with their aliases, type, units and allowed range. Only tags and
+    /// short names starting with PREFIX are listed if given.
+    #[arg(long, value_name = "PREFIX", num_args = 0..=1, default_missing_value = "")]
+    pub list_tags: Option<String>,
     /// Print examples of how to use the program.
     #[arg(short, long, group = "example")]
     pub examples: bool,
     /// Show additional debug information.
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub verbose: bool,
 }
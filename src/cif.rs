@@ -0,0 +1,1205 @@
+//! A lossless representation of CIF files.
+//!
+//! Parsing a CIF and rendering it again reproduces the input byte for byte. Modified values replace
+//! only their own text, so the surrounding formatting, comments and unknown content are preserved.
+//!
+//! Save frames, as used by dictionaries, are kept as nested blocks. CIF2 lists and tables (`[...]`,
+//! `{...}`) and triple-quoted strings are read as single values.
+
+use std::{fmt::Display, ops::Range};
+
+use anyhow::Context;
+
+use crate::utilities::{whitespace_between_two_values, RemoveUncertaintyDigits};
+
+const LOOP_KEYWORD: &str = "loop_";
+const DATA_PREFIX: &str = "data_";
+const SAVE_PREFIX: &str = "save_";
+const GLOBAL_KEYWORD: &str = "global_";
+const STOP_KEYWORD: &str = "stop_";
+
+const DEFAULT_VALUE_COLUMN: usize = 35;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum TokenKind {
+    Tag,
+    Loop,
+    Data,
+    Save,
+    Global,
+    Value,
+    /// Text that cannot be read, e.g. an unterminated quoted value, with the reason.
+    Invalid(&'static str),
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    span: Range<usize>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Cif {
+    blocks: Vec<DataBlock>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DataBlock {
+    name: Option<String>,
+    items: Vec<Item>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Item {
+    /// Content that is not a data item, e.g. comments, whitespace and block headers.
+    Text(String),
+    Value(Value),
+    Loop(Loop),
+    /// A save frame. Its items include the `save_name` header and the closing `save_`.
+    Frame(DataBlock),
+}
+
+/// A single tag-value pair.
+#[derive(Debug, Clone)]
+pub struct Value {
+    tag: String,
+    raw: String,
+    value_span: Range<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Loop {
+    tags: Vec<String>,
+    header: String,
+    body: String,
+    spans: Vec<Range<usize>>,
+}
+
+impl Cif {
+    /// Parses a CIF. Any syntax error fails the whole file.
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let tokens = tokenize(s);
+
+        let blocks = segments(s, &tokens)
+            .iter()
+            .map(|segment| {
+                segment.parse(s).with_context(|| {
+                    format!(
+                        "Cannot read data block {}",
+                        segment.name.as_deref().unwrap_or_default()
+                    )
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Cif { blocks })
+    }
+
+    /// Like [`Cif::parse`], but a data block with a syntax error, e.g. a tag without a value or an
+    /// unterminated text field, is kept as raw text with a warning. Such a block renders unchanged
+    /// and has no values, so instructions do not modify it.
+    pub fn parse_tolerant(s: &str) -> Self {
+        let tokens = tokenize(s);
+
+        let blocks = segments(s, &tokens)
+            .iter()
+            .map(|segment| {
+                segment.parse(s).unwrap_or_else(|e| {
+                    log::warn!(
+                        "Keeping data block {} unchanged: {}",
+                        segment.name.as_deref().unwrap_or_default(),
+                        e
+                    );
+
+                    segment.raw(s)
+                })
+            })
+            .collect();
+
+        Cif { blocks }
+    }
+
+    /// The data blocks of the file. The first block holds the content before the first `data_`
+    /// header and has no name.
+    pub fn blocks(&self) -> &[DataBlock] {
+        &self.blocks
+    }
+
+    pub fn blocks_mut(&mut self) -> &mut [DataBlock] {
+        &mut self.blocks
+    }
+}
+
+impl Display for Cif {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for block in &self.blocks {
+            write!(f, "{}", block)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl DataBlock {
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Renames the block by rewriting its `data_` header. Unnamed blocks get a header.
+    pub fn set_name(&mut self, name: &str) {
+        let header = format!("{}{}", DATA_PREFIX, name);
+
+        let old_length = self
+            .name
+            .as_ref()
+            .map(|old_name| DATA_PREFIX.len() + old_name.len());
+
+        match (old_length, self.items.first_mut()) {
+            (Some(old_length), Some(Item::Text(text))) if text.is_char_boundary(old_length) => {
+                text.replace_range(..old_length, &header)
+            }
+            _ => self.items.insert(0, Item::Text(format!("{}\n", header))),
+        }
+
+        self.name = Some(name.to_string());
+    }
+
+    pub fn items(&self) -> &[Item] {
+        &self.items
+    }
+
+    pub fn items_mut(&mut self) -> &mut [Item] {
+        &mut self.items
+    }
+
+    /// Returns the value of a tag that is not part of a loop.
+    pub fn value(&self, tag: &str) -> Option<String> {
+        self.find_value(tag).map(Value::value)
+    }
+
+    pub fn find_value(&self, tag: &str) -> Option<&Value> {
+        self.items.iter().find_map(|item| match item {
+            Item::Value(value) if value.is(tag) => Some(value),
+            _ => None,
+        })
+    }
+
+    pub fn find_value_mut(&mut self, tag: &str) -> Option<&mut Value> {
+        self.items.iter_mut().find_map(|item| match item {
+            Item::Value(value) if value.is(tag) => Some(value),
+            _ => None,
+        })
+    }
+
+    /// Sets the value of a tag that is not part of a loop. Returns `false` if the tag does not
+    /// exist.
+    pub fn set_value(&mut self, tag: &str, new_value: &str) -> bool {
+        match self.find_value_mut(tag) {
+            Some(value) => {
+                value.set_value(new_value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sets the value of a tag or adds a new line for it at the end of the block.
+    ///
+    /// New values are aligned with the values of the existing tags.
+    pub fn insert_value(&mut self, tag: &str, new_value: &str) {
+        if self.set_value(tag, new_value) {
+            return;
+        }
+
+        let value_column = self
+            .items
+            .iter()
+            .find_map(|item| match item {
+                Item::Value(value) => {
+                    whitespace_between_two_values(&value.raw).map(|w| value.tag.len() + w)
+                }
+                _ => None,
+            })
+            .unwrap_or(DEFAULT_VALUE_COLUMN);
+
+        let whitespace = " ".repeat(value_column.saturating_sub(tag.len()).max(1));
+
+        let raw = format!("{}{}", tag, whitespace);
+        let value_start = raw.len();
+
+        let mut value = Value {
+            tag: tag.to_string(),
+            raw,
+            value_span: value_start..value_start,
+        };
+
+        value.set_value(new_value);
+
+        self.push_item(Item::Value(value));
+    }
+
+    /// The save frames of the block.
+    pub fn frames(&self) -> impl Iterator<Item = &DataBlock> {
+        self.items.iter().filter_map(|item| match item {
+            Item::Frame(frame) => Some(frame),
+            _ => None,
+        })
+    }
+
+    pub fn find_loop(&self, tag: &str) -> Option<&Loop> {
+        self.items.iter().find_map(|item| match item {
+            Item::Loop(l) if l.column(tag).is_some() => Some(l),
+            _ => None,
+        })
+    }
+
+    pub fn find_loop_mut(&mut self, tag: &str) -> Option<&mut Loop> {
+        self.items.iter_mut().find_map(|item| match item {
+            Item::Loop(l) if l.column(tag).is_some() => Some(l),
+            _ => None,
+        })
+    }
+
+    /// Appends an item on a new line after the last data item of the block.
+    pub fn push_item(&mut self, item: Item) {
+        let index = self
+            .items
+            .iter()
+            .rposition(|item| !matches!(item, Item::Text(_)))
+            .map(|index| index + 1)
+            .unwrap_or(self.items.len());
+
+        // The new item starts on the next line, after any whitespace that ends the previous one.
+        let end_of_line = match self.items.get_mut(index) {
+            Some(Item::Text(text)) => {
+                let whitespace = text.len() - text.trim_start_matches([' ', '\t']).len();
+                text.drain(..whitespace).collect()
+            }
+            _ => String::new(),
+        };
+
+        let index = match end_of_line.is_empty() {
+            true => index,
+            false => {
+                self.items.insert(index, Item::Text(end_of_line));
+                index + 1
+            }
+        };
+
+        self.items
+            .splice(index..index, [Item::Text("\n".to_string()), item]);
+    }
+
+    fn push_text(&mut self, text: &str) {
+        if !text.is_empty() {
+            self.items.push(Item::Text(text.to_string()));
+        }
+    }
+}
+
+impl Display for DataBlock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for item in &self.items {
+            match item {
+                Item::Text(text) => write!(f, "{}", text)?,
+                Item::Value(value) => write!(f, "{}", value.raw)?,
+                Item::Loop(l) => write!(f, "{}{}", l.header, l.body)?,
+                Item::Frame(frame) => write!(f, "{}", frame)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for Loop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", self.header, self.body)
+    }
+}
+
+impl Value {
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    pub fn is(&self, tag: &str) -> bool {
+        crate::tags::registry().is_same_tag(&self.tag, tag)
+    }
+
+    /// The value without quotes or text field delimiters.
+    pub fn value(&self) -> String {
+        unquote(&self.raw[self.value_span.clone()])
+    }
+
+    pub fn set_value(&mut self, new_value: &str) {
+        let new_value = quote(new_value);
+        let line_break = line_break_before(&new_value, &self.raw[..self.value_span.start]);
+
+        self.raw.replace_range(
+            self.value_span.clone(),
+            &format!("{}{}", line_break, new_value),
+        );
+        self.value_span.start += line_break.len();
+        self.value_span.end = self.value_span.start + new_value.len();
+    }
+}
+
+impl Loop {
+    /// Creates a loop that is rendered with one tag per line and one row per line.
+    pub fn new(tags: Vec<String>, rows: Vec<Vec<String>>) -> Self {
+        let mut l = Loop {
+            header: String::new(),
+            tags,
+            body: String::new(),
+            spans: Vec::new(),
+        };
+
+        l.set_tags(l.tags.clone());
+        l.set_rows(rows);
+
+        l
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    pub fn column(&self, tag: &str) -> Option<usize> {
+        self.tags
+            .iter()
+            .position(|t| crate::tags::registry().is_same_tag(t, tag))
+    }
+
+    /// The column that identifies a row, i.e. the first `_label` column or the first column.
+    pub fn label_column(&self) -> usize {
+        self.tags
+            .iter()
+            .position(|tag| tag.to_lowercase().ends_with("_label"))
+            .unwrap_or(0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.spans.len() / self.tags.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+
+    pub fn value(&self, row: usize, column: usize) -> String {
+        unquote(&self.body[self.spans[row * self.tags.len() + column].clone()])
+    }
+
+    pub fn row(&self, row: usize) -> Vec<String> {
+        (0..self.tags.len())
+            .map(|column| self.value(row, column))
+            .collect()
+    }
+
+    pub fn rows(&self) -> Vec<Vec<String>> {
+        (0..self.len()).map(|row| self.row(row)).collect()
+    }
+
+    /// Replaces a single value. The formatting of all other values is preserved.
+    pub fn set_value(&mut self, row: usize, column: usize, new_value: &str) {
+        let new_value = quote(new_value);
+
+        let index = row * self.tags.len() + column;
+        let span = self.spans[index].clone();
+
+        let before = match span.start {
+            0 => &self.header,
+            start => &self.body[..start],
+        };
+        let line_break = line_break_before(&new_value, before);
+
+        self.body
+            .replace_range(span.clone(), &format!("{}{}", line_break, new_value));
+
+        let shift = (line_break.len() + new_value.len()) as isize - span.len() as isize;
+
+        self.spans[index].start = span.start + line_break.len();
+        self.spans[index].end = self.spans[index].start + new_value.len();
+
+        for span in self.spans.iter_mut().skip(index + 1) {
+            span.start = (span.start as isize + shift) as usize;
+            span.end = (span.end as isize + shift) as usize;
+        }
+    }
+
+    /// Replaces the tags. If the number of tags changes, the rows have to be set afterwards with
+    /// [`Loop::set_rows`] and are written anew.
+    pub fn set_tags(&mut self, tags: Vec<String>) {
+        if tags.len() != self.tags.len() {
+            self.body.clear();
+            self.spans.clear();
+        }
+
+        self.header = format!("{}\n{}\n", LOOP_KEYWORD, tags.join("\n"));
+        self.tags = tags;
+    }
+
+    /// Replaces all rows. Existing rows keep their formatting and only the values that changed are
+    /// replaced. Additional rows are written on their own line after the last row.
+    pub fn set_rows(&mut self, rows: Vec<Vec<String>>) {
+        if self.is_empty() {
+            let header_length = self.header.trim_end_matches([' ', '\t']).len();
+            self.header.truncate(header_length);
+
+            if !self.header.ends_with('\n') {
+                self.header.push('\n');
+            }
+
+            self.body.clear();
+        }
+
+        let width = self.tags.len();
+        let kept_rows = rows.len().min(self.len());
+
+        for (row, values) in rows.iter().enumerate().take(kept_rows) {
+            for (column, value) in values.iter().enumerate() {
+                if *value != self.value(row, column) {
+                    self.set_value(row, column, value);
+                }
+            }
+        }
+
+        let end = match kept_rows {
+            0 => 0,
+            _ => self.spans[kept_rows * width - 1].end,
+        };
+
+        self.body.truncate(end);
+        self.spans.truncate(kept_rows * width);
+
+        for values in &rows[kept_rows..] {
+            if !self.body.is_empty() {
+                self.body.push('\n');
+            }
+
+            for (column, value) in values.iter().enumerate() {
+                let value = quote(value);
+
+                if column > 0 {
+                    self.body.push(' ');
+                }
+
+                self.body.push_str(line_break_before(&value, &self.body));
+
+                self.spans
+                    .push(self.body.len()..self.body.len() + value.len());
+                self.body.push_str(&value);
+            }
+        }
+    }
+
+    /// Adds a column. `values` has to contain one value per row. The new tag and values are
+    /// written after the last tag and at the end of every row.
+    pub fn push_column(&mut self, tag: &str, values: Vec<String>) {
+        let width = self.tags.len();
+
+        let tags_end = self.header.trim_end().len();
+        self.header.insert_str(tags_end, &format!("\n{}", tag));
+        self.tags.push(tag.to_string());
+
+        let mut spans = Vec::with_capacity(self.spans.len() + values.len());
+        let mut shift = 0;
+
+        for (row, value) in self.spans.chunks(width).zip(values) {
+            let value = quote(&value);
+            let separator = match line_break_before(&value, " ") {
+                "" => " ",
+                line_break => line_break,
+            };
+            let start = separator.len();
+            let value = format!("{}{}", separator, value);
+            let end = row[width - 1].end + shift;
+
+            spans.extend(row.iter().map(|span| span.start + shift..span.end + shift));
+            spans.push(end + start..end + value.len());
+
+            self.body.insert_str(end, &value);
+            shift += value.len();
+        }
+
+        self.spans = spans;
+    }
+}
+
+/// Parses a numeric CIF value, ignoring standard uncertainties. Returns `None` for `?`, `.` and
+/// non-numeric values.
+pub fn parse_number(value: &str) -> Option<f64> {
+    value.remove_uncertainty_digits().parse::<f64>().ok()
+}
+
+/// Removes quotes and text field delimiters from a raw value.
+fn unquote(raw: &str) -> String {
+    if let Some(text) = raw.strip_prefix(';') {
+        let text = text.strip_suffix(';').unwrap_or(text);
+        let text = text
+            .strip_prefix("\r\n")
+            .or(text.strip_prefix('\n'))
+            .unwrap_or(text);
+        let text = text.strip_suffix('\n').unwrap_or(text);
+        let text = text.strip_suffix('\r').unwrap_or(text);
+
+        return text.to_string();
+    }
+
+    for quote in ["'''", "\"\"\""] {
+        if raw.len() >= 6 && raw.starts_with(quote) && raw.ends_with(quote) {
+            return raw[3..raw.len() - 3].to_string();
+        }
+    }
+
+    for quote in ['\'', '"'] {
+        if raw.len() >= 2 && raw.starts_with(quote) && raw.ends_with(quote) {
+            return raw[1..raw.len() - 1].to_string();
+        }
+    }
+
+    raw.to_string()
+}
+
+/// Quotes a value if it could not be read back as a single bare value.
+fn quote(value: &str) -> String {
+    if value.contains('\n') {
+        return format!(";\n{}\n;", value);
+    }
+
+    let needs_quotes = value.is_empty()
+        || value.contains(char::is_whitespace)
+        || value.starts_with(['_', '#', '$', '\'', '"', ';', '[', ']'])
+        || is_reserved_word(value);
+
+    if !needs_quotes {
+        value.to_string()
+    } else if !value.contains("' ") && !value.ends_with('\'') {
+        format!("'{}'", value)
+    } else if !value.contains("\" ") && !value.ends_with('"') {
+        format!("\"{}\"", value)
+    } else {
+        format!(";\n{}\n;", value)
+    }
+}
+
+/// Returns the line break to insert in front of a quoted value that follows `before`. A text field
+/// is only read as one if its opening `;` starts a line.
+fn line_break_before(value: &str, before: &str) -> &'static str {
+    match value.starts_with(';') && !before.is_empty() && !before.ends_with('\n') {
+        true => "\n",
+        false => "",
+    }
+}
+
+fn is_reserved_word(word: &str) -> bool {
+    let word = word.to_lowercase();
+
+    word == LOOP_KEYWORD
+        || word == GLOBAL_KEYWORD
+        || word == STOP_KEYWORD
+        || word.starts_with(DATA_PREFIX)
+        || word.starts_with(SAVE_PREFIX)
+}
+
+/// The text and tokens of a data block, from its `data_` header to the next one.
+struct Segment<'a> {
+    name: Option<String>,
+    span: Range<usize>,
+    tokens: &'a [Token],
+}
+
+/// Splits the tokens at `data_` headers. The first segment holds the content before the first
+/// header.
+fn segments<'a>(s: &str, tokens: &'a [Token]) -> Vec<Segment<'a>> {
+    let mut segments = Vec::new();
+
+    let mut name = None;
+    let mut start = 0;
+    let mut first_token = 0;
+
+    for (index, token) in tokens.iter().enumerate() {
+        if token.kind == TokenKind::Data {
+            segments.push(Segment {
+                name: name.take(),
+                span: start..token.span.start,
+                tokens: &tokens[first_token..index],
+            });
+
+            name = Some(s[token.span.start + DATA_PREFIX.len()..token.span.end].to_string());
+            start = token.span.start;
+            first_token = index + 1;
+        }
+    }
+
+    segments.push(Segment {
+        name,
+        span: start..s.len(),
+        tokens: &tokens[first_token..],
+    });
+
+    segments
+}
+
+impl Segment<'_> {
+    /// A block that holds the text of the segment unparsed.
+    fn raw(&self, s: &str) -> DataBlock {
+        let mut block = DataBlock {
+            name: self.name.clone(),
+            items: Vec::new(),
+        };
+        block.push_text(&s[self.span.clone()]);
+
+        block
+    }
+
+    fn parse(&self, s: &str) -> anyhow::Result<DataBlock> {
+        let tokens = self.tokens;
+
+        let mut block = DataBlock {
+            name: self.name.clone(),
+            items: Vec::new(),
+        };
+
+        // The save frame that is currently open. Items are added to it instead of the block.
+        let mut frame: Option<DataBlock> = None;
+
+        let mut position = self.span.start;
+        let mut index = 0;
+
+        while index < tokens.len() {
+            let token = &tokens[index];
+
+            match token.kind {
+                TokenKind::Data => unreachable!("Segments end before data_ headers"),
+                TokenKind::Invalid(error) => {
+                    return Err(anyhow::anyhow!(
+                        "{} in line {}",
+                        error,
+                        line_number(s, token.span.start)
+                    ));
+                }
+                TokenKind::Save => {
+                    let name = &s[token.span.start + SAVE_PREFIX.len()..token.span.end];
+
+                    match (frame.take(), name.is_empty()) {
+                        (Some(mut open_frame), true) => {
+                            open_frame.push_text(&s[position..token.span.end]);
+
+                            block.items.push(Item::Frame(open_frame));
+
+                            position = token.span.end;
+                        }
+                        (None, false) => {
+                            block.push_text(&s[position..token.span.start]);
+
+                            frame = Some(DataBlock {
+                                name: Some(name.to_string()),
+                                items: Vec::new(),
+                            });
+
+                            position = token.span.start;
+                        }
+                        (Some(_), false) => {
+                            return Err(anyhow::anyhow!(
+                                "Save frame in line {} is nested in another save frame",
+                                line_number(s, token.span.start)
+                            ));
+                        }
+                        (None, true) => {
+                            return Err(anyhow::anyhow!(
+                                "save_ in line {} does not close a save frame",
+                                line_number(s, token.span.start)
+                            ));
+                        }
+                    }
+
+                    index += 1;
+                }
+                TokenKind::Global => index += 1,
+                TokenKind::Tag => {
+                    let value_token = tokens
+                        .get(index + 1)
+                        .filter(|t| t.kind == TokenKind::Value)
+                        .ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "Tag {} in line {} has no value",
+                                &s[token.span.clone()],
+                                line_number(s, token.span.start)
+                            )
+                        })?;
+
+                    let block = frame.as_mut().unwrap_or(&mut block);
+                    block.push_text(&s[position..token.span.start]);
+
+                    let start = token.span.start;
+
+                    block.items.push(Item::Value(Value {
+                        tag: s[token.span.clone()].to_string(),
+                        raw: s[start..value_token.span.end].to_string(),
+                        value_span: value_token.span.start - start..value_token.span.end - start,
+                    }));
+
+                    position = value_token.span.end;
+                    index += 2;
+                }
+                TokenKind::Loop => {
+                    let header_start = token.span.start;
+
+                    let mut tag_end = index + 1;
+
+                    while tokens
+                        .get(tag_end)
+                        .is_some_and(|t| t.kind == TokenKind::Tag)
+                    {
+                        tag_end += 1;
+                    }
+
+                    let mut value_end = tag_end;
+
+                    while tokens
+                        .get(value_end)
+                        .is_some_and(|t| t.kind == TokenKind::Value)
+                    {
+                        value_end += 1;
+                    }
+
+                    let tags: Vec<String> = tokens[index + 1..tag_end]
+                        .iter()
+                        .map(|t| s[t.span.clone()].to_string())
+                        .collect();
+
+                    let values = &tokens[tag_end..value_end];
+
+                    if tags.is_empty() || !values.len().is_multiple_of(tags.len()) {
+                        return Err(anyhow::anyhow!(
+                            "Loop in line {} has {} tags but {} values",
+                            line_number(s, header_start),
+                            tags.len(),
+                            values.len()
+                        ));
+                    }
+
+                    let header_end = tokens[tag_end - 1].span.end;
+
+                    let (body_start, body_end) = match (values.first(), values.last()) {
+                        (Some(first), Some(last)) => (first.span.start, last.span.end),
+                        _ => (header_end, header_end),
+                    };
+
+                    let block = frame.as_mut().unwrap_or(&mut block);
+                    block.push_text(&s[position..header_start]);
+
+                    block.items.push(Item::Loop(Loop {
+                        tags,
+                        header: s[header_start..body_start].to_string(),
+                        body: s[body_start..body_end].to_string(),
+                        spans: values
+                            .iter()
+                            .map(|t| t.span.start - body_start..t.span.end - body_start)
+                            .collect(),
+                    }));
+
+                    position = body_end;
+                    index = value_end;
+                }
+                TokenKind::Value => {
+                    return Err(anyhow::anyhow!(
+                        "Value {} in line {} does not belong to a tag",
+                        &s[token.span.clone()],
+                        line_number(s, token.span.start)
+                    ));
+                }
+            }
+        }
+
+        if let Some(frame) = frame {
+            return Err(anyhow::anyhow!(
+                "Save frame {} is not closed",
+                frame.name().unwrap_or_default()
+            ));
+        }
+
+        block.push_text(&s[position..self.span.end]);
+
+        Ok(block)
+    }
+}
+
+fn line_number(s: &str, position: usize) -> usize {
+    s[..position].matches('\n').count() + 1
+}
+
+/// Returns the end of a CIF2 list or table starting at `start`, including nested lists, tables and
+/// quoted strings, or `None` if it is not closed.
+fn bracketed_value_end(s: &str, start: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut quote = None;
+
+    for (index, c) in s[start..].char_indices() {
+        match (c, quote) {
+            (c, Some(q)) if c == q => quote = None,
+            (_, Some(_)) => (),
+            ('\'' | '"', None) => quote = Some(c),
+            ('[' | '{', None) => depth += 1,
+            (']' | '}', None) => {
+                depth -= 1;
+
+                if depth == 0 {
+                    return Some(start + index + 1);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    None
+}
+
+/// Returns the position of the line break that ends the line containing `position`.
+fn line_end(s: &str, position: usize) -> usize {
+    s[position..]
+        .find('\n')
+        .map(|index| position + index)
+        .unwrap_or(s.len())
+}
+
+/// Splits a CIF into tokens. Unterminated text fields, strings, lists and tables become
+/// [`TokenKind::Invalid`] tokens up to the end of their line, so the following lines are still
+/// read.
+fn tokenize(s: &str) -> Vec<Token> {
+    let bytes = s.as_bytes();
+
+    let mut tokens = Vec::new();
+
+    let mut position = 0;
+
+    while position < bytes.len() {
+        let c = bytes[position];
+
+        if c.is_ascii_whitespace() {
+            position += 1;
+            continue;
+        }
+
+        if c == b'#' {
+            position = line_end(s, position);
+            continue;
+        }
+
+        let is_line_start = position == 0 || bytes[position - 1] == b'\n';
+
+        let start = position;
+
+        let kind = if c == b';' && is_line_start {
+            match s[position..].match_indices("\n;").next() {
+                Some((index, _)) => {
+                    position += index + 2;
+                    TokenKind::Value
+                }
+                None => {
+                    position = line_end(s, position);
+                    TokenKind::Invalid("Unterminated text field")
+                }
+            }
+        } else if s[position..].starts_with("'''") || s[position..].starts_with("\"\"\"") {
+            let delimiter = &s[position..position + 3];
+
+            match s[position + 3..].find(delimiter) {
+                Some(index) => {
+                    position += 3 + index + 3;
+                    TokenKind::Value
+                }
+                None => {
+                    position = line_end(s, position);
+                    TokenKind::Invalid("Unterminated string")
+                }
+            }
+        } else if c == b'[' || c == b'{' {
+            match bracketed_value_end(s, position) {
+                Some(end) => {
+                    position = end;
+                    TokenKind::Value
+                }
+                None => {
+                    position = line_end(s, position);
+                    TokenKind::Invalid("Unterminated list or table")
+                }
+            }
+        } else if c == b'\'' || c == b'"' {
+            let mut end = position + 1;
+
+            let kind = loop {
+                match bytes.get(end) {
+                    Some(&b)
+                        if b == c && bytes.get(end + 1).is_none_or(u8::is_ascii_whitespace) =>
+                    {
+                        end += 1;
+                        break TokenKind::Value;
+                    }
+                    Some(b'\n') | None => break TokenKind::Invalid("Unterminated quoted value"),
+                    _ => (),
+                }
+
+                end += 1;
+            };
+
+            position = end;
+
+            kind
+        } else {
+            while position < bytes.len() && !bytes[position].is_ascii_whitespace() {
+                position += 1;
+            }
+
+            let word = s[start..position].to_lowercase();
+
+            if word.starts_with('_') {
+                TokenKind::Tag
+            } else if word == LOOP_KEYWORD {
+                TokenKind::Loop
+            } else if word.starts_with(DATA_PREFIX) {
+                TokenKind::Data
+            } else if word.starts_with(SAVE_PREFIX) {
+                TokenKind::Save
+            } else if word == GLOBAL_KEYWORD || word == STOP_KEYWORD {
+                TokenKind::Global
+            } else {
+                TokenKind::Value
+            }
+        };
+
+        tokens.push(Token {
+            kind,
+            span: start..position,
+        });
+    }
+
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATH: &str = "tests/BaTiO3.cif";
+
+    #[test]
+    fn test_parse_and_render_is_lossless() {
+        let content = std::fs::read_to_string(PATH).unwrap();
+
+        let cif = Cif::parse(&content).unwrap();
+
+        assert_eq!(cif.to_string(), content);
+        assert_eq!(cif.blocks().len(), 2);
+        assert_eq!(cif.blocks()[1].name(), Some("95437-ICSD"));
+    }
+
+    #[test]
+    fn test_values_and_loops() {
+        let content = std::fs::read_to_string(PATH).unwrap();
+
+        let mut cif = Cif::parse(&content).unwrap();
+        let block = &mut cif.blocks_mut()[1];
+
+        assert_eq!(block.value("_cell_length_a").unwrap(), "4.0094(2)");
+        assert_eq!(
+            block.value("_symmetry_space_group_name_H-M").unwrap(),
+            "P m -3 m"
+        );
+        assert!(block
+            .value("_publ_section_title")
+            .unwrap()
+            .starts_with("Composite"));
+
+        let atom_sites = block.find_loop("_atom_site_fract_x").unwrap();
+        assert_eq!(atom_sites.len(), 3);
+        assert_eq!(atom_sites.row(1)[0], "Ti1");
+        assert_eq!(atom_sites.label_column(), 0);
+
+        let symmetry = block.find_loop("_symmetry_equiv_pos_as_xyz").unwrap();
+        assert_eq!(symmetry.len(), 48);
+        assert_eq!(symmetry.value(0, 1), "z, y, -x");
+
+        let atom_sites = block.find_loop_mut("_atom_site_fract_x").unwrap();
+        atom_sites.set_value(1, 4, "0.51");
+
+        assert!(cif
+            .to_string()
+            .contains("Ti1 Ti 1 b 0.51 0.5 0.5 1. 0 0.0087(6) \nO1 O 3 c"));
+    }
+
+    #[test]
+    fn test_set_rows_keeps_formatting() {
+        let content = "data_a\nloop_\n_atom_site_label\n_atom_site_occupancy\n\
+                       Ba1\t  1.0\nTi1     1.0\n# sites\n";
+
+        let mut cif = Cif::parse(content).unwrap();
+        let sites = cif.blocks_mut()[1]
+            .find_loop_mut("_atom_site_label")
+            .unwrap();
+
+        let mut rows = sites.rows();
+        rows[1][1] = "0.5".to_string();
+        sites.set_rows(rows.clone());
+        assert_eq!(
+            cif.to_string(),
+            "data_a\nloop_\n_atom_site_label\n_atom_site_occupancy\n\
+             Ba1\t  1.0\nTi1     0.5\n# sites\n"
+        );
+
+        let sites = cif.blocks_mut()[1]
+            .find_loop_mut("_atom_site_label")
+            .unwrap();
+        rows.push(vec!["O1".to_string(), "1.0".to_string()]);
+        sites.set_rows(rows);
+        sites.push_column(
+            "_atom_site_type_symbol",
+            vec!["Ba".to_string(), "Ti".to_string(), "O".to_string()],
+        );
+        assert_eq!(
+            cif.to_string(),
+            "data_a\nloop_\n_atom_site_label\n_atom_site_occupancy\n_atom_site_type_symbol\n\
+             Ba1\t  1.0 Ba\nTi1     0.5 Ti\nO1 1.0 O\n# sites\n"
+        );
+
+        let sites = cif.blocks_mut()[1]
+            .find_loop_mut("_atom_site_label")
+            .unwrap();
+        assert_eq!(sites.row(2), ["O1", "1.0", "O"]);
+        let rows = sites.rows()[..1].to_vec();
+        sites.set_rows(rows);
+        assert_eq!(
+            cif.to_string(),
+            "data_a\nloop_\n_atom_site_label\n_atom_site_occupancy\n_atom_site_type_symbol\n\
+             Ba1\t  1.0 Ba\n# sites\n"
+        );
+    }
+
+    #[test]
+    fn test_set_name() {
+        let mut cif = Cif::parse("# comment\ndata_a\n_cell_length_a 4.0\n").unwrap();
+
+        cif.blocks_mut()[1].set_name("a_config2");
+
+        assert_eq!(cif.blocks()[1].name(), Some("a_config2"));
+        assert_eq!(
+            cif.to_string(),
+            "# comment\ndata_a_config2\n_cell_length_a 4.0\n"
+        );
+    }
+
+    #[test]
+    fn test_insert_value() {
+        let mut cif = Cif::parse("data_test\n_cell_length_a   4.0\n").unwrap();
+        let block = &mut cif.blocks_mut()[1];
+
+        block.insert_value("_cell_length_b", "5.0");
+        block.insert_value("_cell_length_a", "4.5");
+        block.insert_value("_chemical_name_common", "barium titanate");
+
+        assert_eq!(
+            cif.to_string(),
+            "data_test\n_cell_length_a   4.5\n_cell_length_b   5.0\n_chemical_name_common 'barium titanate'\n"
+        );
+    }
+
+    #[test]
+    fn test_text_field_round_trip() {
+        let text = "it's a \"quoted\" value' \" ";
+
+        let mut cif =
+            Cif::parse("data_test\n_chemical_name_common x\nloop_\n_a\n_b\n1 2\n").unwrap();
+        let block = &mut cif.blocks_mut()[1];
+
+        block.set_value("_chemical_name_common", text);
+        block.find_loop_mut("_a").unwrap().set_value(0, 1, text);
+        block
+            .find_loop_mut("_a")
+            .unwrap()
+            .push_column("_c", vec![text.to_string()]);
+        block.insert_value("_chemical_name_mineral", text);
+
+        let rendered = cif.to_string();
+        let cif = Cif::parse(&rendered).unwrap();
+        let block = &cif.blocks()[1];
+
+        assert_eq!(block.value("_chemical_name_common").unwrap(), text);
+        assert_eq!(block.value("_chemical_name_mineral").unwrap(), text);
+        assert_eq!(block.find_loop("_a").unwrap().row(0), ["1", text, text]);
+        assert_eq!(cif.to_string(), rendered);
+    }
+
+    #[test]
+    fn test_lookup_by_alias() {
+        let cif = Cif::parse(
+            "data_test\n_cell.length_a 4.0\n_space_group_name_H-M_alt 'P m -3 m'\nloop_\n_space_group_symop_operation_xyz\nx,y,z\n",
+        )
+        .unwrap();
+        let block = &cif.blocks()[1];
+
+        assert_eq!(block.value("_cell_length_a"), Some("4.0".to_string()));
+        assert_eq!(
+            block.value("_symmetry_space_group_name_H-M"),
+            Some("P m -3 m".to_string())
+        );
+        assert!(block.find_loop("_symmetry_equiv_pos_as_xyz").is_some());
+    }
+
+    #[test]
+    fn test_save_frames() {
+        let s = "data_dictionary\n_dictionary_title test\nsave_cell.length_a\n    _definition.id '_cell.length_a'\n    _import.get [{'file':templ_attr.cif 'save':cell_length}]\n    loop_\n    _alias.definition_id\n    '_cell_length_a'\nsave_\n";
+
+        let cif = Cif::parse(s).unwrap();
+        assert_eq!(cif.to_string(), s);
+
+        let block = &cif.blocks()[1];
+        assert_eq!(block.value("_dictionary_title"), Some("test".to_string()));
+        assert!(block.value("_definition.id").is_none());
+
+        let frame = block.frames().next().unwrap();
+        assert_eq!(frame.name(), Some("cell.length_a"));
+        assert_eq!(
+            frame.value("_import.get"),
+            Some("[{'file':templ_attr.cif 'save':cell_length}]".to_string())
+        );
+        assert!(frame.find_loop("_alias.definition_id").is_some());
+
+        assert!(Cif::parse("save_a\n_b c\n").is_err());
+        assert!(Cif::parse("save_a\nsave_b\nsave_\n").is_err());
+    }
+
+    #[test]
+    fn test_invalid_loop() {
+        assert!(Cif::parse("loop_\n_a\n_b\n1 2 3\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_tolerant() {
+        let s = "data_no_value\n_cell_length_a\ndata_good\n_cell_length_a 4.0\n\
+                 data_stray_value\n_cell_length_a 4.0 5.0\n\
+                 data_unterminated\n_publ_section_title\n;\nTitle\n_cell_length_a 4.0\n";
+
+        assert!(Cif::parse(s).is_err());
+
+        let cif = Cif::parse_tolerant(s);
+        assert_eq!(cif.to_string(), s);
+
+        let names: Vec<_> = cif.blocks().iter().filter_map(DataBlock::name).collect();
+        assert_eq!(names, ["no_value", "good", "stray_value", "unterminated"]);
+
+        let lengths: Vec<_> = cif.blocks()[1..]
+            .iter()
+            .map(|block| block.value("_cell_length_a"))
+            .collect();
+        assert_eq!(lengths, [None, Some("4.0".to_string()), None, None]);
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("4.0094(2)"), Some(4.0094));
+        assert_eq!(parse_number("1."), Some(1.0));
+        assert_eq!(parse_number("?"), None);
+    }
+}
@@ -0,0 +1,364 @@
+//! Keeps the atom types and the formula of a data block consistent with its atom sites after
+//! operations that add, remove or replace atoms.
+
+use std::collections::HashSet;
+
+use crate::{
+    formula, parse_number,
+    structure::{self, AtomSites},
+    DataBlock, Formula,
+};
+
+pub(crate) const TYPE_TAG: &str = "_atom_type_symbol";
+pub(crate) const OXIDATION_NUMBER_TAG: &str = "_atom_type_oxidation_number";
+pub(crate) const FORMULA_SUM_TAG: &str = "_chemical_formula_sum";
+pub(crate) const FORMULA_STRUCTURAL_TAG: &str = "_chemical_formula_structural";
+pub(crate) const FORMULA_WEIGHT_TAG: &str = "_chemical_formula_weight";
+pub(crate) const Z_TAG: &str = "_cell_formula_units_Z";
+
+/// The oxidation number of a type symbol in the `_atom_type_` loop.
+pub(crate) fn oxidation_number(block: &DataBlock, symbol: &str) -> Option<String> {
+    let l = block.find_loop(TYPE_TAG)?;
+
+    let symbol_column = l.column(TYPE_TAG)?;
+    let oxidation_column = l.column(OXIDATION_NUMBER_TAG)?;
+
+    l.rows()
+        .into_iter()
+        .find(|row| row[symbol_column] == symbol)
+        .map(|row| row[oxidation_column].clone())
+}
+
+/// Adds the type symbols of `sites` that are missing to the `_atom_type_` loop and removes the type
+/// symbols of `old_sites` that no site uses anymore. New types get the oxidation number given in
+/// `oxidation_numbers`, other columns are unknown. Returns the number of added and removed rows.
+pub(crate) fn update_atom_types(
+    block: &mut DataBlock,
+    old_sites: &AtomSites,
+    sites: &AtomSites,
+    oxidation_numbers: &[(String, String)],
+) -> usize {
+    let Some(l) = block.find_loop_mut(TYPE_TAG) else {
+        return 0;
+    };
+
+    let symbol_column = l.column(TYPE_TAG).expect("The loop contains the tag");
+    let oxidation_column = l.column(OXIDATION_NUMBER_TAG);
+
+    let symbols = |sites: &AtomSites| -> Vec<String> {
+        let mut symbols = Vec::new();
+
+        for row in 0..sites.len() {
+            if let Some(symbol) = sites.value(row, structure::TYPE_SYMBOL_TAG) {
+                if !symbols.iter().any(|s| s == symbol) {
+                    symbols.push(symbol.to_string());
+                }
+            }
+        }
+
+        symbols
+    };
+
+    let old_symbols = symbols(old_sites);
+    let new_symbols = symbols(sites);
+
+    let mut rows = l.rows();
+    let rows_count = rows.len();
+
+    let is_sorted = rows.is_sorted_by(|a, b| a[symbol_column] <= b[symbol_column]);
+
+    for symbol in &new_symbols {
+        if rows.iter().any(|row| row[symbol_column] == *symbol) {
+            continue;
+        }
+
+        let oxidation_number = oxidation_numbers
+            .iter()
+            .find(|(s, _)| s == symbol)
+            .map(|(_, oxidation_number)| oxidation_number.clone());
+
+        let new_row = (0..l.tags().len())
+            .map(|column| match column {
+                c if c == symbol_column => symbol.clone(),
+                c if Some(c) == oxidation_column => {
+                    oxidation_number.clone().unwrap_or_else(|| "?".to_string())
+                }
+                _ => "?".to_string(),
+            })
+            .collect();
+
+        rows.push(new_row);
+    }
+
+    let added_rows_count = rows.len() - rows_count;
+
+    let removed_symbols: HashSet<&String> = old_symbols
+        .iter()
+        .filter(|symbol| !new_symbols.contains(symbol))
+        .collect();
+
+    rows.retain(|row| !removed_symbols.contains(&row[symbol_column]));
+
+    let removed_rows_count = rows_count + added_rows_count - rows.len();
+
+    if added_rows_count + removed_rows_count == 0 {
+        return 0;
+    }
+
+    if is_sorted {
+        rows.sort_by(|a, b| a[symbol_column].cmp(&b[symbol_column]));
+    }
+
+    l.set_rows(rows);
+
+    added_rows_count + removed_rows_count
+}
+
+/// Updates `_chemical_formula_sum` by the change of the cell content per formula unit, so that
+/// `_cell_formula_units_Z` stays the number of formula units. Without Z, the number of formula
+/// units is the ratio of the old content to the old formula. Returns the number of modified values.
+pub(crate) fn update_formula_sum(
+    block: &mut DataBlock,
+    content: &Formula,
+    new_content: &Formula,
+) -> anyhow::Result<usize> {
+    let block_name = block.name().unwrap_or_default().to_string();
+
+    if block.value(FORMULA_WEIGHT_TAG).is_some() {
+        log::warn!(
+            "{} of data_{} is not updated",
+            FORMULA_WEIGHT_TAG,
+            block_name
+        );
+    }
+
+    let Some(sum) = block.value(FORMULA_SUM_TAG) else {
+        return Ok(0);
+    };
+
+    let mut formula = Formula::parse(&sum)?;
+
+    let Some(formula_units) = formula_units(block, content, &formula) else {
+        log::warn!(
+            "{} of data_{} is not updated, the number of formula units is unknown",
+            FORMULA_SUM_TAG,
+            block_name
+        );
+        return Ok(0);
+    };
+
+    let elements: Vec<String> = content
+        .elements()
+        .chain(new_content.elements())
+        .map(|(element, _)| element.to_string())
+        .collect();
+
+    let mut updated_elements = HashSet::new();
+
+    for element in elements {
+        if updated_elements.insert(element.clone()) {
+            formula.add(
+                &element,
+                (new_content.count(&element) - content.count(&element)) / formula_units,
+            );
+        }
+    }
+
+    block.set_value(FORMULA_SUM_TAG, &formula.to_string());
+
+    Ok(1)
+}
+
+/// Recomputes `_chemical_formula_sum` from the occupancies of the atom sites, relative to
+/// `reference`, an earlier state of the block whose formula matches its atom sites. Elements
+/// without atom sites keep their counts. Returns the number of modified values.
+pub(crate) fn recompute_formula_sum(
+    block: &mut DataBlock,
+    reference: &DataBlock,
+) -> anyhow::Result<usize> {
+    let block_name = block.name().unwrap_or_default().to_string();
+
+    let (Some(sum), Some(reference_sum)) = (
+        block.value(FORMULA_SUM_TAG),
+        reference.value(FORMULA_SUM_TAG),
+    ) else {
+        return Ok(0);
+    };
+
+    let mut formula = Formula::parse(&reference_sum)?;
+
+    let reference_content = AtomSites::from_block(reference)?.cell_content(reference)?;
+    let content = AtomSites::from_block(block)?.cell_content(block)?;
+
+    let Some(reference_units) = formula_units(reference, &reference_content, &formula) else {
+        log::warn!(
+            "{} of data_{} is not updated, the number of formula units is unknown",
+            FORMULA_SUM_TAG,
+            block_name
+        );
+        return Ok(0);
+    };
+
+    // Operations like supercells change Z, without it the number of formula units is unchanged.
+    let units = match block.value(Z_TAG).as_deref().and_then(parse_number) {
+        Some(z) if z > 0.0 => z,
+        _ => reference_units,
+    };
+
+    let mut updated_elements = HashSet::new();
+
+    for (element, _) in reference_content.elements().chain(content.elements()) {
+        if updated_elements.insert(element.to_string()) {
+            formula.add(
+                element,
+                content.count(element) / units - reference_content.count(element) / reference_units,
+            );
+        }
+    }
+
+    let new_sum = formula.to_string();
+
+    if new_sum == Formula::parse(&sum)?.to_string() {
+        return Ok(0);
+    }
+
+    if block.value(FORMULA_WEIGHT_TAG).is_some() {
+        log::warn!(
+            "{} of data_{} is not updated",
+            FORMULA_WEIGHT_TAG,
+            block_name
+        );
+    }
+
+    block.set_value(FORMULA_SUM_TAG, &new_sum);
+
+    Ok(1 + update_formula_structural(block, &Formula::parse(&sum)?, &formula))
+}
+
+/// Scales the count of every changed element in `_chemical_formula_structural` from the old to the
+/// new formula sum. An element that is added or removed cannot be placed in the text, then the
+/// formula is set to `?`. Returns the number of modified values.
+fn update_formula_structural(block: &mut DataBlock, sum: &Formula, new_sum: &Formula) -> usize {
+    let Some(structural) = block.value(FORMULA_STRUCTURAL_TAG) else {
+        return 0;
+    };
+
+    let mut new_structural = structural.clone();
+    let mut updated_elements = HashSet::new();
+
+    for (element, _) in sum.elements().chain(new_sum.elements()) {
+        let count = sum.count(element);
+        let new_count = new_sum.count(element);
+
+        if !updated_elements.insert(element)
+            || formula::format_count(count) == formula::format_count(new_count)
+        {
+            continue;
+        }
+
+        let substituted =
+            formula::substitute_element(&new_structural, element, &[(element, new_count / count)]);
+
+        if count <= 0.0 || new_count <= 0.0 || substituted == new_structural {
+            log::warn!(
+                "{} of data_{} is set to ?, {} cannot be updated in {}",
+                FORMULA_STRUCTURAL_TAG,
+                block.name().unwrap_or_default(),
+                element,
+                structural
+            );
+            block.set_value(FORMULA_STRUCTURAL_TAG, "?");
+            return 1;
+        }
+
+        new_structural = substituted;
+    }
+
+    if new_structural == structural {
+        return 0;
+    }
+
+    block.set_value(FORMULA_STRUCTURAL_TAG, &new_structural);
+
+    1
+}
+
+/// The number of formula units in the cell: `_cell_formula_units_Z` or, without it, the ratio of
+/// the cell content to the formula.
+fn formula_units(block: &DataBlock, content: &Formula, formula: &Formula) -> Option<f64> {
+    let formula_units = match block.value(Z_TAG).as_deref().and_then(parse_number) {
+        Some(z) => Some(z),
+        None => content
+            .elements()
+            .find(|(element, count)| *count > 0.0 && formula.count(element) > 0.0)
+            .map(|(element, count)| count / formula.count(element)),
+    };
+
+    formula_units.filter(|units| *units > 0.0)
+}
+
+/// Returns `base` or, if another site has that label, `base` with the first free suffix `_2`,
+/// `_3`, … The label is added to `labels`.
+pub(crate) fn unique_label(base: &str, labels: &mut HashSet<String>) -> String {
+    let mut label = base.to_string();
+    let mut index = 2;
+
+    while labels.contains(&label) {
+        label = format!("{}_{}", base, index);
+        index += 1;
+    }
+
+    labels.insert(label.clone());
+    label
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::block;
+
+    #[test]
+    fn test_update_formula_sum() {
+        let mut block = block("tests/BaTiO3.cif");
+
+        let content = Formula::parse("Ba1 Ti1 O3").unwrap();
+        let new_content = Formula::parse("Ba1 Ti1 O2.5").unwrap();
+
+        assert_eq!(
+            update_formula_sum(&mut block, &content, &new_content).unwrap(),
+            1
+        );
+        assert_eq!(
+            block.value(FORMULA_SUM_TAG).as_deref(),
+            Some("Ba1 O2.5 Ti1")
+        );
+    }
+
+    #[test]
+    fn test_update_atom_types() {
+        let mut block = block("tests/BaTiO3.cif");
+
+        let old_sites = AtomSites::from_block(&block).unwrap();
+        let mut sites = old_sites.clone();
+        sites.set_value(1, structure::TYPE_SYMBOL_TAG, "Zr");
+
+        let oxidation_numbers = [("Zr".to_string(), "4".to_string())];
+
+        assert_eq!(
+            update_atom_types(&mut block, &old_sites, &sites, &oxidation_numbers),
+            2
+        );
+        assert_eq!(oxidation_number(&block, "Zr").as_deref(), Some("4"));
+        assert_eq!(oxidation_number(&block, "Ti"), None);
+        assert_eq!(oxidation_number(&block, "O").as_deref(), Some("-2"));
+    }
+
+    #[test]
+    fn test_unique_label() {
+        let mut labels: HashSet<String> = ["O1".to_string(), "O1_2".to_string()].into();
+
+        assert_eq!(unique_label("O2", &mut labels), "O2");
+        assert_eq!(unique_label("O1", &mut labels), "O1_3");
+        assert!(labels.contains("O1_3"));
+    }
+}
@@ -0,0 +1,146 @@
+use std::io::{BufRead, BufReader, Read, Write};
+
+use flate2::{read::MultiGzDecoder, write::GzEncoder};
+use xz2::{read::XzDecoder, write::XzEncoder};
+
+const GZIP_MAGIC_BYTES: [u8; 2] = [0x1f, 0x8b];
+const XZ_MAGIC_BYTES: [u8; 6] = [0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+const XZ_PRESET: u32 = 6;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+}
+
+impl Compression {
+    /// Determines the compression from the file extension of `path`.
+    pub fn from_path(path: &str) -> Self {
+        if path.ends_with(".gz") || path.ends_with(".tgz") {
+            Compression::Gzip
+        } else if path.ends_with(".xz") || path.ends_with(".txz") {
+            Compression::Xz
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Determines the compression from the first bytes of a stream.
+    pub fn from_magic_bytes(bytes: &[u8]) -> Self {
+        if bytes.starts_with(&GZIP_MAGIC_BYTES) {
+            Compression::Gzip
+        } else if bytes.starts_with(&XZ_MAGIC_BYTES) {
+            Compression::Xz
+        } else {
+            Compression::None
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Xz => ".xz",
+        }
+    }
+
+    /// Wraps `writer` so that everything written to it is compressed.
+    ///
+    /// [`CompressedWriter::finish`] has to be called to write the trailer of the compressed stream.
+    pub fn compress<W: Write>(&self, writer: W) -> CompressedWriter<W> {
+        match self {
+            Compression::None => CompressedWriter::None(writer),
+            Compression::Gzip => {
+                CompressedWriter::Gzip(GzEncoder::new(writer, flate2::Compression::default()))
+            }
+            Compression::Xz => CompressedWriter::Xz(XzEncoder::new(writer, XZ_PRESET)),
+        }
+    }
+}
+
+/// Detects the compression of `reader` from its magic bytes and returns a reader of the
+/// decompressed content.
+pub fn decompress<'a>(reader: impl Read + 'a) -> anyhow::Result<(Compression, Box<dyn Read + 'a>)> {
+    let mut reader = BufReader::new(reader);
+
+    let compression = Compression::from_magic_bytes(reader.fill_buf()?);
+
+    let reader: Box<dyn Read + 'a> = match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        Compression::Xz => Box::new(XzDecoder::new_multi_decoder(reader)),
+    };
+
+    Ok((compression, reader))
+}
+
+pub enum CompressedWriter<W: Write> {
+    None(W),
+    Gzip(GzEncoder<W>),
+    Xz(XzEncoder<W>),
+}
+
+impl<W: Write> CompressedWriter<W> {
+    /// Finishes the compressed stream and returns the inner writer.
+    pub fn finish(self) -> std::io::Result<W> {
+        match self {
+            CompressedWriter::None(mut writer) => {
+                writer.flush()?;
+                Ok(writer)
+            }
+            CompressedWriter::Gzip(encoder) => encoder.finish(),
+            CompressedWriter::Xz(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for CompressedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            CompressedWriter::None(writer) => writer.write(buf),
+            CompressedWriter::Gzip(encoder) => encoder.write(buf),
+            CompressedWriter::Xz(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            CompressedWriter::None(writer) => writer.flush(),
+            CompressedWriter::Gzip(encoder) => encoder.flush(),
+            CompressedWriter::Xz(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compression_from_path() {
+        assert_eq!(Compression::from_path("a.cif"), Compression::None);
+        assert_eq!(Compression::from_path("a.cif.gz"), Compression::Gzip);
+        assert_eq!(Compression::from_path("a.cif.xz"), Compression::Xz);
+        assert_eq!(Compression::from_path("a.tgz"), Compression::Gzip);
+    }
+
+    #[test]
+    fn test_compress_and_decompress() {
+        let content = "_cell_length_a  4.0094(2)\n";
+
+        for compression in [Compression::None, Compression::Gzip, Compression::Xz] {
+            let mut writer = compression.compress(Vec::new());
+            writer.write_all(content.as_bytes()).unwrap();
+            let compressed = writer.finish().unwrap();
+
+            let (detected, mut reader) = decompress(compressed.as_slice()).unwrap();
+            let mut decompressed = String::new();
+            reader.read_to_string(&mut decompressed).unwrap();
+
+            assert_eq!(detected, compression);
+            assert_eq!(decompressed, content);
+        }
+    }
+}
@@ -0,0 +1,655 @@
+//! Point defects: vacancies that remove atom sites and interstitials that add them.
+//!
+//! Like substitutions, both keep the `_atom_type_` loop and `_chemical_formula_sum` consistent with
+//! the atom sites. `_cell_formula_units_Z` stays the number of formula units, so the formula
+//! contains the defects.
+
+use std::{collections::HashSet, fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    composition,
+    instructions::matches_selector,
+    linalg::Vector3,
+    structure::{self, AtomSites},
+    symmetry::{self, SymmetryExpansion},
+    DataBlock, Lattice, Species,
+};
+
+const COUNT_OPTION: &str = "count";
+const LABEL_OPTION: &str = "label";
+const OCCUPANCY_OPTION: &str = "occupancy";
+const LIST_SEPARATOR: char = ',';
+
+/// Interstitials closer than this to another atom in Å are reported.
+const CLOSE_CONTACT_DISTANCE: f64 = 1.0;
+
+/// How many of the selected sites a [`Vacancy`] removes.
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum Amount {
+    All,
+    Fraction(f64),
+    Count(usize),
+}
+
+/// Removes atom sites by label or element.
+///
+/// Without an amount every selected site is removed, e.g. all sites labelled `O1` including their
+/// symmetry images. A fraction or a count removes randomly chosen sites, so structures with symmetry
+/// are expanded to P1 first. Selectors that are elements or ions, e.g. `O` or `O2-`, select by
+/// element, others by label, where `*` stands for any sequence of characters.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "VacancySpec", into = "VacancySpec")]
+pub struct Vacancy {
+    sites: Vec<String>,
+    amount: Amount,
+}
+
+impl Vacancy {
+    /// Fails if there are no selectors.
+    pub fn new(sites: Vec<String>) -> anyhow::Result<Self> {
+        if sites.is_empty() {
+            return Err(anyhow::anyhow!("vacancy takes a label or an element"));
+        }
+
+        Ok(Vacancy {
+            sites,
+            amount: Amount::All,
+        })
+    }
+
+    /// Removes a random fraction of the selected sites. Fails if the fraction is not within (0, 1].
+    pub fn with_fraction(mut self, fraction: f64) -> anyhow::Result<Self> {
+        if !(fraction > 0.0 && fraction <= 1.0) {
+            return Err(anyhow::anyhow!(
+                "The vacancy fraction {} has to be within (0, 1]",
+                fraction
+            ));
+        }
+
+        self.amount = Amount::Fraction(fraction);
+        Ok(self)
+    }
+
+    /// Removes `count` randomly chosen sites.
+    pub fn with_count(mut self, count: usize) -> Self {
+        self.amount = Amount::Count(count);
+        self
+    }
+
+    fn is_selected(&self, sites: &AtomSites, row: usize) -> bool {
+        self.sites
+            .iter()
+            .any(|selector| match selector.parse::<Species>() {
+                Ok(species) => species.matches(sites, row),
+                Err(_) => matches_selector(selector, sites.label(row)),
+            })
+    }
+
+    /// Returns whether the removed sites are chosen at random.
+    pub(crate) fn is_random(&self) -> bool {
+        self.amount != Amount::All
+    }
+
+    /// Returns the number of modified values.
+    pub(crate) fn apply_to_block(&self, block: &mut DataBlock) -> anyhow::Result<usize> {
+        if block.find_loop(structure::LABEL_TAG).is_none() {
+            return Ok(0);
+        }
+
+        let block_name = block.name().unwrap_or_default().to_string();
+
+        let mut modified_values_counter = 0;
+
+        if self.amount != Amount::All && !symmetry::is_p1(block) {
+            log::info!(
+                "Expanding data_{} to P1 before choosing vacancies",
+                block_name
+            );
+
+            modified_values_counter += SymmetryExpansion::default().apply_to_block(block)?;
+        }
+
+        let sites = AtomSites::from_block(block)?;
+
+        let rows: Vec<usize> = (0..sites.len())
+            .filter(|row| self.is_selected(&sites, *row))
+            .collect();
+
+        let description = format!("{} sites of data_{}", self.sites.join(","), block_name);
+
+        let removed_rows = match self.amount {
+            Amount::All => rows,
+            Amount::Fraction(fraction) => {
+                let count = structure::fraction_of_sites(fraction, rows.len(), &description);
+                structure::sample_rows(&rows, count)
+            }
+            Amount::Count(count) if count > rows.len() => {
+                return Err(anyhow::anyhow!(
+                    "Cannot remove {} of the {} {}",
+                    count,
+                    rows.len(),
+                    description
+                ));
+            }
+            Amount::Count(count) => structure::sample_rows(&rows, count),
+        };
+
+        if removed_rows.is_empty() {
+            log::warn!("No {} to remove", description);
+            return Ok(modified_values_counter);
+        }
+
+        let content = sites.cell_content(block)?;
+
+        let mut new_sites = sites.without_rows();
+        let mut aniso_labels = Vec::new();
+
+        for row in (0..sites.len()).filter(|row| !removed_rows.contains(row)) {
+            new_sites.push_row(sites.row(row).to_vec());
+
+            let label = sites.label(row).to_string();
+            aniso_labels.push((label.clone(), label));
+        }
+
+        new_sites.write_to(block);
+        structure::relabel_aniso_sites(block, &aniso_labels);
+
+        modified_values_counter += removed_rows.len();
+        modified_values_counter += composition::update_atom_types(block, &sites, &new_sites, &[]);
+
+        let new_content = new_sites.cell_content(block)?;
+        modified_values_counter += composition::update_formula_sum(block, &content, &new_content)?;
+
+        warn_formula_structural(block);
+
+        Ok(modified_values_counter)
+    }
+}
+
+impl FromStr for Vacancy {
+    type Err = anyhow::Error;
+
+    /// Reads e.g. `O1`, `O 0.1` or `O count=2`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+
+        let sites = words
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("vacancy takes a label or an element"))?;
+
+        let mut vacancy = Vacancy::new(parse_list(sites))?;
+
+        for word in words {
+            if vacancy.amount != Amount::All {
+                return Err(anyhow::anyhow!("Unexpected {} in {}", word, s));
+            }
+
+            vacancy = match word.split_once('=') {
+                Some((COUNT_OPTION, count)) => vacancy.with_count(
+                    count
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("{} is not a number of sites", count))?,
+                ),
+                Some(_) => return Err(anyhow::anyhow!("Unknown option {}", word)),
+                None => vacancy.with_fraction(
+                    word.parse()
+                        .map_err(|_| anyhow::anyhow!("{} is not a fraction", word))?,
+                )?,
+            };
+        }
+
+        Ok(vacancy)
+    }
+}
+
+impl Display for Vacancy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.sites.join(","))?;
+
+        match self.amount {
+            Amount::All => Ok(()),
+            Amount::Fraction(fraction) => write!(f, " {}", fraction),
+            Amount::Count(count) => write!(f, " {}={}", COUNT_OPTION, count),
+        }
+    }
+}
+
+/// The structured form of a [`Vacancy`], e.g. `{ sites = ["O"], fraction = 0.1 }`.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VacancySpec {
+    sites: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fraction: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    count: Option<usize>,
+}
+
+impl TryFrom<VacancySpec> for Vacancy {
+    type Error = anyhow::Error;
+
+    fn try_from(spec: VacancySpec) -> Result<Self, Self::Error> {
+        let vacancy = Vacancy::new(spec.sites)?;
+
+        match (spec.fraction, spec.count) {
+            (None, None) => Ok(vacancy),
+            (Some(fraction), None) => vacancy.with_fraction(fraction),
+            (None, Some(count)) => Ok(vacancy.with_count(count)),
+            _ => Err(anyhow::anyhow!(
+                "vacancy takes either a fraction or a count"
+            )),
+        }
+    }
+}
+
+impl From<Vacancy> for VacancySpec {
+    fn from(vacancy: Vacancy) -> Self {
+        let (fraction, count) = match vacancy.amount {
+            Amount::All => (None, None),
+            Amount::Fraction(fraction) => (Some(fraction), None),
+            Amount::Count(count) => (None, Some(count)),
+        };
+
+        VacancySpec {
+            sites: vacancy.sites,
+            fraction,
+            count,
+        }
+    }
+}
+
+/// Adds an atom site at a fractional position.
+///
+/// In a structure with symmetry the new site gets all its symmetry images. The label defaults to
+/// the element followed by `_i`. Columns of the atom sites other than the label, the type symbol, the
+/// position, the occupancy and the multiplicity are unknown.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "InterstitialSpec", into = "InterstitialSpec")]
+pub struct Interstitial {
+    species: Species,
+    position: Vector3,
+    label: Option<String>,
+    occupancy: f64,
+}
+
+impl Interstitial {
+    pub fn new(species: Species, position: Vector3) -> Self {
+        Interstitial {
+            species,
+            position,
+            label: None,
+            occupancy: 1.0,
+        }
+    }
+
+    pub fn with_label(mut self, label: &str) -> Self {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    /// Fails if the occupancy is not within (0, 1].
+    pub fn with_occupancy(mut self, occupancy: f64) -> anyhow::Result<Self> {
+        if !(occupancy > 0.0 && occupancy <= 1.0) {
+            return Err(anyhow::anyhow!(
+                "The occupancy {} has to be within (0, 1]",
+                occupancy
+            ));
+        }
+
+        self.occupancy = occupancy;
+        Ok(self)
+    }
+
+    /// Returns the number of modified values.
+    pub(crate) fn apply_to_block(&self, block: &mut DataBlock) -> anyhow::Result<usize> {
+        if block.find_loop(structure::LABEL_TAG).is_none() {
+            return Ok(0);
+        }
+
+        let block_name = block.name().unwrap_or_default().to_string();
+
+        let lattice = Lattice::from_block(block)?;
+        let operators = symmetry::symmetry_operators(block)?;
+
+        let sites = AtomSites::from_block(block)?;
+        let content = sites.cell_content(block)?;
+
+        let position = structure::wrap(self.position);
+
+        let images: Vec<Vector3> = match operators.is_empty() {
+            true => vec![position],
+            false => symmetry::images(&lattice, &operators, position, symmetry::DEFAULT_TOLERANCE)
+                .into_iter()
+                .map(|(image, _)| image)
+                .collect(),
+        };
+
+        self.warn_close_contacts(&sites, &lattice, &operators, &images, &block_name)?;
+
+        let mut new_sites = sites.clone();
+
+        if self.occupancy != 1.0 && new_sites.column(structure::OCCUPANCY_TAG).is_none() {
+            new_sites.push_column(structure::OCCUPANCY_TAG, "1");
+        }
+
+        let mut labels: HashSet<String> = (0..sites.len())
+            .map(|row| sites.label(row).to_string())
+            .collect();
+
+        let base = match &self.label {
+            Some(label) => label.clone(),
+            None => format!("{}_i", self.species.element()),
+        };
+        let label = composition::unique_label(&base, &mut labels);
+
+        if self.label.as_ref().is_some_and(|given| *given != label) {
+            log::warn!(
+                "data_{} already has a site {}, the interstitial is labelled {}",
+                block_name,
+                base,
+                label
+            );
+        }
+
+        let row = new_sites.push_row(vec!["?".to_string(); new_sites.row(0).len()]);
+
+        new_sites.set_label(row, &label);
+        new_sites.set_value(row, structure::TYPE_SYMBOL_TAG, &self.species.to_string());
+        new_sites.set_value(row, structure::OCCUPANCY_TAG, &self.occupancy.to_string());
+        new_sites.set_value(row, symmetry::MULTIPLICITY_TAG, &images.len().to_string());
+        new_sites.set_position(row, position);
+
+        new_sites.write_to(block);
+
+        let oxidation_numbers: Vec<(String, String)> = self
+            .species
+            .oxidation_number()
+            .map(|oxidation_number| (self.species.to_string(), oxidation_number.to_string()))
+            .into_iter()
+            .collect();
+
+        let mut modified_values_counter = new_sites.row(row).len();
+        modified_values_counter +=
+            composition::update_atom_types(block, &sites, &new_sites, &oxidation_numbers);
+
+        let new_content = new_sites.cell_content(block)?;
+        modified_values_counter += composition::update_formula_sum(block, &content, &new_content)?;
+
+        warn_formula_structural(block);
+
+        Ok(modified_values_counter)
+    }
+
+    /// Warns if an image of the interstitial is closer than [`CLOSE_CONTACT_DISTANCE`] to an atom.
+    fn warn_close_contacts(
+        &self,
+        sites: &AtomSites,
+        lattice: &Lattice,
+        operators: &[symmetry::SymmetryOperator],
+        images: &[Vector3],
+        block_name: &str,
+    ) -> anyhow::Result<()> {
+        for row in 0..sites.len() {
+            let position = sites.position(row)?;
+
+            let site_images: Vec<Vector3> = match operators.is_empty() {
+                true => vec![position],
+                false => {
+                    symmetry::images(lattice, operators, position, symmetry::DEFAULT_TOLERANCE)
+                        .into_iter()
+                        .map(|(image, _)| image)
+                        .collect()
+                }
+            };
+
+            let distance = images
+                .iter()
+                .flat_map(|image| {
+                    site_images.iter().map(|site_image| {
+                        structure::periodic_distance(lattice, *image, *site_image)
+                    })
+                })
+                .fold(f64::INFINITY, f64::min);
+
+            if distance < CLOSE_CONTACT_DISTANCE {
+                log::warn!(
+                    "The interstitial {} in data_{} is {:.3} Å from {}",
+                    self.species,
+                    block_name,
+                    distance,
+                    sites.label(row)
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Interstitial {
+    type Err = anyhow::Error;
+
+    /// Reads e.g. `Li 0.25 0.25 0.5` or `Li+ 0.25 0.25 0.5 label=Li9 occupancy=0.5`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+
+        let species: Species = words
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("interstitial takes an element and a position"))?
+            .parse()?;
+
+        let mut position = [0.0; 3];
+
+        for coordinate in &mut position {
+            let word = words.next().ok_or_else(|| {
+                anyhow::anyhow!("interstitial takes three fractional coordinates")
+            })?;
+
+            *coordinate = symmetry::parse_fraction(word)?;
+        }
+
+        let mut interstitial = Interstitial::new(species, position);
+
+        for option in words {
+            interstitial = match option.split_once('=') {
+                Some((LABEL_OPTION, label)) => interstitial.with_label(label),
+                Some((OCCUPANCY_OPTION, occupancy)) => interstitial.with_occupancy(
+                    occupancy
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("{} is not an occupancy", occupancy))?,
+                )?,
+                _ => return Err(anyhow::anyhow!("Unknown option {}", option)),
+            };
+        }
+
+        Ok(interstitial)
+    }
+}
+
+impl Display for Interstitial {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [x, y, z] = self.position;
+
+        write!(f, "{} {} {} {}", self.species, x, y, z)?;
+
+        if let Some(label) = &self.label {
+            write!(f, " {}={}", LABEL_OPTION, label)?;
+        }
+
+        if self.occupancy != 1.0 {
+            write!(f, " {}={}", OCCUPANCY_OPTION, self.occupancy)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The structured form of an [`Interstitial`], e.g. `{ species = "Li", position = [0.25, 0.25, 0.5] }`.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InterstitialSpec {
+    species: Species,
+    position: Vector3,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    occupancy: Option<f64>,
+}
+
+impl TryFrom<InterstitialSpec> for Interstitial {
+    type Error = anyhow::Error;
+
+    fn try_from(spec: InterstitialSpec) -> Result<Self, Self::Error> {
+        let mut interstitial = Interstitial::new(spec.species, spec.position);
+
+        if let Some(label) = &spec.label {
+            interstitial = interstitial.with_label(label);
+        }
+
+        match spec.occupancy {
+            Some(occupancy) => interstitial.with_occupancy(occupancy),
+            None => Ok(interstitial),
+        }
+    }
+}
+
+impl From<Interstitial> for InterstitialSpec {
+    fn from(interstitial: Interstitial) -> Self {
+        InterstitialSpec {
+            species: interstitial.species,
+            position: interstitial.position,
+            label: interstitial.label,
+            occupancy: (interstitial.occupancy != 1.0).then_some(interstitial.occupancy),
+        }
+    }
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value.split(LIST_SEPARATOR).map(str::to_string).collect()
+}
+
+fn warn_formula_structural(block: &DataBlock) {
+    if block.value(composition::FORMULA_STRUCTURAL_TAG).is_some() {
+        log::warn!(
+            "{} of data_{} is not updated",
+            composition::FORMULA_STRUCTURAL_TAG,
+            block.name().unwrap_or_default()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::composition::{FORMULA_SUM_TAG, TYPE_TAG, Z_TAG};
+    use crate::test_utils::block;
+
+    #[test]
+    fn test_vacancy_by_label() {
+        let mut block = block("tests/BaTiO3.cif");
+
+        let vacancy: Vacancy = "Ti1".parse().unwrap();
+        vacancy.apply_to_block(&mut block).unwrap();
+
+        let sites = AtomSites::from_block(&block).unwrap();
+        assert_eq!(sites.len(), 2);
+        assert_eq!(sites.label(1), "O1");
+
+        let type_symbols: Vec<String> = block
+            .find_loop(TYPE_TAG)
+            .unwrap()
+            .rows()
+            .into_iter()
+            .map(|row| row[0].clone())
+            .collect();
+        assert_eq!(type_symbols, ["Ba", "O"]);
+
+        assert_eq!(block.value(FORMULA_SUM_TAG).as_deref(), Some("Ba1 O3"));
+    }
+
+    #[test]
+    fn test_random_vacancies() {
+        let mut block = block("tests/BaTiO3.cif");
+
+        crate::Supercell::diagonal(2, 2, 2)
+            .unwrap()
+            .apply_to_block(&mut block)
+            .unwrap();
+
+        let vacancy: Vacancy = "O count=2".parse().unwrap();
+        vacancy.apply_to_block(&mut block).unwrap();
+
+        let sites = AtomSites::from_block(&block).unwrap();
+        assert_eq!(sites.len(), 38);
+        assert_eq!(sites.cell_content(&block).unwrap().count("O"), 22.0);
+        assert_eq!(block.value(Z_TAG).as_deref(), Some("8"));
+        assert_eq!(
+            block.value(FORMULA_SUM_TAG).as_deref(),
+            Some("Ba1 O2.75 Ti1")
+        );
+
+        let vacancy: Vacancy = "O 0.5".parse().unwrap();
+        vacancy.apply_to_block(&mut block).unwrap();
+        assert_eq!(AtomSites::from_block(&block).unwrap().len(), 27);
+
+        assert!("O count=30"
+            .parse::<Vacancy>()
+            .unwrap()
+            .apply_to_block(&mut block)
+            .is_err());
+    }
+
+    #[test]
+    fn test_interstitial() {
+        let mut block = block("tests/BaTiO3.cif");
+
+        let interstitial: Interstitial = "Li+ 0.5 0 0 occupancy=0.5".parse().unwrap();
+        interstitial.apply_to_block(&mut block).unwrap();
+
+        let sites = AtomSites::from_block(&block).unwrap();
+        assert_eq!(sites.len(), 4);
+        assert_eq!(sites.label(3), "Li_i");
+        assert_eq!(sites.value(3, structure::TYPE_SYMBOL_TAG), Some("Li+"));
+        assert_eq!(sites.value(3, symmetry::MULTIPLICITY_TAG), Some("3"));
+        assert_eq!(sites.position(3).unwrap(), [0.5, 0.0, 0.0]);
+
+        assert_eq!(
+            composition::oxidation_number(&block, "Li+").as_deref(),
+            Some("1")
+        );
+        assert_eq!(
+            block.value(FORMULA_SUM_TAG).as_deref(),
+            Some("Ba1 Li1.5 O3 Ti1")
+        );
+    }
+
+    #[test]
+    fn test_defects_from_str() {
+        let vacancy: Vacancy = "O1,O2".parse().unwrap();
+        assert_eq!(vacancy.to_string(), "O1,O2");
+
+        let vacancy: Vacancy = "O 0.25".parse().unwrap();
+        assert_eq!(vacancy.to_string(), "O 0.25");
+
+        let vacancy: Vacancy = serde_json::from_str(r#"{"sites": ["O"], "count": 2}"#).unwrap();
+        assert_eq!(vacancy.to_string(), "O count=2");
+
+        assert!("".parse::<Vacancy>().is_err());
+        assert!("O 1.5".parse::<Vacancy>().is_err());
+        assert!("O 0.5 count=2".parse::<Vacancy>().is_err());
+        assert!(serde_json::from_str::<Vacancy>(
+            r#"{"sites": ["O"], "count": 2, "fraction": 0.5}"#
+        )
+        .is_err());
+
+        let interstitial: Interstitial = "Li 0.25 0.25 1/2 label=Li9".parse().unwrap();
+        assert_eq!(interstitial.to_string(), "Li 0.25 0.25 0.5 label=Li9");
+
+        assert!("Li 0.25 0.25".parse::<Interstitial>().is_err());
+        assert!("Li 0.25 0.25 0.5 occupancy=2"
+            .parse::<Interstitial>()
+            .is_err());
+    }
+}
@@ -0,0 +1,519 @@
+//! CIF dictionaries in DDL1 and DDLm.
+//!
+//! DDL1 dictionaries, e.g. `cif_core.dic` version 2, define every tag in its own data block with
+//! `_name`, `_type`, `_list`, `_enumeration_range` and `_enumeration`. DDLm dictionaries, e.g.
+//! `cif_core.dic` version 3, define tags in save frames with `_definition.id`, `_type.contents`,
+//! `_enumeration.range`, `_enumeration_set.state` and `_alias.definition_id`. Attributes imported
+//! with `_import.get` from template files next to the dictionary are resolved.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::{cif::parse_number, tags::TagKind, Cif, DataBlock};
+
+/// Values that stand for unknown (`?`) and inapplicable (`.`) and are valid for every tag.
+const NULL_VALUES: [&str; 2] = ["?", "."];
+
+/// Whether a tag has to appear in a loop.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub enum List {
+    Required,
+    #[default]
+    Forbidden,
+    Allowed,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Definition {
+    pub name: String,
+    pub aliases: Vec<String>,
+    pub category: String,
+    pub kind: TagKind,
+    pub list: List,
+    /// Allowed values. Empty if any value is allowed.
+    pub enumeration: Vec<String>,
+    /// Inclusive lower bound of numeric values.
+    pub minimum: Option<f64>,
+    /// Inclusive upper bound of numeric values.
+    pub maximum: Option<f64>,
+}
+
+impl Definition {
+    fn new(name: &str) -> Self {
+        Definition {
+            name: name.to_string(),
+            aliases: Vec::new(),
+            category: String::new(),
+            kind: TagKind::Char,
+            list: List::default(),
+            enumeration: Vec::new(),
+            minimum: None,
+            maximum: None,
+        }
+    }
+
+    /// Checks a single value against the type, enumeration and range of the definition.
+    pub fn check_value(&self, value: &str) -> anyhow::Result<()> {
+        if NULL_VALUES.contains(&value) {
+            return Ok(());
+        }
+
+        if !self.enumeration.is_empty()
+            && !self
+                .enumeration
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(value))
+        {
+            return Err(anyhow::anyhow!(
+                "{} is not one of the allowed values of {}: {}",
+                value,
+                self.name,
+                self.enumeration.join(", ")
+            ));
+        }
+
+        if self.kind == TagKind::Char {
+            return Ok(());
+        }
+
+        let number = parse_number(value)
+            .ok_or_else(|| anyhow::anyhow!("{} of {} is not a number", value, self.name))?;
+
+        let is_too_small = self.minimum.is_some_and(|minimum| number < minimum);
+        let is_too_large = self.maximum.is_some_and(|maximum| number > maximum);
+
+        if !number.is_finite() || is_too_small || is_too_large {
+            return Err(anyhow::anyhow!(
+                "{} of {} is outside the allowed range {}:{}",
+                value,
+                self.name,
+                self.minimum.map(|m| m.to_string()).unwrap_or_default(),
+                self.maximum.map(|m| m.to_string()).unwrap_or_default()
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Clone, Default)]
+pub struct Dictionary {
+    definitions: Vec<Definition>,
+    by_name: HashMap<String, usize>,
+}
+
+impl std::fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Dictionary with {} definitions", self.definitions.len())
+    }
+}
+
+impl Dictionary {
+    pub fn from_file(path: &str) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read dictionary {}", path))?;
+
+        let directory = Path::new(path)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+
+        Self::parse(&content, &directory).with_context(|| format!("Invalid dictionary {}", path))
+    }
+
+    /// Parses a dictionary. Files imported by DDLm definitions are resolved against `directory`.
+    pub fn parse(content: &str, directory: &Path) -> anyhow::Result<Self> {
+        let cif = Cif::parse(content)?;
+
+        let mut dictionary = Dictionary::default();
+
+        let mut imports = Imports {
+            directory: directory.to_path_buf(),
+            files: HashMap::new(),
+        };
+
+        let mut categories = HashMap::new();
+
+        for block in cif.blocks() {
+            dictionary.read_ddl1_block(block);
+
+            for frame in block.frames() {
+                match frame.value("_definition.scope") {
+                    Some(scope) if scope.eq_ignore_ascii_case("category") => {
+                        if let (Some(id), Some(class)) = (
+                            frame.value("_definition.id"),
+                            frame.value("_definition.class"),
+                        ) {
+                            categories.insert(id.to_lowercase(), class.to_lowercase());
+                        }
+                    }
+                    _ => dictionary.read_ddlm_frame(frame, &mut imports)?,
+                }
+            }
+        }
+
+        // Items of DDLm loop categories may appear in a loop or, with a single row, outside of one.
+        for definition in &mut dictionary.definitions {
+            if let Some(class) = categories.get(&definition.category) {
+                definition.list = match class.as_str() {
+                    "loop" => List::Allowed,
+                    _ => List::Forbidden,
+                };
+            }
+        }
+
+        if dictionary.definitions.is_empty() {
+            return Err(anyhow::anyhow!("No definitions found"));
+        }
+
+        Ok(dictionary)
+    }
+
+    pub fn definitions(&self) -> &[Definition] {
+        &self.definitions
+    }
+
+    /// Looks up a tag by its name or an alias. Tags that are known under other names in the
+    /// [`crate::registry`] are found under these names as well.
+    pub fn get(&self, tag: &str) -> Option<&Definition> {
+        let index = self.by_name.get(&tag.to_lowercase()).or_else(|| {
+            crate::tags::registry()
+                .get(tag)?
+                .names()
+                .find_map(|name| self.by_name.get(&name.to_lowercase()))
+        })?;
+
+        Some(&self.definitions[*index])
+    }
+
+    /// Checks the values and loop membership of `tags` in a data block.
+    ///
+    /// Tags that are not defined by the dictionary are an error. Tags that do not occur in the block
+    /// are skipped.
+    pub fn validate_block(&self, block: &DataBlock, tags: &[&str]) -> anyhow::Result<()> {
+        let block_name = block.name().unwrap_or_default();
+
+        for &tag in tags {
+            let definition = self
+                .get(tag)
+                .ok_or_else(|| anyhow::anyhow!("{} is not defined in the dictionary", tag))?;
+
+            if let Some(value) = block.find_value(tag) {
+                if definition.list == List::Required {
+                    return Err(anyhow::anyhow!(
+                        "{} has to be in a loop in data_{}",
+                        tag,
+                        block_name
+                    ));
+                }
+
+                definition
+                    .check_value(&value.value())
+                    .with_context(|| format!("Invalid value in data_{}", block_name))?;
+            } else if let Some(l) = block.find_loop(tag) {
+                if definition.list == List::Forbidden {
+                    return Err(anyhow::anyhow!(
+                        "{} must not be in a loop in data_{}",
+                        tag,
+                        block_name
+                    ));
+                }
+
+                if let Some(other) = l.tags().iter().find(|other| {
+                    self.get(other)
+                        .is_some_and(|other| other.category != definition.category)
+                }) {
+                    return Err(anyhow::anyhow!(
+                        "{} and {} belong to different categories but share a loop in data_{}",
+                        tag,
+                        other,
+                        block_name
+                    ));
+                }
+
+                let column = l.column(tag).expect("The loop contains the tag");
+
+                for row in 0..l.len() {
+                    definition
+                        .check_value(&l.value(row, column))
+                        .with_context(|| {
+                            format!("Invalid value in row {} of data_{}", row + 1, block_name)
+                        })?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn push(&mut self, definition: Definition) {
+        let index = self.definitions.len();
+
+        for name in std::iter::once(&definition.name).chain(&definition.aliases) {
+            self.by_name.insert(name.to_lowercase(), index);
+        }
+
+        self.definitions.push(definition);
+    }
+
+    /// Reads the definitions of a DDL1 data block. A block can define several tags with a looped
+    /// `_name`.
+    fn read_ddl1_block(&mut self, block: &DataBlock) {
+        let names = values(block, "_name");
+
+        let kind = match block.value("_type").as_deref() {
+            Some("numb") => TagKind::Numeric,
+            _ => TagKind::Char,
+        };
+
+        // Category overviews have the type null and define no tag.
+        if names.is_empty() || block.value("_type").as_deref() == Some("null") {
+            return;
+        }
+
+        let list = match block.value("_list").as_deref() {
+            Some("yes") => List::Required,
+            Some("both") => List::Allowed,
+            _ => List::Forbidden,
+        };
+
+        let (minimum, maximum) = block
+            .value("_enumeration_range")
+            .map(|range| parse_range(&range))
+            .unwrap_or_default();
+
+        for name in names {
+            self.push(Definition {
+                category: block.value("_category").unwrap_or_default().to_lowercase(),
+                kind,
+                list,
+                enumeration: values(block, "_enumeration"),
+                minimum,
+                maximum,
+                ..Definition::new(&name)
+            });
+        }
+    }
+
+    fn read_ddlm_frame(&mut self, frame: &DataBlock, imports: &mut Imports) -> anyhow::Result<()> {
+        let Some(name) = frame.value("_definition.id") else {
+            return Ok(());
+        };
+
+        let mut definition = Definition::new(&name);
+
+        if let Some(import) = frame.value("_import.get") {
+            for (file, save) in parse_imports(&import) {
+                let template = imports.frame(&file, &save)?;
+
+                read_ddlm_attributes(&template, &mut definition);
+            }
+        }
+
+        read_ddlm_attributes(frame, &mut definition);
+
+        definition.aliases = values(frame, "_alias.definition_id");
+
+        // `_name.category_id` is missing in some definitions, but the id is `_category.object`.
+        if definition.category.is_empty() {
+            if let Some((category, _)) = name.trim_start_matches('_').split_once('.') {
+                definition.category = category.to_lowercase();
+            }
+        }
+
+        self.push(definition);
+
+        Ok(())
+    }
+}
+
+/// Save frames of imported template files, loaded on first use.
+struct Imports {
+    directory: PathBuf,
+    files: HashMap<String, Cif>,
+}
+
+impl Imports {
+    fn frame(&mut self, file: &str, save: &str) -> anyhow::Result<DataBlock> {
+        if !self.files.contains_key(file) {
+            let path = self.directory.join(file);
+
+            let content = std::fs::read_to_string(&path)
+                .with_context(|| format!("Could not read imported file {}", path.display()))?;
+
+            self.files.insert(file.to_string(), Cif::parse(&content)?);
+        }
+
+        self.files[file]
+            .blocks()
+            .iter()
+            .flat_map(DataBlock::frames)
+            .find(|frame| frame.name().is_some_and(|n| n.eq_ignore_ascii_case(save)))
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Save frame {} not found in {}", save, file))
+    }
+}
+
+fn read_ddlm_attributes(frame: &DataBlock, definition: &mut Definition) {
+    if let Some(category) = frame.value("_name.category_id") {
+        definition.category = category.to_lowercase();
+    }
+
+    if let Some(contents) = frame.value("_type.contents") {
+        definition.kind = match contents.to_lowercase().as_str() {
+            "real" | "integer" | "count" | "index" => TagKind::Numeric,
+            _ => TagKind::Char,
+        };
+    }
+
+    if let Some(range) = frame.value("_enumeration.range") {
+        (definition.minimum, definition.maximum) = parse_range(&range);
+    }
+
+    let enumeration = values(frame, "_enumeration_set.state");
+
+    if !enumeration.is_empty() {
+        definition.enumeration = enumeration;
+    }
+}
+
+/// The values of a tag, whether it is looped or not.
+fn values(block: &DataBlock, tag: &str) -> Vec<String> {
+    if let Some(value) = block.value(tag) {
+        return vec![value];
+    }
+
+    match block.find_loop(tag) {
+        Some(l) => {
+            let column = l.column(tag).expect("The loop contains the tag");
+
+            (0..l.len()).map(|row| l.value(row, column)).collect()
+        }
+        None => Vec::new(),
+    }
+}
+
+/// Parses a range like `0.0:180.0`, `0.0:` or `:10`.
+fn parse_range(range: &str) -> (Option<f64>, Option<f64>) {
+    match range.split_once(':') {
+        Some((minimum, maximum)) => (parse_number(minimum), parse_number(maximum)),
+        None => (parse_number(range), parse_number(range)),
+    }
+}
+
+/// Returns the file and save frame of every table in an import list like
+/// `[{'file':templ_attr.cif 'save':cell_length}]`.
+fn parse_imports(import: &str) -> Vec<(String, String)> {
+    let entry = |table: &str, key: &str| {
+        let start = table.find(key)? + key.len();
+
+        let rest = table[start..].trim_start_matches([' ', '\'', '"']);
+
+        let end = rest
+            .find(|c: char| c.is_whitespace() || c == '\'' || c == '"' || c == '}')
+            .unwrap_or(rest.len());
+
+        Some(rest[..end].to_string())
+    };
+
+    import
+        .split('{')
+        .skip(1)
+        .filter_map(|table| {
+            Some((
+                entry(table, "file':").or_else(|| entry(table, "file\":"))?,
+                entry(table, "save':").or_else(|| entry(table, "save\":"))?,
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::block;
+
+    #[test]
+    fn test_ddl1_dictionary() {
+        let dictionary = Dictionary::from_file("tests/cif_core_ddl1.dic").unwrap();
+
+        let definition = dictionary.get("_cell_length_b").unwrap();
+        assert_eq!(definition.kind, TagKind::Numeric);
+        assert_eq!(definition.list, List::Forbidden);
+        assert_eq!(definition.minimum, Some(0.0));
+
+        assert_eq!(
+            dictionary.get("_atom_site_occupancy").unwrap().list,
+            List::Required
+        );
+
+        // Found through the DDLm name of the tag registry.
+        assert!(dictionary.get("_cell.length_a").is_some());
+        assert!(dictionary.get("_atom_site_category_overview").is_none());
+
+        let adp_type = dictionary.get("_atom_site_adp_type").unwrap();
+        assert!(adp_type.check_value("Uani").is_ok());
+        assert!(adp_type.check_value("Uxyz").is_err());
+    }
+
+    #[test]
+    fn test_ddlm_dictionary_with_imports() {
+        let dictionary = Dictionary::from_file("tests/cif_core_ddlm.dic").unwrap();
+
+        let definition = dictionary.get("_cell_length_a").unwrap();
+        assert_eq!(definition.name, "_cell.length_a");
+        assert_eq!(definition.kind, TagKind::Numeric);
+        assert_eq!(definition.minimum, Some(0.0));
+        assert_eq!(definition.list, List::Forbidden);
+
+        let definition = dictionary.get("_symmetry_space_group_name_H-M").unwrap();
+        assert_eq!(definition.name, "_space_group.name_H-M_alt");
+        assert_eq!(definition.kind, TagKind::Char);
+
+        let definition = dictionary.get("_atom_site_occupancy").unwrap();
+        assert_eq!(definition.list, List::Allowed);
+        assert_eq!(definition.maximum, Some(1.0));
+    }
+
+    #[test]
+    fn test_check_value() {
+        let dictionary = Dictionary::from_file("tests/cif_core_ddl1.dic").unwrap();
+
+        let length = dictionary.get("_cell_length_a").unwrap();
+        assert!(length.check_value("4.0094(2)").is_ok());
+        assert!(length.check_value("?").is_ok());
+        assert!(length.check_value("-1.2").is_err());
+        assert!(length.check_value("long").is_err());
+        assert!(length.check_value("inf").is_err());
+    }
+
+    #[test]
+    fn test_validate_block() {
+        let dictionary = Dictionary::from_file("tests/cif_core_ddl1.dic").unwrap();
+
+        let block = &mut block("tests/BaTiO3.cif");
+
+        let tags = ["_cell_length_a", "_atom_site_occupancy"];
+
+        assert!(dictionary.validate_block(block, &tags).is_ok());
+        assert!(dictionary.validate_block(block, &["_unknown_tag"]).is_err());
+
+        let atom_sites = block.find_loop_mut("_atom_site_occupancy").unwrap();
+        let column = atom_sites.column("_atom_site_occupancy").unwrap();
+        atom_sites.set_value(1, column, "1.2");
+
+        assert!(dictionary.validate_block(block, &tags).is_err());
+    }
+
+    #[test]
+    fn test_parse_imports() {
+        assert_eq!(
+            parse_imports("[{'file':templ_attr.cif  'save':cell_length}]"),
+            vec![("templ_attr.cif".to_string(), "cell_length".to_string())]
+        );
+    }
+}
@@ -0,0 +1,1020 @@
+//! Simulated powder X-ray diffraction patterns of the atom sites, e.g. to compare the peaks of a
+//! structure before and after a modification.
+//!
+//! Structure factors use the Cromer–Mann coefficients of the neutral atoms, the occupancies and the
+//! isotropic displacement parameters of the sites and all their symmetry images. Peak intensities
+//! include the multiplicity and the Lorentz-polarisation factor of an unpolarised beam and are
+//! scaled to 100 for the strongest peak.
+//!
+//! Reflection lists only need the cell and the symmetry operators, whose extinction rules remove the
+//! systematic absences.
+
+use std::{
+    f64::consts::{LN_2, PI},
+    fmt::Display,
+    io::Read,
+};
+
+use serde::Serialize;
+
+use crate::{
+    adp, decompress,
+    linalg::{self, Vector3},
+    structure::{self, AtomSites},
+    symmetry::{self, SymmetryOperator},
+    Cif, DataBlock, Instructions, Lattice,
+};
+
+/// Cu Kα1 in Å.
+pub const DEFAULT_WAVELENGTH: f64 = 1.5406;
+
+/// The 2θ range in degrees of a pattern by default.
+pub const DEFAULT_TWO_THETA_RANGE: (f64, f64) = (5.0, 90.0);
+
+/// Kα1 wavelengths in Å of common anodes.
+const ANODES: [(&str, f64); 6] = [
+    ("Cu", 1.5406),
+    ("Mo", 0.70930),
+    ("Co", 1.78896),
+    ("Fe", 1.93604),
+    ("Cr", 2.28970),
+    ("Ag", 0.55941),
+];
+
+/// Cromer–Mann coefficients a1, b1, a2, b2, a3, b3, a4, b4, c of the neutral atoms from the
+/// International Tables for Crystallography, Vol. C, Table 6.1.1.4. f(s) = Σ ai exp(-bi s²) + c with
+/// s = sin θ / λ.
+#[rustfmt::skip]
+const SCATTERING_FACTORS: [(&str, [f64; 9]); 32] = [
+    ("H", [0.489918, 20.6593, 0.262003, 7.74039, 0.196767, 49.5519, 0.049879, 2.20159, 0.001305]),
+    ("He", [0.8734, 9.1037, 0.6309, 3.3568, 0.3112, 22.9276, 0.178, 0.9821, 0.0064]),
+    ("Li", [1.1282, 3.9546, 0.7508, 1.0524, 0.6175, 85.3905, 0.4653, 168.261, 0.0377]),
+    ("Be", [1.5919, 43.6427, 1.1278, 1.8623, 0.5391, 103.483, 0.7029, 0.542, 0.0385]),
+    ("B", [2.0545, 23.2185, 1.3326, 1.021, 1.0979, 60.3498, 0.7068, 0.1403, -0.1932]),
+    ("C", [2.31, 20.8439, 1.02, 10.2075, 1.5886, 0.5687, 0.865, 51.6512, 0.2156]),
+    ("N", [12.2126, 0.0057, 3.1322, 9.8933, 2.0125, 28.9975, 1.1663, 0.5826, -11.529]),
+    ("O", [3.0485, 13.2771, 2.2868, 5.7011, 1.5463, 0.3239, 0.867, 32.9089, 0.2508]),
+    ("F", [3.5392, 10.2825, 2.6412, 4.2944, 1.517, 0.2615, 1.0243, 26.1476, 0.2776]),
+    ("Na", [4.7626, 3.285, 3.1736, 8.8422, 1.2674, 0.3136, 1.1128, 129.424, 0.676]),
+    ("Mg", [5.4204, 2.8275, 2.1735, 79.2611, 1.2269, 0.3808, 2.3073, 7.1937, 0.8584]),
+    ("Al", [6.4202, 3.0387, 1.9002, 0.7426, 1.5936, 31.5472, 1.9646, 85.0886, 1.1151]),
+    ("Si", [6.2915, 2.4386, 3.0353, 32.3337, 1.9891, 0.6785, 1.541, 81.6937, 1.1407]),
+    ("P", [6.4345, 1.9067, 4.1791, 27.157, 1.78, 0.526, 1.4908, 68.1645, 1.1149]),
+    ("S", [6.9053, 1.4679, 5.2034, 22.2151, 1.4379, 0.2536, 1.5863, 56.172, 0.8669]),
+    ("Cl", [11.4604, 0.0104, 7.1964, 1.1662, 6.2556, 18.5194, 1.6455, 47.7784, -9.5574]),
+    ("K", [8.2186, 12.7949, 7.4398, 0.7748, 1.0519, 213.187, 0.8659, 41.6841, 1.4228]),
+    ("Ca", [8.6266, 10.4421, 7.3873, 0.6599, 1.5899, 85.7484, 1.0211, 178.437, 1.3751]),
+    ("Ti", [9.7595, 7.8508, 7.3558, 0.5, 1.6991, 35.6338, 1.9021, 116.105, 1.2807]),
+    ("V", [10.2971, 6.8657, 7.3511, 0.4385, 2.0703, 26.8938, 2.0571, 102.478, 1.2199]),
+    ("Cr", [10.6406, 6.1038, 7.3537, 0.392, 3.324, 20.2626, 1.4922, 98.7399, 1.1832]),
+    ("Mn", [11.2819, 5.3409, 7.3573, 0.3432, 3.0193, 17.8674, 2.2441, 83.7543, 1.0896]),
+    ("Fe", [11.7695, 4.7611, 7.3573, 0.3072, 3.5222, 15.3535, 2.3045, 76.8805, 1.0369]),
+    ("Co", [12.2841, 4.2791, 7.3409, 0.2784, 4.0034, 13.5359, 2.3488, 71.1692, 1.0118]),
+    ("Ni", [12.8376, 3.8785, 7.292, 0.2565, 4.4438, 12.1763, 2.38, 66.3421, 1.0341]),
+    ("Cu", [13.338, 3.5828, 7.1676, 0.247, 5.6158, 11.3966, 1.6735, 64.8126, 1.191]),
+    ("Zn", [14.0743, 3.2655, 7.0318, 0.2333, 5.1652, 10.3163, 2.41, 58.7097, 1.3041]),
+    ("Sr", [17.5663, 1.5564, 9.8184, 14.0988, 5.422, 0.1664, 2.6694, 132.376, 2.5064]),
+    ("Zr", [17.8765, 1.27618, 10.948, 11.916, 5.41732, 0.117622, 3.65721, 87.6627, 2.06929]),
+    ("Nb", [17.6142, 1.18865, 12.0144, 11.766, 4.04183, 0.204785, 3.53346, 69.7957, 3.75591]),
+    ("Ba", [20.3361, 3.216, 19.297, 0.2756, 10.888, 20.2073, 2.6959, 167.202, 2.7731]),
+    ("La", [20.578, 2.94817, 19.599, 0.244475, 11.3727, 18.7726, 3.28719, 133.124, 2.14678]),
+];
+
+/// Reflections whose d-spacings differ by less than this in Å form one peak.
+const SAME_PEAK_D_SPACING: f64 = 1e-5;
+
+/// Peaks weaker than this fraction of the strongest peak are systematic absences.
+const ABSENT_INTENSITY: f64 = 1e-8;
+
+/// Tolerance of the indices and phases in the extinction rules.
+const EXTINCTION_TOLERANCE: f64 = 1e-6;
+
+/// The intensity of the strongest peak.
+const MAX_INTENSITY: f64 = 100.0;
+
+/// Reads a wavelength in Å or the name of an anode for its Kα1 wavelength, e.g. `Cu` or `0.7093`.
+pub fn parse_wavelength(s: &str) -> anyhow::Result<f64> {
+    if let Some((_, wavelength)) = ANODES
+        .iter()
+        .find(|(anode, _)| anode.eq_ignore_ascii_case(s))
+    {
+        return Ok(*wavelength);
+    }
+
+    match s.parse::<f64>() {
+        Ok(wavelength) if wavelength > 0.0 && wavelength.is_finite() => Ok(wavelength),
+        _ => Err(anyhow::anyhow!(
+            "Invalid wavelength {}. Use a positive number in Å or one of {}",
+            s,
+            ANODES.map(|(anode, _)| anode).join(", ")
+        )),
+    }
+}
+
+/// A diffraction peak: all reflections with the same d-spacing.
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub struct Peak {
+    /// The indices of one of the reflections, preferably without negative indices.
+    pub hkl: [i64; 3],
+    pub multiplicity: usize,
+    /// The d-spacing in Å.
+    pub d: f64,
+    /// The diffraction angle 2θ in degrees.
+    pub two_theta: f64,
+    pub intensity: f64,
+}
+
+/// The shape of the peaks of a pattern.
+#[derive(PartialEq, Debug, Clone, Copy, Serialize)]
+pub enum Profile {
+    Gaussian,
+    /// The mixing ratio η of a Lorentzian and a Gaussian with the same width.
+    PseudoVoigt(f64),
+}
+
+impl Profile {
+    /// The profile of unit area at `x` from the centre of the peak.
+    fn value(&self, x: f64, fwhm: f64) -> f64 {
+        let gaussian =
+            (4.0 * LN_2 / PI).sqrt() / fwhm * (-4.0 * LN_2 * x * x / (fwhm * fwhm)).exp();
+        let lorentzian = 2.0 / (PI * fwhm) / (1.0 + 4.0 * x * x / (fwhm * fwhm));
+
+        match self {
+            Profile::Gaussian => gaussian,
+            Profile::PseudoVoigt(eta) => eta * lorentzian + (1.0 - eta) * gaussian,
+        }
+    }
+}
+
+/// The wavelength and 2θ range of a simulated powder pattern.
+#[derive(PartialEq, Debug, Clone, Copy, Serialize)]
+pub struct Diffraction {
+    wavelength: f64,
+    min_two_theta: f64,
+    max_two_theta: f64,
+}
+
+impl Default for Diffraction {
+    fn default() -> Self {
+        Diffraction {
+            wavelength: DEFAULT_WAVELENGTH,
+            min_two_theta: DEFAULT_TWO_THETA_RANGE.0,
+            max_two_theta: DEFAULT_TWO_THETA_RANGE.1,
+        }
+    }
+}
+
+impl Diffraction {
+    /// Fails if the wavelength is not positive.
+    pub fn new(wavelength: f64) -> anyhow::Result<Self> {
+        if !(wavelength > 0.0 && wavelength.is_finite()) {
+            return Err(anyhow::anyhow!(
+                "The wavelength {} Å has to be positive",
+                wavelength
+            ));
+        }
+
+        Ok(Diffraction {
+            wavelength,
+            ..Default::default()
+        })
+    }
+
+    /// Fails if the range is not within [0, 180] degrees.
+    pub fn with_range(mut self, min_two_theta: f64, max_two_theta: f64) -> anyhow::Result<Self> {
+        if !(0.0 <= min_two_theta && min_two_theta < max_two_theta && max_two_theta <= 180.0) {
+            return Err(anyhow::anyhow!(
+                "The 2θ range {} to {} has to be within 0 to 180 degrees",
+                min_two_theta,
+                max_two_theta
+            ));
+        }
+
+        self.min_two_theta = min_two_theta;
+        self.max_two_theta = max_two_theta;
+        Ok(self)
+    }
+
+    pub fn wavelength(&self) -> f64 {
+        self.wavelength
+    }
+
+    /// The smallest d-spacing in Å within the range.
+    pub fn min_d_spacing(&self) -> f64 {
+        self.wavelength / (2.0 * (self.max_two_theta / 2.0).to_radians().sin())
+    }
+
+    /// The diffraction angle 2θ in degrees of a d-spacing, or `None` if it is out of reach.
+    pub fn two_theta(&self, d: f64) -> Option<f64> {
+        let sine = self.wavelength / (2.0 * d);
+
+        (sine <= 1.0).then(|| 2.0 * sine.asin().to_degrees())
+    }
+
+    /// Limits the range to reflections with a d-spacing of at least `min_d_spacing` in Å.
+    pub fn with_min_d_spacing(self, min_d_spacing: f64) -> anyhow::Result<Self> {
+        if !(min_d_spacing > 0.0 && min_d_spacing.is_finite()) {
+            return Err(anyhow::anyhow!(
+                "The smallest d-spacing {} Å has to be positive",
+                min_d_spacing
+            ));
+        }
+
+        let max_two_theta = self.two_theta(min_d_spacing).unwrap_or(180.0);
+
+        self.with_range(self.min_two_theta, max_two_theta)
+    }
+
+    fn is_in_range(&self, two_theta: f64) -> bool {
+        self.min_two_theta <= two_theta && two_theta <= self.max_two_theta
+    }
+
+    /// The indices hkl in the range with their d-spacing and 2θ, sorted by decreasing d-spacing.
+    fn indices_in_range(&self, lattice: &Lattice) -> Vec<([i64; 3], f64, f64)> {
+        let mut indices: Vec<([i64; 3], f64, f64)> = indices_within(lattice, self.min_d_spacing())
+            .into_iter()
+            .filter_map(|(hkl, d)| {
+                let two_theta = self.two_theta(d)?;
+
+                self.is_in_range(two_theta).then_some((hkl, d, two_theta))
+            })
+            .collect();
+
+        indices.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        indices
+    }
+
+    /// The reflections of a block in the range that the symmetry operators allow, sorted by 2θ.
+    /// Only the cell and the symmetry are read.
+    pub fn reflections(&self, block: &DataBlock) -> anyhow::Result<Vec<Reflection>> {
+        let lattice = Lattice::from_block(block)?;
+        let operators = symmetry::symmetry_operators(block)?;
+
+        let allowed = self
+            .indices_in_range(&lattice)
+            .into_iter()
+            .filter(|(hkl, _, _)| !is_extinct(*hkl, &operators))
+            .map(|(hkl, d, _)| (hkl, d, 0.0));
+
+        Ok(group_by_d_spacing(allowed)
+            .into_iter()
+            .map(|(hkl, multiplicity, d, _)| Reflection {
+                hkl,
+                multiplicity,
+                d,
+                two_theta: self.two_theta(d).unwrap_or_default(),
+                original_two_theta: None,
+                shift: None,
+            })
+            .collect())
+    }
+
+    /// The peaks of a block in the range, sorted by 2θ. Blocks without atom sites have no peaks.
+    pub fn peaks(&self, block: &DataBlock) -> anyhow::Result<Vec<Peak>> {
+        if block.find_loop(structure::LABEL_TAG).is_none() {
+            return Ok(Vec::new());
+        }
+
+        let lattice = Lattice::from_block(block)?;
+        let sites = AtomSites::from_block(block)?;
+
+        let mut operators = symmetry::symmetry_operators(block)?;
+
+        if operators.is_empty() {
+            operators.push(SymmetryOperator::identity());
+        }
+
+        let scatterers = (0..sites.len())
+            .map(|row| Scatterer::new(&lattice, &operators, &sites, row))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let reflections = self
+            .indices_in_range(&lattice)
+            .into_iter()
+            .map(|(hkl, d, two_theta)| {
+                let f_squared = structure_factor_squared(&scatterers, hkl, d);
+
+                (hkl, d, f_squared * lorentz_polarisation(two_theta))
+            });
+
+        let mut peaks: Vec<Peak> = group_by_d_spacing(reflections)
+            .into_iter()
+            .map(|(hkl, multiplicity, d, intensity)| Peak {
+                hkl,
+                multiplicity,
+                d,
+                two_theta: self.two_theta(d).unwrap_or_default(),
+                intensity,
+            })
+            .collect();
+
+        let max_intensity = peaks.iter().map(|peak| peak.intensity).fold(0.0, f64::max);
+
+        peaks.retain(|peak| peak.intensity > ABSENT_INTENSITY * max_intensity);
+        peaks
+            .iter_mut()
+            .for_each(|peak| peak.intensity *= MAX_INTENSITY / max_intensity);
+
+        Ok(peaks)
+    }
+}
+
+/// A family of reflections with the same d-spacing that the symmetry allows.
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub struct Reflection {
+    /// The indices of one of the reflections, preferably without negative indices.
+    pub hkl: [i64; 3],
+    pub multiplicity: usize,
+    /// The d-spacing in Å.
+    pub d: f64,
+    /// The diffraction angle 2θ in degrees.
+    pub two_theta: f64,
+    /// 2θ of the same indices in the original cell after a modification.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub original_two_theta: Option<f64>,
+    /// The change of 2θ from the original cell in degrees.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shift: Option<f64>,
+}
+
+/// The broadening of the peaks into a continuous pattern.
+#[derive(PartialEq, Debug, Clone, Copy, Serialize)]
+pub struct Broadening {
+    profile: Profile,
+    /// The full width at half maximum in degrees 2θ.
+    fwhm: f64,
+    /// The step between two points of the pattern in degrees 2θ.
+    step: f64,
+}
+
+impl Broadening {
+    /// Fails if the width or the step is not positive or η is not within [0, 1].
+    pub fn new(profile: Profile, fwhm: f64, step: f64) -> anyhow::Result<Self> {
+        if !(fwhm > 0.0 && step > 0.0) {
+            return Err(anyhow::anyhow!(
+                "The width {} and the step {} have to be positive",
+                fwhm,
+                step
+            ));
+        }
+
+        if let Profile::PseudoVoigt(eta) = profile {
+            if !(0.0..=1.0).contains(&eta) {
+                return Err(anyhow::anyhow!("η {} has to be within 0 to 1", eta));
+            }
+        }
+
+        Ok(Broadening {
+            profile,
+            fwhm,
+            step,
+        })
+    }
+
+    /// The points (2θ, intensity) of the broadened peaks in the range of `diffraction`, scaled to
+    /// 100 at the maximum.
+    pub fn pattern(&self, diffraction: &Diffraction, peaks: &[Peak]) -> Vec<[f64; 2]> {
+        let steps = ((diffraction.max_two_theta - diffraction.min_two_theta) / self.step) as usize;
+
+        let mut pattern: Vec<[f64; 2]> = (0..=steps)
+            .map(|step| {
+                let two_theta = diffraction.min_two_theta + step as f64 * self.step;
+
+                let intensity = peaks
+                    .iter()
+                    .map(|peak| {
+                        peak.intensity * self.profile.value(two_theta - peak.two_theta, self.fwhm)
+                    })
+                    .sum();
+
+                [two_theta, intensity]
+            })
+            .collect();
+
+        let max_intensity = pattern.iter().map(|point| point[1]).fold(0.0, f64::max);
+
+        if max_intensity > 0.0 {
+            pattern
+                .iter_mut()
+                .for_each(|point| point[1] *= MAX_INTENSITY / max_intensity);
+        }
+
+        pattern
+    }
+}
+
+/// An atom site with all its images in the cell.
+struct Scatterer {
+    coefficients: &'static [f64; 9],
+    occupancy: f64,
+    u: f64,
+    images: Vec<Vector3>,
+}
+
+impl Scatterer {
+    fn new(
+        lattice: &Lattice,
+        operators: &[SymmetryOperator],
+        sites: &AtomSites,
+        row: usize,
+    ) -> anyhow::Result<Self> {
+        let element = sites
+            .element(row)
+            .ok_or_else(|| anyhow::anyhow!("No element for {}", sites.label(row)))?;
+
+        let (_, coefficients) = SCATTERING_FACTORS
+            .iter()
+            .find(|(symbol, _)| *symbol == element)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "No scattering factors for {} of {}",
+                    element,
+                    sites.label(row)
+                )
+            })?;
+
+        let images = symmetry::images(
+            lattice,
+            operators,
+            sites.position(row)?,
+            symmetry::DEFAULT_TOLERANCE,
+        );
+
+        Ok(Scatterer {
+            coefficients,
+            occupancy: sites.occupancy(row),
+            u: adp::isotropic_u(sites, row).unwrap_or_default(),
+            images: images.into_iter().map(|(image, _)| image).collect(),
+        })
+    }
+
+    /// The atomic scattering factor with the displacement factor at s = sin θ / λ.
+    fn scattering_factor(&self, s: f64) -> f64 {
+        let c = self.coefficients;
+
+        let f = (0..4)
+            .map(|i| c[2 * i] * (-c[2 * i + 1] * s * s).exp())
+            .sum::<f64>()
+            + c[8];
+
+        f * (-8.0 * PI * PI * self.u * s * s).exp()
+    }
+}
+
+/// |F(hkl)|² of a reflection with the d-spacing `d`.
+fn structure_factor_squared(scatterers: &[Scatterer], hkl: [i64; 3], d: f64) -> f64 {
+    let s = 1.0 / (2.0 * d);
+    let hkl = hkl.map(|index| index as f64);
+
+    let (real, imaginary) = scatterers
+        .iter()
+        .fold((0.0, 0.0), |(real, imaginary), scatterer| {
+            let f = scatterer.occupancy * scatterer.scattering_factor(s);
+
+            scatterer
+                .images
+                .iter()
+                .fold((real, imaginary), |(real, imaginary), image| {
+                    let phase = 2.0 * PI * linalg::dot(hkl, *image);
+
+                    (real + f * phase.cos(), imaginary + f * phase.sin())
+                })
+        });
+
+    real * real + imaginary * imaginary
+}
+
+/// The Lorentz-polarisation factor (1 + cos² 2θ) / (sin² θ cos θ) of an unpolarised beam.
+fn lorentz_polarisation(two_theta: f64) -> f64 {
+    let two_theta = two_theta.to_radians();
+    let theta = two_theta / 2.0;
+
+    (1.0 + two_theta.cos().powi(2)) / (theta.sin().powi(2) * theta.cos())
+}
+
+/// Groups reflections (hkl, d, weight) sorted by d-spacing into families with the same d-spacing.
+/// Returns the preferred indices, the multiplicity, the d-spacing and the summed weight of every
+/// family.
+fn group_by_d_spacing(
+    reflections: impl IntoIterator<Item = ([i64; 3], f64, f64)>,
+) -> Vec<([i64; 3], usize, f64, f64)> {
+    let mut families: Vec<([i64; 3], usize, f64, f64)> = Vec::new();
+
+    for (hkl, d, weight) in reflections {
+        match families.last_mut() {
+            Some(family) if (family.2 - d).abs() < SAME_PEAK_D_SPACING => {
+                family.1 += 1;
+                family.3 += weight;
+
+                if preference(hkl) > preference(family.0) {
+                    family.0 = hkl;
+                }
+            }
+            _ => families.push((hkl, 1, d, weight)),
+        }
+    }
+
+    families
+}
+
+/// Returns whether a reflection is a systematic absence, i.e. whether an operator (R, t) with
+/// h R = h has a non-integer h · t, which makes the structure factor vanish for every structure.
+fn is_extinct(hkl: [i64; 3], operators: &[SymmetryOperator]) -> bool {
+    let h = hkl.map(|index| index as f64);
+
+    operators.iter().any(|operator| {
+        let image = linalg::vec_mat(h, operator.rotation());
+        let is_invariant = (0..3).all(|i| (image[i] - h[i]).abs() < EXTINCTION_TOLERANCE);
+
+        let phase = linalg::dot(h, operator.translation());
+
+        is_invariant && (phase - phase.round()).abs() > EXTINCTION_TOLERANCE
+    })
+}
+
+/// All indices hkl except 000 with a d-spacing of at least `min_d_spacing` and their d-spacings.
+pub(crate) fn indices_within(lattice: &Lattice, min_d_spacing: f64) -> Vec<([i64; 3], f64)> {
+    // The index along an axis is the projection of d* onto it, so |h| ≤ a / d.
+    let ranges = lattice
+        .lengths()
+        .map(|length| (length / min_d_spacing).floor() as i64);
+
+    let mut indices = Vec::new();
+
+    for h in -ranges[0]..=ranges[0] {
+        for k in -ranges[1]..=ranges[1] {
+            for l in -ranges[2]..=ranges[2] {
+                if [h, k, l] == [0; 3] {
+                    continue;
+                }
+
+                let d = lattice.d_spacing([h as f64, k as f64, l as f64]);
+
+                if d >= min_d_spacing {
+                    indices.push(([h, k, l], d));
+                }
+            }
+        }
+    }
+
+    indices
+}
+
+/// Orders equivalent indices so that indices without negative values and with larger leading
+/// values come first, e.g. `1 1 0` before `0 1 1` and `-1 1 0`.
+pub(crate) fn preference(hkl: [i64; 3]) -> (bool, [i64; 3]) {
+    (hkl.iter().all(|index| *index >= 0), hkl)
+}
+
+/// The peaks of a data block and, with instructions, of the modified block.
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub struct BlockDiffraction {
+    pub block: String,
+    pub peaks: Vec<Peak>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified_peaks: Option<Vec<Peak>>,
+}
+
+impl BlockDiffraction {
+    /// The patterns of the block and the modified block as two columns 2θ and intensity, each
+    /// after a comment line. Without a broadening, the columns are the peaks.
+    pub fn to_columns(&self, diffraction: &Diffraction, broadening: Option<&Broadening>) -> String {
+        let mut columns = String::new();
+
+        let patterns = std::iter::once(("original", &self.peaks))
+            .chain(self.modified_peaks.iter().map(|peaks| ("modified", peaks)));
+
+        for (name, peaks) in patterns {
+            columns.push_str(&format!("# data_{} {}\n", self.block, name));
+
+            let points = match broadening {
+                Some(broadening) => broadening.pattern(diffraction, peaks),
+                None => peaks
+                    .iter()
+                    .map(|peak| [peak.two_theta, peak.intensity])
+                    .collect(),
+            };
+
+            for [two_theta, intensity] in points {
+                columns.push_str(&format!("{:.4} {:.4}\n", two_theta, intensity));
+            }
+        }
+
+        columns
+    }
+}
+
+impl Display for BlockDiffraction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "  data_{}:", self.block)?;
+
+        let patterns = std::iter::once(("original", &self.peaks))
+            .chain(self.modified_peaks.iter().map(|peaks| ("modified", peaks)));
+
+        for (name, peaks) in patterns {
+            if self.modified_peaks.is_some() {
+                writeln!(f, "    {}", name)?;
+            }
+
+            writeln!(
+                f,
+                "      {:>4}{:>4}{:>4}{:>10}{:>10}{:>10}{:>6}",
+                "h", "k", "l", "d", "2θ", "I", "m"
+            )?;
+
+            for peak in peaks {
+                writeln!(
+                    f,
+                    "      {:>4}{:>4}{:>4}{:>10.4}{:>10.3}{:>10.2}{:>6}",
+                    peak.hkl[0],
+                    peak.hkl[1],
+                    peak.hkl[2],
+                    peak.d,
+                    peak.two_theta,
+                    peak.intensity,
+                    peak.multiplicity
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The diffraction peaks of every data block of a file.
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub struct FileDiffraction {
+    pub path: String,
+    pub blocks: Vec<BlockDiffraction>,
+}
+
+impl FileDiffraction {
+    /// The patterns of all blocks as two columns, see [`BlockDiffraction::to_columns`].
+    pub fn to_columns(&self, diffraction: &Diffraction, broadening: Option<&Broadening>) -> String {
+        let mut columns = format!("# {}\n", self.path);
+
+        for block in &self.blocks {
+            columns.push_str(&block.to_columns(diffraction, broadening));
+        }
+
+        columns
+    }
+}
+
+impl Display for FileDiffraction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.path)?;
+
+        for block in &self.blocks {
+            write!(f, "{}", block)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The allowed reflections of a data block.
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub struct BlockReflections {
+    pub block: String,
+    pub reflections: Vec<Reflection>,
+}
+
+impl Display for BlockReflections {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "  data_{}:", self.block)?;
+
+        let is_modified = self.reflections.iter().any(|r| r.shift.is_some());
+
+        write!(
+            f,
+            "      {:>4}{:>4}{:>4}{:>10}{:>10}{:>6}",
+            "h", "k", "l", "d", "2θ", "m"
+        )?;
+
+        match is_modified {
+            true => writeln!(f, "{:>10}{:>10}", "before", "Δ2θ")?,
+            false => writeln!(f)?,
+        }
+
+        for reflection in &self.reflections {
+            write!(
+                f,
+                "      {:>4}{:>4}{:>4}{:>10.4}{:>10.3}{:>6}",
+                reflection.hkl[0],
+                reflection.hkl[1],
+                reflection.hkl[2],
+                reflection.d,
+                reflection.two_theta,
+                reflection.multiplicity
+            )?;
+
+            match (reflection.original_two_theta, reflection.shift) {
+                (Some(original_two_theta), Some(shift)) => {
+                    writeln!(f, "{:>10.3}{:>+10.3}", original_two_theta, shift)?
+                }
+                _ => writeln!(f)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The allowed reflections of every data block of a file.
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub struct FileReflections {
+    pub path: String,
+    pub blocks: Vec<BlockReflections>,
+}
+
+impl Display for FileReflections {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.path)?;
+
+        for block in &self.blocks {
+            write!(f, "{}", block)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads a CIF, which may be gzip or xz compressed, and lists the allowed reflections of every
+/// named data block with a cell. With instructions, the reflections of the modified blocks are
+/// listed with the shift of 2θ from the same indices in the original cell.
+pub fn reflections_of_reader(
+    path: &str,
+    reader: impl Read,
+    diffraction: &Diffraction,
+    instructions: Option<&Instructions>,
+) -> anyhow::Result<FileReflections> {
+    let (_, mut reader) = decompress(reader)?;
+
+    let mut content = String::new();
+    reader.read_to_string(&mut content)?;
+
+    let cif = Cif::parse(&content)?;
+
+    let mut blocks = Vec::new();
+
+    for block in cif.blocks().iter().filter(|block| block.name().is_some()) {
+        let Ok(lattice) = Lattice::from_block(block) else {
+            continue;
+        };
+
+        let reflections = match instructions {
+            Some(instructions) => {
+                let mut modified_block = block.clone();
+                instructions.apply_to_block(&mut modified_block)?;
+
+                let mut reflections = diffraction.reflections(&modified_block)?;
+
+                for reflection in &mut reflections {
+                    let d = lattice.d_spacing(reflection.hkl.map(|index| index as f64));
+
+                    reflection.original_two_theta = diffraction.two_theta(d);
+                    reflection.shift = reflection
+                        .original_two_theta
+                        .map(|original_two_theta| reflection.two_theta - original_two_theta);
+                }
+
+                reflections
+            }
+            None => diffraction.reflections(block)?,
+        };
+
+        blocks.push(BlockReflections {
+            block: block.name().unwrap_or_default().to_string(),
+            reflections,
+        });
+    }
+
+    Ok(FileReflections {
+        path: path.to_string(),
+        blocks,
+    })
+}
+
+/// Reads a CIF, which may be gzip or xz compressed, and computes the diffraction peaks of every
+/// named data block. With instructions, the peaks of the modified blocks are computed as well.
+pub fn diffraction_of_reader(
+    path: &str,
+    reader: impl Read,
+    diffraction: &Diffraction,
+    instructions: Option<&Instructions>,
+) -> anyhow::Result<FileDiffraction> {
+    let (_, mut reader) = decompress(reader)?;
+
+    let mut content = String::new();
+    reader.read_to_string(&mut content)?;
+
+    let cif = Cif::parse(&content)?;
+
+    let mut blocks = Vec::new();
+
+    for block in cif.blocks().iter().filter(|block| block.name().is_some()) {
+        let modified_peaks = match instructions {
+            Some(instructions) => {
+                let mut modified_block = block.clone();
+                instructions.apply_to_block(&mut modified_block)?;
+
+                Some(diffraction.peaks(&modified_block)?)
+            }
+            None => None,
+        };
+
+        blocks.push(BlockDiffraction {
+            block: block.name().unwrap_or_default().to_string(),
+            peaks: diffraction.peaks(block)?,
+            modified_peaks,
+        });
+    }
+
+    Ok(FileDiffraction {
+        path: path.to_string(),
+        blocks,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{assert_close, block};
+
+    #[test]
+    fn test_peaks() {
+        let diffraction = Diffraction::default();
+        let peaks = diffraction.peaks(&block("tests/BaTiO3.cif")).unwrap();
+
+        // Cubic with a = 4.0094 Å: 100 at 22.15°, 110 at 31.53° is the strongest peak.
+        assert_eq!(peaks[0].hkl, [1, 0, 0]);
+        assert_eq!(peaks[0].multiplicity, 6);
+        assert_close(peaks[0].d, 4.0094, 1e-4);
+        assert_close(peaks[0].two_theta, 22.153, 1e-3);
+
+        let strongest = peaks
+            .iter()
+            .max_by(|a, b| a.intensity.total_cmp(&b.intensity))
+            .unwrap();
+        assert_eq!(strongest.hkl, [1, 1, 0]);
+        assert_eq!(strongest.multiplicity, 12);
+        assert_close(strongest.intensity, 100.0, 1e-9);
+        assert!(peaks
+            .windows(2)
+            .all(|pair| pair[0].two_theta < pair[1].two_theta));
+        assert!(peaks.iter().all(|peak| peak.two_theta <= 90.0));
+
+        // The expanded structure gives the same pattern.
+        let p1_peaks = diffraction.peaks(&block("tests/BaTiO3_P1.cif")).unwrap();
+        assert_eq!(p1_peaks.len(), peaks.len());
+
+        for (p1_peak, peak) in p1_peaks.iter().zip(&peaks) {
+            assert_close(p1_peak.two_theta, peak.two_theta, 1e-6);
+            assert_close(p1_peak.intensity, peak.intensity, 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_modified_peaks() {
+        let content = std::fs::read_to_string("tests/BaTiO3.cif").unwrap();
+        let instructions = Instructions::from_string("a + 0.05").unwrap();
+
+        let diffraction = Diffraction::new(parse_wavelength("Mo").unwrap())
+            .unwrap()
+            .with_range(10.0, 30.0)
+            .unwrap();
+        let file_diffraction = diffraction_of_reader(
+            "BaTiO3.cif",
+            content.as_bytes(),
+            &diffraction,
+            Some(&instructions),
+        )
+        .unwrap();
+
+        let block = &file_diffraction.blocks[0];
+        let modified_peaks = block.modified_peaks.as_ref().unwrap();
+
+        // A longer a splits the cubic 100 into 100 and 010/001 at higher angles.
+        assert_eq!(block.peaks[0].multiplicity, 6);
+        assert_eq!(modified_peaks[0].multiplicity, 2);
+        assert_eq!(modified_peaks[0].hkl, [1, 0, 0]);
+        assert!(modified_peaks[0].two_theta < block.peaks[0].two_theta);
+        assert_close(modified_peaks[1].two_theta, block.peaks[0].two_theta, 1e-9);
+
+        let columns = file_diffraction.to_columns(&diffraction, None);
+        assert!(columns.starts_with("# BaTiO3.cif\n# data_95437-ICSD original\n10."));
+        assert!(columns.contains("# data_95437-ICSD modified\n"));
+    }
+
+    #[test]
+    fn test_broadening() {
+        let diffraction = Diffraction::default().with_range(20.0, 25.0).unwrap();
+        let peaks = diffraction.peaks(&block("tests/BaTiO3.cif")).unwrap();
+        assert_eq!(peaks.len(), 1);
+
+        for profile in [Profile::Gaussian, Profile::PseudoVoigt(0.5)] {
+            let broadening = Broadening::new(profile, 0.1, 0.01).unwrap();
+            let pattern = broadening.pattern(&diffraction, &peaks);
+
+            assert_eq!(pattern.len(), 501);
+            assert_close(pattern[0][0], 20.0, 1e-9);
+
+            let maximum = pattern.iter().max_by(|a, b| a[1].total_cmp(&b[1])).unwrap();
+            assert_close(maximum[0], peaks[0].two_theta, 0.01);
+            assert_close(maximum[1], 100.0, 1e-9);
+
+            // Half the maximum at half the width from the centre.
+            let half = profile.value(0.05, 0.1) / profile.value(0.0, 0.1);
+            assert_close(half, 0.5, 1e-9);
+        }
+
+        assert!(Broadening::new(Profile::PseudoVoigt(1.5), 0.1, 0.01).is_err());
+        assert!(Broadening::new(Profile::Gaussian, 0.0, 0.01).is_err());
+    }
+
+    #[test]
+    fn test_reflections() {
+        let diffraction = Diffraction::default().with_min_d_spacing(1.5).unwrap();
+        let reflections = diffraction.reflections(&block("tests/BaTiO3.cif")).unwrap();
+
+        // Pm-3m has no systematic absences.
+        let indices: Vec<[i64; 3]> = reflections.iter().map(|r| r.hkl).collect();
+        assert_eq!(
+            indices,
+            [
+                [1, 0, 0],
+                [1, 1, 0],
+                [1, 1, 1],
+                [2, 0, 0],
+                [2, 1, 0],
+                [2, 1, 1]
+            ]
+        );
+        assert_eq!(reflections[2].multiplicity, 8);
+        assert!(reflections.iter().all(|r| r.d >= 1.5 && r.shift.is_none()));
+
+        // Body centring removes the reflections with odd h + k + l.
+        let content = "data_I\n_cell_length_a 4\n_cell_length_b 4\n_cell_length_c 4\n\
+            _cell_angle_alpha 90\n_cell_angle_beta 90\n_cell_angle_gamma 90\n\
+            loop_\n_symmetry_equiv_pos_as_xyz\n'x, y, z'\n'x+1/2, y+1/2, z+1/2'\n";
+        let cif = Cif::parse(content).unwrap();
+        let reflections = diffraction.reflections(&cif.blocks()[1]).unwrap();
+
+        let indices: Vec<[i64; 3]> = reflections.iter().map(|r| r.hkl).collect();
+        assert_eq!(indices, [[1, 1, 0], [2, 0, 0], [2, 1, 1]]);
+        assert!(is_extinct(
+            [0, 0, 1],
+            &symmetry::symmetry_operators(&cif.blocks()[1]).unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_reflection_shifts() {
+        let content = std::fs::read_to_string("tests/BaTiO3.cif").unwrap();
+        let instructions = Instructions::from_string("c + 0.04").unwrap();
+
+        let diffraction = Diffraction::default().with_range(20.0, 46.0).unwrap();
+        let file_reflections = reflections_of_reader(
+            "BaTiO3.cif",
+            content.as_bytes(),
+            &diffraction,
+            Some(&instructions),
+        )
+        .unwrap();
+
+        let reflections = &file_reflections.blocks[0].reflections;
+
+        // A longer c moves 001 to a lower angle and leaves 100 where it was.
+        assert_eq!(reflections[0].hkl, [0, 0, 1]);
+        assert_eq!(reflections[0].multiplicity, 2);
+        assert!(reflections[0].shift.unwrap() < -0.2);
+        assert_eq!(reflections[1].hkl, [1, 0, 0]);
+        assert_eq!(reflections[1].multiplicity, 4);
+        assert_close(reflections[1].shift.unwrap(), 0.0, 1e-9);
+        assert_close(reflections[1].original_two_theta.unwrap(), 22.153, 1e-3);
+
+        let text = file_reflections.to_string();
+        assert!(text.contains("Δ2θ"));
+    }
+
+    #[test]
+    fn test_parse_wavelength() {
+        assert_eq!(parse_wavelength("Cu").unwrap(), DEFAULT_WAVELENGTH);
+        assert_eq!(parse_wavelength("mo").unwrap(), 0.7093);
+        assert_eq!(parse_wavelength("1.0").unwrap(), 1.0);
+        assert!(parse_wavelength("Xx").is_err());
+        assert!(parse_wavelength("-1").is_err());
+        assert!(Diffraction::default().with_range(50.0, 10.0).is_err());
+        assert!(Diffraction::default().with_min_d_spacing(0.0).is_err());
+        assert_close(
+            Diffraction::default()
+                .with_min_d_spacing(0.5)
+                .unwrap()
+                .min_d_spacing(),
+            0.7703,
+            1e-9,
+        );
+    }
+}
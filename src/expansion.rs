@@ -0,0 +1,815 @@
+//! Changes of the cell with temperature and pressure: thermal expansion from expansion coefficients
+//! and compression from the Birch–Murnaghan equation of state.
+//!
+//! Both scale the cell lengths and keep the angles and the fractional coordinates. The new
+//! temperature or pressure is written to the measurement and ambient tags of the block.
+
+use std::{fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{lattice::CELL_TAGS, parse_number, structure, DataBlock, Lattice};
+
+const ARROW: &str = "->";
+const WITH_KEYWORD: &str = "with";
+
+const ALPHA_V_OPTION: &str = "alpha_V";
+const ALPHA_OPTION: &str = "alpha";
+const ALPHA_AXIS_OPTIONS: [&str; 3] = ["alpha_a", "alpha_b", "alpha_c"];
+const BULK_MODULUS_OPTION: &str = "B0";
+const DERIVATIVE_OPTION: &str = "B0_prime";
+
+const TEMPERATURE_TAGS: [&str; 2] = [
+    "_cell_measurement_temperature",
+    "_diffrn_ambient_temperature",
+];
+const PRESSURE_TAGS: [&str; 2] = ["_cell_measurement_pressure", "_diffrn_ambient_pressure"];
+
+/// Pressures are given in GPa, the pressure tags are in kPa.
+const KILOPASCALS_PER_GIGAPASCAL: f64 = 1e6;
+
+/// The pressure derivative of the bulk modulus of the second-order Birch–Murnaghan equation.
+const DEFAULT_DERIVATIVE: f64 = 4.0;
+
+/// The range of V/V0 in which the equation of state is solved.
+const VOLUME_RATIO_RANGE: (f64, f64) = (0.2, 2.0);
+
+/// Iterations of the bisection that solves the equation of state.
+const BISECTION_STEPS: usize = 100;
+
+/// The coefficients `c0 c1 c2 …` of an expansion coefficient `α(T) = c0 + c1 T + c2 T² + …` in 1/K.
+type Polynomial = Vec<f64>;
+
+/// `∫ α(T) dT` from `from` to `to`.
+fn integral(polynomial: &[f64], from: f64, to: f64) -> f64 {
+    polynomial
+        .iter()
+        .enumerate()
+        .map(|(k, c)| {
+            let power = (k + 1) as i32;
+            c * (to.powi(power) - from.powi(power)) / power as f64
+        })
+        .sum()
+}
+
+/// How the cell expands with temperature.
+#[derive(PartialEq, Debug, Clone)]
+pub enum Expansion {
+    /// The volumetric expansion coefficient `α_V = (1/V) dV/dT`, every axis expands by a third.
+    Volumetric(Polynomial),
+    /// The linear expansion coefficients `α = (1/a) da/dT` of the axes a, b and c. An axis without
+    /// coefficients keeps its length.
+    Linear([Polynomial; 3]),
+}
+
+impl Expansion {
+    /// Builds the expansion from the options of the text or structured form. `alpha_V` excludes the
+    /// linear coefficients, `alpha` applies to the axes without their own coefficients.
+    fn from_options(
+        alpha_v: Option<Polynomial>,
+        alpha: Option<Polynomial>,
+        alpha_axes: [Option<Polynomial>; 3],
+    ) -> anyhow::Result<Self> {
+        let coefficients = alpha_v
+            .iter()
+            .chain(alpha.iter())
+            .chain(alpha_axes.iter().flatten());
+
+        for polynomial in coefficients {
+            if polynomial.is_empty() || polynomial.iter().any(|c| !c.is_finite()) {
+                return Err(anyhow::anyhow!(
+                    "{:?} are not valid expansion coefficients",
+                    polynomial
+                ));
+            }
+        }
+
+        let is_linear = alpha.is_some() || alpha_axes.iter().any(Option::is_some);
+
+        match (alpha_v, is_linear) {
+            (Some(alpha_v), false) => Ok(Expansion::Volumetric(alpha_v)),
+            (None, true) => {
+                Ok(Expansion::Linear(alpha_axes.map(|axis| {
+                    axis.or_else(|| alpha.clone()).unwrap_or_default()
+                })))
+            }
+            (Some(_), true) => Err(anyhow::anyhow!(
+                "{} cannot be combined with linear expansion coefficients",
+                ALPHA_V_OPTION
+            )),
+            (None, false) => Err(anyhow::anyhow!(
+                "temperature takes {}, {} or {}",
+                ALPHA_V_OPTION,
+                ALPHA_OPTION,
+                ALPHA_AXIS_OPTIONS.join(", ")
+            )),
+        }
+    }
+
+    /// The factors of the lengths of a, b and c between two temperatures.
+    fn length_factors(&self, from: f64, to: f64) -> [f64; 3] {
+        match self {
+            Expansion::Volumetric(alpha_v) => [(integral(alpha_v, from, to) / 3.0).exp(); 3],
+            Expansion::Linear(alphas) => {
+                alphas.clone().map(|alpha| integral(&alpha, from, to).exp())
+            }
+        }
+    }
+
+    /// The options of the text and the structured form: the name and the coefficients.
+    fn options(&self) -> Vec<(&'static str, &Polynomial)> {
+        match self {
+            Expansion::Volumetric(alpha_v) => vec![(ALPHA_V_OPTION, alpha_v)],
+            Expansion::Linear([a, b, c]) if a == b && b == c => vec![(ALPHA_OPTION, a)],
+            Expansion::Linear(alphas) => ALPHA_AXIS_OPTIONS
+                .into_iter()
+                .zip(alphas)
+                .filter(|(_, alpha)| !alpha.is_empty())
+                .collect(),
+        }
+    }
+}
+
+/// Scales the cell from one temperature to another with expansion coefficients, e.g.
+/// `300 -> 800 with alpha_V = 3e-5`.
+///
+/// The lengths scale by `exp(∫ α dT)`. Without a start temperature, the temperature of the block is
+/// read from `_cell_measurement_temperature` or `_diffrn_ambient_temperature`. Temperatures are in
+/// K.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "ThermalExpansionSpec", into = "ThermalExpansionSpec")]
+pub struct ThermalExpansion {
+    from: Option<f64>,
+    to: f64,
+    expansion: Expansion,
+}
+
+impl ThermalExpansion {
+    /// Fails if the temperature is not positive.
+    pub fn new(to: f64, expansion: Expansion) -> anyhow::Result<Self> {
+        Ok(ThermalExpansion {
+            from: None,
+            to: check_temperature(to)?,
+            expansion,
+        })
+    }
+
+    /// Fails if the temperature is not positive.
+    pub fn with_from(mut self, from: f64) -> anyhow::Result<Self> {
+        self.from = Some(check_temperature(from)?);
+        Ok(self)
+    }
+
+    /// Returns the number of modified values. Blocks without a cell are left unchanged.
+    pub(crate) fn apply_to_block(&self, block: &mut DataBlock) -> anyhow::Result<usize> {
+        if block.value(CELL_TAGS[0]).is_none() {
+            return Ok(0);
+        }
+
+        let from = match self.from {
+            Some(from) => from,
+            None => condition(block, &TEMPERATURE_TAGS).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "data_{} has no temperature, give it as `temperature <from> {} {}`",
+                    block.name().unwrap_or_default(),
+                    ARROW,
+                    self.to
+                )
+            })?,
+        };
+
+        let factors = self.expansion.length_factors(from, self.to);
+
+        log::info!(
+            "{} K -> {} K scales a, b and c of data_{} by {:.6}, {:.6} and {:.6}",
+            from,
+            self.to,
+            block.name().unwrap_or_default(),
+            factors[0],
+            factors[1],
+            factors[2]
+        );
+
+        let mut modified_values_counter = scale_cell(block, factors)?;
+        modified_values_counter += write_condition(block, &TEMPERATURE_TAGS, self.to);
+
+        Ok(modified_values_counter)
+    }
+}
+
+fn check_temperature(temperature: f64) -> anyhow::Result<f64> {
+    if !(temperature.is_finite() && temperature > 0.0) {
+        return Err(anyhow::anyhow!("{} is not a temperature in K", temperature));
+    }
+
+    Ok(temperature)
+}
+
+impl FromStr for ThermalExpansion {
+    type Err = anyhow::Error;
+
+    /// Reads e.g. `300 -> 800 with alpha_V = 3e-5` or `800 with alpha_a = 1e-5 2e-9 alpha_c = 2e-5`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, to, options) = parse_change(s)?;
+
+        let mut alpha_v = None;
+        let mut alpha = None;
+        let mut alpha_axes = [None, None, None];
+
+        for (name, values) in options {
+            let option = match name.as_str() {
+                ALPHA_V_OPTION => &mut alpha_v,
+                ALPHA_OPTION => &mut alpha,
+                _ => match ALPHA_AXIS_OPTIONS.iter().position(|axis| *axis == name) {
+                    Some(index) => &mut alpha_axes[index],
+                    None => return Err(anyhow::anyhow!("Unknown option {} in {}", name, s)),
+                },
+            };
+
+            if option.replace(values).is_some() {
+                return Err(anyhow::anyhow!("{} is given twice in {}", name, s));
+            }
+        }
+
+        let expansion =
+            ThermalExpansion::new(to, Expansion::from_options(alpha_v, alpha, alpha_axes)?)?;
+
+        match from {
+            Some(from) => expansion.with_from(from),
+            None => Ok(expansion),
+        }
+    }
+}
+
+impl Display for ThermalExpansion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(from) = self.from {
+            write!(f, "{} {} ", from, ARROW)?;
+        }
+
+        write!(f, "{} {}", self.to, WITH_KEYWORD)?;
+
+        for (name, polynomial) in self.expansion.options() {
+            write!(f, " {} =", name)?;
+
+            for c in polynomial {
+                write!(f, " {}", c)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Expansion coefficients in the structured form: a constant or the coefficients of a polynomial.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum CoefficientsSpec {
+    Constant(f64),
+    Polynomial(Polynomial),
+}
+
+impl From<CoefficientsSpec> for Polynomial {
+    fn from(spec: CoefficientsSpec) -> Self {
+        match spec {
+            CoefficientsSpec::Constant(c) => vec![c],
+            CoefficientsSpec::Polynomial(polynomial) => polynomial,
+        }
+    }
+}
+
+impl From<Polynomial> for CoefficientsSpec {
+    fn from(polynomial: Polynomial) -> Self {
+        match polynomial.as_slice() {
+            [c] => CoefficientsSpec::Constant(*c),
+            _ => CoefficientsSpec::Polynomial(polynomial),
+        }
+    }
+}
+
+/// The structured form of a [`ThermalExpansion`], e.g. `{ from = 300, to = 800, alpha_V = 3e-5 }`.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ThermalExpansionSpec {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    from: Option<f64>,
+    to: f64,
+    #[serde(rename = "alpha_V", default, skip_serializing_if = "Option::is_none")]
+    alpha_v: Option<CoefficientsSpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    alpha: Option<CoefficientsSpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    alpha_a: Option<CoefficientsSpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    alpha_b: Option<CoefficientsSpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    alpha_c: Option<CoefficientsSpec>,
+}
+
+impl TryFrom<ThermalExpansionSpec> for ThermalExpansion {
+    type Error = anyhow::Error;
+
+    fn try_from(spec: ThermalExpansionSpec) -> Result<Self, Self::Error> {
+        let expansion = Expansion::from_options(
+            spec.alpha_v.map(Polynomial::from),
+            spec.alpha.map(Polynomial::from),
+            [spec.alpha_a, spec.alpha_b, spec.alpha_c].map(|axis| axis.map(Polynomial::from)),
+        )?;
+
+        let expansion = ThermalExpansion::new(spec.to, expansion)?;
+
+        match spec.from {
+            Some(from) => expansion.with_from(from),
+            None => Ok(expansion),
+        }
+    }
+}
+
+impl From<ThermalExpansion> for ThermalExpansionSpec {
+    fn from(expansion: ThermalExpansion) -> Self {
+        let mut spec = ThermalExpansionSpec {
+            from: expansion.from,
+            to: expansion.to,
+            alpha_v: None,
+            alpha: None,
+            alpha_a: None,
+            alpha_b: None,
+            alpha_c: None,
+        };
+
+        for (name, polynomial) in expansion.expansion.options() {
+            let coefficients = Some(CoefficientsSpec::from(polynomial.clone()));
+
+            match name {
+                ALPHA_V_OPTION => spec.alpha_v = coefficients,
+                ALPHA_OPTION => spec.alpha = coefficients,
+                "alpha_a" => spec.alpha_a = coefficients,
+                "alpha_b" => spec.alpha_b = coefficients,
+                _ => spec.alpha_c = coefficients,
+            }
+        }
+
+        spec
+    }
+}
+
+/// Scales the cell from one pressure to another with the third-order Birch–Murnaghan equation of
+/// state, e.g. `0 -> 5 with B0 = 160 B0_prime = 4`.
+///
+/// ```text
+/// P(V) = 3/2 B0 [(V0/V)^(7/3) - (V0/V)^(5/3)] {1 + 3/4 (B0' - 4) [(V0/V)^(2/3) - 1]}
+/// ```
+///
+/// Every axis scales by the cube root of the ratio of the volumes. Pressures and the bulk modulus
+/// `B0` are in GPa, `B0'` defaults to 4. Without a start pressure, the pressure of the block is read
+/// from `_cell_measurement_pressure` or `_diffrn_ambient_pressure`. Blocks without these tags are
+/// assumed to be at 0 GPa with a warning.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "BirchMurnaghanSpec", into = "BirchMurnaghanSpec")]
+pub struct BirchMurnaghan {
+    from: Option<f64>,
+    to: f64,
+    bulk_modulus: f64,
+    derivative: f64,
+}
+
+impl BirchMurnaghan {
+    /// Fails if the bulk modulus is not positive.
+    pub fn new(to: f64, bulk_modulus: f64) -> anyhow::Result<Self> {
+        if !(bulk_modulus.is_finite() && bulk_modulus > 0.0) {
+            return Err(anyhow::anyhow!(
+                "The bulk modulus {} GPa has to be positive",
+                bulk_modulus
+            ));
+        }
+
+        Ok(BirchMurnaghan {
+            from: None,
+            to: check_pressure(to)?,
+            bulk_modulus,
+            derivative: DEFAULT_DERIVATIVE,
+        })
+    }
+
+    pub fn with_from(mut self, from: f64) -> anyhow::Result<Self> {
+        self.from = Some(check_pressure(from)?);
+        Ok(self)
+    }
+
+    /// Sets the pressure derivative `B0'` of the bulk modulus.
+    pub fn with_derivative(mut self, derivative: f64) -> anyhow::Result<Self> {
+        if !derivative.is_finite() {
+            return Err(anyhow::anyhow!("{} is not a valid B0'", derivative));
+        }
+
+        self.derivative = derivative;
+        Ok(self)
+    }
+
+    /// The pressure in GPa at the volume `V = ratio × V0`.
+    fn pressure(&self, ratio: f64) -> f64 {
+        let compression = ratio.powf(-2.0 / 3.0);
+
+        1.5 * self.bulk_modulus
+            * (compression.powf(3.5) - compression.powf(2.5))
+            * (1.0 + 0.75 * (self.derivative - 4.0) * (compression - 1.0))
+    }
+
+    /// The ratio `V/V0` at a pressure in GPa.
+    fn volume_ratio(&self, pressure: f64) -> anyhow::Result<f64> {
+        let (mut low, mut high) = VOLUME_RATIO_RANGE;
+
+        if !(self.pressure(low) >= pressure && pressure >= self.pressure(high)) {
+            return Err(anyhow::anyhow!(
+                "{} GPa is outside the range of the equation of state with B0 = {} GPa and B0' = {}",
+                pressure,
+                self.bulk_modulus,
+                self.derivative
+            ));
+        }
+
+        // The pressure decreases with the volume.
+        for _ in 0..BISECTION_STEPS {
+            let middle = 0.5 * (low + high);
+
+            if self.pressure(middle) > pressure {
+                low = middle;
+            } else {
+                high = middle;
+            }
+        }
+
+        Ok(0.5 * (low + high))
+    }
+
+    /// Returns the number of modified values. Blocks without a cell are left unchanged.
+    pub(crate) fn apply_to_block(&self, block: &mut DataBlock) -> anyhow::Result<usize> {
+        if block.value(CELL_TAGS[0]).is_none() {
+            return Ok(0);
+        }
+
+        // Unlike the temperature, a missing pressure usually means ambient conditions.
+        let from = match (self.from, condition(block, &PRESSURE_TAGS)) {
+            (Some(from), _) => from,
+            (None, Some(pressure)) => pressure / KILOPASCALS_PER_GIGAPASCAL,
+            (None, None) => {
+                log::warn!(
+                    "data_{} has no pressure, assuming 0 GPa. Give it as `pressure <from> {} {}`",
+                    block.name().unwrap_or_default(),
+                    ARROW,
+                    self.to
+                );
+
+                0.0
+            }
+        };
+
+        let volume_factor = self.volume_ratio(self.to)? / self.volume_ratio(from)?;
+
+        log::info!(
+            "{} GPa -> {} GPa scales the volume of data_{} by {:.6}",
+            from,
+            self.to,
+            block.name().unwrap_or_default(),
+            volume_factor
+        );
+
+        let mut modified_values_counter = scale_cell(block, [volume_factor.cbrt(); 3])?;
+        modified_values_counter +=
+            write_condition(block, &PRESSURE_TAGS, self.to * KILOPASCALS_PER_GIGAPASCAL);
+
+        Ok(modified_values_counter)
+    }
+}
+
+fn check_pressure(pressure: f64) -> anyhow::Result<f64> {
+    if !pressure.is_finite() {
+        return Err(anyhow::anyhow!("{} is not a pressure in GPa", pressure));
+    }
+
+    Ok(pressure)
+}
+
+impl FromStr for BirchMurnaghan {
+    type Err = anyhow::Error;
+
+    /// Reads e.g. `0 -> 5 with B0 = 160 B0_prime = 4` or `5 with B0 = 160`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, to, options) = parse_change(s)?;
+
+        let mut bulk_modulus = None;
+        let mut derivative = None;
+
+        for (name, values) in options {
+            let option = match name.as_str() {
+                BULK_MODULUS_OPTION => &mut bulk_modulus,
+                DERIVATIVE_OPTION => &mut derivative,
+                _ => return Err(anyhow::anyhow!("Unknown option {} in {}", name, s)),
+            };
+
+            let value = match values.as_slice() {
+                [value] => *value,
+                _ => return Err(anyhow::anyhow!("{} takes a single number in {}", name, s)),
+            };
+
+            if option.replace(value).is_some() {
+                return Err(anyhow::anyhow!("{} is given twice in {}", name, s));
+            }
+        }
+
+        let bulk_modulus = bulk_modulus.ok_or_else(|| {
+            anyhow::anyhow!(
+                "pressure takes the bulk modulus {} in GPa",
+                BULK_MODULUS_OPTION
+            )
+        })?;
+
+        let mut eos = BirchMurnaghan::new(to, bulk_modulus)?
+            .with_derivative(derivative.unwrap_or(DEFAULT_DERIVATIVE))?;
+
+        if let Some(from) = from {
+            eos = eos.with_from(from)?;
+        }
+
+        Ok(eos)
+    }
+}
+
+impl Display for BirchMurnaghan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(from) = self.from {
+            write!(f, "{} {} ", from, ARROW)?;
+        }
+
+        write!(
+            f,
+            "{} {} {} = {}",
+            self.to, WITH_KEYWORD, BULK_MODULUS_OPTION, self.bulk_modulus
+        )?;
+
+        if self.derivative != DEFAULT_DERIVATIVE {
+            write!(f, " {} = {}", DERIVATIVE_OPTION, self.derivative)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The structured form of a [`BirchMurnaghan`], e.g. `{ from = 0, to = 5, B0 = 160 }`.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BirchMurnaghanSpec {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    from: Option<f64>,
+    to: f64,
+    #[serde(rename = "B0")]
+    bulk_modulus: f64,
+    #[serde(rename = "B0_prime", default = "default_derivative")]
+    derivative: f64,
+}
+
+fn default_derivative() -> f64 {
+    DEFAULT_DERIVATIVE
+}
+
+impl TryFrom<BirchMurnaghanSpec> for BirchMurnaghan {
+    type Error = anyhow::Error;
+
+    fn try_from(spec: BirchMurnaghanSpec) -> Result<Self, Self::Error> {
+        let eos =
+            BirchMurnaghan::new(spec.to, spec.bulk_modulus)?.with_derivative(spec.derivative)?;
+
+        match spec.from {
+            Some(from) => eos.with_from(from),
+            None => Ok(eos),
+        }
+    }
+}
+
+impl From<BirchMurnaghan> for BirchMurnaghanSpec {
+    fn from(eos: BirchMurnaghan) -> Self {
+        BirchMurnaghanSpec {
+            from: eos.from,
+            to: eos.to,
+            bulk_modulus: eos.bulk_modulus,
+            derivative: eos.derivative,
+        }
+    }
+}
+
+/// The named options of the text form with their numbers, e.g. `alpha_a = 1e-5 2e-9`.
+type Options = Vec<(String, Vec<f64>)>;
+
+/// Splits the text form `<from> -> <to> with <name> = <numbers> …` into the optional start, the
+/// target and the named options with their numbers.
+fn parse_change(s: &str) -> anyhow::Result<(Option<f64>, f64, Options)> {
+    let (change, options) = s
+        .split_once(WITH_KEYWORD)
+        .ok_or_else(|| anyhow::anyhow!("Missing `{}` and the parameters in {}", WITH_KEYWORD, s))?;
+
+    let number = |word: &str| {
+        parse_number(word.trim())
+            .ok_or_else(|| anyhow::anyhow!("{} is not a number in {}", word, s))
+    };
+
+    let (from, to) = match change.split_once(ARROW) {
+        Some((from, to)) => (Some(number(from)?), number(to)?),
+        None => (None, number(change)?),
+    };
+
+    let mut parsed_options: Options = Vec::new();
+
+    for word in options.replace('=', " = ").split_whitespace() {
+        match (word, parsed_options.last_mut()) {
+            ("=", _) => {}
+            (word, Some((_, values))) if parse_number(word).is_some() => values.push(number(word)?),
+            (word, _) if parse_number(word).is_some() => {
+                return Err(anyhow::anyhow!("{} has no name in {}", word, s))
+            }
+            (name, _) => parsed_options.push((name.to_string(), Vec::new())),
+        }
+    }
+
+    Ok((from, to, parsed_options))
+}
+
+/// The first of `tags` with a numeric value in the block.
+fn condition(block: &DataBlock, tags: &[&str]) -> Option<f64> {
+    tags.iter()
+        .find_map(|tag| block.value(tag).as_deref().and_then(parse_number))
+}
+
+/// Writes a temperature or pressure to the `tags` the block has, or to the first tag if it has
+/// none. Returns the number of modified values.
+fn write_condition(block: &mut DataBlock, tags: &[&str], value: f64) -> usize {
+    // Rounding removes floating-point noise from converted units, e.g. 1e-4 GPa to 100 kPa.
+    let value = format!("{}", (value * 1e6).round() / 1e6);
+
+    let modified_values_counter = tags
+        .iter()
+        .filter(|tag| block.set_value(tag, &value))
+        .count();
+
+    if modified_values_counter > 0 {
+        return modified_values_counter;
+    }
+
+    block.insert_value(tags[0], &value);
+    1
+}
+
+/// Multiplies the lengths of a, b and c by `factors` and updates `_cell_volume`. The anisotropic
+/// displacement parameters follow the strain. Returns the number of modified values.
+fn scale_cell(block: &mut DataBlock, factors: [f64; 3]) -> anyhow::Result<usize> {
+    let lattice = Lattice::from_block(block)?;
+
+    let [a, b, c] = lattice.lengths();
+    let [alpha, beta, gamma] = lattice.angles();
+
+    let new_lattice = Lattice::new(
+        a * factors[0],
+        b * factors[1],
+        c * factors[2],
+        alpha,
+        beta,
+        gamma,
+    )?;
+
+    let modified_values_counter = new_lattice.write_to(block);
+
+    structure::strain_aniso_sites(block, &lattice, &new_lattice);
+
+    Ok(modified_values_counter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{assert_close, block};
+
+    #[test]
+    fn test_thermal_expansion() {
+        let mut block = block("tests/BaTiO3.cif");
+        let volume = Lattice::from_block(&block).unwrap().volume();
+
+        let expansion: ThermalExpansion = "300 -> 800 with alpha_V = 3e-5".parse().unwrap();
+        expansion.apply_to_block(&mut block).unwrap();
+
+        let lattice = Lattice::from_block(&block).unwrap();
+        assert_close(lattice.volume() / volume, (3e-5 * 500.0f64).exp(), 1e-4);
+        assert_close(
+            parse_number(&block.value("_cell_volume").unwrap()).unwrap(),
+            lattice.volume(),
+            1e-2,
+        );
+        assert_eq!(
+            block.value("_cell_measurement_temperature").as_deref(),
+            Some("800")
+        );
+        assert!(block
+            .to_string()
+            .contains("0.005(1) \n_cell_measurement_temperature      800\n"));
+
+        // The temperature of the block is the start of the next change.
+        let a = lattice.lengths()[0];
+
+        let expansion: ThermalExpansion = "1000 with alpha_c = 1e-5 2e-8".parse().unwrap();
+        expansion.apply_to_block(&mut block).unwrap();
+
+        let lattice = Lattice::from_block(&block).unwrap();
+        assert_close(lattice.lengths()[0], a, 1e-12);
+        assert_close(
+            lattice.lengths()[2] / a,
+            (1e-5 * 200.0 + 1e-8 * (1000.0f64.powi(2) - 800.0f64.powi(2))).exp(),
+            1e-4,
+        );
+    }
+
+    #[test]
+    fn test_birch_murnaghan() {
+        let eos = BirchMurnaghan::new(5.0, 160.0)
+            .unwrap()
+            .with_derivative(4.5)
+            .unwrap();
+
+        for pressure in [-2.0, 0.0, 5.0, 50.0] {
+            assert_close(
+                eos.pressure(eos.volume_ratio(pressure).unwrap()),
+                pressure,
+                1e-9,
+            );
+        }
+        assert_close(eos.volume_ratio(0.0).unwrap(), 1.0, 1e-12);
+
+        let mut block = block("tests/BaTiO3.cif");
+        let volume = Lattice::from_block(&block).unwrap().volume();
+
+        let eos: BirchMurnaghan = "0 -> 5 with B0 = 160".parse().unwrap();
+        eos.apply_to_block(&mut block).unwrap();
+
+        let ratio = Lattice::from_block(&block).unwrap().volume() / volume;
+        assert!(ratio < 1.0);
+        assert_close(eos.pressure(ratio), 5.0, 0.05);
+        assert_eq!(
+            block.value("_cell_measurement_pressure").as_deref(),
+            Some("5000000")
+        );
+        assert!(block
+            .to_string()
+            .contains("\n_cell_measurement_pressure         5000000\n"));
+
+        // Without a start pressure and pressure tags, the block is at 0 GPa.
+        let mut block_without_pressure = self::block("tests/BaTiO3.cif");
+        "5 with B0 = 160"
+            .parse::<BirchMurnaghan>()
+            .unwrap()
+            .apply_to_block(&mut block_without_pressure)
+            .unwrap();
+        assert_eq!(
+            block_without_pressure.value("_cell_length_a"),
+            block.value("_cell_length_a")
+        );
+    }
+
+    #[test]
+    fn test_expansion_from_str() {
+        let expansion: ThermalExpansion = "300 -> 800 with alpha_V = 3e-5".parse().unwrap();
+        assert_eq!(expansion.to_string(), "300 -> 800 with alpha_V = 0.00003");
+
+        let expansion: ThermalExpansion =
+            "800 with alpha=1e-5 alpha_c = 2e-5 1e-9".parse().unwrap();
+        assert_eq!(
+            expansion.to_string(),
+            "800 with alpha_a = 0.00001 alpha_b = 0.00001 alpha_c = 0.00002 0.000000001"
+        );
+        assert_eq!(
+            expansion.to_string().parse::<ThermalExpansion>().unwrap(),
+            expansion
+        );
+
+        let expansion: ThermalExpansion =
+            serde_json::from_str(r#"{"to": 500, "alpha": [1e-5, 1e-9]}"#).unwrap();
+        assert_eq!(
+            expansion.to_string(),
+            "500 with alpha = 0.00001 0.000000001"
+        );
+
+        assert!("800".parse::<ThermalExpansion>().is_err());
+        assert!("-5 with alpha = 1e-5".parse::<ThermalExpansion>().is_err());
+        assert!("800 with alpha_V = 3e-5 alpha = 1e-5"
+            .parse::<ThermalExpansion>()
+            .is_err());
+        assert!("800 with alpha_x = 1e-5"
+            .parse::<ThermalExpansion>()
+            .is_err());
+
+        let eos: BirchMurnaghan = "0 -> 5 with B0 = 160 B0_prime = 4.5".parse().unwrap();
+        assert_eq!(eos.to_string(), "0 -> 5 with B0 = 160 B0_prime = 4.5");
+
+        let eos: BirchMurnaghan = serde_json::from_str(r#"{"to": 5, "B0": 160}"#).unwrap();
+        assert_eq!(eos.to_string(), "5 with B0 = 160");
+
+        assert!("5 with B0 = -1".parse::<BirchMurnaghan>().is_err());
+        assert!("5 with B0_prime = 4".parse::<BirchMurnaghan>().is_err());
+    }
+}
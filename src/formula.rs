@@ -0,0 +1,210 @@
+//! Chemical formulas as written in `_chemical_formula_sum`, e.g. `Ba1 O3 Ti1`.
+
+use std::{collections::BTreeMap, fmt::Display};
+
+/// Counts of elements. Elements are ordered alphabetically, as in ICSD and COD files.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Formula {
+    counts: BTreeMap<String, f64>,
+}
+
+impl Formula {
+    /// Parses a formula of space-separated element symbols with optional counts, e.g. `Ba O3 Ti` or
+    /// `C12 H10.5 N`. Charges and parentheses are not supported.
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let mut formula = Formula::default();
+
+        for part in s.split_whitespace() {
+            let symbol_length = part
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(part.len());
+
+            let (symbol, count) = part.split_at(symbol_length);
+
+            if symbol.is_empty() {
+                return Err(anyhow::anyhow!("{} in {} is not an element", part, s));
+            }
+
+            let count = match count {
+                "" => 1.0,
+                count => count
+                    .parse::<f64>()
+                    .map_err(|_| anyhow::anyhow!("{} in {} has no valid count", part, s))?,
+            };
+
+            formula.add(symbol, count);
+        }
+
+        Ok(formula)
+    }
+
+    pub fn add(&mut self, element: &str, count: f64) {
+        *self.counts.entry(element.to_string()).or_default() += count;
+    }
+
+    pub fn count(&self, element: &str) -> f64 {
+        self.counts.get(element).copied().unwrap_or_default()
+    }
+
+    pub fn elements(&self) -> impl Iterator<Item = (&str, f64)> {
+        self.counts
+            .iter()
+            .map(|(element, count)| (element.as_str(), *count))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /// Multiplies every count by `factor`.
+    pub fn scaled(&self, factor: f64) -> Self {
+        Formula {
+            counts: self
+                .counts
+                .iter()
+                .map(|(element, count)| (element.clone(), count * factor))
+                .collect(),
+        }
+    }
+}
+
+impl Display for Formula {
+    /// Writes counts with up to four decimal places and omits trailing zeros.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parts: Vec<String> = self
+            .counts
+            .iter()
+            .map(|(element, count)| (element, format_count(*count)))
+            .filter(|(_, count)| !count.starts_with('-') && count != "0")
+            .map(|(element, count)| format!("{}{}", element, count))
+            .collect();
+
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+/// Writes a count with up to four decimal places without trailing zeros.
+pub(crate) fn format_count(count: f64) -> String {
+    let count = format!("{:.4}", count);
+
+    count
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_string()
+}
+
+/// Replaces an element in a formula as text, e.g. in `_chemical_formula_structural`. Every
+/// replacement gets its fraction of the count, e.g. `Ba (Ti O3)` becomes `Ba (Ti0.9 Zr0.1 O3)`.
+pub(crate) fn substitute_element(
+    text: &str,
+    element: &str,
+    replacements: &[(&str, f64)],
+) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find(|c: char| c.is_ascii_uppercase()) {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let symbol_length = 1 + rest[1..]
+            .find(|c: char| !c.is_ascii_lowercase())
+            .unwrap_or(rest.len() - 1);
+        let count_length = rest[symbol_length..]
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(rest.len() - symbol_length);
+
+        let (symbol, count) = rest[..symbol_length + count_length].split_at(symbol_length);
+        rest = &rest[symbol_length + count_length..];
+
+        if symbol != element {
+            result.push_str(symbol);
+            result.push_str(count);
+            continue;
+        }
+
+        let has_count = !count.is_empty();
+        let count = count.parse::<f64>().unwrap_or(1.0);
+
+        let parts: Vec<String> = replacements
+            .iter()
+            .map(|(new_element, fraction)| {
+                let new_count = count * fraction;
+
+                if has_count || format_count(new_count) != "1" {
+                    format!("{}{}", new_element, format_count(new_count))
+                } else {
+                    new_element.to_string()
+                }
+            })
+            .collect();
+
+        result.push_str(&parts.join(" "));
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// Returns the element of an atom type or site label, e.g. `Ti` for `Ti4+` or `Ti1`, and `O` for
+/// `O1a`.
+pub fn element_of(symbol: &str) -> Option<String> {
+    let mut chars = symbol.chars();
+
+    let first = chars.next().filter(char::is_ascii_alphabetic)?;
+
+    let mut element = first.to_ascii_uppercase().to_string();
+
+    if let Some(second) = chars.next().filter(char::is_ascii_lowercase) {
+        element.push(second);
+    }
+
+    Some(element)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display() {
+        let formula = Formula::parse("Ba1 O3 Ti1").unwrap();
+        assert_eq!(formula.count("O"), 3.0);
+        assert_eq!(formula.count("Zr"), 0.0);
+        assert_eq!(formula.to_string(), "Ba1 O3 Ti1");
+
+        let formula = Formula::parse("C12 H10.5 N").unwrap();
+        assert_eq!(formula.count("N"), 1.0);
+        assert_eq!(formula.scaled(2.0).to_string(), "C24 H21 N2");
+
+        assert!(Formula::parse("Ba1 3O").is_err());
+    }
+
+    #[test]
+    fn test_substitute_element() {
+        assert_eq!(
+            substitute_element("Ba (Ti O3)", "Ti", &[("Ti", 0.9), ("Zr", 0.1)]),
+            "Ba (Ti0.9 Zr0.1 O3)"
+        );
+        assert_eq!(
+            substitute_element("Ba (Ti O3)", "Ti", &[("Zr", 1.0)]),
+            "Ba (Zr O3)"
+        );
+        assert_eq!(
+            substitute_element("Ba2 Ti2 O6", "Ti", &[("Zr", 1.0)]),
+            "Ba2 Zr2 O6"
+        );
+        assert_eq!(
+            substitute_element("Ba Ti O3", "O", &[("F", 1.0)]),
+            "Ba Ti F3"
+        );
+    }
+
+    #[test]
+    fn test_element_of() {
+        assert_eq!(element_of("Ti4+").as_deref(), Some("Ti"));
+        assert_eq!(element_of("O1a").as_deref(), Some("O"));
+        assert_eq!(element_of("Ba1").as_deref(), Some("Ba"));
+        assert_eq!(element_of("1"), None);
+    }
+}
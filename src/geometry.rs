@@ -0,0 +1,949 @@
+//! Interatomic distances and bond angles of the atom sites, including their periodic and symmetry
+//! images, e.g. to find unphysically short contacts after random displacements.
+//!
+//! Symmetry codes follow the `_geom_bond_site_symmetry_2` convention: `2_565` is the image of a site
+//! under the second symmetry operator, translated by (0, 1, 0). The identity without translation
+//! is `.`.
+//!
+//! A [`MinDistance`] rejects modified structures with atoms closer than a cutoff.
+
+use std::{fmt::Display, io::Read, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    decompress,
+    linalg::{self, Vector3},
+    structure::{self, AtomSites},
+    symmetry::{self, SymmetryOperator},
+    Cif, DataBlock, Instructions, Item, Lattice, Loop,
+};
+
+/// Contacts up to this distance in Å are reported by default.
+pub const DEFAULT_MAX_DISTANCE: f64 = 3.0;
+
+/// Images closer than this in Å to the central atom are the atom itself or share its site.
+const SAME_SITE_DISTANCE: f64 = symmetry::DEFAULT_TOLERANCE;
+
+/// Distances in Å and angles in degrees that change by less than this are unchanged.
+const CHANGE_TOLERANCE: f64 = 1e-4;
+
+/// Decimal places of distances in Å and angles in degrees in the `_geom_` loops.
+const DISTANCE_DIGITS: usize = 4;
+const ANGLE_DIGITS: usize = 2;
+
+pub(crate) const MIN_DISTANCE_KEYWORD: &str = "min_distance";
+
+/// Optional unit after the cutoff of a [`MinDistance`].
+const ANGSTROM_UNITS: [&str; 2] = ["Å", "A"];
+
+/// The symmetry code of the identity without translation.
+const IDENTITY_CODE: &str = ".";
+
+const BOND_TAGS: [&str; 4] = [
+    "_geom_bond_atom_site_label_1",
+    "_geom_bond_atom_site_label_2",
+    "_geom_bond_distance",
+    "_geom_bond_site_symmetry_2",
+];
+
+const ANGLE_TAGS: [&str; 6] = [
+    "_geom_angle_atom_site_label_1",
+    "_geom_angle_atom_site_label_2",
+    "_geom_angle_atom_site_label_3",
+    "_geom_angle",
+    "_geom_angle_site_symmetry_1",
+    "_geom_angle_site_symmetry_3",
+];
+
+/// The distance from `site_1` to the image `symmetry_2` of `site_2` in Å.
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub struct Bond {
+    pub site_1: String,
+    pub site_2: String,
+    pub symmetry_2: String,
+    pub distance: f64,
+}
+
+impl Bond {
+    /// The bond as `Ti1-O1 (2_565)`, the key that matches bonds before and after a modification.
+    pub fn description(&self) -> String {
+        format!("{}-{} ({})", self.site_1, self.site_2, self.symmetry_2)
+    }
+}
+
+/// The angle at `site_2` between the images `symmetry_1` of `site_1` and `symmetry_3` of `site_3`
+/// in degrees.
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub struct Angle {
+    pub site_1: String,
+    pub site_2: String,
+    pub site_3: String,
+    pub symmetry_1: String,
+    pub symmetry_3: String,
+    pub angle: f64,
+}
+
+impl Angle {
+    /// The angle as `O1-Ti1-O1 (., 2_565)`.
+    pub fn description(&self) -> String {
+        format!(
+            "{}-{}-{} ({}, {})",
+            self.site_1, self.site_2, self.site_3, self.symmetry_1, self.symmetry_3
+        )
+    }
+}
+
+/// The change of a distance or angle by a modification. Contacts that appear or disappear within
+/// the maximum distance have no value before or after.
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub struct Change {
+    pub description: String,
+    pub before: Option<f64>,
+    pub after: Option<f64>,
+}
+
+/// A neighbour of a central atom.
+struct Neighbour {
+    row: usize,
+    symmetry: String,
+    /// The Cartesian vector from the central atom in Å.
+    vector: Vector3,
+    distance: f64,
+}
+
+/// The bonds from every atom site to the images of all sites within a maximum distance and the
+/// angles between them.
+#[derive(PartialEq, Debug, Clone, Default, Serialize)]
+pub struct Geometry {
+    pub bonds: Vec<Bond>,
+    pub angles: Vec<Angle>,
+}
+
+impl Geometry {
+    /// Computes the geometry of the atom sites of a block. Blocks without atom sites have none.
+    pub fn from_block(block: &DataBlock, max_distance: f64) -> anyhow::Result<Self> {
+        if block.find_loop(structure::LABEL_TAG).is_none() {
+            return Ok(Geometry::default());
+        }
+
+        let lattice = Lattice::from_block(block)?;
+        let sites = AtomSites::from_block(block)?;
+
+        let mut operators = symmetry::symmetry_operators(block)?;
+
+        if operators.is_empty() {
+            operators.push(SymmetryOperator::identity());
+        }
+
+        let positions = (0..sites.len())
+            .map(|row| sites.position(row))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let mut geometry = Geometry::default();
+
+        for (row, position) in positions.iter().enumerate() {
+            let neighbours = neighbours(&lattice, &operators, &positions, *position, max_distance);
+
+            for neighbour in &neighbours {
+                geometry.bonds.push(Bond {
+                    site_1: sites.label(row).to_string(),
+                    site_2: sites.label(neighbour.row).to_string(),
+                    symmetry_2: neighbour.symmetry.clone(),
+                    distance: neighbour.distance,
+                });
+            }
+
+            // Angles use a fixed neighbour order so they keep their names when distances change.
+            let mut ordered: Vec<&Neighbour> = neighbours.iter().collect();
+            ordered.sort_by(|a, b| (a.row, &a.symmetry).cmp(&(b.row, &b.symmetry)));
+
+            for (index, first) in ordered.iter().enumerate() {
+                for second in &ordered[index + 1..] {
+                    let cosine = linalg::dot(first.vector, second.vector)
+                        / (first.distance * second.distance);
+
+                    geometry.angles.push(Angle {
+                        site_1: sites.label(first.row).to_string(),
+                        site_2: sites.label(row).to_string(),
+                        site_3: sites.label(second.row).to_string(),
+                        symmetry_1: first.symmetry.clone(),
+                        symmetry_3: second.symmetry.clone(),
+                        angle: cosine.clamp(-1.0, 1.0).acos().to_degrees(),
+                    });
+                }
+            }
+        }
+
+        Ok(geometry)
+    }
+
+    /// The shortest bond, i.e. the closest contact between two atoms.
+    pub fn shortest_bond(&self) -> Option<&Bond> {
+        self.bonds
+            .iter()
+            .min_by(|a, b| a.distance.total_cmp(&b.distance))
+    }
+
+    /// The distances and angles that changed from `before` to this geometry by more than
+    /// `tolerance`, including contacts that appeared or disappeared.
+    pub fn changes_from(&self, before: &Geometry, tolerance: f64) -> Vec<Change> {
+        let bonds = |geometry: &Geometry| -> Vec<(String, f64)> {
+            geometry
+                .bonds
+                .iter()
+                .map(|bond| (bond.description(), bond.distance))
+                .collect()
+        };
+
+        let angles = |geometry: &Geometry| -> Vec<(String, f64)> {
+            geometry
+                .angles
+                .iter()
+                .map(|angle| (angle.description(), angle.angle))
+                .collect()
+        };
+
+        let mut bond_changes = changes(&bonds(before), &bonds(self), tolerance);
+        bond_changes.extend(changes(&angles(before), &angles(self), tolerance));
+
+        bond_changes
+    }
+
+    /// Writes the `_geom_bond_` and `_geom_angle_` loops to the block. Existing loops are replaced.
+    /// Every bond is written once, from the site that comes first in the atom sites. Returns the
+    /// number of written rows.
+    pub fn write_to(&self, block: &mut DataBlock) -> usize {
+        let [bonds, angles] = self.loops();
+        let rows_count = bonds.len() + angles.len();
+
+        for l in [bonds, angles] {
+            let tag = l.tags()[0].clone();
+
+            let position = block
+                .items()
+                .iter()
+                .position(|item| matches!(item, Item::Loop(other) if other.column(&tag).is_some()));
+
+            match position {
+                Some(position) => block.items_mut()[position] = Item::Loop(l),
+                None => block.push_item(Item::Loop(l)),
+            }
+        }
+
+        rows_count
+    }
+
+    /// The `_geom_bond_` and `_geom_angle_` loops.
+    pub fn loops(&self) -> [Loop; 2] {
+        let labels: Vec<&str> = self.bonds.iter().map(|bond| bond.site_1.as_str()).fold(
+            Vec::new(),
+            |mut labels, label| {
+                if labels.last() != Some(&label) {
+                    labels.push(label);
+                }
+                labels
+            },
+        );
+
+        let index = |label: &str| labels.iter().position(|l| *l == label);
+
+        let bond_rows = self
+            .bonds
+            .iter()
+            .filter(|bond| index(&bond.site_1) <= index(&bond.site_2))
+            .map(|bond| {
+                vec![
+                    bond.site_1.clone(),
+                    bond.site_2.clone(),
+                    format!("{:.*}", DISTANCE_DIGITS, bond.distance),
+                    bond.symmetry_2.clone(),
+                ]
+            })
+            .collect();
+
+        let angle_rows = self
+            .angles
+            .iter()
+            .map(|angle| {
+                vec![
+                    angle.site_1.clone(),
+                    angle.site_2.clone(),
+                    angle.site_3.clone(),
+                    format!("{:.*}", ANGLE_DIGITS, angle.angle),
+                    angle.symmetry_1.clone(),
+                    angle.symmetry_3.clone(),
+                ]
+            })
+            .collect();
+
+        let tags = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect();
+
+        [
+            Loop::new(tags(&BOND_TAGS), bond_rows),
+            Loop::new(tags(&ANGLE_TAGS), angle_rows),
+        ]
+    }
+}
+
+/// The images of all sites within `max_distance` of `center`, sorted by distance.
+fn neighbours(
+    lattice: &Lattice,
+    operators: &[SymmetryOperator],
+    positions: &[Vector3],
+    center: Vector3,
+    max_distance: f64,
+) -> Vec<Neighbour> {
+    // A vector of length r has fractional components of at most r |a*|.
+    let ranges = lattice
+        .reciprocal()
+        .lengths()
+        .map(|length| (max_distance * length + 0.5).ceil() as i64);
+
+    let mut neighbours: Vec<Neighbour> = Vec::new();
+
+    for (row, position) in positions.iter().enumerate() {
+        let mut images: Vec<Neighbour> = Vec::new();
+
+        // The identity goes first so that atoms in the asymmetric unit are named `.`.
+        let mut order: Vec<usize> = (0..operators.len()).collect();
+        order.sort_by_key(|index| !is_identity(&operators[*index]));
+
+        for index in order {
+            let operator = &operators[index];
+            let image = operator.apply(*position);
+            let closest = linalg::sub(center, image).map(f64::round);
+
+            for i in -ranges[0]..=ranges[0] {
+                for j in -ranges[1]..=ranges[1] {
+                    for k in -ranges[2]..=ranges[2] {
+                        let translation = linalg::add(closest, [i as f64, j as f64, k as f64]);
+
+                        let vector = lattice
+                            .to_cartesian(linalg::sub(linalg::add(image, translation), center));
+                        let distance = linalg::norm(vector);
+
+                        // Operators that map a site on a special position onto itself give the same
+                        // image more than once.
+                        let is_duplicate = images.iter().any(|other| {
+                            linalg::norm(linalg::sub(other.vector, vector)) < SAME_SITE_DISTANCE
+                        });
+
+                        if distance < SAME_SITE_DISTANCE || distance > max_distance || is_duplicate
+                        {
+                            continue;
+                        }
+
+                        images.push(Neighbour {
+                            row,
+                            symmetry: symmetry_code(index, is_identity(operator), translation),
+                            vector,
+                            distance,
+                        });
+                    }
+                }
+            }
+        }
+
+        neighbours.extend(images);
+    }
+
+    neighbours.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+
+    neighbours
+}
+
+/// The symmetry code of the operator with the given index followed by a translation, e.g. `2_565`,
+/// or `.` for the untranslated identity.
+fn symmetry_code(operator_index: usize, identity: bool, translation: Vector3) -> String {
+    let translation = translation.map(|t| t.round() as i64);
+
+    if identity && translation == [0; 3] {
+        return IDENTITY_CODE.to_string();
+    }
+
+    format!(
+        "{}_{}{}{}",
+        operator_index + 1,
+        5 + translation[0],
+        5 + translation[1],
+        5 + translation[2]
+    )
+}
+
+fn is_identity(operator: &SymmetryOperator) -> bool {
+    *operator == SymmetryOperator::identity()
+}
+
+/// The values of `after` that differ from `before` by more than `tolerance` or only exist in one.
+fn changes(before: &[(String, f64)], after: &[(String, f64)], tolerance: f64) -> Vec<Change> {
+    let mut changes: Vec<Change> = after
+        .iter()
+        .map(|(description, value)| Change {
+            description: description.clone(),
+            before: before
+                .iter()
+                .find(|(other, _)| other == description)
+                .map(|(_, value)| *value),
+            after: Some(*value),
+        })
+        .filter(|change| {
+            change
+                .before
+                .is_none_or(|before| (before - change.after.unwrap_or_default()).abs() > tolerance)
+        })
+        .collect();
+
+    changes.extend(
+        before
+            .iter()
+            .filter(|(description, _)| after.iter().all(|(other, _)| other != description))
+            .map(|(description, value)| Change {
+                description: description.clone(),
+                before: Some(*value),
+                after: None,
+            }),
+    );
+
+    changes
+}
+
+/// The geometry of a data block and, for a modified block, how it changed.
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub struct BlockGeometry {
+    pub block: String,
+    pub geometry: Geometry,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub changes: Option<Vec<Change>>,
+}
+
+impl Display for BlockGeometry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "  data_{}:", self.block)?;
+
+        if let Some(bond) = self.geometry.shortest_bond() {
+            writeln!(
+                f,
+                "    shortest contact {} {:.*} Å",
+                bond.description(),
+                DISTANCE_DIGITS,
+                bond.distance
+            )?;
+        }
+
+        let format_value = |value: Option<f64>| match value {
+            Some(value) => format!("{:.*}", DISTANCE_DIGITS, value),
+            None => "-".to_string(),
+        };
+
+        if let Some(changes) = &self.changes {
+            writeln!(f, "    changes")?;
+
+            for change in changes {
+                writeln!(
+                    f,
+                    "      {:<32}{:>10} -> {}",
+                    change.description,
+                    format_value(change.before),
+                    format_value(change.after)
+                )?;
+            }
+
+            return Ok(());
+        }
+
+        writeln!(f, "    bonds")?;
+
+        for bond in &self.geometry.bonds {
+            writeln!(
+                f,
+                "      {:<8}{:<8}{:<10}{:.*}",
+                bond.site_1, bond.site_2, bond.symmetry_2, DISTANCE_DIGITS, bond.distance
+            )?;
+        }
+
+        writeln!(f, "    angles")?;
+
+        for angle in &self.geometry.angles {
+            writeln!(
+                f,
+                "      {:<8}{:<8}{:<8}{:<10}{:<10}{:.*}",
+                angle.site_1,
+                angle.site_2,
+                angle.site_3,
+                angle.symmetry_1,
+                angle.symmetry_3,
+                ANGLE_DIGITS,
+                angle.angle
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub struct FileGeometry {
+    pub path: String,
+    pub blocks: Vec<BlockGeometry>,
+}
+
+impl FileGeometry {
+    /// The `_geom_` loops of every block as CIF.
+    pub fn to_cif(&self) -> String {
+        let mut cif = String::new();
+
+        for block in &self.blocks {
+            cif.push_str(&format!("data_{}\n", block.block));
+
+            for l in block.geometry.loops() {
+                cif.push_str(&format!("{}\n", l));
+            }
+
+            cif.push('\n');
+        }
+
+        cif
+    }
+}
+
+impl Display for FileGeometry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.path)?;
+
+        for block in &self.blocks {
+            write!(f, "{}", block)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The shortest allowed distance in Å between two atoms, e.g. `min_distance 1.5` for all atoms or
+/// `min_distance Ti O 1.8` for a pair of elements. A cutoff for a pair of elements takes precedence
+/// over one for all atoms.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "MinDistanceSpec", into = "MinDistanceSpec")]
+pub struct MinDistance {
+    elements: Option<[String; 2]>,
+    distance: f64,
+}
+
+impl MinDistance {
+    /// Fails if the distance is not positive.
+    pub fn new(distance: f64) -> anyhow::Result<Self> {
+        if !(distance > 0.0 && distance.is_finite()) {
+            return Err(anyhow::anyhow!(
+                "The minimum distance {} Å has to be positive",
+                distance
+            ));
+        }
+
+        Ok(MinDistance {
+            elements: None,
+            distance,
+        })
+    }
+
+    pub fn with_elements(mut self, element_1: &str, element_2: &str) -> Self {
+        self.elements = Some([element_1.to_string(), element_2.to_string()]);
+        self
+    }
+
+    pub fn distance(&self) -> f64 {
+        self.distance
+    }
+
+    fn is_pair(&self, element_1: Option<&str>, element_2: Option<&str>) -> bool {
+        match (&self.elements, element_1, element_2) {
+            (Some([a, b]), Some(element_1), Some(element_2)) => {
+                (a == element_1 && b == element_2) || (a == element_2 && b == element_1)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for MinDistance {
+    type Err = anyhow::Error;
+
+    /// Reads e.g. `1.5`, `1.5 Å` or `Ti O 1.8`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s
+            .split_whitespace()
+            .filter(|word| !ANGSTROM_UNITS.contains(word))
+            .collect();
+
+        let parse_distance = |word: &str| {
+            word.parse::<f64>()
+                .map_err(|_| anyhow::anyhow!("Invalid minimum distance {}", word))
+        };
+
+        match words[..] {
+            [distance] => MinDistance::new(parse_distance(distance)?),
+            [element_1, element_2, distance] => Ok(
+                MinDistance::new(parse_distance(distance)?)?.with_elements(element_1, element_2)
+            ),
+            _ => Err(anyhow::anyhow!(
+                "{} takes a distance or two elements and a distance, not {}",
+                MIN_DISTANCE_KEYWORD,
+                s.trim()
+            )),
+        }
+    }
+}
+
+impl Display for MinDistance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some([element_1, element_2]) = &self.elements {
+            write!(f, "{} {} ", element_1, element_2)?;
+        }
+
+        write!(f, "{}", self.distance)
+    }
+}
+
+/// The structured form of a [`MinDistance`], e.g. `{ elements = ["Ti", "O"], distance = 1.8 }`.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MinDistanceSpec {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    elements: Option<[String; 2]>,
+    distance: f64,
+}
+
+impl TryFrom<MinDistanceSpec> for MinDistance {
+    type Error = anyhow::Error;
+
+    fn try_from(spec: MinDistanceSpec) -> Result<Self, Self::Error> {
+        let min_distance = MinDistance::new(spec.distance)?;
+
+        Ok(match spec.elements {
+            Some([element_1, element_2]) => min_distance.with_elements(&element_1, &element_2),
+            None => min_distance,
+        })
+    }
+}
+
+impl From<MinDistance> for MinDistanceSpec {
+    fn from(min_distance: MinDistance) -> Self {
+        MinDistanceSpec {
+            elements: min_distance.elements,
+            distance: min_distance.distance,
+        }
+    }
+}
+
+/// Checks that no two atoms of a block, including their symmetry and periodic images, are closer
+/// than the minimum distances. Atoms that share a site, e.g. of a mixed occupancy, are not checked.
+pub(crate) fn check_min_distances(
+    block: &DataBlock,
+    min_distances: &[MinDistance],
+) -> anyhow::Result<()> {
+    if min_distances.is_empty() || block.find_loop(structure::LABEL_TAG).is_none() {
+        return Ok(());
+    }
+
+    let lattice = Lattice::from_block(block)?;
+    let sites = AtomSites::from_block(block)?;
+
+    let mut operators = symmetry::symmetry_operators(block)?;
+
+    if operators.is_empty() {
+        operators.push(SymmetryOperator::identity());
+    }
+
+    let positions = (0..sites.len())
+        .map(|row| sites.position(row))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let elements: Vec<Option<String>> = (0..sites.len()).map(|row| sites.element(row)).collect();
+
+    let cutoff = |row_1: usize, row_2: usize| {
+        let (element_1, element_2) = (elements[row_1].as_deref(), elements[row_2].as_deref());
+
+        min_distances
+            .iter()
+            .find(|min_distance| min_distance.is_pair(element_1, element_2))
+            .or_else(|| {
+                min_distances
+                    .iter()
+                    .filter(|min_distance| min_distance.elements.is_none())
+                    .max_by(|a, b| a.distance.total_cmp(&b.distance))
+            })
+            .map(MinDistance::distance)
+    };
+
+    let max_distance = min_distances
+        .iter()
+        .map(MinDistance::distance)
+        .fold(0.0, f64::max);
+
+    for (row, position) in positions.iter().enumerate() {
+        for neighbour in neighbours(&lattice, &operators, &positions, *position, max_distance) {
+            let Some(cutoff) = cutoff(row, neighbour.row) else {
+                continue;
+            };
+
+            if neighbour.distance < cutoff {
+                return Err(anyhow::anyhow!(
+                    "{}-{} ({}) in data_{} is {:.*} Å, shorter than the minimum distance of {} Å",
+                    sites.label(row),
+                    sites.label(neighbour.row),
+                    neighbour.symmetry,
+                    block.name().unwrap_or_default(),
+                    DISTANCE_DIGITS,
+                    neighbour.distance,
+                    cutoff
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The labels of the atom sites of a block. Blocks without atom sites have none.
+fn site_labels(block: &DataBlock) -> anyhow::Result<Vec<String>> {
+    if block.find_loop(structure::LABEL_TAG).is_none() {
+        return Ok(Vec::new());
+    }
+
+    let sites = AtomSites::from_block(block)?;
+
+    Ok((0..sites.len())
+        .map(|row| sites.label(row).to_string())
+        .collect())
+}
+
+/// Returns whether sites disappeared and sites with new labels appeared, e.g. `O1` became `O1_1`,
+/// `O1_2` and `O1_3`. Bonds and angles are matched by label, so they cannot be compared then.
+fn is_relabelled(before: &[String], after: &[String]) -> bool {
+    before.iter().any(|label| !after.contains(label))
+        && after.iter().any(|label| !before.contains(label))
+}
+
+/// Reads a CIF, which may be gzip or xz compressed, and computes the geometry of every named data
+/// block. With instructions, the geometry of the modified blocks is compared to the original.
+/// Distances and angles that changed by less than `CHANGE_TOLERANCE` are not reported as changes.
+pub fn geometry_of_reader(
+    path: &str,
+    reader: impl Read,
+    max_distance: f64,
+    instructions: Option<&Instructions>,
+) -> anyhow::Result<FileGeometry> {
+    let (_, mut reader) = decompress(reader)?;
+
+    let mut content = String::new();
+    reader.read_to_string(&mut content)?;
+
+    let cif = Cif::parse(&content)?;
+
+    let mut blocks = Vec::new();
+
+    for block in cif.blocks().iter().filter(|block| block.name().is_some()) {
+        let geometry = Geometry::from_block(block, max_distance)?;
+
+        let block_geometry = match instructions {
+            Some(instructions) => {
+                let mut modified_block = block.clone();
+                instructions.apply_to_block(&mut modified_block)?;
+
+                if is_relabelled(&site_labels(block)?, &site_labels(&modified_block)?) {
+                    return Err(anyhow::anyhow!(
+                        "Cannot compare the geometry of data_{} before and after the instructions, \
+                         because they relabel the atom sites, e.g. with expand or supercell. \
+                         Compute the geometry of the modified CIF instead.",
+                        block.name().unwrap_or_default()
+                    ));
+                }
+
+                let modified_geometry = Geometry::from_block(&modified_block, max_distance)?;
+
+                BlockGeometry {
+                    block: block.name().unwrap_or_default().to_string(),
+                    changes: Some(modified_geometry.changes_from(&geometry, CHANGE_TOLERANCE)),
+                    geometry: modified_geometry,
+                }
+            }
+            None => BlockGeometry {
+                block: block.name().unwrap_or_default().to_string(),
+                geometry,
+                changes: None,
+            },
+        };
+
+        blocks.push(block_geometry);
+    }
+
+    Ok(FileGeometry {
+        path: path.to_string(),
+        blocks,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{assert_close, block};
+
+    #[test]
+    fn test_geometry() {
+        let block = block("tests/BaTiO3.cif");
+        let a = 4.0094;
+
+        let geometry = Geometry::from_block(&block, 2.5).unwrap();
+
+        // Only the Ti-O bonds are shorter than 2.5 Å: six around Ti and two around every O.
+        let ti_bonds: Vec<&Bond> = geometry
+            .bonds
+            .iter()
+            .filter(|bond| bond.site_1 == "Ti1")
+            .collect();
+        assert_eq!(ti_bonds.len(), 6);
+        assert!(ti_bonds
+            .iter()
+            .all(|bond| (bond.distance - a / 2.0).abs() < 1e-4));
+        assert_eq!(geometry.bonds.len(), 8);
+
+        let shortest = geometry.shortest_bond().unwrap();
+        assert_close(shortest.distance, a / 2.0, 1e-4);
+
+        // The octahedron has 12 angles of 90° and 3 of 180°, the O has one of 180°.
+        let angles: Vec<f64> = geometry.angles.iter().map(|angle| angle.angle).collect();
+        assert_eq!(angles.len(), 16);
+        assert_eq!(
+            angles.iter().filter(|a| (**a - 90.0).abs() < 1e-6).count(),
+            12
+        );
+        assert_eq!(
+            angles.iter().filter(|a| (**a - 180.0).abs() < 1e-6).count(),
+            4
+        );
+
+        // Symmetry images in Pm-3m.
+        let o_bonds: Vec<&Bond> = geometry
+            .bonds
+            .iter()
+            .filter(|bond| bond.site_1 == "O1")
+            .collect();
+        assert_eq!(o_bonds.len(), 2);
+        assert!(o_bonds.iter().any(|bond| bond.symmetry_2 == "."));
+        assert!(o_bonds.iter().any(|bond| bond.symmetry_2 == "48_545"));
+    }
+
+    #[test]
+    fn test_geometry_in_p1() {
+        let geometry = Geometry::from_block(&block("tests/BaTiO3_P1.cif"), 2.5).unwrap();
+
+        let bonds: Vec<&Bond> = geometry
+            .bonds
+            .iter()
+            .filter(|bond| bond.site_1 == "Ti1")
+            .collect();
+        assert_eq!(bonds.len(), 6);
+        assert_eq!(
+            bonds
+                .iter()
+                .filter(|bond| bond.symmetry_2 == IDENTITY_CODE)
+                .count(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_changes_and_loops() {
+        let mut block = block("tests/BaTiO3_P1.cif");
+        let before = Geometry::from_block(&block, 2.5).unwrap();
+
+        Instructions::from_string("a * 1.01")
+            .unwrap()
+            .apply_to_block(&mut block)
+            .unwrap();
+        let after = Geometry::from_block(&block, 2.5).unwrap();
+
+        let changes = after.changes_from(&before, CHANGE_TOLERANCE);
+        let change = changes
+            .iter()
+            .find(|change| change.description == "Ti1-O2 (.)")
+            .unwrap();
+        assert_close(change.before.unwrap(), 2.0047, 1e-4);
+        assert_close(change.after.unwrap(), 2.0248, 1e-4);
+        assert!(!changes
+            .iter()
+            .any(|change| change.description == "Ti1-O1 (.)"));
+        assert!(changes
+            .iter()
+            .all(|change| change.before.is_some() && change.after.is_some()));
+
+        // Every bond is written once, the angles of Ti1 and of every O.
+        assert_eq!(after.write_to(&mut block), 6 + 18);
+        assert_eq!(after.write_to(&mut block), 6 + 18);
+
+        let bonds = block.find_loop("_geom_bond_distance").unwrap();
+        assert_eq!(bonds.len(), 6);
+        assert_eq!(bonds.row(0)[..2], ["Ti1".to_string(), "O1".to_string()]);
+        assert!(block.find_loop("_geom_angle").is_some());
+    }
+
+    #[test]
+    fn test_changes_need_the_same_labels() {
+        let content = std::fs::read_to_string("tests/BaTiO3.cif").unwrap();
+        let geometry = |instructions: &str| {
+            geometry_of_reader(
+                "tests/BaTiO3.cif",
+                content.as_bytes(),
+                2.5,
+                Some(&Instructions::from_string(instructions).unwrap()),
+            )
+        };
+
+        let error = geometry("expand").unwrap_err();
+        assert!(format!("{:#}", error).contains("relabel the atom sites"));
+        assert!(geometry("supercell 2 1 1").is_err());
+
+        let removed = geometry("vacancy O1").unwrap();
+        let changes = removed.blocks[0].changes.as_ref().unwrap();
+        assert!(!changes.is_empty());
+        assert!(changes.iter().all(|change| change.after.is_none()));
+    }
+
+    #[test]
+    fn test_min_distance() {
+        let min_distance: MinDistance = "Ti O 1.8 Å".parse().unwrap();
+        assert_eq!(min_distance.to_string(), "Ti O 1.8");
+        assert!(min_distance.is_pair(Some("O"), Some("Ti")));
+        assert!(!min_distance.is_pair(Some("O"), Some("O")));
+        assert_eq!("1.5".parse::<MinDistance>().unwrap().to_string(), "1.5");
+        assert!("0".parse::<MinDistance>().is_err());
+        assert!("Ti 1.5".parse::<MinDistance>().is_err());
+
+        let json = r#"{"elements":["Ti","O"],"distance":1.8}"#;
+        assert_eq!(serde_json::to_string(&min_distance).unwrap(), json);
+        assert_eq!(
+            serde_json::from_str::<MinDistance>(json).unwrap(),
+            min_distance
+        );
+
+        // The shortest contact of BaTiO3 is Ti-O at 2.0047 Å, symmetry images included.
+        let block = block("tests/BaTiO3.cif");
+        let check = |min_distances: &[&str]| {
+            let min_distances: Vec<MinDistance> =
+                min_distances.iter().map(|s| s.parse().unwrap()).collect();
+            check_min_distances(&block, &min_distances)
+        };
+
+        assert!(check(&["2.0"]).is_ok());
+        assert!(check(&["2.1"]).is_err());
+        assert!(check(&["2.1", "Ti O 2.0"]).is_ok());
+        assert!(check(&["Ba O 2.9"]).is_err());
+        assert!(check(&["Ba Ti 3.0"]).is_ok());
+    }
+
+    #[test]
+    fn test_symmetry_code() {
+        assert_eq!(symmetry_code(0, true, [0.0, 0.0, 0.0]), ".");
+        assert_eq!(symmetry_code(0, true, [0.0, 1.0, 0.0]), "1_565");
+        assert_eq!(symmetry_code(2, false, [0.0, 0.0, 0.0]), "3_555");
+        assert_eq!(symmetry_code(2, false, [-1.0, 0.0, 1.0]), "3_456");
+    }
+}
@@ -0,0 +1,438 @@
+//! Parser for instruction files.
+//!
+//! ```text
+//! # Comments start with `#` and run to the end of the line.
+//! let strain = 1.01
+//!
+//! include "common.txt"
+//! a * $strain
+//!
+//! [preset strain_1pct]
+//! b * $strain; c * $strain
+//! ```
+//!
+//! - Every line holds one instruction. Several instructions on one line are separated by `;`, or by
+//!   `,` at the end of a word.
+//! - Lines that start with the name of an operation, e.g. `supercell 2 2 1`, are operations on the
+//!   whole structure, see [`crate::Operation`].
+//! - `let name = value` defines a variable that is referenced as `$name` in later lines.
+//! - `include "path"` inserts the instructions of another file at this point. Relative paths are
+//!   resolved against the directory of the including file. Presets started in the included file
+//!   end with it.
+//! - `evaluation original` reads the values of other tags from the unmodified file, see
+//!   [`crate::Evaluation`].
+//! - `safeguard clamp` sets what happens to values outside the bounds of their tag, see
+//!   [`crate::Safeguard`].
+//! - `min_distance 1.5` or `min_distance Ti O 1.8` rejects results with atoms closer than the
+//!   cutoff in Å, see [`crate::MinDistance`].
+//! - `[preset name]` starts a named preset. All following lines belong to it until the next preset
+//!   header. Lines before the first preset header are always applied, presets only when selected.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::{
+    geometry::MIN_DISTANCE_KEYWORD, instructions::EVALUATION_KEYWORD,
+    safeguards::SAFEGUARD_KEYWORD, Evaluation, Instructions, MinDistance, Safeguard, Step,
+};
+
+const COMMENT: char = '#';
+const SEPARATOR: char = ';';
+const COMMA: char = ',';
+const VARIABLE_PREFIX: char = '$';
+
+const LET_KEYWORD: &str = "let";
+const INCLUDE_KEYWORD: &str = "include";
+const PRESET_KEYWORD: &str = "preset";
+
+#[derive(Debug, Clone, Default)]
+pub struct InstructionFile {
+    common: Vec<Step>,
+    presets: Vec<(String, Vec<Step>)>,
+    evaluation: Evaluation,
+    safeguard: Safeguard,
+    min_distances: Vec<MinDistance>,
+}
+
+impl InstructionFile {
+    pub fn from_file(path: &str) -> anyhow::Result<Self> {
+        let mut parser = Parser::default();
+
+        parser.parse_file(Path::new(path))?;
+
+        Ok(parser.file)
+    }
+
+    pub fn from_string(s: &str) -> anyhow::Result<Self> {
+        let mut parser = Parser::default();
+
+        parser.parse_str(s, Path::new("."), "<string>")?;
+
+        Ok(parser.file)
+    }
+
+    pub fn preset_names(&self) -> Vec<&str> {
+        self.presets.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    /// Returns the instructions outside of presets followed by the instructions of the selected
+    /// presets in the given order.
+    pub fn instructions(&self, presets: &[String]) -> anyhow::Result<Instructions> {
+        let mut instructions = self.common.clone();
+
+        for preset in presets {
+            let (_, preset_instructions) = self
+                .presets
+                .iter()
+                .find(|(name, _)| name == preset)
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Unknown preset {}. Available presets: {}",
+                        preset,
+                        self.preset_names().join(", ")
+                    )
+                })?;
+
+            instructions.extend(preset_instructions.iter().cloned());
+        }
+
+        let instructions = Instructions::from(instructions)
+            .with_evaluation(self.evaluation)
+            .with_safeguard(self.safeguard);
+
+        Ok(self
+            .min_distances
+            .iter()
+            .cloned()
+            .fold(instructions, Instructions::with_min_distance))
+    }
+}
+
+#[derive(Default)]
+struct Parser {
+    file: InstructionFile,
+    variables: HashMap<String, String>,
+    current_preset: Option<usize>,
+    include_stack: Vec<PathBuf>,
+}
+
+impl Parser {
+    fn parse_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        let canonical_path = path
+            .canonicalize()
+            .with_context(|| format!("Could not open instruction file {}", path.display()))?;
+
+        if self.include_stack.contains(&canonical_path) {
+            return Err(anyhow::anyhow!(
+                "{} includes itself",
+                canonical_path.display()
+            ));
+        }
+
+        let content = std::fs::read_to_string(&canonical_path)
+            .with_context(|| format!("Could not read instruction file {}", path.display()))?;
+
+        let directory = canonical_path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+
+        // Preset headers of an included file only apply to the rest of that file.
+        let current_preset = self.current_preset;
+
+        self.include_stack.push(canonical_path);
+
+        let result = self.parse_str(&content, &directory, &path.display().to_string());
+
+        self.include_stack.pop();
+
+        self.current_preset = current_preset;
+
+        result
+    }
+
+    fn parse_str(&mut self, s: &str, directory: &Path, source: &str) -> anyhow::Result<()> {
+        for (index, line) in s.lines().enumerate() {
+            self.parse_line(line, directory)
+                .with_context(|| format!("{}:{}: {}", source, index + 1, line.trim()))?;
+        }
+
+        Ok(())
+    }
+
+    fn parse_line(&mut self, line: &str, directory: &Path) -> anyhow::Result<()> {
+        let line = strip_comment(line).trim();
+
+        if line.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(header) = line.strip_prefix('[') {
+            let header = header
+                .strip_suffix(']')
+                .ok_or_else(|| anyhow::anyhow!("Missing closing ] in preset header"))?;
+
+            let name = header
+                .trim()
+                .strip_prefix(PRESET_KEYWORD)
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .ok_or_else(|| anyhow::anyhow!("Expected [preset <name>]"))?;
+
+            self.current_preset = match self.file.presets.iter().position(|(n, _)| n == name) {
+                Some(index) => Some(index),
+                None => {
+                    self.file.presets.push((name.to_string(), Vec::new()));
+                    Some(self.file.presets.len() - 1)
+                }
+            };
+
+            return Ok(());
+        }
+
+        let mut words = line.splitn(2, char::is_whitespace);
+
+        match (words.next(), words.next()) {
+            (Some(LET_KEYWORD), Some(definition)) => {
+                let (name, value) = definition
+                    .split_once('=')
+                    .ok_or_else(|| anyhow::anyhow!("Expected let <name> = <value>"))?;
+
+                let value = self.substitute_variables(value.trim())?;
+
+                self.variables.insert(name.trim().to_string(), value);
+            }
+            (Some(EVALUATION_KEYWORD), Some(evaluation)) => {
+                self.file.evaluation = evaluation.trim().parse()?;
+            }
+            (Some(SAFEGUARD_KEYWORD), Some(safeguard)) => {
+                self.file.safeguard = safeguard.trim().parse()?;
+            }
+            (Some(MIN_DISTANCE_KEYWORD), Some(min_distance)) => {
+                let min_distance = self.substitute_variables(min_distance.trim())?;
+
+                self.file.min_distances.push(min_distance.parse()?);
+            }
+            (Some(INCLUDE_KEYWORD), Some(path)) => {
+                let path = unquote(path.trim());
+
+                self.parse_file(&directory.join(path))?;
+            }
+            _ => {
+                for instruction in split_instructions(line) {
+                    let instruction = self.substitute_variables(instruction.trim())?;
+
+                    if instruction.is_empty() {
+                        continue;
+                    }
+
+                    let step = Step::parse(&instruction)?;
+
+                    match self.current_preset {
+                        Some(index) => self.file.presets[index].1.push(step),
+                        None => self.file.common.push(step),
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn substitute_variables(&self, s: &str) -> anyhow::Result<String> {
+        let mut result = String::new();
+
+        let mut rest = s;
+
+        while let Some(index) = rest.find(VARIABLE_PREFIX) {
+            result.push_str(&rest[..index]);
+
+            let after_prefix = &rest[index + 1..];
+
+            let name_length = after_prefix
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(after_prefix.len());
+
+            let name = &after_prefix[..name_length];
+
+            let value = self
+                .variables
+                .get(name)
+                .ok_or_else(|| anyhow::anyhow!("Undefined variable ${}", name))?;
+
+            result.push_str(value);
+
+            rest = &after_prefix[name_length..];
+        }
+
+        result.push_str(rest);
+
+        Ok(result)
+    }
+}
+
+/// Removes a comment that is not inside quotes.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut previous = None;
+
+    for (index, c) in line.char_indices() {
+        match (c, quote) {
+            ('\'' | '"', None) if is_word_start(previous) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            (COMMENT, None) => return &line[..index],
+            _ => (),
+        }
+
+        previous = Some(c);
+    }
+
+    line
+}
+
+/// Splits a line at separators that are not inside quotes. A comma at the end of a word separates
+/// instructions too, while a comma inside a word separates the items of a list, e.g. `O1,O2`.
+pub(crate) fn split_instructions(line: &str) -> Vec<&str> {
+    let mut instructions = Vec::new();
+
+    let mut quote = None;
+    let mut previous = None;
+    let mut start = 0;
+
+    for (index, c) in line.char_indices() {
+        match (c, quote) {
+            ('\'' | '"', None) if is_word_start(previous) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            (COMMA, None) if !is_word_end(&line[index + 1..]) => (),
+            (SEPARATOR | COMMA, None) => {
+                instructions.push(&line[start..index]);
+                start = index + 1;
+            }
+            _ => (),
+        }
+
+        previous = Some(c);
+    }
+
+    instructions.push(&line[start..]);
+
+    instructions
+}
+
+/// Returns whether a character after `previous` starts a word. Quotes only open at the start of a
+/// word, so a prime in an atom label like `O1'` is part of the label.
+fn is_word_start(previous: Option<char>) -> bool {
+    previous.is_none_or(|c| c.is_whitespace() || c == SEPARATOR || c == '=')
+}
+
+/// Returns whether `rest`, the text after a character, starts after the end of a word.
+fn is_word_end(rest: &str) -> bool {
+    rest.chars().next().is_none_or(char::is_whitespace)
+}
+
+fn unquote(s: &str) -> &str {
+    s.strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .or_else(|| s.strip_prefix('\'').and_then(|s| s.strip_suffix('\'')))
+        .unwrap_or(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_comment() {
+        assert_eq!(strip_comment("a + 1 # comment"), "a + 1 ");
+        assert_eq!(strip_comment("# comment"), "");
+        assert_eq!(strip_comment("x = '#1' # comment"), "x = '#1' ");
+        assert_eq!(strip_comment("vacancy O1' # comment"), "vacancy O1' ");
+    }
+
+    #[test]
+    fn test_split_instructions() {
+        assert_eq!(split_instructions("a + 1; b + 2"), vec!["a + 1", " b + 2"]);
+        assert_eq!(split_instructions("x = 'x; y'"), vec!["x = 'x; y'"]);
+        assert_eq!(
+            split_instructions("vacancy O1'; a + 1"),
+            vec!["vacancy O1'", " a + 1"]
+        );
+        assert_eq!(split_instructions("a + 1, b + 2"), vec!["a + 1", " b + 2"]);
+        assert_eq!(
+            split_instructions("vacancy O1,O2, a + 1"),
+            vec!["vacancy O1,O2", " a + 1"]
+        );
+        assert_eq!(split_instructions("x = 'x, y'"), vec!["x = 'x, y'"]);
+    }
+
+    #[test]
+    fn test_variables_and_presets() {
+        let file = InstructionFile::from_string(
+            "# common part\nlet strain = 1.01\na * $strain\n\n[preset larger]\nb * $strain; c + 1\n[preset smaller]\nb / 2",
+        )
+        .unwrap();
+
+        assert_eq!(file.preset_names(), vec!["larger", "smaller"]);
+        assert_eq!(file.common.len(), 1);
+        assert_eq!(file.presets[0].1.len(), 2);
+
+        let instructions = file.instructions(&["larger".to_string()]).unwrap();
+        assert_eq!(
+            instructions
+                .apply("_cell_length_b", "2.00".to_string())
+                .unwrap(),
+            Some("2.02".to_string())
+        );
+
+        assert!(file.instructions(&["unknown".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_min_distance() {
+        let file = InstructionFile::from_string(
+            "let cutoff = 1.8\nmin_distance Ti O $cutoff\nrattle 0.05",
+        )
+        .unwrap();
+
+        let instructions = file.instructions(&[]).unwrap();
+        assert_eq!(
+            instructions.min_distances(),
+            &[MinDistance::new(1.8).unwrap().with_elements("Ti", "O")]
+        );
+        assert_eq!(instructions.steps().len(), 1);
+
+        assert!(InstructionFile::from_string("min_distance -1").is_err());
+    }
+
+    #[test]
+    fn test_undefined_variable() {
+        assert!(InstructionFile::from_string("a * $strain").is_err());
+    }
+
+    #[test]
+    fn test_include() {
+        let file = InstructionFile::from_file("tests/instructions_with_include.txt").unwrap();
+
+        assert_eq!(file.common.len(), 5);
+        assert_eq!(file.preset_names(), vec!["strain_1pct"]);
+    }
+
+    #[test]
+    fn test_include_with_presets() {
+        let file =
+            InstructionFile::from_file("tests/instructions_with_preset_include.txt").unwrap();
+
+        assert_eq!(file.common.len(), 2);
+        assert_eq!(file.preset_names(), vec!["c_strain", "strain_1pct"]);
+
+        let (_, c_strain) = &file.presets[0];
+        assert_eq!(c_strain.len(), 1);
+
+        let (_, strain_1pct) = &file.presets[1];
+        assert_eq!(strain_1pct.len(), 4);
+    }
+}
@@ -1,12 +1,47 @@
 use crate::{
+    geometry::{self, MIN_DISTANCE_KEYWORD},
+    instruction_file,
+    lattice::{self, Axes, CELL_TAGS},
+    safeguards::{self, MAX_RESAMPLES, SAFEGUARD_KEYWORD},
+    structure,
     utilities::{precision_of_value, RemoveUncertaintyDigits},
-    RNG,
+    DataBlock, Dictionary, InstructionFile, Lattice, MinDistance, Operation, Safeguard, RNG,
 };
-use std::collections::HashMap;
+use std::{fmt::Display, str::FromStr, sync::Arc};
 
+use anyhow::Context;
 use rand::Rng;
+use rand_distr::Distribution as _;
+use serde::{Deserialize, Serialize};
 
-#[derive(PartialEq, Debug, Clone)]
+const SELECTOR_START: char = '[';
+const SELECTOR_END: char = ']';
+const SELECTOR_WILDCARD: char = '*';
+const OPTION_SEPARATOR: char = '=';
+const ASSIGNMENT: &str = "=";
+
+pub(crate) const EVALUATION_KEYWORD: &str = "evaluation";
+
+const DISTRIBUTION_OPTION: &str = "distribution";
+const PRECISION_OPTION: &str = "precision";
+const AXES_OPTION: &str = "axes";
+
+/// The field that marks an entry of the structured form as an [`Operation`].
+const OPERATION_FIELD: &str = "operation";
+
+/// `preserve volume` keeps the cell volume of a cell parameter edit constant.
+const PRESERVE_KEYWORD: &str = "preserve";
+const PRESERVED_VOLUME: &str = "volume";
+
+/// Decimal places of a computed cell volume that replaces a missing `_cell_volume`.
+const VOLUME_DIGITS: usize = 4;
+
+/// Number of standard deviations between the middle and the bounds of a range with a normal
+/// distribution.
+const NORMAL_SIGMAS_PER_HALF_RANGE: f64 = 2.0;
+
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Operator {
     Add,
     Subtract,
@@ -14,6 +49,8 @@ pub enum Operator {
     Divide,
     Power,
     Range,
+    /// Replaces the value with the operand.
+    Set,
     None,
 }
 
@@ -31,17 +68,127 @@ impl From<&str> for Operator {
     }
 }
 
-#[derive(Debug, Clone)]
+impl Display for Operator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let symbol = match self {
+            Operator::Add => "+",
+            Operator::Subtract => "-",
+            Operator::Multiply => "*",
+            Operator::Divide => "/",
+            Operator::Power => "^",
+            Operator::Range => "--",
+            Operator::Set => ASSIGNMENT,
+            Operator::None => "",
+        };
+
+        write!(f, "{}", symbol)
+    }
+}
+
+/// The distribution random values of [`Operator::Range`] are drawn from.
+#[derive(PartialEq, Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Distribution {
+    /// Every value in the range is equally likely.
+    #[default]
+    Uniform,
+    /// Gaussian centred on the middle of the range. The bounds of the range are two standard
+    /// deviations away from the middle and values outside of the range are redrawn.
+    Normal,
+}
+
+impl FromStr for Distribution {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uniform" => Ok(Distribution::Uniform),
+            "normal" => Ok(Distribution::Normal),
+            _ => Err(anyhow::anyhow!("Unknown distribution {}", s)),
+        }
+    }
+}
+
+impl Display for Distribution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Distribution::Uniform => write!(f, "uniform"),
+            Distribution::Normal => write!(f, "normal"),
+        }
+    }
+}
+
+/// How many decimal places a modified value is written with.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub enum Precision {
+    /// The number of decimal places of the original value.
+    #[default]
+    Keep,
+    /// As many decimal places as needed to represent the value exactly.
+    Full,
+    /// A fixed number of decimal places.
+    Fixed(usize),
+}
+
+impl FromStr for Precision {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(Precision::Keep),
+            "full" => Ok(Precision::Full),
+            _ => s
+                .parse::<usize>()
+                .map(Precision::Fixed)
+                .map_err(|_| anyhow::anyhow!("Unknown precision {}", s)),
+        }
+    }
+}
+
+impl Precision {
+    /// The number of decimal places of a new value that replaces a value with `original_digits`
+    /// decimal places, or `None` for all digits.
+    fn digits(&self, original_digits: usize) -> Option<usize> {
+        match self {
+            Precision::Keep => Some(original_digits),
+            Precision::Full => None,
+            Precision::Fixed(digits) => Some(*digits),
+        }
+    }
+
+    fn format(&self, value: f64, original_digits: usize) -> String {
+        match self.digits(original_digits) {
+            Some(digits) => format!("{:.*}", digits, value),
+            None => value.to_string(),
+        }
+    }
+}
+
+impl Display for Precision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Precision::Keep => write!(f, "keep"),
+            Precision::Full => write!(f, "full"),
+            Precision::Fixed(digits) => write!(f, "{}", digits),
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "InstructionSpec", into = "InstructionSpec")]
 pub struct Instruction {
     keyword: String,
     operator: Operator,
     value_a: f64,
     value_b: Option<f64>,
+    source: Option<String>,
+    selector: Option<String>,
+    distribution: Distribution,
+    precision: Precision,
+    axes: Option<Axes>,
+    preserves_volume: bool,
 }
 
-// TODO: Make this a command line option.
-const KEEP_PRECISION: bool = true;
-
 impl Instruction {
     pub fn new(keyword: String, operator: Operator, value_a: f64, value_b: Option<f64>) -> Self {
         Instruction {
@@ -49,16 +196,150 @@ impl Instruction {
             operator,
             value_a,
             value_b,
+            source: None,
+            selector: None,
+            distribution: Distribution::default(),
+            precision: Precision::default(),
+            axes: None,
+            preserves_volume: false,
         }
     }
 
-    fn apply(&self, cif_value: String) -> anyhow::Result<String> {
-        let value = cif_value.remove_uncertainty_digits();
+    /// Reads the input value from the tag `source` instead of the modified tag, e.g. `c = a * 2`.
+    ///
+    /// If both tags are in the same loop the value of the same row is used.
+    pub fn with_source(mut self, source: &str) -> Self {
+        self.source = Some(source.to_string());
+        self
+    }
 
-        let value_precision = precision_of_value(&value);
+    /// Restricts the instruction to the loop rows whose label matches `selector`. `*` matches any
+    /// sequence of characters.
+    pub fn with_selector(mut self, selector: &str) -> Self {
+        self.selector = Some(selector.to_string());
+        self
+    }
 
-        let value = value.parse::<f64>()?;
+    pub fn with_distribution(mut self, distribution: Distribution) -> Self {
+        self.distribution = distribution;
+        self
+    }
+
+    pub fn with_precision(mut self, precision: Precision) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    /// Selects the cell axes that are scaled by a change of the cell volume, or that compensate an
+    /// edit with [`Instruction::with_preserved_volume`].
+    pub fn with_axes(mut self, axes: Axes) -> Self {
+        self.axes = Some(axes);
+        self
+    }
+
+    /// Keeps the cell volume of an edit of a cell length or angle constant by scaling the other
+    /// lengths, e.g. a and b for `c * 1.02`. Edits of angles scale all three lengths.
+    pub fn with_preserved_volume(mut self) -> Self {
+        self.preserves_volume = true;
+        self
+    }
+
+    pub fn keyword(&self) -> &str {
+        &self.keyword
+    }
+
+    /// The tags the instruction can modify.
+    fn modified_tags(&self) -> Vec<&str> {
+        let mut tags = vec![self.keyword.as_str()];
+
+        if self.is_lattice_scaled() {
+            tags.extend(&CELL_TAGS[..3]);
+        }
+
+        tags
+    }
+
+    /// Returns whether the instruction changes the cell parameters.
+    fn is_cell_modified(&self) -> bool {
+        safeguards::is_cell_tag(&self.keyword) || self.is_lattice_scaled()
+    }
+
+    /// Returns whether the instruction scales cell lengths besides its own tag.
+    fn is_lattice_scaled(&self) -> bool {
+        self.preserves_volume || lattice::is_volume_tag(&self.keyword)
+    }
+
+    /// Checks that axes and `preserve volume` are only used with cell parameters.
+    fn check_cell_options(&self) -> anyhow::Result<()> {
+        let is_volume = lattice::is_volume_tag(&self.keyword);
+
+        if self.preserves_volume && (is_volume || !safeguards::is_cell_tag(&self.keyword)) {
+            return Err(anyhow::anyhow!(
+                "{} {} only applies to cell lengths and angles, not to {}",
+                PRESERVE_KEYWORD,
+                PRESERVED_VOLUME,
+                self.keyword
+            ));
+        }
+
+        if self.axes.is_some() && !self.is_lattice_scaled() {
+            return Err(anyhow::anyhow!(
+                "{} only applies to {} and to edits with {} {}, not to {}",
+                AXES_OPTION,
+                lattice::VOLUME_TAG,
+                PRESERVE_KEYWORD,
+                PRESERVED_VOLUME,
+                self.keyword
+            ));
+        }
+
+        if let (Some(axes), Some(index)) = (self.axes, lattice::length_index(&self.keyword)) {
+            if axes.contains(index) {
+                return Err(anyhow::anyhow!(
+                    "{} cannot be compensated by scaling itself",
+                    self.keyword
+                ));
+            }
+        }
 
+        Ok(())
+    }
+
+    /// Returns whether the instruction applies to a value with the given row label. Values outside
+    /// of loops have no label and are only modified by instructions without selector.
+    fn is_selected(&self, label: Option<&str>) -> bool {
+        match (&self.selector, label) {
+            (None, _) => true,
+            (Some(selector), Some(label)) => matches_selector(selector, label),
+            (Some(_), None) => false,
+        }
+    }
+
+    fn random_value(&self, lower_value: f64, upper_value: f64) -> f64 {
+        match self.distribution {
+            Distribution::Uniform => {
+                RNG.with(|rng| rng.borrow_mut().gen_range(lower_value..upper_value))
+            }
+            Distribution::Normal => {
+                let mean = (lower_value + upper_value) / 2.0;
+                let sigma = (upper_value - lower_value) / 2.0 / NORMAL_SIGMAS_PER_HALF_RANGE;
+
+                let normal = rand_distr::Normal::new(mean, sigma)
+                    .expect("The standard deviation is positive and finite");
+
+                RNG.with(|rng| loop {
+                    let value = normal.sample(&mut *rng.borrow_mut());
+
+                    if (lower_value..upper_value).contains(&value) {
+                        break value;
+                    }
+                })
+            }
+        }
+    }
+
+    /// Computes the new value. Random operators draw a new value on every call.
+    fn compute(&self, value: f64) -> anyhow::Result<f64> {
         let new_value = match self.operator {
             Operator::Add => value + self.value_a,
             Operator::Subtract => value - self.value_a,
@@ -77,7 +358,7 @@ impl Instruction {
                         )));
                     }
 
-                    RNG.with(|rng| rng.borrow_mut().gen_range(lower_value..upper_value))
+                    self.random_value(lower_value, upper_value)
                 } else {
                     let lower_value = self.value_a.min(value);
                     let upper_value = self.value_a.max(value);
@@ -89,182 +370,1163 @@ impl Instruction {
                         )));
                     }
 
-                    RNG.with(|rng| rng.borrow_mut().gen_range(lower_value..upper_value))
+                    self.random_value(lower_value, upper_value)
                 }
             }
+            Operator::Set => self.value_a,
             Operator::None => value,
         };
 
-        if KEEP_PRECISION {
-            let new_value_precision = precision_of_value(&new_value.to_string());
+        Ok(new_value)
+    }
 
-            if new_value_precision < value_precision {
-                let new_value = format!("{:.*}", value_precision, new_value);
-                Ok(new_value)
-            } else {
-                Ok(format!("{:.*}", value_precision, new_value))
+    /// Applies the instruction to a single value and keeps the result within the bounds of the tag
+    /// according to `safeguard`.
+    fn apply(&self, cif_value: String, safeguard: Safeguard) -> anyhow::Result<String> {
+        let value = cif_value.remove_uncertainty_digits();
+
+        let value_precision = precision_of_value(&value);
+
+        let value = value.parse::<f64>()?;
+
+        let digits = self.precision.digits(value_precision);
+
+        let mut new_value = self.compute(value)?;
+
+        if safeguard == Safeguard::Resample && self.operator == Operator::Range {
+            for _ in 1..MAX_RESAMPLES {
+                if safeguards::check_value(&self.keyword, new_value).is_ok() {
+                    break;
+                }
+
+                new_value = self.compute(value)?;
             }
-        } else {
-            Ok(new_value.to_string())
         }
+
+        let new_value = match safeguards::check_value(&self.keyword, new_value) {
+            Ok(()) => new_value,
+            Err(e) if safeguard == Safeguard::Clamp => {
+                match safeguards::clamp_value(&self.keyword, new_value, digits) {
+                    Some(clamped_value) => {
+                        log::debug!("{:#}. Clamped to {}.", e, clamped_value);
+                        clamped_value
+                    }
+                    None => return Err(e.context(format!("Cannot apply {}", self))),
+                }
+            }
+            Err(e) => return Err(e.context(format!("Cannot apply {}", self))),
+        };
+
+        Ok(self.precision.format(new_value, value_precision))
     }
 }
 
-impl From<&str> for Instruction {
-    fn from(s: &str) -> Self {
+impl Instruction {
+    /// Applies the instruction to every matching value of the block.
+    ///
+    /// Values of other tags are read from `original` if given and from `block` otherwise. Returns the
+    /// number of modified values.
+    fn apply_to_block(
+        &self,
+        block: &mut DataBlock,
+        original: Option<&DataBlock>,
+        safeguard: Safeguard,
+    ) -> anyhow::Result<usize> {
+        if lattice::is_volume_tag(&self.keyword) {
+            return self.apply_to_volume(block, original, safeguard);
+        }
+
+        if !self.preserves_volume {
+            return self.apply_to_values(block, original, safeguard);
+        }
+
+        let lattice = match Lattice::from_block(block) {
+            Ok(lattice) => lattice,
+            Err(e) if block.find_value(&self.keyword).is_some() => {
+                return Err(e.context(format!("Cannot apply {}", self)))
+            }
+            Err(_) => return Ok(0),
+        };
+
+        let modified_values_counter = self.apply_to_values(block, original, safeguard)?;
+
+        let new_lattice =
+            Lattice::from_block(block).with_context(|| format!("Cannot apply {}", self))?;
+
+        let axes = match (self.axes, lattice::length_index(&self.keyword)) {
+            (Some(axes), _) => axes,
+            (None, Some(index)) => Axes::without(index),
+            (None, None) => Axes::ALL,
+        };
+
+        let factor = (lattice.volume() / new_lattice.volume()).powf(1.0 / axes.count() as f64);
+
+        Ok(modified_values_counter + self.scale_lengths(block, axes, factor))
+    }
+
+    /// Sets the cell volume by scaling the lengths of the selected axes, all three by default, and
+    /// writes the volume of the new cell to `_cell_volume`.
+    ///
+    /// The input volume is `_cell_volume`, or the volume computed from the cell parameters if the
+    /// block has no `_cell_volume`.
+    fn apply_to_volume(
+        &self,
+        block: &mut DataBlock,
+        original: Option<&DataBlock>,
+        safeguard: Safeguard,
+    ) -> anyhow::Result<usize> {
+        if self.selector.is_some() {
+            log::warn!(
+                "{} is not part of a loop. Ignoring the row selector of {}.",
+                self.keyword,
+                self
+            );
+            return Ok(0);
+        }
+
+        let volume = block.value(&self.keyword);
+
+        let lattice = match (Lattice::from_block(block), &volume) {
+            (Ok(lattice), _) => lattice,
+            (Err(e), Some(_)) => return Err(e.context(format!("Cannot apply {}", self))),
+            (Err(_), None) => return Ok(0),
+        };
+
+        let input = match &self.source {
+            Some(source) => original.unwrap_or(block).value(source).ok_or_else(|| {
+                anyhow::anyhow!("{} reads {} which is not a single value", self, source)
+            })?,
+            None => volume
+                .clone()
+                .unwrap_or_else(|| format!("{:.*}", VOLUME_DIGITS, lattice.volume())),
+        };
+
+        let target_volume = self.apply(input, safeguard)?.parse::<f64>()?;
+
+        let axes = self.axes.unwrap_or(Axes::ALL);
+
+        let factor = (target_volume / lattice.volume()).powf(1.0 / axes.count() as f64);
+
+        let mut modified_values_counter = self.scale_lengths(block, axes, factor);
+
+        if let Some(volume) = volume {
+            let new_volume = Lattice::from_block(block)
+                .with_context(|| format!("Cannot apply {}", self))?
+                .volume();
+
+            let new_volume = self.precision.format(
+                new_volume,
+                precision_of_value(&volume.remove_uncertainty_digits()),
+            );
+
+            log::debug!("{} {} -> {}", self.keyword, volume, new_volume);
+
+            block.set_value(&self.keyword, &new_volume);
+            modified_values_counter += 1;
+        }
+
+        Ok(modified_values_counter)
+    }
+
+    /// Multiplies the lengths of the given axes by `factor`. The lengths keep their number of
+    /// decimal places unless the instruction writes all digits. Returns the number of modified
+    /// values.
+    fn scale_lengths(&self, block: &mut DataBlock, axes: Axes, factor: f64) -> usize {
+        let precision = match self.precision {
+            Precision::Full => Precision::Full,
+            _ => Precision::Keep,
+        };
+
+        let mut modified_values_counter = 0;
+
+        for index in axes.indices() {
+            let tag = CELL_TAGS[index];
+
+            let Some(value) = block.value(tag) else {
+                continue;
+            };
+
+            let Some(length) = crate::parse_number(&value) else {
+                continue;
+            };
+
+            let new_value = precision.format(
+                length * factor,
+                precision_of_value(&value.remove_uncertainty_digits()),
+            );
+
+            log::debug!("{} {} -> {}", tag, value, new_value);
+
+            block.set_value(tag, &new_value);
+            modified_values_counter += 1;
+        }
+
+        modified_values_counter
+    }
+
+    /// Applies the instruction to the single value or the selected loop values of its tag.
+    fn apply_to_values(
+        &self,
+        block: &mut DataBlock,
+        original: Option<&DataBlock>,
+        safeguard: Safeguard,
+    ) -> anyhow::Result<usize> {
+        let source_block = original.unwrap_or(block);
+
+        if let Some(value) = block.find_value(&self.keyword) {
+            if self.selector.is_some() {
+                log::warn!(
+                    "{} is not part of a loop. Ignoring the row selector of {}.",
+                    self.keyword,
+                    self
+                );
+                return Ok(0);
+            }
+
+            let input = match &self.source {
+                Some(source) => source_block.value(source).ok_or_else(|| {
+                    anyhow::anyhow!("{} reads {} which is not a single value", self, source)
+                })?,
+                None => value.value(),
+            };
+
+            let new_value = self.apply(input, safeguard)?;
+
+            log::debug!("{} {} -> {}", self.keyword, value.value(), new_value);
+
+            block.set_value(&self.keyword, &new_value);
+
+            return Ok(1);
+        }
+
+        let Some(l) = block.find_loop(&self.keyword) else {
+            return Ok(0);
+        };
+
+        let column = l.column(&self.keyword).expect("The loop contains the tag");
+        let label_column = l.label_column();
+
+        let source_loop = self
+            .source
+            .as_ref()
+            .and_then(|source| source_block.find_loop(source))
+            .filter(|source_loop| source_loop.column(&self.keyword).is_some());
+
+        let mut new_values = Vec::new();
+
+        for row in 0..l.len() {
+            let label = l.value(row, label_column);
+
+            if !self.is_selected(Some(&label)) {
+                continue;
+            }
+
+            let input = match (&self.source, source_loop) {
+                (None, _) => l.value(row, column),
+                (Some(source), Some(source_loop)) => {
+                    let source_column = source_loop
+                        .column(source)
+                        .expect("The loop contains the tag");
+
+                    if row >= source_loop.len() {
+                        return Err(anyhow::anyhow!("{} has no row {} in {}", source, row, self));
+                    }
+
+                    source_loop.value(row, source_column)
+                }
+                (Some(source), None) => source_block.value(source).ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "{} reads {} which is neither a single value nor in the same loop",
+                        self,
+                        source
+                    )
+                })?,
+            };
+
+            let new_value = self.apply(input, safeguard)?;
+
+            log::debug!(
+                "{} {} {} -> {}",
+                self.keyword,
+                label,
+                l.value(row, column),
+                new_value
+            );
+
+            new_values.push((row, new_value));
+        }
+
+        let l = block
+            .find_loop_mut(&self.keyword)
+            .expect("The loop contains the tag");
+
+        for (row, new_value) in &new_values {
+            l.set_value(*row, column, new_value);
+        }
+
+        Ok(new_values.len())
+    }
+}
+
+impl Display for Instruction {
+    /// Writes the instruction in the text form that [`Instruction::from_str`] reads.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let target = match &self.selector {
+            Some(selector) => format!(
+                "{}{}{}{}",
+                self.keyword, SELECTOR_START, selector, SELECTOR_END
+            ),
+            None => self.keyword.clone(),
+        };
+
+        let target = match &self.source {
+            Some(source) => format!("{} {} {}", target, ASSIGNMENT, source),
+            None => target,
+        };
+
+        match (&self.operator, self.value_b, &self.source) {
+            (Operator::Range, Some(value_b), None) => {
+                write!(f, "{} -- {} -- {}", self.value_a, target, value_b)?
+            }
+            (Operator::None, _, Some(_)) => write!(f, "{}", target)?,
+            (Operator::None, _, None) => write!(f, "{} {}", target, self.value_a)?,
+            (operator, _, _) => write!(f, "{} {} {}", target, operator, self.value_a)?,
+        }
+
+        if self.distribution != Distribution::default() {
+            write!(
+                f,
+                " {}{}{}",
+                DISTRIBUTION_OPTION, OPTION_SEPARATOR, self.distribution
+            )?;
+        }
+
+        if self.precision != Precision::default() {
+            write!(
+                f,
+                " {}{}{}",
+                PRECISION_OPTION, OPTION_SEPARATOR, self.precision
+            )?;
+        }
+
+        if let Some(axes) = self.axes {
+            write!(f, " {}{}{}", AXES_OPTION, OPTION_SEPARATOR, axes)?;
+        }
+
+        if self.preserves_volume {
+            write!(f, " {} {}", PRESERVE_KEYWORD, PRESERVED_VOLUME)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Matches a label against a selector in which `*` stands for any sequence of characters.
+pub(crate) fn matches_selector(selector: &str, label: &str) -> bool {
+    let mut parts = selector.split(SELECTOR_WILDCARD);
+
+    let first = parts.next().unwrap_or_default();
+
+    let Some(mut rest) = label.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+/// The structured form of an [`Instruction`] used for TOML and JSON.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InstructionSpec {
+    tag: String,
+    #[serde(default = "default_operator")]
+    operator: Operator,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    operands: Vec<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    distribution: Option<Distribution>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rows: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    precision: Option<PrecisionSpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    axes: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    preserve: Option<String>,
+}
+
+fn default_operator() -> Operator {
+    Operator::None
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum PrecisionSpec {
+    Digits(usize),
+    Name(String),
+}
+
+impl TryFrom<InstructionSpec> for Instruction {
+    type Error = anyhow::Error;
+
+    fn try_from(spec: InstructionSpec) -> Result<Self, Self::Error> {
+        if spec.operator == Operator::None && spec.source.is_none() {
+            return Err(anyhow::anyhow!("No operator found for {}", spec.tag));
+        }
+
+        let (value_a, value_b) = match spec.operands.as_slice() {
+            [] if spec.operator == Operator::None && spec.source.is_some() => (0.0, None),
+            [value_a] => (*value_a, None),
+            [value_a, value_b] if spec.operator == Operator::Range => (*value_a, Some(*value_b)),
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "{:?} of {} takes {} operands but {} were given",
+                    spec.operator,
+                    spec.tag,
+                    if spec.operator == Operator::Range {
+                        "one or two"
+                    } else {
+                        "one"
+                    },
+                    spec.operands.len()
+                ))
+            }
+        };
+
+        let precision = match spec.precision {
+            Some(PrecisionSpec::Digits(digits)) => Precision::Fixed(digits),
+            Some(PrecisionSpec::Name(name)) => name.parse()?,
+            None => Precision::default(),
+        };
+
+        let preserves_volume = match spec.preserve.as_deref() {
+            Some(PRESERVED_VOLUME) => true,
+            Some(preserved) => {
+                return Err(anyhow::anyhow!(
+                    "Only the {} can be preserved, not {}",
+                    PRESERVED_VOLUME,
+                    preserved
+                ))
+            }
+            None => false,
+        };
+
+        let instruction = Instruction {
+            keyword: crate::full_keyword(&spec.tag),
+            operator: spec.operator,
+            value_a,
+            value_b,
+            source: spec.source.as_deref().map(crate::full_keyword),
+            selector: spec.rows,
+            distribution: spec.distribution.unwrap_or_default(),
+            precision,
+            axes: spec.axes.as_deref().map(str::parse).transpose()?,
+            preserves_volume,
+        };
+
+        instruction.check_cell_options()?;
+
+        Ok(instruction)
+    }
+}
+
+impl From<Instruction> for InstructionSpec {
+    fn from(instruction: Instruction) -> Self {
+        let operands = match (&instruction.operator, &instruction.source) {
+            (Operator::None, Some(_)) => Vec::new(),
+            _ => std::iter::once(instruction.value_a)
+                .chain(instruction.value_b)
+                .collect(),
+        };
+
+        InstructionSpec {
+            tag: instruction.keyword,
+            operator: instruction.operator,
+            operands,
+            source: instruction.source,
+            distribution: Some(instruction.distribution)
+                .filter(|distribution| *distribution != Distribution::default()),
+            rows: instruction.selector,
+            precision: match instruction.precision {
+                Precision::Keep => None,
+                Precision::Full => Some(PrecisionSpec::Name(Precision::Full.to_string())),
+                Precision::Fixed(digits) => Some(PrecisionSpec::Digits(digits)),
+            },
+            axes: instruction.axes.map(|axes| axes.to_string()),
+            preserve: instruction
+                .preserves_volume
+                .then(|| PRESERVED_VOLUME.to_string()),
+        }
+    }
+}
+
+impl FromStr for Instruction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
         let words = s.split_whitespace();
 
         let mut keyword: Option<String> = None;
         let mut operator: Option<Operator> = None;
         let mut value_a: Option<f64> = None;
         let mut value_b: Option<f64> = None;
+        let mut is_assignment = false;
+        let mut source: Option<String> = None;
+        let mut selector: Option<String> = None;
+        let mut distribution = Distribution::default();
+        let mut precision = Precision::default();
+        let mut axes = None;
+        let mut preserves_volume = false;
+        let mut is_preserve = false;
 
         for word in words.into_iter() {
             let possible_operator = Operator::from(word);
 
             let possible_value = word.parse::<f64>();
 
-            if let Ok(value) = possible_value {
+            if is_preserve {
+                is_preserve = false;
+
+                if word != PRESERVED_VOLUME {
+                    return Err(anyhow::anyhow!(
+                        "Only the {} can be preserved, not {}",
+                        PRESERVED_VOLUME,
+                        word
+                    ));
+                }
+
+                preserves_volume = true;
+            } else if word == PRESERVE_KEYWORD {
+                is_preserve = true;
+            } else if word == ASSIGNMENT {
+                is_assignment = true;
+            } else if let Ok(value) = possible_value {
                 if value_a.is_none() {
                     value_a = Some(value);
-                } else {
+                } else if value_b.is_none() {
                     value_b = Some(value);
+                } else {
+                    return Err(anyhow::anyhow!("Unexpected value {}", word));
                 }
             } else if possible_operator != Operator::None {
-                operator = Some(possible_operator);
+                match operator {
+                    Some(Operator::Range) if possible_operator == Operator::Range => (),
+                    Some(_) => return Err(anyhow::anyhow!("Unexpected operator {}", word)),
+                    None => operator = Some(possible_operator),
+                }
+            } else if let Some((option, value)) = word.split_once(OPTION_SEPARATOR) {
+                match option {
+                    DISTRIBUTION_OPTION => distribution = value.parse()?,
+                    PRECISION_OPTION => precision = value.parse()?,
+                    AXES_OPTION => axes = Some(value.parse()?),
+                    _ => return Err(anyhow::anyhow!("Unknown option {}", option)),
+                }
             } else {
-                let is_key_word_shortcut = crate::SHORT_KEYWORDS.contains(&word);
-
-                if is_key_word_shortcut {
-                    let index = match crate::SHORT_KEYWORDS.iter().position(|&r| r == word) {
-                        Some(index) => index,
-                        None => {
-                            log::warn!("This should never happen. Please report this bug. Short keyword found but could not determine index. Results may be unexpected.");
-                            0
-                        }
-                    };
-
-                    keyword = Some(crate::KEYWORDS[index].to_string());
+                let (word, row_selector) = match word
+                    .strip_suffix(SELECTOR_END)
+                    .and_then(|word| word.split_once(SELECTOR_START))
+                {
+                    Some((word, row_selector)) => (word, Some(row_selector.to_string())),
+                    None => (word, None),
+                };
+
+                if keyword.is_none() {
+                    keyword = Some(crate::full_keyword(word));
+                    selector = row_selector;
+                } else if is_assignment && source.is_none() && row_selector.is_none() {
+                    source = Some(crate::full_keyword(word));
                 } else {
-                    log::warn!(
-                        "{} is not a known keyword. Results may be unexpected.",
-                        word
-                    );
-
-                    keyword = Some(word.to_string())
+                    return Err(anyhow::anyhow!("Unexpected word {}", word));
                 }
             }
         }
 
-        let keyword = match keyword {
-            Some(keyword) => keyword,
-            None => {
-                log::warn!("No keyword found. Results may be unexpected.");
-                "".to_string()
-            }
+        if is_preserve {
+            return Err(anyhow::anyhow!(
+                "{} is missing what to preserve",
+                PRESERVE_KEYWORD
+            ));
+        }
+
+        if is_assignment && source.is_none() && operator.is_none() {
+            operator = Some(Operator::Set);
+        }
+
+        let keyword = keyword.ok_or_else(|| anyhow::anyhow!("No keyword found in {}", s.trim()))?;
+
+        let operator = match (operator, &source) {
+            (Some(operator), _) => operator,
+            (None, Some(_)) => Operator::None,
+            (None, None) => return Err(anyhow::anyhow!("No operator found in {}", s.trim())),
         };
 
-        let operator = match operator {
-            Some(operator) => operator,
-            None => {
-                log::warn!("No operator found. Results may be unexpected.");
-                Operator::None
-            }
+        let value_a = match (value_a, &source) {
+            (Some(value_a), _) => value_a,
+            (None, Some(_)) if operator == Operator::None => 0.0,
+            (None, _) => return Err(anyhow::anyhow!("No value found in {}", s.trim())),
         };
 
-        let value_a = match value_a {
-            Some(value_a) => value_a,
-            None => {
-                log::warn!("No value found. Results may be unexpected.");
-                0.0
+        if value_b.is_some() && operator != Operator::Range {
+            return Err(anyhow::anyhow!(
+                "Only a range takes two values, not {}",
+                s.trim()
+            ));
+        }
+
+        let instruction = Instruction {
+            keyword,
+            operator,
+            value_a,
+            value_b,
+            source,
+            selector,
+            distribution,
+            precision,
+            axes,
+            preserves_volume,
+        };
+
+        instruction.check_cell_options()?;
+
+        Ok(instruction)
+    }
+}
+
+/// How instructions read the values of other tags, e.g. `a` in `c = a * 2`.
+#[derive(PartialEq, Debug, Clone, Copy, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Evaluation {
+    /// Instructions run in order and see the results of all earlier instructions.
+    #[default]
+    Sequential,
+    /// Values of other tags are read from the unmodified file, so the order of instructions for
+    /// different tags does not matter. Instructions for the same tag are still applied one after
+    /// another.
+    Original,
+}
+
+impl FromStr for Evaluation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sequential" => Ok(Evaluation::Sequential),
+            "original" => Ok(Evaluation::Original),
+            _ => Err(anyhow::anyhow!("Unknown evaluation {}", s)),
+        }
+    }
+}
+
+impl Display for Evaluation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Evaluation::Sequential => write!(f, "sequential"),
+            Evaluation::Original => write!(f, "original"),
+        }
+    }
+}
+
+/// A step of a program: an instruction for the values of a single tag or an [`Operation`] on the
+/// whole structure.
+#[derive(PartialEq, Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Step {
+    Instruction(Instruction),
+    Operation(Operation),
+}
+
+impl Step {
+    /// Reads a line of the text form. Lines that start with the name of an operation are
+    /// operations, all other lines instructions.
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        if Operation::is_operation(s) {
+            Ok(Step::Operation(s.parse()?))
+        } else {
+            Ok(Step::Instruction(s.parse()?))
+        }
+    }
+
+    /// Returns whether the step draws random numbers.
+    fn is_random(&self) -> bool {
+        match self {
+            Step::Instruction(instruction) => instruction.operator == Operator::Range,
+            Step::Operation(operation) => operation.is_random(),
+        }
+    }
+
+    /// Returns whether the step reads the block as it was before the program ran.
+    fn reads_unmodified(&self) -> bool {
+        matches!(self, Step::Operation(Operation::Occupancies(_)))
+    }
+
+    /// `original` is the block that instructions read other tags from, `unmodified` the block
+    /// before the program ran if a step reads it.
+    fn apply_to_block(
+        &self,
+        block: &mut DataBlock,
+        original: Option<&DataBlock>,
+        unmodified: Option<&DataBlock>,
+        safeguard: Safeguard,
+    ) -> anyhow::Result<usize> {
+        match self {
+            Step::Instruction(instruction) if instruction.is_cell_modified() => {
+                let lattice = Lattice::from_block(block).ok();
+
+                let modified_values_counter =
+                    instruction.apply_to_block(block, original, safeguard)?;
+
+                // The anisotropic displacement parameters follow the strain of the cell.
+                if let (Some(lattice), Ok(new_lattice)) = (lattice, Lattice::from_block(block)) {
+                    if new_lattice != lattice {
+                        structure::strain_aniso_sites(block, &lattice, &new_lattice);
+                    }
+                }
+
+                Ok(modified_values_counter)
+            }
+            Step::Instruction(instruction) => {
+                instruction.apply_to_block(block, original, safeguard)
             }
+            Step::Operation(Operation::Occupancies(constraint)) => {
+                constraint.apply_to_block(block, unmodified)
+            }
+            Step::Operation(operation) => operation.apply_to_block(block),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Step {
+    /// Entries with an `operation` field are operations, all others instructions.
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+
+        let step = if value.get(OPERATION_FIELD).is_some() {
+            serde_json::from_value(value).map(Step::Operation)
+        } else {
+            serde_json::from_value(value).map(Step::Instruction)
         };
 
-        Instruction {
-            keyword: keyword,
-            operator: operator,
-            value_a: value_a,
-            value_b,
+        step.map_err(serde::de::Error::custom)
+    }
+}
+
+impl From<Instruction> for Step {
+    fn from(instruction: Instruction) -> Self {
+        Step::Instruction(instruction)
+    }
+}
+
+impl From<Operation> for Step {
+    fn from(operation: Operation) -> Self {
+        Step::Operation(operation)
+    }
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Step::Instruction(instruction) => write!(f, "{}", instruction),
+            Step::Operation(operation) => write!(f, "{}", operation),
         }
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Instructions(HashMap<String, Vec<Instruction>>);
+/// An ordered program of instructions and operations.
+///
+/// The steps are applied to every data block one after another in the order they were given. An
+/// instruction modifies all values of its tag in the block, i.e. the single value or every selected
+/// row of the loop. How values of other tags are read is set by [`Evaluation`].
+///
+/// With a [`Dictionary`], the tags modified by instructions are checked against it after the
+/// program ran on a block.
+///
+/// With [`MinDistance`]s, a modified block with atoms closer than the cutoffs is drawn again from
+/// the unmodified block if the program is random, and is an error otherwise.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Instructions {
+    steps: Vec<Step>,
+    evaluation: Evaluation,
+    safeguard: Safeguard,
+    min_distances: Vec<MinDistance>,
+    dictionary: Option<Arc<Dictionary>>,
+}
 
 impl Instructions {
+    pub fn with_evaluation(mut self, evaluation: Evaluation) -> Self {
+        self.evaluation = evaluation;
+        self
+    }
+
+    /// Validates modified values against `dictionary`. Fails if an instruction modifies a tag that
+    /// the dictionary does not define.
+    pub fn with_dictionary(mut self, dictionary: Arc<Dictionary>) -> anyhow::Result<Self> {
+        if let Some(instruction) = self
+            .instructions()
+            .find(|instruction| dictionary.get(&instruction.keyword).is_none())
+        {
+            return Err(anyhow::anyhow!(
+                "{} is not defined in the dictionary",
+                instruction.keyword
+            ));
+        }
+
+        self.dictionary = Some(dictionary);
+        Ok(self)
+    }
+
+    pub fn evaluation(&self) -> Evaluation {
+        self.evaluation
+    }
+
+    pub fn with_safeguard(mut self, safeguard: Safeguard) -> Self {
+        self.safeguard = safeguard;
+        self
+    }
+
+    pub fn safeguard(&self) -> Safeguard {
+        self.safeguard
+    }
+
+    pub fn with_min_distance(mut self, min_distance: MinDistance) -> Self {
+        self.min_distances.push(min_distance);
+        self
+    }
+
+    pub fn min_distances(&self) -> &[MinDistance] {
+        &self.min_distances
+    }
+
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    /// The instructions of the program without the operations.
+    pub fn instructions(&self) -> impl Iterator<Item = &Instruction> {
+        self.steps.iter().filter_map(|step| match step {
+            Step::Instruction(instruction) => Some(instruction),
+            Step::Operation(_) => None,
+        })
+    }
+
+    /// Applies all instructions of `keyword` in order to a single value.
+    ///
+    /// Returns `None` if there is no instruction for `keyword`. Instructions that read other tags
+    /// or select loop rows need a data block and are an error here.
     pub fn apply(&self, keyword: &str, value: String) -> anyhow::Result<Option<String>> {
-        let instructions = self.0.get(keyword);
+        let mut new_value = None;
+
+        for instruction in self.instructions().filter(|i| i.keyword == keyword) {
+            if instruction.source.is_some()
+                || instruction.selector.is_some()
+                || instruction.is_lattice_scaled()
+            {
+                return Err(anyhow::anyhow!(
+                    "{} can only be applied to a data block",
+                    instruction
+                ));
+            }
 
-        if let Some(instructions) = instructions {
-            let mut new_value = value;
+            new_value = Some(
+                instruction.apply(new_value.unwrap_or_else(|| value.clone()), self.safeguard)?,
+            );
+        }
 
-            for instruction in instructions {
-                new_value = instruction.apply(new_value)?;
+        Ok(new_value)
+    }
+
+    /// Runs the program on a data block. Returns the number of modified values.
+    ///
+    /// Cell parameters have to form a lattice afterwards. With [`Safeguard::Resample`], programs
+    /// with random instructions run again on the unmodified block until they do. Random programs
+    /// also run again until no atoms are closer than the minimum distances.
+    pub fn apply_to_block(&self, block: &mut DataBlock) -> anyhow::Result<usize> {
+        let is_random = self.steps.iter().any(Step::is_random);
+        let is_cell_resampled = self.safeguard == Safeguard::Resample
+            && self
+                .instructions()
+                .any(|instruction| instruction.operator == Operator::Range);
+        let is_distance_resampled = is_random && !self.min_distances.is_empty();
+
+        let unmodified = (is_cell_resampled || is_distance_resampled).then(|| block.clone());
+
+        let mut attempts = 1;
+
+        let modified_values_counter = loop {
+            let modified_values_counter = self.run(block)?;
+
+            if modified_values_counter == 0 {
+                break modified_values_counter;
             }
 
-            Ok(Some(new_value))
-        } else {
-            Ok(None)
+            let is_cell_modified = self
+                .instructions()
+                .any(|instruction| instruction.is_cell_modified());
+
+            let cell_check = match is_cell_modified {
+                true => safeguards::check_cell(block).map_err(|e| (e, is_cell_resampled)),
+                false => Ok(()),
+            };
+
+            let check = cell_check.and_then(|()| {
+                geometry::check_min_distances(block, &self.min_distances)
+                    .map_err(|e| (e, is_distance_resampled))
+            });
+
+            match (check, &unmodified) {
+                (Ok(()), _) => break modified_values_counter,
+                (Err((e, true)), Some(unmodified)) if attempts < MAX_RESAMPLES => {
+                    log::debug!("{:#}. Drawing again.", e);
+
+                    *block = unmodified.clone();
+                    attempts += 1;
+                }
+                (Err((e, true)), Some(_)) => {
+                    return Err(e.context(format!("No valid sample in {} attempts", attempts)))
+                }
+                (Err((e, _)), _) => return Err(e),
+            }
+        };
+
+        if let (Some(dictionary), true) = (&self.dictionary, modified_values_counter > 0) {
+            let mut tags: Vec<&str> = self
+                .instructions()
+                .flat_map(|i| i.modified_tags())
+                .collect();
+            tags.sort_unstable();
+            tags.dedup();
+
+            dictionary.validate_block(block, &tags)?;
         }
+
+        Ok(modified_values_counter)
+    }
+
+    fn run(&self, block: &mut DataBlock) -> anyhow::Result<usize> {
+        let unmodified = (self.evaluation == Evaluation::Original
+            || self.steps.iter().any(Step::reads_unmodified))
+        .then(|| block.clone());
+
+        let original = match self.evaluation {
+            Evaluation::Sequential => None,
+            Evaluation::Original => unmodified.as_ref(),
+        };
+
+        let mut modified_values_counter = 0;
+
+        for step in &self.steps {
+            modified_values_counter +=
+                step.apply_to_block(block, original, unmodified.as_ref(), self.safeguard)?;
+        }
+
+        Ok(modified_values_counter)
+    }
+
+    pub fn from_toml(s: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str::<InstructionsSpec>(s)?.into())
+    }
+
+    pub fn from_json(s: &str) -> anyhow::Result<Self> {
+        Ok(serde_json::from_str::<InstructionsSpec>(s)?.into())
+    }
+
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        Ok(toml::to_string(&InstructionsSpec::from(self))?)
+    }
+
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string_pretty(&InstructionsSpec::from(self))?)
     }
 
-    pub fn from_string(s: &str) -> Self {
-        s.replace(";", "\n").replace(",", "\n").as_str().into()
+    /// Reads the text form with instructions separated by newlines, or by `;` or `,` at the end of a
+    /// word outside of quotes.
+    pub fn from_string(s: &str) -> anyhow::Result<Self> {
+        s.lines()
+            .flat_map(instruction_file::split_instructions)
+            .collect::<Vec<_>>()
+            .join("\n")
+            .parse()
     }
 
+    /// Reads instructions from a file.
+    ///
+    /// `.toml` and `.json` files contain the structured form, all other files the text form
+    /// described in [`crate::InstructionFile`].
     pub fn from_file(path: &str) -> anyhow::Result<Self> {
-        let s = std::fs::read_to_string(path)?
-            .replace(";", "\n")
-            .replace(",", "\n");
+        Self::from_file_with_presets(path, &[])
+    }
 
-        Ok(s.as_str().into())
+    /// Like [`Instructions::from_file`], but additionally applies the named presets of the file.
+    pub fn from_file_with_presets(path: &str, presets: &[String]) -> anyhow::Result<Self> {
+        let is_structured = path.ends_with(TOML_EXTENSION) || path.ends_with(JSON_EXTENSION);
+
+        if is_structured && !presets.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Presets are only supported in text instruction files"
+            ));
+        }
+
+        if path.ends_with(TOML_EXTENSION) {
+            Self::from_toml(&std::fs::read_to_string(path)?)
+        } else if path.ends_with(JSON_EXTENSION) {
+            Self::from_json(&std::fs::read_to_string(path)?)
+        } else {
+            InstructionFile::from_file(path)?.instructions(presets)
+        }
     }
 }
 
-impl From<&str> for Instructions {
-    fn from(s: &str) -> Self {
-        let mut map: HashMap<String, Vec<Instruction>> = HashMap::new();
+impl Display for Instructions {
+    /// Writes one instruction per line in the text form.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.evaluation != Evaluation::default() {
+            writeln!(f, "{} {}", EVALUATION_KEYWORD, self.evaluation)?;
+        }
 
-        let lines = s.split('\n');
+        if self.safeguard != Safeguard::default() {
+            writeln!(f, "{} {}", SAFEGUARD_KEYWORD, self.safeguard)?;
+        }
 
-        for line in lines {
-            let instruction = Instruction::from(line);
+        for min_distance in &self.min_distances {
+            writeln!(f, "{} {}", MIN_DISTANCE_KEYWORD, min_distance)?;
+        }
 
-            let keyword = instruction.keyword.clone();
+        for step in &self.steps {
+            writeln!(f, "{}", step)?;
+        }
 
-            if let Some(instructions) = map.get_mut(&keyword) {
-                instructions.push(instruction);
-            } else {
-                map.insert(keyword, vec![instruction]);
-            }
+        Ok(())
+    }
+}
+
+const TOML_EXTENSION: &str = ".toml";
+const JSON_EXTENSION: &str = ".json";
+
+/// The structured form of [`Instructions`] used for TOML and JSON.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InstructionsSpec {
+    #[serde(default, skip_serializing_if = "is_default_evaluation")]
+    evaluation: Evaluation,
+    #[serde(default, skip_serializing_if = "is_default_safeguard")]
+    safeguard: Safeguard,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    min_distances: Vec<MinDistance>,
+    instructions: Vec<Step>,
+}
+
+fn is_default_evaluation(evaluation: &Evaluation) -> bool {
+    *evaluation == Evaluation::default()
+}
+
+fn is_default_safeguard(safeguard: &Safeguard) -> bool {
+    *safeguard == Safeguard::default()
+}
+
+impl From<&Instructions> for InstructionsSpec {
+    fn from(instructions: &Instructions) -> Self {
+        InstructionsSpec {
+            evaluation: instructions.evaluation,
+            safeguard: instructions.safeguard,
+            min_distances: instructions.min_distances.clone(),
+            instructions: instructions.steps.clone(),
         }
+    }
+}
+
+impl From<InstructionsSpec> for Instructions {
+    fn from(spec: InstructionsSpec) -> Self {
+        let mut instructions = Instructions::from(spec.instructions)
+            .with_evaluation(spec.evaluation)
+            .with_safeguard(spec.safeguard);
+        instructions.min_distances = spec.min_distances;
 
-        Instructions(map)
+        instructions
     }
 }
 
-impl From<Vec<Instruction>> for Instructions {
-    fn from(instructions: Vec<Instruction>) -> Self {
-        let mut map: HashMap<String, Vec<Instruction>> = HashMap::new();
+impl FromStr for Instructions {
+    type Err = anyhow::Error;
 
-        for instruction in instructions {
-            let keyword = instruction.keyword.clone();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut instructions = Instructions::default();
 
-            if let Some(instructions) = map.get_mut(&keyword) {
-                instructions.push(instruction);
-            } else {
-                map.insert(keyword, vec![instruction]);
+        for line in s.split('\n') {
+            if line.trim().is_empty() {
+                continue;
             }
+
+            let mut words = line.split_whitespace();
+
+            match (words.next(), words.next(), words.next()) {
+                (Some(EVALUATION_KEYWORD), Some(evaluation), None) => {
+                    instructions.evaluation = evaluation.parse()?;
+
+                    continue;
+                }
+                (Some(SAFEGUARD_KEYWORD), Some(safeguard), None) => {
+                    instructions.safeguard = safeguard.parse()?;
+
+                    continue;
+                }
+                (Some(MIN_DISTANCE_KEYWORD), _, _) => {
+                    let arguments = line.trim_start()[MIN_DISTANCE_KEYWORD.len()..].trim();
+
+                    instructions.min_distances.push(arguments.parse()?);
+
+                    continue;
+                }
+                _ => (),
+            }
+
+            let step = Step::parse(line)
+                .with_context(|| format!("Invalid instruction {}", line.trim()))?;
+
+            instructions.steps.push(step);
         }
 
-        Instructions(map)
+        Ok(instructions)
+    }
+}
+
+impl From<Vec<Step>> for Instructions {
+    fn from(steps: Vec<Step>) -> Self {
+        Instructions {
+            steps,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<Vec<Instruction>> for Instructions {
+    fn from(instructions: Vec<Instruction>) -> Self {
+        Instructions::from(
+            instructions
+                .into_iter()
+                .map(Step::from)
+                .collect::<Vec<Step>>(),
+        )
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_utils::block;
 
     #[test]
     fn test_operator_from() {
@@ -279,43 +1541,43 @@ mod tests {
 
     #[test]
     fn test_instruction_from() {
-        let instruction = Instruction::from("a + 1");
+        let instruction = "a + 1".parse::<Instruction>().unwrap();
         assert_eq!(instruction.keyword, "_cell_length_a");
         assert_eq!(instruction.operator, Operator::Add);
         assert_eq!(instruction.value_a, 1.0);
         assert_eq!(instruction.value_b, None);
 
-        let instruction = Instruction::from("a - 1");
+        let instruction = "a - 1".parse::<Instruction>().unwrap();
         assert_eq!(instruction.keyword, "_cell_length_a");
         assert_eq!(instruction.operator, Operator::Subtract);
         assert_eq!(instruction.value_a, 1.0);
         assert_eq!(instruction.value_b, None);
 
-        let instruction = Instruction::from("a * 1");
+        let instruction = "a * 1".parse::<Instruction>().unwrap();
         assert_eq!(instruction.keyword, "_cell_length_a");
         assert_eq!(instruction.operator, Operator::Multiply);
         assert_eq!(instruction.value_a, 1.0);
         assert_eq!(instruction.value_b, None);
 
-        let instruction = Instruction::from("a / 1");
+        let instruction = "a / 1".parse::<Instruction>().unwrap();
         assert_eq!(instruction.keyword, "_cell_length_a");
         assert_eq!(instruction.operator, Operator::Divide);
         assert_eq!(instruction.value_a, 1.0);
         assert_eq!(instruction.value_b, None);
 
-        let instruction = Instruction::from("a ^ 1");
+        let instruction = "a ^ 1".parse::<Instruction>().unwrap();
         assert_eq!(instruction.keyword, "_cell_length_a");
         assert_eq!(instruction.operator, Operator::Power);
         assert_eq!(instruction.value_a, 1.0);
         assert_eq!(instruction.value_b, None);
 
-        let instruction = Instruction::from("a -- 1");
+        let instruction = "a -- 1".parse::<Instruction>().unwrap();
         assert_eq!(instruction.keyword, "_cell_length_a");
         assert_eq!(instruction.operator, Operator::Range);
         assert_eq!(instruction.value_a, 1.0);
         assert_eq!(instruction.value_b, None);
 
-        let instruction = Instruction::from("0 -- b -- 1");
+        let instruction = "0 -- b -- 1".parse::<Instruction>().unwrap();
         assert_eq!(instruction.keyword, "_cell_length_b");
         assert_eq!(instruction.operator, Operator::Range);
         assert_eq!(instruction.value_a, 0.0);
@@ -324,28 +1586,44 @@ mod tests {
 
     #[test]
     fn test_apply() {
-        let instruction = Instruction::from("a + 1");
-        let result = instruction.apply("1.0".to_string()).unwrap();
+        let instruction = "a + 1".parse::<Instruction>().unwrap();
+        let result = instruction
+            .apply("1.0".to_string(), Safeguard::Error)
+            .unwrap();
         assert_eq!(result, "2.0");
 
-        let instruction = Instruction::from("a - 1");
-        let result = instruction.apply("1.0".to_string()).unwrap();
-        assert_eq!(result, "0.0");
+        let instruction = "a - 1".parse::<Instruction>().unwrap();
+        // A cell length of 0 is outside the bounds of `_cell_length_a`.
+        assert!(instruction
+            .apply("1.0".to_string(), Safeguard::Error)
+            .is_err());
+        let result = instruction
+            .apply("2.0".to_string(), Safeguard::Error)
+            .unwrap();
+        assert_eq!(result, "1.0");
 
-        let instruction = Instruction::from("a * 2");
-        let result = instruction.apply("1.0".to_string()).unwrap();
+        let instruction = "a * 2".parse::<Instruction>().unwrap();
+        let result = instruction
+            .apply("1.0".to_string(), Safeguard::Error)
+            .unwrap();
         assert_eq!(result, "2.0");
 
-        let instruction = Instruction::from("a / 2");
-        let result = instruction.apply("1.0".to_string()).unwrap();
+        let instruction = "a / 2".parse::<Instruction>().unwrap();
+        let result = instruction
+            .apply("1.0".to_string(), Safeguard::Error)
+            .unwrap();
         assert_eq!(result, "0.5");
 
-        let instruction = Instruction::from("a ^ 2");
-        let result = instruction.apply("1.0".to_string()).unwrap();
+        let instruction = "a ^ 2".parse::<Instruction>().unwrap();
+        let result = instruction
+            .apply("1.0".to_string(), Safeguard::Error)
+            .unwrap();
         assert_eq!(result, "1.0");
 
-        let instruction = Instruction::from("a -- 2");
-        let result = instruction.apply("1.0".to_string()).unwrap();
+        let instruction = "a -- 2".parse::<Instruction>().unwrap();
+        let result = instruction
+            .apply("1.0".to_string(), Safeguard::Error)
+            .unwrap();
         assert!(result.parse::<f64>().unwrap() >= 1.0);
     }
 
@@ -357,7 +1635,12 @@ mod tests {
         assert_eq!(instruction.value_a, 1.0);
         assert_eq!(instruction.value_b, None);
 
-        assert_eq!(instruction.apply("1.0".to_string()).unwrap(), "2.0");
+        assert_eq!(
+            instruction
+                .apply("1.0".to_string(), Safeguard::Error)
+                .unwrap(),
+            "2.0"
+        );
 
         let instruction =
             Instruction::new("_cell_length_a".to_string(), Operator::Subtract, 1.0, None);
@@ -366,6 +1649,433 @@ mod tests {
         assert_eq!(instruction.value_a, 1.0);
         assert_eq!(instruction.value_b, None);
 
-        assert_eq!(instruction.apply("1.0".to_string()).unwrap(), "0.0");
+        assert!(instruction
+            .apply("1.0".to_string(), Safeguard::Error)
+            .is_err());
+        assert_eq!(
+            instruction
+                .apply("2.0".to_string(), Safeguard::Error)
+                .unwrap(),
+            "1.0"
+        );
+    }
+
+    #[test]
+    fn test_instruction_options_and_selector() {
+        let instruction = "_atom_site_fract_x[Ti*] -- 0.6 distribution=normal precision=3"
+            .parse::<Instruction>()
+            .unwrap();
+        assert_eq!(instruction.keyword, "_atom_site_fract_x");
+        assert_eq!(instruction.selector, Some("Ti*".to_string()));
+        assert_eq!(instruction.distribution, Distribution::Normal);
+        assert_eq!(instruction.precision, Precision::Fixed(3));
+
+        assert!(instruction.is_selected(Some("Ti1")));
+        assert!(!instruction.is_selected(Some("O1")));
+        assert!(!instruction.is_selected(None));
+
+        let result = instruction
+            .apply("0.5".to_string(), Safeguard::Error)
+            .unwrap();
+        assert_eq!(result.len(), 5);
+        assert!((0.5..0.6).contains(&result.parse::<f64>().unwrap()));
+    }
+
+    #[test]
+    fn test_matches_selector() {
+        assert!(matches_selector("Ti1", "Ti1"));
+        assert!(!matches_selector("Ti1", "Ti10"));
+        assert!(matches_selector("*", "O1"));
+        assert!(matches_selector("O*", "O12"));
+        assert!(matches_selector("*1", "Ba1"));
+        assert!(matches_selector("T*i*1", "Ti_site1"));
+        assert!(!matches_selector("O*", "Ba1"));
+    }
+
+    #[test]
+    fn test_text_and_structured_form_roundtrip() {
+        let instructions = Instructions::from_file("tests/instructions.toml").unwrap();
+
+        let text = instructions.to_string();
+        assert_eq!(
+            text,
+            "_cell_length_a + 1\n\
+             70 -- _cell_angle_alpha -- 120 distribution=normal precision=2\n\
+             _atom_site_U_iso_or_equiv[Ti*] * 1.1 precision=full\n"
+        );
+
+        let from_text = Instructions::from_string(text.trim()).unwrap();
+        assert_eq!(from_text, instructions);
+
+        let from_toml = Instructions::from_toml(&instructions.to_toml().unwrap()).unwrap();
+        assert_eq!(from_toml, instructions);
+
+        let from_json = Instructions::from_json(&instructions.to_json().unwrap()).unwrap();
+        assert_eq!(from_json, instructions);
+    }
+
+    #[test]
+    fn test_invalid_structured_form() {
+        assert!(Instructions::from_json(
+            r#"{"instructions": [{"tag": "a", "operator": "add", "operands": [1, 2]}]}"#
+        )
+        .is_err());
+        assert!(Instructions::from_json(
+            r#"{"instructions": [{"tag": "a", "operator": "add", "operands": [1], "unknown": 1}]}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_assignment_from() {
+        let instruction = "c = a * 2".parse::<Instruction>().unwrap();
+        assert_eq!(instruction.keyword, "_cell_length_c");
+        assert_eq!(instruction.source, Some("_cell_length_a".to_string()));
+        assert_eq!(instruction.operator, Operator::Multiply);
+        assert_eq!(instruction.value_a, 2.0);
+        assert_eq!(
+            instruction.to_string(),
+            "_cell_length_c = _cell_length_a * 2"
+        );
+
+        let instruction = "c = a".parse::<Instruction>().unwrap();
+        assert_eq!(instruction.operator, Operator::None);
+        assert_eq!(instruction.to_string(), "_cell_length_c = _cell_length_a");
+
+        let instruction = "c = 5.5".parse::<Instruction>().unwrap();
+        assert_eq!(instruction.source, None);
+        assert_eq!(instruction.operator, Operator::Set);
+        assert_eq!(
+            instruction
+                .apply("4.00".to_string(), Safeguard::Error)
+                .unwrap(),
+            "5.50"
+        );
+        assert_eq!(instruction.to_string(), "_cell_length_c = 5.5");
+    }
+
+    #[test]
+    fn test_evaluation() {
+        let cif = "data_test\n_cell_length_a 4.00\n_cell_length_c 3.00\n";
+
+        let mut block = crate::Cif::parse(cif).unwrap().blocks()[1].clone();
+        let instructions: Instructions = "a + 1\nc = a * 2".parse().unwrap();
+        assert_eq!(instructions.apply_to_block(&mut block).unwrap(), 2);
+        assert_eq!(block.value("_cell_length_c").unwrap(), "10.00");
+
+        let mut block = crate::Cif::parse(cif).unwrap().blocks()[1].clone();
+        let instructions: Instructions = "evaluation original\na + 1\nc = a * 2".parse().unwrap();
+        assert_eq!(instructions.evaluation(), Evaluation::Original);
+        assert_eq!(instructions.apply_to_block(&mut block).unwrap(), 2);
+        assert_eq!(block.value("_cell_length_a").unwrap(), "5.00");
+        assert_eq!(block.value("_cell_length_c").unwrap(), "8.00");
+
+        assert_eq!(
+            instructions.to_string(),
+            "evaluation original\n_cell_length_a + 1\n_cell_length_c = _cell_length_a * 2\n"
+        );
+    }
+
+    #[test]
+    fn test_assignment_within_loop() {
+        let mut block = block("tests/BaTiO3.cif");
+
+        let instructions: Instructions =
+            "_atom_site_fract_y = _atom_site_fract_x + 0.1 precision=2"
+                .parse()
+                .unwrap();
+        assert_eq!(instructions.apply_to_block(&mut block).unwrap(), 3);
+
+        let atom_sites = block.find_loop("_atom_site_fract_y").unwrap();
+        assert_eq!(atom_sites.value(0, 5), "0.10");
+        assert_eq!(atom_sites.value(1, 5), "0.60");
+        assert_eq!(atom_sites.value(2, 5), "0.60");
+    }
+
+    #[test]
+    fn test_dictionary_validation() {
+        let dictionary = Arc::new(Dictionary::from_file("tests/cif_core_ddlm.dic").unwrap());
+
+        let block = block("tests/BaTiO3.cif");
+
+        let instructions = Instructions::from_string("a * 1.01\n_atom_site_occupancy[Ti1] * 0.5")
+            .unwrap()
+            .with_dictionary(dictionary.clone())
+            .unwrap();
+        assert_eq!(instructions.apply_to_block(&mut block.clone()).unwrap(), 2);
+
+        let instructions = Instructions::from_string("a - 10")
+            .unwrap()
+            .with_dictionary(dictionary.clone())
+            .unwrap();
+        assert!(instructions.apply_to_block(&mut block.clone()).is_err());
+
+        let instructions = Instructions::from_string("_atom_site_occupancy[Ti1] + 0.5")
+            .unwrap()
+            .with_dictionary(dictionary.clone())
+            .unwrap();
+        assert!(instructions.apply_to_block(&mut block.clone()).is_err());
+
+        assert!(Instructions::from_string("_cell_measurement_pressure * 2")
+            .unwrap()
+            .with_dictionary(dictionary)
+            .is_err());
+    }
+
+    #[test]
+    fn test_safeguards() {
+        let instruction = "a - 10".parse::<Instruction>().unwrap();
+        assert!(instruction
+            .apply("4.0094".to_string(), Safeguard::Error)
+            .is_err());
+        assert_eq!(
+            instruction
+                .apply("4.0094".to_string(), Safeguard::Clamp)
+                .unwrap(),
+            "0.0001"
+        );
+        assert!(instruction
+            .apply("4.0094".to_string(), Safeguard::Resample)
+            .is_err());
+
+        let instruction = "gamma * 3".parse::<Instruction>().unwrap();
+        assert_eq!(
+            instruction
+                .apply("90.00".to_string(), Safeguard::Clamp)
+                .unwrap(),
+            "179.99"
+        );
+
+        assert!("a / 0"
+            .parse::<Instruction>()
+            .unwrap()
+            .apply("4.0".to_string(), Safeguard::Clamp)
+            .is_err());
+        assert!("_unknown_tag ^ 0.5"
+            .parse::<Instruction>()
+            .unwrap()
+            .apply("-1.0".to_string(), Safeguard::Clamp)
+            .is_err());
+
+        let instruction = "-10 -- a -- 10".parse::<Instruction>().unwrap();
+        for _ in 0..10 {
+            let result = instruction
+                .apply("4.0".to_string(), Safeguard::Resample)
+                .unwrap();
+            assert!(result.parse::<f64>().unwrap() > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_cell_is_resampled() {
+        let block = block("tests/BaTiO3.cif");
+
+        let instructions = Instructions::from_string("alpha = 170\nbeta = 40\ngamma = 40").unwrap();
+        assert!(instructions.apply_to_block(&mut block.clone()).is_err());
+
+        let instructions = Instructions::from_string(
+            "safeguard resample\n1 -- alpha -- 179\n1 -- beta -- 179\n1 -- gamma -- 179",
+        )
+        .unwrap();
+        assert_eq!(instructions.safeguard(), Safeguard::Resample);
+        assert!(instructions.to_string().starts_with("safeguard resample\n"));
+        assert_eq!(
+            Instructions::from_toml(&instructions.to_toml().unwrap()).unwrap(),
+            instructions
+        );
+
+        for _ in 0..10 {
+            let mut block = block.clone();
+            instructions.apply_to_block(&mut block).unwrap();
+            assert!(safeguards::check_cell(&block).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_min_distance_is_resampled() {
+        let block = block("tests/BaTiO3_P1.cif");
+
+        let instructions = Instructions::from_string("min_distance 1.95 Å\nrattle 0.1").unwrap();
+        assert_eq!(instructions.min_distances().len(), 1);
+        assert!(instructions.to_string().starts_with("min_distance 1.95\n"));
+        assert_eq!(
+            Instructions::from_toml(&instructions.to_toml().unwrap()).unwrap(),
+            instructions
+        );
+
+        for _ in 0..10 {
+            let mut block = block.clone();
+            instructions.apply_to_block(&mut block).unwrap();
+            assert!(geometry::check_min_distances(&block, instructions.min_distances()).is_ok());
+        }
+
+        // Programs without random steps cannot be drawn again.
+        let instructions = Instructions::from_string("min_distance 2.1\na * 1.01").unwrap();
+        assert!(instructions.apply_to_block(&mut block.clone()).is_err());
+
+        // The cutoff of a pair takes precedence.
+        let instructions =
+            Instructions::from_string("min_distance 2.1\nmin_distance O Ti 1.9\na * 1.01").unwrap();
+        assert_eq!(instructions.apply_to_block(&mut block.clone()).unwrap(), 1);
+
+        // A cutoff that cannot be read must not silently disable the check.
+        assert!(Instructions::from_string("min_distance abc; a + 1").is_err());
+    }
+
+    #[test]
+    fn test_volume_scaling() {
+        let original = block("tests/BaTiO3.cif");
+        let number =
+            |block: &DataBlock, tag: &str| crate::parse_number(&block.value(tag).unwrap()).unwrap();
+
+        let mut block = original.clone();
+        let instructions = Instructions::from_string("volume = 70.0").unwrap();
+        assert_eq!(instructions.apply_to_block(&mut block).unwrap(), 4);
+        assert_eq!(block.value("_cell_length_a"), block.value("_cell_length_c"));
+        assert_eq!(block.value("_cell_length_a").unwrap().len(), 6);
+        assert!((number(&block, "_cell_volume") - 70.0).abs() < 0.01);
+        assert!((Lattice::from_block(&block).unwrap().volume() - 70.0).abs() < 0.01);
+
+        let mut block = original.clone();
+        let instructions = Instructions::from_string("volume * 1.05 axes=c").unwrap();
+        assert_eq!(instructions.apply_to_block(&mut block).unwrap(), 2);
+        assert_eq!(block.value("_cell_length_a").as_deref(), Some("4.0094(2)"));
+        assert_eq!(block.value("_cell_length_c").as_deref(), Some("4.2096"));
+        assert_eq!(block.value("_cell_volume").as_deref(), Some("67.67"));
+
+        let mut block = original.clone();
+        let instructions = Instructions::from_string("c * 1.02 preserve volume").unwrap();
+        assert_eq!(instructions.apply_to_block(&mut block).unwrap(), 3);
+        assert_eq!(block.value("_cell_length_c").as_deref(), Some("4.0896"));
+        assert_eq!(block.value("_cell_length_a").as_deref(), Some("3.9699"));
+        assert_eq!(block.value("_cell_length_b").as_deref(), Some("3.9699"));
+        assert!((Lattice::from_block(&block).unwrap().volume() - 64.45).abs() < 0.01);
+
+        assert!(Instructions::from_string("volume = 70.0")
+            .unwrap()
+            .apply("_cell_volume", "64.45".to_string())
+            .is_err());
+    }
+
+    #[test]
+    fn test_aniso_parameters_follow_strain() {
+        let mut block = block("tests/BaTiO3_P1.cif");
+
+        Instructions::from_string("a * 1.1")
+            .unwrap()
+            .apply_to_block(&mut block)
+            .unwrap();
+
+        let l = block.find_loop("_atom_site_aniso_label").unwrap();
+        assert_eq!(l.row(0), ["Ti1", "0.0105", "0.0087", "0.0087"]);
+    }
+
+    #[test]
+    fn test_volume_options() {
+        let instructions =
+            Instructions::from_string("volume * 1.05 axes=ab; c * 1.02 preserve volume").unwrap();
+        let text = instructions.to_string();
+        assert_eq!(
+            text,
+            "_cell_volume * 1.05 axes=ab\n_cell_length_c * 1.02 preserve volume\n"
+        );
+        assert_eq!(
+            Instructions::from_string(text.trim()).unwrap(),
+            instructions
+        );
+        assert_eq!(
+            Instructions::from_json(&instructions.to_json().unwrap()).unwrap(),
+            instructions
+        );
+
+        assert!("occupancy * 2 preserve volume"
+            .parse::<Instruction>()
+            .is_err());
+        assert!("c * 1.02 preserve volume axes=c"
+            .parse::<Instruction>()
+            .is_err());
+        assert!("a * 1.02 axes=c".parse::<Instruction>().is_err());
+        assert!(Instructions::from_json(
+            r#"{"instructions": [{"tag": "c", "operator": "multiply", "operands": [1.02], "preserve": "mass"}]}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_from_string_keeps_lists() {
+        let instructions =
+            Instructions::from_string("expand; rattle sigma=0.02 elements=O,Ti labels=O1*")
+                .unwrap();
+        assert_eq!(instructions.steps().len(), 2);
+        assert_eq!(
+            instructions.to_string(),
+            "expand\nrattle sigma=0.02 elements=O,Ti labels=O1*\n"
+        );
+    }
+
+    #[test]
+    fn test_invalid_instructions() {
+        let error = |s: &str| format!("{:#}", s.parse::<Instruction>().unwrap_err());
+
+        assert!(error("a + 1 b").contains("Unexpected word b"));
+        assert!(error("a + 1, b + 2").contains("Unexpected word 1,"));
+        assert!(error("a + 1 2").contains("a + 1 2"));
+        assert!(error("a + * 1").contains("Unexpected operator *"));
+        assert!(error("a + 1 shape=normal").contains("Unknown option shape"));
+        assert!(error("a -- 1 distribution=flat").contains("flat"));
+        assert!(error("a * 1.02 preserve mass").contains("mass"));
+        assert!(error("+ 1").contains("No keyword found in + 1"));
+        assert!(error("a 1").contains("No operator found in a 1"));
+        assert!(error("a +").contains("No value found in a +"));
+    }
+
+    #[test]
+    fn test_from_string_rejects_invalid_lines() {
+        assert!(Instructions::from_string("supercell 2 2").is_err());
+        assert!(Instructions::from_string("a * 1.01; evaluation later").is_err());
+        assert!(Instructions::from_string("a * 1.01;\n; b * 1.01").is_ok());
+    }
+
+    #[test]
+    fn test_from_string_splits_at_commas() {
+        assert_eq!(
+            Instructions::from_string("a + 1, b + 2").unwrap(),
+            Instructions::from_string("a + 1; b + 2").unwrap()
+        );
+        assert!(Instructions::from_string("a + 1,b + 2").is_err());
+    }
+
+    #[test]
+    fn test_operations_in_program() {
+        let instructions = Instructions::from_string("a * 1.01; supercell 2 2 1").unwrap();
+        assert_eq!(instructions.steps().len(), 2);
+        assert_eq!(instructions.instructions().count(), 1);
+
+        let text = instructions.to_string();
+        assert_eq!(text, "_cell_length_a * 1.01\nsupercell 2 2 1\n");
+        assert_eq!(
+            Instructions::from_string(text.trim()).unwrap(),
+            instructions
+        );
+        assert_eq!(
+            Instructions::from_toml(&instructions.to_toml().unwrap()).unwrap(),
+            instructions
+        );
+        assert_eq!(
+            Instructions::from_json(
+                r#"{"instructions": [{"tag": "a", "operator": "multiply", "operands": [1.01]}, {"operation": "supercell", "matrix": [2, 2, 1]}]}"#
+            )
+            .unwrap(),
+            instructions
+        );
+        assert!(Instructions::from_json(
+            r#"{"instructions": [{"operation": "supercell", "matrix": [2, 0, 1]}]}"#
+        )
+        .is_err());
+
+        let mut block = block("tests/BaTiO3_P1.cif");
+        instructions.apply_to_block(&mut block).unwrap();
+        assert_eq!(block.value("_cell_length_a").as_deref(), Some("8.0990"));
+        assert_eq!(block.find_loop("_atom_site_label").unwrap().len(), 20);
     }
 }
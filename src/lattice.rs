@@ -0,0 +1,399 @@
+//! Crystal lattices from cell parameters.
+//!
+//! Orientation convention for Cartesian coordinates, as in the PDB and most crystallographic
+//! programs: `a` is along x, `b` lies in the xy plane and `c` completes a right-handed system.
+//!
+//! ```text
+//! a = (a, 0, 0)
+//! b = (b cos γ, b sin γ, 0)
+//! c = (c cos β, c (cos α - cos β cos γ) / sin γ, V / (a b sin γ))
+//! ```
+//!
+//! Lattice vectors are the rows of [`Lattice::matrix`], so a fractional position `f` has the
+//! Cartesian position `f · M = x a + y b + z c`. Reciprocal lattice vectors follow the
+//! crystallographic convention `a · a* = 1` without a factor of 2π.
+
+use std::{fmt::Display, str::FromStr};
+
+use crate::{
+    linalg::{self, Matrix3, Vector3},
+    parse_number,
+    utilities::{decimal_places, RemoveUncertaintyDigits},
+    DataBlock,
+};
+
+/// Changed cell lengths and angles are written with at least this many decimal places.
+const MIN_PARAMETER_DIGITS: [usize; 6] = [4; 6];
+
+pub(crate) const CELL_TAGS: [&str; 6] = [
+    "_cell_length_a",
+    "_cell_length_b",
+    "_cell_length_c",
+    "_cell_angle_alpha",
+    "_cell_angle_beta",
+    "_cell_angle_gamma",
+];
+
+pub(crate) const VOLUME_TAG: &str = "_cell_volume";
+
+const AXIS_NAMES: [char; 3] = ['a', 'b', 'c'];
+
+/// Returns 0, 1 or 2 if `tag` is the length of a, b or c.
+pub(crate) fn length_index(tag: &str) -> Option<usize> {
+    CELL_TAGS[..3]
+        .iter()
+        .position(|length_tag| crate::tags::registry().is_same_tag(length_tag, tag))
+}
+
+pub(crate) fn is_volume_tag(tag: &str) -> bool {
+    crate::tags::registry().is_same_tag(VOLUME_TAG, tag)
+}
+
+/// A non-empty selection of the cell axes a, b and c, written as e.g. `ab`.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Axes([bool; 3]);
+
+impl Axes {
+    pub const ALL: Axes = Axes([true; 3]);
+
+    /// All axes except the one with the given index.
+    pub fn without(index: usize) -> Self {
+        let mut axes = Self::ALL;
+        axes.0[index] = false;
+        axes
+    }
+
+    pub fn contains(&self, index: usize) -> bool {
+        self.0[index]
+    }
+
+    /// The indices of the selected axes.
+    pub fn indices(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..3).filter(|index| self.0[*index])
+    }
+
+    pub fn count(&self) -> usize {
+        self.indices().count()
+    }
+}
+
+impl FromStr for Axes {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut axes = [false; 3];
+
+        for c in s.chars() {
+            let index = AXIS_NAMES
+                .iter()
+                .position(|name| *name == c)
+                .ok_or_else(|| anyhow::anyhow!("{} in {} is not an axis", c, s))?;
+
+            axes[index] = true;
+        }
+
+        if axes == [false; 3] {
+            return Err(anyhow::anyhow!("No axes given"));
+        }
+
+        Ok(Axes(axes))
+    }
+}
+
+impl Display for Axes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for index in self.indices() {
+            write!(f, "{}", AXIS_NAMES[index])?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Lattice {
+    /// Lengths in Å.
+    lengths: [f64; 3],
+    /// Angles α, β and γ in degrees.
+    angles: [f64; 3],
+}
+
+impl Lattice {
+    /// Creates a lattice from lengths in Å and angles in degrees. Fails if the parameters do not
+    /// form a lattice, i.e. if the metric tensor is not positive definite.
+    pub fn new(a: f64, b: f64, c: f64, alpha: f64, beta: f64, gamma: f64) -> anyhow::Result<Self> {
+        let lattice = Lattice {
+            lengths: [a, b, c],
+            angles: [alpha, beta, gamma],
+        };
+
+        let is_finite = lattice.parameters().iter().all(|p| p.is_finite());
+
+        if !is_finite || a <= 0.0 || b <= 0.0 || c <= 0.0 || lattice.volume_factor() <= 0.0 {
+            return Err(anyhow::anyhow!(
+                "The cell parameters {} {} {} {} {} {} do not form a lattice. The metric tensor is not positive definite.",
+                a,
+                b,
+                c,
+                alpha,
+                beta,
+                gamma
+            ));
+        }
+
+        Ok(lattice)
+    }
+
+    /// Reads the six cell parameters of a data block.
+    pub fn from_block(block: &DataBlock) -> anyhow::Result<Self> {
+        let mut parameters = [0.0; 6];
+
+        for (parameter, tag) in parameters.iter_mut().zip(CELL_TAGS) {
+            let value = block
+                .value(tag)
+                .ok_or_else(|| anyhow::anyhow!("{} is missing", tag))?;
+
+            *parameter = parse_number(&value)
+                .ok_or_else(|| anyhow::anyhow!("{} of {} is not a number", value, tag))?;
+        }
+
+        let [a, b, c, alpha, beta, gamma] = parameters;
+
+        Self::new(a, b, c, alpha, beta, gamma)
+    }
+
+    /// Creates a lattice from the lattice vectors given as rows of a matrix. The orientation of the
+    /// vectors is not kept.
+    pub fn from_matrix(matrix: Matrix3) -> anyhow::Result<Self> {
+        let [a, b, c] = matrix;
+
+        let angle = |u: Vector3, v: Vector3| {
+            (linalg::dot(u, v) / (linalg::norm(u) * linalg::norm(v)))
+                .clamp(-1.0, 1.0)
+                .acos()
+                .to_degrees()
+        };
+
+        Self::new(
+            linalg::norm(a),
+            linalg::norm(b),
+            linalg::norm(c),
+            angle(b, c),
+            angle(a, c),
+            angle(a, b),
+        )
+    }
+
+    /// `a`, `b`, `c`, `α`, `β` and `γ`.
+    pub fn parameters(&self) -> [f64; 6] {
+        let [a, b, c] = self.lengths;
+        let [alpha, beta, gamma] = self.angles;
+
+        [a, b, c, alpha, beta, gamma]
+    }
+
+    pub fn lengths(&self) -> [f64; 3] {
+        self.lengths
+    }
+
+    pub fn angles(&self) -> [f64; 3] {
+        self.angles
+    }
+
+    /// `1 - cos²α - cos²β - cos²γ + 2 cos α cos β cos γ`, i.e. `(V / abc)²`.
+    fn volume_factor(&self) -> f64 {
+        let [cos_alpha, cos_beta, cos_gamma] = self.angles.map(|angle| angle.to_radians().cos());
+
+        1.0 - cos_alpha.powi(2) - cos_beta.powi(2) - cos_gamma.powi(2)
+            + 2.0 * cos_alpha * cos_beta * cos_gamma
+    }
+
+    /// The volume in Å³.
+    pub fn volume(&self) -> f64 {
+        let [a, b, c] = self.lengths;
+
+        a * b * c * self.volume_factor().sqrt()
+    }
+
+    /// The lattice vectors a, b and c as rows, in the orientation described in the module
+    /// documentation.
+    pub fn matrix(&self) -> Matrix3 {
+        let [a, b, c] = self.lengths;
+        let [alpha, beta, gamma] = self.angles.map(f64::to_radians);
+
+        let c_x = c * beta.cos();
+        let c_y = c * (alpha.cos() - beta.cos() * gamma.cos()) / gamma.sin();
+        let c_z = self.volume() / (a * b * gamma.sin());
+
+        [
+            [a, 0.0, 0.0],
+            [b * gamma.cos(), b * gamma.sin(), 0.0],
+            [c_x, c_y, c_z],
+        ]
+    }
+
+    /// The metric tensor `G` with `G_ij = a_i · a_j`.
+    pub fn metric_tensor(&self) -> Matrix3 {
+        let [a, b, c] = self.lengths;
+        let [cos_alpha, cos_beta, cos_gamma] = self.angles.map(|angle| angle.to_radians().cos());
+
+        [
+            [a * a, a * b * cos_gamma, a * c * cos_beta],
+            [a * b * cos_gamma, b * b, b * c * cos_alpha],
+            [a * c * cos_beta, b * c * cos_alpha, c * c],
+        ]
+    }
+
+    /// The reciprocal lattice vectors a*, b* and c* as rows, with `a_i · a*_j = δ_ij`.
+    pub fn reciprocal_matrix(&self) -> Matrix3 {
+        let inverse = linalg::inverse(self.matrix()).expect("A lattice is never singular");
+
+        linalg::transpose(inverse)
+    }
+
+    /// The reciprocal lattice with lengths in Å⁻¹.
+    pub fn reciprocal(&self) -> Lattice {
+        Lattice::from_matrix(self.reciprocal_matrix()).expect("A reciprocal lattice is a lattice")
+    }
+
+    /// The spacing in Å of the lattice planes (hkl).
+    pub fn d_spacing(&self, hkl: Vector3) -> f64 {
+        let reciprocal_vector = linalg::vec_mat(hkl, self.reciprocal_matrix());
+
+        1.0 / linalg::norm(reciprocal_vector)
+    }
+
+    pub fn to_cartesian(&self, fractional: Vector3) -> Vector3 {
+        linalg::vec_mat(fractional, self.matrix())
+    }
+
+    pub fn to_fractional(&self, cartesian: Vector3) -> Vector3 {
+        linalg::vec_mat(cartesian, linalg::transpose(self.reciprocal_matrix()))
+    }
+
+    /// Writes the cell parameters and, if the block has one, `_cell_volume` to the block. Every
+    /// value keeps its number of decimal places, but changed lengths and angles get at least
+    /// [`MIN_PARAMETER_DIGITS`]. Returns the number of modified values.
+    pub(crate) fn write_to(&self, block: &mut DataBlock) -> usize {
+        let values = CELL_TAGS
+            .iter()
+            .zip(self.parameters())
+            .zip(MIN_PARAMETER_DIGITS)
+            .chain([((&VOLUME_TAG, self.volume()), 0)]);
+
+        let mut modified_values_counter = 0;
+
+        for ((tag, value), min_digits) in values {
+            let Some(old_value) = block.value(tag) else {
+                continue;
+            };
+
+            let old_value_without_uncertainty = old_value.remove_uncertainty_digits();
+
+            let mut digits = decimal_places(&old_value_without_uncertainty);
+
+            // A rounded value stays as it is, e.g. an angle of 90 that is still 90.
+            if parse_number(&old_value_without_uncertainty)
+                .is_none_or(|old| (old - value).abs() > 0.5 * 10f64.powi(-(digits as i32)))
+            {
+                digits = digits.max(min_digits);
+            }
+
+            let new_value = format!("{:.*}", digits, value);
+
+            log::debug!("{} {} -> {}", tag, old_value, new_value);
+
+            block.set_value(tag, &new_value);
+            modified_values_counter += 1;
+        }
+
+        modified_values_counter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::block;
+
+    fn assert_close(a: &[f64], b: &[f64]) {
+        for (x, y) in a.iter().zip(b) {
+            assert!((x - y).abs() < 1e-9, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_from_block() {
+        let lattice = Lattice::from_block(&block("tests/BaTiO3.cif")).unwrap();
+
+        assert_close(
+            &lattice.parameters(),
+            &[4.0094, 4.0094, 4.0094, 90.0, 90.0, 90.0],
+        );
+        assert!((lattice.volume() - 64.452).abs() < 1e-3);
+        assert!((lattice.d_spacing([1.0, 1.0, 0.0]) - 4.0094 / 2f64.sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_triclinic_lattice() {
+        let lattice = Lattice::new(5.0, 6.0, 7.0, 80.0, 95.0, 105.0).unwrap();
+
+        // The metric tensor is M · Mᵀ.
+        let matrix = lattice.matrix();
+        for (row, expected_row) in linalg::mat_mul(matrix, linalg::transpose(matrix))
+            .iter()
+            .zip(lattice.metric_tensor())
+        {
+            assert_close(row, &expected_row);
+        }
+
+        assert!((linalg::determinant(matrix) - lattice.volume()).abs() < 1e-9);
+        assert_close(
+            &Lattice::from_matrix(matrix).unwrap().parameters(),
+            &lattice.parameters(),
+        );
+
+        let reciprocal = lattice.reciprocal();
+        assert!((reciprocal.volume() * lattice.volume() - 1.0).abs() < 1e-9);
+        assert_close(&reciprocal.reciprocal().parameters(), &lattice.parameters());
+        assert!((lattice.d_spacing([1.0, 0.0, 0.0]) - 1.0 / reciprocal.lengths()[0]).abs() < 1e-9);
+
+        let fractional = [0.1, 0.2, 0.3];
+        assert_close(
+            &lattice.to_fractional(lattice.to_cartesian(fractional)),
+            &fractional,
+        );
+        assert_close(&lattice.to_cartesian([1.0, 0.0, 0.0]), &[5.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_write_to() {
+        let mut block = block("tests/BaTiO3.cif");
+
+        let lattice = Lattice::new(4.0094, 5.67014, 4.0094, 90.0, 54.7356, 90.0).unwrap();
+        assert_eq!(lattice.write_to(&mut block), 7);
+
+        assert_eq!(block.value("_cell_length_a").as_deref(), Some("4.0094"));
+        assert_eq!(block.value("_cell_length_b").as_deref(), Some("5.6701"));
+        assert_eq!(block.value("_cell_angle_alpha").as_deref(), Some("90"));
+        assert_eq!(block.value("_cell_angle_beta").as_deref(), Some("54.7356"));
+    }
+
+    #[test]
+    fn test_axes() {
+        let axes: Axes = "ca".parse().unwrap();
+        assert_eq!(axes.to_string(), "ac");
+        assert_eq!(axes.count(), 2);
+        assert!(!axes.contains(1));
+        assert_eq!(Axes::without(2), "ab".parse().unwrap());
+        assert!("".parse::<Axes>().is_err());
+        assert!("ad".parse::<Axes>().is_err());
+    }
+
+    #[test]
+    fn test_invalid_lattice() {
+        assert!(Lattice::new(5.0, 5.0, 5.0, 170.0, 40.0, 40.0).is_err());
+        assert!(Lattice::new(-5.0, 5.0, 5.0, 90.0, 90.0, 90.0).is_err());
+        assert!(Lattice::new(5.0, 5.0, 5.0, 90.0, 90.0, f64::NAN).is_err());
+    }
+}
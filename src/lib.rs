@@ -1,25 +1,161 @@
+mod adp;
+mod archive;
 mod arguments;
+mod cif;
+mod composition;
+mod compression;
+mod defects;
+mod dictionary;
+mod diffraction;
+mod expansion;
+mod formula;
+mod geometry;
+mod instruction_file;
 mod instructions;
+mod lattice;
+pub mod linalg;
+mod occupancy;
+mod operations;
+mod rattle;
+mod safeguards;
+mod structure;
+mod substitution;
+mod supercell;
+mod symmetry;
+mod tags;
+#[cfg(test)]
+pub(crate) mod test_utils;
+mod transformation;
 mod utilities;
+mod validation;
 
 use std::{
     cell::RefCell,
-    fs::File,
-    io::{BufRead, BufReader},
+    io::{Read, Write},
 };
 
 use rand::{rngs::StdRng, SeedableRng};
 
+pub use adp::Adp;
+pub use adp::AdpKind;
+
+pub use archive::apply_instructions_to_archive;
+pub use archive::for_each_cif_in_archive;
+pub use archive::ArchiveFormat;
+
 pub use arguments::Args;
+pub use arguments::Command;
+pub use arguments::DiffractionFormat;
+pub use arguments::GeometryFormat;
+pub use arguments::InstructionFormat;
+pub use arguments::ProfileArgument;
+pub use arguments::ReportFormat;
+
+pub use cif::parse_number;
+pub use cif::Cif;
+pub use cif::DataBlock;
+pub use cif::Item;
+pub use cif::Loop;
+pub use cif::Value;
+
+pub use compression::decompress;
+pub use compression::Compression;
+
+pub use defects::Interstitial;
+pub use defects::Vacancy;
+
+pub use dictionary::Definition;
+pub use dictionary::Dictionary;
+pub use dictionary::List;
+
+pub use diffraction::diffraction_of_reader;
+pub use diffraction::parse_wavelength;
+pub use diffraction::reflections_of_reader;
+pub use diffraction::BlockDiffraction;
+pub use diffraction::BlockReflections;
+pub use diffraction::Broadening;
+pub use diffraction::Diffraction;
+pub use diffraction::FileDiffraction;
+pub use diffraction::FileReflections;
+pub use diffraction::Peak;
+pub use diffraction::Profile;
+pub use diffraction::Reflection;
+pub use diffraction::DEFAULT_TWO_THETA_RANGE;
+pub use diffraction::DEFAULT_WAVELENGTH;
+
+pub use expansion::BirchMurnaghan;
+pub use expansion::Expansion;
+pub use expansion::ThermalExpansion;
+
+pub use formula::element_of;
+pub use formula::Formula;
+
+pub use geometry::geometry_of_reader;
+pub use geometry::Angle;
+pub use geometry::BlockGeometry;
+pub use geometry::Bond;
+pub use geometry::Change;
+pub use geometry::FileGeometry;
+pub use geometry::Geometry;
+pub use geometry::MinDistance;
+pub use geometry::DEFAULT_MAX_DISTANCE;
+
+pub use instruction_file::InstructionFile;
 
 pub use instructions::Instruction;
 pub use instructions::Instructions;
+pub use instructions::Step;
 
+pub use instructions::Distribution;
+pub use instructions::Evaluation;
 pub use instructions::Operator;
+pub use instructions::Precision;
+
+pub use lattice::Axes;
+pub use lattice::Lattice;
+
+pub use occupancy::OccupancyConstraint;
+pub use occupancy::OccupancySum;
+
+pub use operations::Operation;
+
+pub use rattle::Displacement;
+pub use rattle::Rattle;
+
+pub use supercell::Supercell;
+
+pub use substitution::Doping;
+pub use substitution::Species;
+pub use substitution::Substitution;
+
+pub use symmetry::SymmetryExpansion;
+pub use symmetry::SymmetryOperator;
+
+pub use transformation::CellTransform;
+pub use transformation::Setting;
 
+pub use safeguards::check_cell;
+pub use safeguards::Safeguard;
+
+pub use tags::registry;
+pub use tags::TagDefinition;
+pub use tags::TagKind;
+pub use tags::TagRegistry;
+
+pub use utilities::configuration_path;
+pub use utilities::derive_seed;
 pub use utilities::directory_content_from_path;
+pub use utilities::is_input_path;
+pub use utilities::modified_path;
+pub use utilities::write_atomically;
 
-use utilities::whitespace_between_two_values;
+pub use validation::validate_block;
+pub use validation::validate_cif;
+pub use validation::validate_reader;
+pub use validation::BlockReport;
+pub use validation::FileReport;
+pub use validation::Issue;
+pub use validation::Severity;
 
 thread_local! {
     #[cfg(not(test))]
@@ -28,78 +164,127 @@ thread_local! {
     pub static RNG: RefCell<StdRng> = RefCell::new(StdRng::seed_from_u64(0));
 }
 
-pub const KEYWORDS: [&str; 7] = [
-    "_cell_length_a",
-    "_cell_length_b",
-    "_cell_length_c",
-    "_cell_angle_alpha",
-    "_cell_angle_beta",
-    "_cell_angle_gamma",
-    "_cell_volume",
-];
+/// Replaces the random number generator of the current thread with one seeded by `seed`.
+pub fn seed_rng(seed: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = StdRng::seed_from_u64(seed));
+}
+
+/// Expands a short keyword to the full CIF tag. Full tags are returned unchanged.
+pub fn full_keyword(word: &str) -> String {
+    let registry = tags::registry();
+
+    match registry.get_short(word) {
+        Some(definition) => definition.name.clone(),
+        None => {
+            if registry.get(word).is_none() {
+                log::warn!(
+                    "{} is not a known keyword. Results may be unexpected.",
+                    word
+                );
+            }
 
-pub const SHORT_KEYWORDS: [&str; 7] = ["a", "b", "c", "alpha", "beta", "gamma", "volume"];
+            word.to_string()
+        }
+    }
+}
 
 pub fn apply_instructions_to_cif_file(
     path: &str,
     instructions: Instructions,
 ) -> anyhow::Result<Vec<String>> {
-    let mut new_lines = Vec::new();
+    let mut cif = Cif::parse(&std::fs::read_to_string(path)?)?;
 
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
-    let mut lines = reader.lines();
+    let modified_values_counter = apply_instructions_to_cif(&mut cif, &instructions)?;
 
-    let mut modified_lines_counter = 0;
+    log::debug!("Modified {} values in {}", modified_values_counter, path);
 
-    while let Some(Ok(line)) = lines.next() {
-        let mut modified_line = None;
+    Ok(cif.to_string().lines().map(str::to_string).collect())
+}
 
-        let mut words = line.split_whitespace();
+/// Reads a CIF from `reader`, applies the instructions and writes the result to `writer`. A syntax
+/// error in any data block fails the whole CIF and nothing is written.
+///
+/// Returns the number of modified values.
+pub fn apply_instructions(
+    mut reader: impl Read,
+    mut writer: impl Write,
+    instructions: &Instructions,
+) -> anyhow::Result<usize> {
+    let mut content = String::new();
+    reader.read_to_string(&mut content)?;
 
-        if let Some(key) = words.next() {
-            if KEYWORDS.contains(&key) {
-                if let Some(value) = words.next() {
-                    let whitespace_count = match whitespace_between_two_values(&line) {
-                        Some(whitespace) => whitespace,
-                        None => {
-                            log::error!(
-                                "Failed to find whitespace between key and value in {}. Skipping line.",
-                                line
-                            );
-                            continue;
-                        }
-                    };
-                    let whitespace = " ".repeat(whitespace_count);
+    let mut cif = Cif::parse(&content)?;
 
-                    let new_value = instructions.apply(key, value.to_string())?;
+    let modified_values_counter = apply_instructions_to_cif(&mut cif, instructions)?;
 
-                    match new_value {
-                        Some(new_value) => {
-                            let line_result = format!("{}{}{}", key, whitespace, new_value);
+    write!(writer, "{}", cif)?;
 
-                            log::debug!("{} -> {}", line, line_result);
+    writer.flush()?;
 
-                            modified_line = Some(line_result);
+    Ok(modified_values_counter)
+}
 
-                            modified_lines_counter += 1;
-                        }
-                        None => (),
-                    }
-                }
-            }
-        }
+/// Like [`apply_instructions`], but appends `_config<configuration>` to the name of every data
+/// block, so configurations written one after another have distinct block names.
+pub fn apply_instructions_to_configuration(
+    mut reader: impl Read,
+    mut writer: impl Write,
+    instructions: &Instructions,
+    configuration: usize,
+) -> anyhow::Result<usize> {
+    let mut content = String::new();
+    reader.read_to_string(&mut content)?;
+
+    let mut cif = Cif::parse(&content)?;
+
+    let modified_values_counter = apply_instructions_to_cif(&mut cif, instructions)?;
 
-        if let Some(modified_line) = modified_line {
-            new_lines.push(modified_line);
-        } else {
-            new_lines.push(line);
+    for block in cif.blocks_mut() {
+        if let Some(name) = block.name() {
+            let name = format!("{}_config{}", name, configuration);
+            block.set_name(&name);
         }
     }
 
-    log::debug!("Modified {} lines in {}", modified_lines_counter, path);
+    write!(writer, "{}", cif)?;
 
-    Ok(new_lines)
+    writer.flush()?;
+
+    Ok(modified_values_counter)
+}
+
+/// Like [`apply_instructions`], but decompresses gzip or xz input and compresses the output with the
+/// same method as the input.
+pub fn apply_instructions_to_compressed(
+    reader: impl Read,
+    writer: impl Write,
+    instructions: &Instructions,
+) -> anyhow::Result<usize> {
+    let (compression, reader) = decompress(reader)?;
+
+    let mut writer = compression.compress(writer);
+
+    let modified_values_counter = apply_instructions(reader, &mut writer, instructions)?;
+
+    writer.finish()?.flush()?;
+
+    Ok(modified_values_counter)
+}
+
+/// Applies the instructions to every data block of a parsed CIF.
+///
+/// Returns the number of modified values.
+pub fn apply_instructions_to_cif(
+    cif: &mut Cif,
+    instructions: &Instructions,
+) -> anyhow::Result<usize> {
+    let mut modified_values_counter = 0;
+
+    for block in cif.blocks_mut() {
+        modified_values_counter += instructions.apply_to_block(block)?;
+    }
+
+    Ok(modified_values_counter)
 }
 
 #[cfg(test)]
@@ -109,7 +294,9 @@ mod tests {
     #[test]
     fn test_cif_map_from_cif_file() {
         let instructions: Instructions =
-            "a + 1.0\nb * 2.0\nc - 1.0\nalpha + 1.0\n45.0 -- beta -- 90.0\ngamma / 2.0".into();
+            "a + 1.0\nb * 2.0\nc - 1.0\nalpha + 1.0\n45.0 -- beta -- 90.0\ngamma / 2.0"
+                .parse()
+                .unwrap();
 
         let new_lines = super::apply_instructions_to_cif_file("tests/BaTiO3.cif", instructions)
             .expect("Failed to modify CIF file");
@@ -123,4 +310,92 @@ mod tests {
 
         assert_eq!(new_lines[32], "_cell_angle_gamma                  45.00");
     }
+
+    #[test]
+    fn test_apply_instructions_to_writer() {
+        let instructions: Instructions = "a + 1.0".parse().unwrap();
+
+        let input = "_cell_length_a  4.0094(2)\n_cell_length_b  4.0094(2)\n";
+        let mut output = Vec::new();
+
+        let modified_lines =
+            super::apply_instructions(input.as_bytes(), &mut output, &instructions)
+                .expect("Failed to modify CIF");
+
+        assert_eq!(modified_lines, 1);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "_cell_length_a  5.0094\n_cell_length_b  4.0094(2)\n"
+        );
+    }
+
+    #[test]
+    fn test_apply_instructions_fails_on_syntax_error() {
+        let instructions: Instructions = "a + 1.0".parse().unwrap();
+
+        let input = "data_good\n_cell_length_a 4.0\ndata_bad\n_cell_length_a\n";
+        let mut output = Vec::new();
+
+        let error =
+            super::apply_instructions(input.as_bytes(), &mut output, &instructions).unwrap_err();
+
+        assert!(format!("{:#}", error).contains("Cannot read data block bad"));
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_apply_instructions_to_configurations() {
+        let instructions = Instructions::from_string("vacancy O1,O2; a * 1.01").unwrap();
+        assert_eq!(instructions.steps().len(), 2);
+
+        let input = std::fs::read_to_string("tests/BaTiO3_P1.cif").unwrap();
+        let mut output = Vec::new();
+
+        for configuration in 1..=2 {
+            super::apply_instructions_to_configuration(
+                input.as_bytes(),
+                &mut output,
+                &instructions,
+                configuration,
+            )
+            .expect("Failed to modify CIF");
+        }
+
+        let cif = crate::Cif::parse(&String::from_utf8(output).unwrap()).unwrap();
+        let names: Vec<_> = cif
+            .blocks()
+            .iter()
+            .filter_map(|block| block.name())
+            .collect();
+        assert_eq!(names, ["BaTiO3_P1_config1", "BaTiO3_P1_config2"]);
+
+        for block in &cif.blocks()[1..] {
+            let labels = block.find_loop("_atom_site_label").unwrap().rows();
+            let labels: Vec<_> = labels.iter().map(|row| row[0].as_str()).collect();
+            assert_eq!(labels, ["Ba1", "Ti1", "O3"]);
+        }
+    }
+
+    #[test]
+    fn test_apply_instructions_to_loop_rows() {
+        let instructions: Instructions =
+            "_atom_site_U_iso_or_equiv[Ti1] * 2 precision=4\n_atom_site_fract_z + 0.1"
+                .parse()
+                .unwrap();
+
+        let new_lines = super::apply_instructions_to_cif_file("tests/BaTiO3.cif", instructions)
+            .expect("Failed to modify CIF file");
+
+        // Only the changed values are replaced. The rows of the test file end with a space, which
+        // is kept like the rest of the original formatting.
+        assert_eq!(new_lines[106], "Ba1 Ba 1 a 0 0 0.1 1. 0 0.0049(2) ");
+        assert_eq!(new_lines[107], "Ti1 Ti 1 b 0.5 0.5 0.6 1. 0 0.0174 ");
+        assert_eq!(new_lines[108], "O1 O 3 c 0.5 0 0.6 1. 0 0.005(1) ");
+
+        let original = std::fs::read_to_string("tests/BaTiO3.cif").unwrap();
+        let original_lines: Vec<_> = original.lines().collect();
+        assert_eq!(new_lines.len(), original_lines.len());
+        assert_eq!(new_lines[..106], original_lines[..106]);
+        assert_eq!(new_lines[109..], original_lines[109..]);
+    }
 }
@@ -0,0 +1,111 @@
+//! Small fixed-size vector and matrix helpers for crystallographic calculations.
+//!
+//! Matrices are stored row by row.
+
+pub type Vector3 = [f64; 3];
+pub type Matrix3 = [[f64; 3]; 3];
+
+pub const IDENTITY: Matrix3 = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+pub fn dot(a: Vector3, b: Vector3) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+pub fn cross(a: Vector3, b: Vector3) -> Vector3 {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+pub fn norm(a: Vector3) -> f64 {
+    dot(a, a).sqrt()
+}
+
+pub fn add(a: Vector3, b: Vector3) -> Vector3 {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+pub fn sub(a: Vector3, b: Vector3) -> Vector3 {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+pub fn scale(a: Vector3, factor: f64) -> Vector3 {
+    a.map(|x| x * factor)
+}
+
+/// The matrix-vector product `m · v` with `v` as a column vector.
+pub fn mat_vec(m: Matrix3, v: Vector3) -> Vector3 {
+    m.map(|row| dot(row, v))
+}
+
+/// The vector-matrix product `v · m` with `v` as a row vector.
+pub fn vec_mat(v: Vector3, m: Matrix3) -> Vector3 {
+    mat_vec(transpose(m), v)
+}
+
+pub fn mat_mul(a: Matrix3, b: Matrix3) -> Matrix3 {
+    let b = transpose(b);
+
+    a.map(|row| b.map(|column| dot(row, column)))
+}
+
+pub fn transpose(m: Matrix3) -> Matrix3 {
+    [
+        [m[0][0], m[1][0], m[2][0]],
+        [m[0][1], m[1][1], m[2][1]],
+        [m[0][2], m[1][2], m[2][2]],
+    ]
+}
+
+pub fn determinant(m: Matrix3) -> f64 {
+    dot(m[0], cross(m[1], m[2]))
+}
+
+/// The inverse of a matrix, or `None` if it is singular.
+pub fn inverse(m: Matrix3) -> Option<Matrix3> {
+    let determinant = determinant(m);
+
+    if determinant.abs() < 1e-12 {
+        return None;
+    }
+
+    // The columns of the inverse are the cross products of the rows divided by the determinant.
+    let columns = [cross(m[1], m[2]), cross(m[2], m[0]), cross(m[0], m[1])];
+
+    Some(transpose(columns).map(|row| scale(row, 1.0 / determinant)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inverse() {
+        let m = [[2.0, 1.0, 0.0], [0.0, 1.0, 0.0], [1.0, 0.0, 3.0]];
+
+        let product = mat_mul(m, inverse(m).unwrap());
+
+        for (row, identity_row) in product.iter().zip(IDENTITY) {
+            for (x, y) in row.iter().zip(identity_row) {
+                assert!((x - y).abs() < 1e-12);
+            }
+        }
+
+        assert_eq!(
+            inverse([[1.0, 2.0, 3.0], [2.0, 4.0, 6.0], [0.0, 0.0, 1.0]]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_products() {
+        let m = [[1.0, 2.0, 3.0], [4.0, 5.0, 6.0], [7.0, 8.0, 9.0]];
+
+        assert_eq!(mat_vec(m, [1.0, 0.0, 0.0]), [1.0, 4.0, 7.0]);
+        assert_eq!(vec_mat([1.0, 0.0, 0.0], m), [1.0, 2.0, 3.0]);
+        assert_eq!(cross([1.0, 0.0, 0.0], [0.0, 1.0, 0.0]), [0.0, 0.0, 1.0]);
+        assert_eq!(determinant(IDENTITY), 1.0);
+    }
+}
@@ -1,20 +1,121 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, Read, Write},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
 use anyhow::Context;
-use cif_modder::directory_content_from_path;
+use cif_modder::{
+    configuration_path, directory_content_from_path, is_input_path, modified_path, ArchiveFormat,
+    Args, Broadening, Command, Diffraction, DiffractionFormat, FileReport, GeometryFormat,
+    InstructionFormat, Instructions, Profile, ProfileArgument, ReportFormat,
+};
 use clap::Parser;
+use rayon::prelude::*;
+
+/// Path that stands for reading the CIF from stdin.
+const STDIN_PATH: &str = "-";
+
+thread_local! {
+    /// The input file the current thread is processing and the log messages written for it so far.
+    static CURRENT_FILE: RefCell<Option<FileLog>> = const { RefCell::new(None) };
+}
+
+/// Log messages written while processing an input file.
+struct FileLog {
+    path: String,
+    records: Vec<LogRecord>,
+}
+
+/// A log message that is kept until all earlier input files have been reported.
+struct LogRecord {
+    level: log::Level,
+    target: String,
+    module_path: Option<String>,
+    file: Option<String>,
+    line: Option<u32>,
+    message: String,
+}
+
+impl LogRecord {
+    /// Writes the message to the logger.
+    fn log(&self) {
+        log::logger().log(
+            &log::Record::builder()
+                .args(format_args!("{}", self.message))
+                .level(self.level)
+                .target(&self.target)
+                .module_path(self.module_path.as_deref())
+                .file(self.file.as_deref())
+                .line(self.line)
+                .build(),
+        )
+    }
+}
+
+/// Writes log messages with `simple_logger`. Messages written while processing an input file are
+/// prefixed with its path and kept until [`with_current_path`] returns, so they can be written in
+/// input order, although the files are processed in parallel.
+struct PathLogger(simple_logger::SimpleLogger);
+
+impl log::Log for PathLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        CURRENT_FILE.with_borrow_mut(|file| match file {
+            Some(file) => file.records.push(LogRecord {
+                level: record.level(),
+                target: record.target().to_string(),
+                module_path: record.module_path().map(str::to_string),
+                file: record.file().map(str::to_string),
+                line: record.line(),
+                message: format!("{}: {}", file.path, record.args()),
+            }),
+            None => self.0.log(record),
+        })
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+/// Runs `f` with log messages prefixed by `path`. Returns the result of `f` and the log messages it
+/// wrote, which are not written to the logger yet.
+fn with_current_path<T>(path: &str, f: impl FnOnce() -> T) -> (T, Vec<LogRecord>) {
+    let previous = CURRENT_FILE.replace(Some(FileLog {
+        path: path.to_string(),
+        records: Vec::new(),
+    }));
+    let result = f();
+    let records = CURRENT_FILE
+        .replace(previous)
+        .map(|file| file.records)
+        .unwrap_or_default();
+    (result, records)
+}
 
 fn main() {
     let args = cif_modder::Args::parse();
 
     let is_verbose = args.verbose;
 
-    simple_logger::SimpleLogger::new()
-        .with_level(if is_verbose {
-            log::LevelFilter::Debug
-        } else {
-            log::LevelFilter::Info
-        })
-        .init()
-        .unwrap();
+    let logger = simple_logger::SimpleLogger::new().with_level(if is_verbose {
+        log::LevelFilter::Debug
+    } else {
+        log::LevelFilter::Info
+    });
+
+    log::set_max_level(logger.max_level());
+    log::set_boxed_logger(Box::new(PathLogger(logger))).unwrap();
 
     if args.examples {
         print!(indoc::indoc!(
@@ -38,13 +139,205 @@ fn main() {
             cif_modder -c path/to/cif -i \"path/to/instructions.txt\"
 
             - The instructions can also be read from a file.
-            - Valid delimiters are `;`, `,`, and `\\n`.
+            - Instructions are separated by `;`, `,` or `\\n`. Commas inside a word separate the items of a list, e.g. `elements=O,Ti`, and `;` and `,` inside quotes do not separate instructions.
+            - `#` starts a comment that runs to the end of the line.
+            - `let strain = 1.01` defines a variable that is used as `$strain` in later lines.
+            - `include \"other.txt\"` inserts the instructions of another file. Relative paths are resolved against the directory of the including file. A preset started in the included file ends with it.
+            - `[preset strain_1pct]` starts a named preset that is only applied when selected with `--preset strain_1pct`. Lines before the first preset are always applied.
+
+            cif-modder -c path/to/directory -i \"a -- 5.00\" --jobs 8 --keep-going --seed 42
+
+            - `--jobs` processes several files in parallel. Log messages about a file start with its path and are written in input order, like the output.
+            - `--keep-going` continues after a failed file and lists all failures at the end. The exit code is nonzero if any file failed. A failed file leaves no `_modified` file behind, as results are written to a temporary file that is renamed only on success. Without it, files after the failed one in input order no longer write their outputs, except for those that `--jobs` had already finished.
+            - `--seed` makes random instructions reproducible. Every file gets its own random stream derived from the seed and its path.
+
+            gzip -dc structure.cif.gz | cif-modder -c - -i \"a * 1.01\" | gzip > structure_modified.cif.gz
+            cif-modder -c path/to/directory -i \"a * 1.01\" --stdout > all_modified.cif
+
+            - `-` as path reads a single CIF from stdin and writes the modified CIF to stdout.
+            - `--stdout` writes the modified CIFs to stdout in the order of their paths instead of creating `_modified.cif` files. Each file is written as soon as it and all files before it are done.
+            - Log messages are written to stderr.
+
+            cif-modder -c structures.tar.gz -i \"a * 1.01\"
+
+            - gzip (`.cif.gz`) and xz (`.cif.xz`) compressed CIFs are decompressed transparently. The modified file is compressed the same way.
+            - tar (`.tar`, `.tar.gz`, `.tgz`, `.tar.xz`, `.txz`) and zip archives are streamed entry by entry into a new archive, e.g. `structures_modified.tar.gz`. Entries that are not CIFs are copied unchanged.
+
+            cif-modder -c path/to/cif -i \"_atom_site_U_iso_or_equiv[Ti*] * 1.1 precision=4; 0.45 -- _atom_site_fract_x[O1] -- 0.55 distribution=normal\"
+
+            - Tags inside loops are modified in every row. `[Ti*]` restricts an instruction to the rows whose label matches. `*` matches any sequence of characters.
+            - `precision=4` writes the result with 4 decimal places. `precision=full` writes all digits. By default the precision of the original value is kept.
+            - `distribution=normal` draws random values from a Gaussian centred on the middle of the range, with the bounds two standard deviations away. The default is `distribution=uniform`.
+
+            cif-modder -c path/to/cif -i \"path/to/instructions.toml\"
+            cif-modder -i \"a + 1; 70 -- alpha -- 120\" --print-instructions toml
+
+            - Instructions can also be given in a structured form as TOML (`.toml`) or JSON (`.json`) files.
+            - `--print-instructions text|toml|json` converts instructions between the text and the structured form.
+
+            cif-modder -c path/to/cif -i \"a + 1; c = a * 2; b = 5.0\"
+            cif-modder -c path/to/cif -i \"a + 1; c = a * 2\" --evaluation original
+
+            - Instructions are applied in the order they are given. Each instruction modifies all values of its tag, i.e. the single value or every selected row of a loop.
+            - `c = a * 2` sets `c` to the value of `a` multiplied by 2. If both tags are in the same loop, the value of the same row is used. `b = 5.0` sets `b` to 5.0.
+            - With `--evaluation sequential` (the default) an instruction sees the results of all earlier instructions, so `c` becomes `(a + 1) * 2`.
+            - With `--evaluation original` values of other tags are read from the unmodified file, so `c` becomes `a * 2` regardless of the order. Instructions for the same tag are still applied one after another.
+            - The evaluation can also be set with a line `evaluation original` in an instruction file or with `evaluation = \"original\"` in the structured form.
+
+            cif-modder -c path/to/cif -i \"a * 1.01; _atom_site_occupancy[Ti1] - 0.1\" --dictionary path/to/cif_core.dic
+
+            - `--dictionary` reads a DDL1 (`cif_core.dic` version 2) or DDLm (version 3) dictionary. Files imported with `_import.get` are read from the directory of the dictionary.
+            - Every modified tag has to be defined in the dictionary. After the instructions ran, the modified values are checked against the type, enumeration, range and loop membership of their definition.
+            - Edits that would produce a file that is invalid according to the dictionary, e.g. a negative `_cell_length_a` or an occupancy above 1, are an error.
+
+            cif-modder -c path/to/cif -i \"a - 10; gamma * 3\" --safeguard clamp
+            cif-modder -c path/to/cif -i \"60 -- alpha -- 120; 60 -- beta -- 120; 60 -- gamma -- 120\" --safeguard resample
+
+            - Every modified value has to be finite and within the physical bounds of its tag: positive cell lengths and volume, cell angles in (0, 180), occupancies in [0, 1]. `--list-tags` shows the bounds of every known tag.
+            - The cell angles together have to form a lattice, i.e. the metric tensor has to be positive definite.
+            - `--safeguard error` (the default) stops with an error, `--safeguard clamp` moves the value to the nearest allowed value and `--safeguard resample` draws random values again. With `resample`, instructions that are not random are still an error.
+            - The safeguard can also be set with a line `safeguard clamp` in an instruction file or with `safeguard = \"clamp\"` in the structured form.
+
+            cif-modder -c path/to/cif -i \"volume = 70.0\"
+            cif-modder -c path/to/cif -i \"volume * 1.05 axes=c\"
+            cif-modder -c path/to/cif -i \"c * 1.02 preserve volume\"
+
+            - `volume` instructions change the cell itself: the cell lengths are scaled isotropically so that the cell has the new volume, and `_cell_volume` is recomputed from the new lengths. Blocks without `_cell_volume` start from the volume computed from the cell parameters.
+            - `axes=c` scales only the selected axes, e.g. `axes=ab` for a and b.
+            - `preserve volume` keeps the volume of an edit of a cell length or angle constant. `c * 1.02 preserve volume` scales a and b to compensate, edits of angles scale all three lengths. `axes=a` selects the compensating axes.
+            - Scaled lengths keep their number of decimal places unless `precision=full` is given.
 
-            List of currently recognized CIF keywords:
-            `_cell_length_a`, `_cell_length_b`, `_cell_length_c`, `_cell_angle_alpha`, `_cell_angle_beta`, `_cell_angle_gamma`, `_cell_volume`
+            cif-modder -c path/to/cif -i \"supercell 2 2 1\"
+            cif-modder -c path/to/cif -i \"supercell 1 1 0 -1 1 0 0 0 1\"
+
+            - Operations change the whole structure instead of the values of a single tag. They can be mixed with instructions and run in the order they are given.
+            - `supercell 2 2 1` builds a 2×2×1 supercell. Nine integers give the full matrix row by row, i.e. the new axes as integer combinations of the old ones: `1 1 0 -1 1 0 0 0 1` means a' = a + b, b' = -a + b, c' = c. The determinant has to be positive.
+            - The cell parameters, `_cell_volume` and `_cell_formula_units_Z` are updated. Every atom site is repeated once per original cell with the labels `Ti1_1`, `Ti1_2`, ... and the `_atom_site_aniso_` loop is expanded the same way. The space group is set to P1 with the identity as the only operator.
+            - Structures with other symmetry are expanded to P1 first, see `expand`. The anisotropic displacement parameters are transformed to the new axes. All other metadata of the file is kept.
+            - In the structured form an operation is an entry with an `operation` field, e.g. `{{ operation = \"supercell\", matrix = [2, 2, 1] }}`.
+
+            cif-modder -c path/to/cif -i \"expand\"
+            cif-modder -c path/to/cif -i \"expand tolerance=0.05\"
+
+            - `expand` applies the symmetry operators of `_symmetry_equiv_pos_as_xyz` or `_space_group_symop_operation_xyz`, e.g. `-y, x-y, z+1/3`, to every atom site and writes the structure in P1.
+            - Images of a site closer than the tolerance, 0.01 Å by default, are merged, e.g. on special positions. Sites with several images get the labels `O1_1`, `O1_2`, ... A warning is logged if the number of images differs from `_atom_site_symmetry_multiplicity`.
+            - The anisotropic displacement parameters are rotated with the operators. Structures already in P1 are left unchanged.
+
+            cif-modder -c path/to/cif -i \"transform niggli\"
+            cif-modder -c path/to/cif -i \"transform primitive\"
+            cif-modder -c path/to/cif -i \"transform 0 1/2 1/2 1/2 0 1/2 1/2 1/2 0\"
+
+            - `transform` changes the basis of the cell without changing the structure. Fractional coordinates, the symmetry operators, the anisotropic displacement parameters, the cell parameters, `_cell_volume` and `_cell_formula_units_Z` are transformed, and the site multiplicities are recomputed. Symmetry operators that do not map the new cell onto itself, e.g. a 4-fold axis along `a` in the cell `1 1 0 -1 1 0 0 0 1`, are removed, and the atom sites that only they related get the labels `O1_1`, `O1_2`, ...
+            - `niggli` and `delaunay` transform to the Niggli and Delaunay reduced cells, e.g. to compare structures by their reduced cells.
+            - `primitive` removes the centring translations of the symmetry operators, or in P1 the translations that map every atom site onto another one. Centrings A, B, C, I, F and R get the primitive cells of the International Tables. Atom sites that become equivalent are removed.
+            - `conventional` transforms a primitive cell back to the centred cell of the Hermann-Mauguin symbol, e.g. `I` in `I 4/m m m`.
+            - Nine numbers give the new axes row by row like for `supercell`, but may be fractions. The new axes have to be lattice translations of the structure. Larger cells get the additional lattice translations as symmetry operators.
+            - The space group symbols and number and the Wyckoff symbols describe the original setting and are set to `?`, so only the symmetry operators describe the new one. `conventional` keeps them, as the result is the setting of the symbol, and so does P1 in a cell of the same volume. In the structured form the setting is given as `to`, e.g. `{{ operation = \"transform\", to = \"niggli\" }}`, or the matrix as `matrix`.
+
+            cif-modder -c path/to/cif -i \"rattle 0.05\" --seed 42
+            cif-modder -c path/to/cif -i \"expand; rattle sigma=0.02 elements=O,Ti labels=O1*\"
+
+            - `rattle 0.05` moves every atom site by 0.05 Å in a random direction. `sigma=0.02` draws every Cartesian component of the displacement from a normal distribution with a standard deviation of 0.02 Å instead. The displacements are converted to fractional shifts with the cell.
+            - `elements=O,Ti` and `labels=O1*` restrict the displaced sites. `*` matches any sequence of characters.
+            - `keep special` keeps sites on special positions fixed. Displacing such a site in a structure with symmetry separates its symmetry images, so a warning is logged unless the structure is expanded to P1 first.
+            - The displacements use the same random numbers as random instructions, so `--seed` makes them reproducible. In the structured form the options are `amplitude`, `sigma`, `elements`, `labels` and `keep_special`.
+
+            cif-modder -c path/to/cif -i \"substitute Ti -> Zr\"
+            cif-modder -c path/to/cif -i \"dope Ti -> Zr 0.1\"
+            cif-modder -c path/to/cif -i \"supercell 2 2 2; dope Ti -> Nb5+ 0.125 ordered\" --seed 42
+
+            - `substitute Ti -> Zr` replaces the element on every site, `labels=Ti1` only on the sites with matching labels. Labels and type symbols follow the new element, e.g. `Ti1` becomes `Zr1` and `Ti4+` becomes `Zr4+`. A charge such as `Nb5+` sets the type symbol and the oxidation number.
+            - `dope Ti -> Zr 0.1` splits every Ti site into a Ti site with 90 % and a Zr site with 10 % of its occupancy at the same position.
+            - `ordered` fully substitutes randomly chosen sites instead, e.g. 1 of the 8 Ti sites of a 2×2×2 supercell for 0.125. Structures with symmetry are expanded to P1 first.
+            - The `_atom_type_` loop, the labels of the `_atom_site_aniso_` loop, `_chemical_formula_sum` and `_chemical_formula_structural` are updated. `_chemical_formula_weight` is not, so a warning is logged. In the structured form the options are `from`, `to`, `fraction`, `labels` and `ordered`.
+
+            cif-modder -c path/to/cif -i \"vacancy O1\"
+            cif-modder -c path/to/cif -i \"supercell 2 2 2; vacancy O count=1\" --configurations 10 --seed 42
+            cif-modder -c path/to/cif -i \"vacancy O 0.05; interstitial Li+ 0.5 0 0 occupancy=0.5\"
+
+            - `vacancy O1` removes the sites labelled `O1` with their symmetry images. Selectors that are elements or ions, e.g. `O` or `O2-`, select by element, others by label. `*` matches any sequence of characters and `O1,O2` selects several.
+            - `vacancy O 0.05` removes 5 % of the selected sites, `count=1` a number of them. The sites are chosen at random, so structures with symmetry are expanded to P1 first.
+            - `interstitial Li 0.25 0.25 0.5` adds a site at a fractional position with all its symmetry images. `label=Li9` and `occupancy=0.5` are optional, the label defaults to `Li_i`. A warning is logged if the new site is closer than 1 Å to another atom.
+            - The `_atom_type_` loop and `_chemical_formula_sum` follow the atom sites, `_cell_formula_units_Z` stays the number of formula units. In the structured form the options are `sites`, `fraction` and `count` for vacancies and `species`, `position`, `label` and `occupancy` for interstitials.
+            - `--configurations 10` writes ten configurations of every file to `_modified_1.cif` … `_modified_10.cif`, each with its own random numbers. With `--stdout` they are written one after another with the block names `data_<name>_config1` … `data_<name>_config10`.
+
+            cif-modder -c path/to/cif -i \"adp scale 1.2\"
+            cif-modder -c path/to/cif -i \"adp to B\"
+            cif-modder -c path/to/cif -i \"adp ueq\"
+
+            - `adp scale 1.2` multiplies every isotropic and anisotropic displacement parameter, e.g. for temperature-scaling experiments. The values keep their decimal places.
+            - `adp to B` converts `_atom_site_U_iso_or_equiv` and `_atom_site_aniso_U_11` … `_U_23` to B = 8π² U and renames the tags and the `Uani`/`Uiso` values of `_atom_site_adp_type`. `adp to U` converts back. Converted values keep at least three significant digits.
+            - `adp ueq` writes Ueq = ⅓ Σ Uij ai* aj* ai·aj of every site with an anisotropic tensor to `_atom_site_U_iso_or_equiv`, or to `_atom_site_B_iso_or_equiv` if the file uses B.
+            - The anisotropic parameters follow changes of the cell: instructions for cell parameters keep the fractional components of the tensors, like the fractional coordinates of the atoms, and `transform` and `supercell` rotate them into the new axes. In the structured form the options are `scale`, `to` and `ueq = true`.
+
+            cif-modder -c path/to/cif -i \"0.3 -- _atom_site_occupancy[Zr1] -- 0.5 precision=2; occupancies sum=original\"
+            cif-modder -c path/to/cif -i \"occupancies sum=one tolerance=0.05\"
+
+            - `occupancies` constrains the occupancies of shared sites, i.e. atom sites closer than `tolerance` in Å (default 0.01) to each other or to a symmetry image of each other, e.g. the Ti and Zr of a doped site.
+            - `sum=original` (the default) keeps the sum of every shared site as it was before the instructions ran, `sum=one` makes every shared site fully occupied. Sums above 1 are reduced to 1, as is the occupancy of a single atom above 1.
+            - Occupancies edited by earlier instructions are kept and the unedited atoms of the site take up the rest. If all atoms were edited, all occupancies of the site are scaled.
+            - `_chemical_formula_sum` is recomputed from the new occupancies and `_chemical_formula_structural` follows it, or is set to `?` if an element is added or removed. In the structured form the options are `sum = \"one\"` or `sum = \"original\"` and `tolerance`.
+
+            cif-modder -c path/to/cif -i \"temperature 300 -> 800 with alpha_V = 3e-5\"
+            cif-modder -c path/to/cif -i \"temperature 800 with alpha_a = 1e-5 2e-9 alpha_c = 2.5e-5\"
+            cif-modder -c path/to/cif -i \"pressure 0 -> 5 with B0 = 160 B0_prime = 4.5\"
+
+            - `temperature 300 -> 800 with alpha_V = 3e-5` scales the cell from 300 K to 800 K with the volumetric expansion coefficient α_V in 1/K. The volume scales by exp(∫ α_V dT) and every axis by its cube root.
+            - `alpha = …` gives the linear expansion coefficient of all axes, `alpha_a`, `alpha_b` and `alpha_c` those of single axes. Axes without a coefficient keep their length. Several numbers are the coefficients of a polynomial α(T) = c0 + c1 T + c2 T² + ….
+            - `pressure 0 -> 5 with B0 = 160` scales the cell from 0 GPa to 5 GPa with the third-order Birch–Murnaghan equation of state with the bulk modulus `B0` in GPa and its pressure derivative `B0_prime`, which defaults to 4.
+            - Without a start value, the temperature is read from `_cell_measurement_temperature` or `_diffrn_ambient_temperature` and the pressure from `_cell_measurement_pressure` or `_diffrn_ambient_pressure`. A missing temperature is an error, a missing pressure is taken as 0 GPa with a warning. The new temperature in K and pressure in kPa are written to these tags. The angles and fractional coordinates are kept and `_cell_volume` is recomputed.
+            - In the structured form the options are `from`, `to`, `alpha_V`, `alpha`, `alpha_a`, `alpha_b` and `alpha_c` as a number or a list of polynomial coefficients, and `from`, `to`, `B0` and `B0_prime`.
+
+            cif-modder geometry path/to/cif --max-distance 2.5
+            cif-modder geometry path/to/cif -i \"rattle 0.05\" --seed 42
+            cif-modder geometry path/to/cif --format cif > geometry.cif
+
+            - `geometry` lists the distances between atoms up to `--max-distance`, 3 Å by default, and the angles between them, including symmetry equivalent and periodic images, and the shortest contact of every data block. Images are named by symmetry codes like `2_565`, the operator number followed by the translation plus 5, or `.` for the atom itself.
+            - With `-i`, the instructions are applied and only the distances and angles that changed are listed before and after, e.g. to check that random displacements did not create unphysically short contacts. `--preset`, `--evaluation`, `--safeguard` and `--dictionary` apply to them like when modifying files. Distances and angles are matched by the labels of the atoms, so instructions that relabel the atom sites, e.g. `expand` and `supercell`, are an error.
+            - `--format cif` writes `_geom_bond_` and `_geom_angle_` loops, `--format json` all values.
+
+            cif-modder -c path/to/cif -i \"min_distance 1.5; rattle 0.2\" --seed 42
+            cif-modder -c path/to/cif -i \"min_distance 1.5; min_distance O O 2.4; 3.9 -- a -- 4.1; rattle 0.1\"
+
+            - `min_distance 1.5` rejects results with two atoms closer than 1.5 Å, including symmetry equivalent and periodic images. `min_distance O O 2.4` sets the cutoff of a pair of elements, which takes precedence over the cutoff for all atoms. Atoms that share a site, e.g. of a mixed occupancy, are not checked.
+            - Programs with random steps, i.e. `--` ranges, `rattle`, `ordered` doping and random vacancies, are drawn again from the unmodified structure up to 100 times. Other programs and samples that still fail are errors, so no file with overlapping atoms is written.
+            - In the structured form the cutoffs are a `min_distances` list of entries with a `distance` and optional `elements`, e.g. `{{ elements = [\"O\", \"O\"], distance = 2.4 }}`.
+
+            cif-modder xrd path/to/cif > pattern.xy
+            cif-modder xrd path/to/cif -i \"a + 0.01\" --format peaks
+            cif-modder xrd path/to/cif --wavelength Mo --profile pseudo-voigt --fwhm 0.05 --max-two-theta 60
+
+            - `xrd` simulates the powder X-ray diffraction pattern of every data block from the cell, the symmetry, the atom sites, their occupancies and their `Uiso` or `Biso`, with the Cromer–Mann scattering factors of the neutral atoms. Intensities include the multiplicity and the Lorentz-polarisation factor and are scaled to 100 for the strongest peak.
+            - The default wavelength is Cu Kα1, 1.5406 Å. `--wavelength` takes a wavelength in Å or one of `Cu`, `Mo`, `Co`, `Fe`, `Cr` and `Ag`. The range is set by `--min-two-theta` and `--max-two-theta`, 5° to 90° by default.
+            - The output has two columns, 2θ and intensity, after a `#` comment line with the data block. Without `--profile` the columns are the peaks, with `--profile gaussian` or `--profile pseudo-voigt` a pattern with the width `--fwhm` and the step `--step` in degrees. `--eta` is the Lorentzian fraction of the pseudo-Voigt profile.
+            - With `-i`, the pattern of the modified structure follows the original one. `--preset`, `--evaluation`, `--safeguard` and `--dictionary` apply to the instructions like when modifying files. `--format peaks` lists the peaks with their indices, d-spacing, 2θ, intensity and multiplicity, `--format json` all values.
+
+            cif-modder reflections path/to/cif --min-d 1.5
+            cif-modder reflections path/to/cif -i \"a + 0.01\" --wavelength Mo --max-two-theta 40
+
+            - `reflections` lists the reflections that the cell and the symmetry operators allow, with their indices, d-spacing, 2θ and multiplicity. Only the cell and the symmetry are read, not the atom sites. A reflection is a systematic absence if an operator (R, t) with h R = h gives a non-integer h · t.
+            - The list runs from `--min-two-theta` to `--max-two-theta`, 5° to 90° by default, or down to the d-spacing `--min-d` in Å. The wavelength is set like for `xrd`.
+            - With `-i`, the reflections of the modified structure are listed with 2θ of the same indices in the original cell and the shift Δ2θ, e.g. to see how `a + 0.01` moves and splits the peaks. `--preset`, `--evaluation`, `--safeguard` and `--dictionary` apply to the instructions like when modifying files. `--format json` writes all values.
+
+            cif-modder validate path/to/cif path/to/cif_modified.cif
+            cif-modder validate path/to/directory --format json > report.json
+
+            - `validate` checks CIFs, archives and directories for structural problems and prints a report, like checkCIF. Run it before and after a modification batch to see which problems the modification introduced.
+            - Checks: stated vs computed cell volume, `_cell_formula_units_Z` vs the formula units implied by occupancy × multiplicity of the atom sites, `_chemical_formula_sum` vs the composition of the atom sites, duplicate atom site labels, missing mandatory tags and the number of symmetry operators vs the order of the space group.
+            - `--format json` writes the report as JSON. The exit code is nonzero if any file has errors.
+
+            [[instructions]]
+            tag = \"_cell_angle_alpha\"
+            operator = \"range\"            # add, subtract, multiply, divide, power, range or none
+            operands = [70.0, 120.0]
+            distribution = \"normal\"       # optional
+            rows = \"Ti*\"                  # optional, only for tags in loops
+            precision = 2                 # optional, a number of decimal places, \"keep\" or \"full\"
+
+            Recognized CIF tags include the cell, diffrn, exptl density, refine, atom_site, atom_site_aniso and geom tags of the core dictionary. Every tag can be given by its DDL1 name (`_cell_length_a`) or its DDLm name (`_cell.length_a`, `_chemical_formula.sum`) and matches the tag in the CIF under any of its names, e.g. `_symmetry_space_group_name_H-M` and `_space_group.name_H-M_alt`. `--list-tags` prints all known tags with their type, units and allowed range, `--list-tags _atom_site` only those starting with `_atom_site`.
 
             Short keywords:
-            `a`, `b`, `c`, `alpha`, `beta`, `gamma`, `volume`
+            `a`, `b`, `c`, `alpha`, `beta`, `gamma`, `volume`, `Z`, `x`, `y`, `z`, `occupancy`, `Uiso`, `Biso`
 
             List of all possible operators:
 
@@ -60,9 +353,179 @@ fn main() {
         std::process::exit(0);
     }
 
+    if let Some(Command::Validate { paths, format }) = &args.command {
+        std::process::exit(validate(paths, *format));
+    }
+
+    if let Some(Command::Geometry {
+        paths,
+        instructions,
+        max_distance,
+        format,
+        seed,
+    }) = &args.command
+    {
+        let instructions = match instructions
+            .as_deref()
+            .map(|instructions| build_instructions(instructions, &args))
+            .transpose()
+        {
+            Ok(instructions) => instructions,
+            Err(e) => {
+                log::error!("{:#}", e);
+                std::process::exit(1);
+            }
+        };
+
+        let seed = seed.unwrap_or_else(rand::random);
+
+        log::debug!("Seed: {}", seed);
+
+        std::process::exit(geometry(
+            paths,
+            instructions.as_ref(),
+            *max_distance,
+            *format,
+            seed,
+        ));
+    }
+
+    if let Some(Command::Xrd {
+        paths,
+        instructions,
+        wavelength,
+        min_two_theta,
+        max_two_theta,
+        profile,
+        fwhm,
+        eta,
+        step,
+        format,
+        seed,
+    }) = &args.command
+    {
+        let instructions = match instructions
+            .as_deref()
+            .map(|instructions| build_instructions(instructions, &args))
+            .transpose()
+        {
+            Ok(instructions) => instructions,
+            Err(e) => {
+                log::error!("{:#}", e);
+                std::process::exit(1);
+            }
+        };
+
+        let profile = match profile {
+            ProfileArgument::None => None,
+            ProfileArgument::Gaussian => Some(Profile::Gaussian),
+            ProfileArgument::PseudoVoigt => Some(Profile::PseudoVoigt(*eta)),
+        };
+
+        let settings = Diffraction::new(*wavelength)
+            .and_then(|diffraction| diffraction.with_range(*min_two_theta, *max_two_theta))
+            .and_then(|diffraction| {
+                let broadening = profile
+                    .map(|profile| Broadening::new(profile, *fwhm, *step))
+                    .transpose()?;
+
+                Ok((diffraction, broadening))
+            });
+
+        let (diffraction, broadening) = match settings {
+            Ok(settings) => settings,
+            Err(e) => {
+                log::error!("{:#}", e);
+                std::process::exit(1);
+            }
+        };
+
+        let seed = seed.unwrap_or_else(rand::random);
+
+        log::debug!("Seed: {}", seed);
+
+        std::process::exit(xrd(
+            paths,
+            instructions.as_ref(),
+            &diffraction,
+            broadening.as_ref(),
+            *format,
+            seed,
+        ));
+    }
+
+    if let Some(Command::Reflections {
+        paths,
+        instructions,
+        wavelength,
+        min_two_theta,
+        max_two_theta,
+        min_d,
+        format,
+        seed,
+    }) = &args.command
+    {
+        let instructions = match instructions
+            .as_deref()
+            .map(|instructions| build_instructions(instructions, &args))
+            .transpose()
+        {
+            Ok(instructions) => instructions,
+            Err(e) => {
+                log::error!("{:#}", e);
+                std::process::exit(1);
+            }
+        };
+
+        let diffraction = Diffraction::new(*wavelength)
+            .and_then(|diffraction| diffraction.with_range(*min_two_theta, *max_two_theta))
+            .and_then(|diffraction| match min_d {
+                Some(min_d) => diffraction.with_min_d_spacing(*min_d),
+                None => Ok(diffraction),
+            });
+
+        let diffraction = match diffraction {
+            Ok(diffraction) => diffraction,
+            Err(e) => {
+                log::error!("{:#}", e);
+                std::process::exit(1);
+            }
+        };
+
+        let seed = seed.unwrap_or_else(rand::random);
+
+        log::debug!("Seed: {}", seed);
+
+        std::process::exit(reflections(
+            paths,
+            instructions.as_ref(),
+            &diffraction,
+            *format,
+            seed,
+        ));
+    }
+
+    if let Some(prefix) = &args.list_tags {
+        let registry = cif_modder::registry();
+
+        let completions = registry.completions(prefix);
+
+        for definition in registry.definitions() {
+            if definition
+                .names()
+                .chain(definition.short_name)
+                .any(|name| completions.contains(&name))
+            {
+                println!("{}", definition);
+            }
+        }
+
+        std::process::exit(0);
+    }
+
     log::debug!("{:#?}", args);
 
-    let instructions = match args.instructions {
+    let instructions = match &args.instructions {
         Some(instructions) => instructions,
         None => {
             log::error!("Error: No instructions provided.");
@@ -70,6 +533,34 @@ fn main() {
         }
     };
 
+    let instructions = match build_instructions(instructions, &args) {
+        Ok(instructions) => instructions,
+        Err(e) => {
+            log::error!("{:#}", e);
+            std::process::exit(1);
+        }
+    };
+
+    log::debug!("Instructions: {:#?}", instructions);
+
+    if let Some(format) = args.print_instructions {
+        let printed = match format {
+            InstructionFormat::Text => Ok(instructions.to_string()),
+            InstructionFormat::Toml => instructions.to_toml(),
+            InstructionFormat::Json => instructions.to_json(),
+        };
+
+        match printed {
+            Ok(printed) => print!("{}", printed),
+            Err(e) => {
+                log::error!("{:#}", e);
+                std::process::exit(1);
+            }
+        }
+
+        return;
+    }
+
     let path = match args.cif {
         Some(path) => path,
         None => {
@@ -78,30 +569,26 @@ fn main() {
         }
     };
 
-    let is_instructions_a_file = std::fs::metadata(&instructions).is_ok();
+    let seed = args.seed.unwrap_or_else(rand::random);
 
-    log::debug!("is_instructions_file: {}", is_instructions_a_file);
+    log::debug!("Seed: {}", seed);
 
-    let instructions = match is_instructions_a_file {
-        true => {
-            let instructions = match cif_modder::Instructions::from_file(&instructions) {
-                Ok(instructions) => instructions,
-                Err(e) => {
-                    log::error!("{:#}", e);
-                    std::process::exit(1);
-                }
-            };
-            instructions
-        }
-        false => {
-            let instructions = cif_modder::Instructions::from_string(&instructions);
-            instructions
+    let configurations = args.configurations as usize;
+
+    if path == STDIN_PATH {
+        let mut stdout = std::io::stdout().lock();
+
+        if let Err(e) = process_stdin(&mut stdout, &instructions, seed, configurations)
+            .context("Could not apply instructions to CIF from stdin.")
+        {
+            log::error!("{:#}", e);
+            std::process::exit(1);
         }
-    };
 
-    log::debug!("Instructions: {:#?}", instructions);
+        return;
+    }
 
-    let paths: Vec<String> = match std::fs::metadata(&path) {
+    let mut paths: Vec<String> = match std::fs::metadata(&path) {
         Ok(metadata) => {
             if metadata.is_dir() {
                 match directory_content_from_path(&path) {
@@ -121,35 +608,647 @@ fn main() {
         }
     }
     .into_iter()
-    .filter(|path| path.ends_with(".cif") && !path.contains("_modified.cif"))
+    .filter(|path| is_input_path(path))
     .collect();
 
+    paths.sort();
+
     log::debug!("Paths: {:#?}", paths);
 
-    for path in paths {
-        let new_lines =
-            match cif_modder::apply_instructions_to_cif_file(&path, instructions.clone())
-                .context("Could not apply instructions to CIF file.")
-            {
-                Ok(new_lines) => new_lines,
-                Err(e) => {
-                    log::error!("{:#}", e);
-                    std::process::exit(1);
+    let pool = match rayon::ThreadPoolBuilder::new()
+        .num_threads(args.jobs)
+        .build()
+        .context("Could not create thread pool.")
+    {
+        Ok(pool) => pool,
+        Err(e) => {
+            log::error!("{:#}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let keep_going = args.keep_going;
+    let is_stdout = args.stdout;
+    // The index of the first file that failed so far. Without --keep-going, the files after it are
+    // skipped and do not write their outputs.
+    let first_failure = AtomicUsize::new(usize::MAX);
+
+    let mut failures = Vec::new();
+
+    // Results arrive in the order the threads finish them and are handled in input order: each
+    // result waits until all earlier files are done, then its log messages are written and its
+    // output goes to stdout right away.
+    let (sender, receiver) = std::sync::mpsc::channel();
+
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            pool.install(|| {
+                paths
+                    .par_iter()
+                    .enumerate()
+                    .for_each_with(sender, |sender, (index, path)| {
+                        let is_aborted =
+                            || !keep_going && first_failure.load(Ordering::Relaxed) < index;
+
+                        let (result, records) = match is_aborted() {
+                            true => (None, Vec::new()),
+                            false => {
+                                let (result, records) = with_current_path(path, || {
+                                    process_file(
+                                        path,
+                                        &instructions,
+                                        seed,
+                                        is_stdout,
+                                        configurations,
+                                        &is_aborted,
+                                    )
+                                });
+
+                                match result {
+                                    Err(e) if e.is::<Aborted>() => (None, records),
+                                    result => (Some(result), records),
+                                }
+                            }
+                        };
+
+                        if result.as_ref().is_some_and(Result::is_err) {
+                            first_failure.fetch_min(index, Ordering::Relaxed);
+                        }
+
+                        let _ = sender.send((index, (result, records)));
+                    })
+            })
+        });
+
+        let mut stdout = std::io::stdout().lock();
+
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+
+        for (index, result) in receiver {
+            pending.insert(index, result);
+
+            while let Some((result, records)) = pending.remove(&next) {
+                let path = &paths[next];
+                next += 1;
+
+                // Without --keep-going, nothing after the first failure is written to stdout or
+                // reported.
+                if !keep_going && !failures.is_empty() {
+                    continue;
                 }
-            };
 
-        let new_path = path.replace(".cif", "_modified.cif");
+                for record in records {
+                    record.log();
+                }
 
-        match std::fs::write(&new_path, new_lines.join("\n"))
-            .context("Could not write modified CIF file.")
-        {
-            Ok(_) => (),
+                match result {
+                    Some(Ok(Output::Files(new_paths))) => {
+                        for new_path in new_paths {
+                            log::debug!("Wrote to {}", new_path);
+                        }
+                    }
+                    Some(Ok(Output::Buffer(buffer))) => {
+                        if let Err(e) = stdout.write_all(&buffer).and_then(|_| stdout.flush()) {
+                            log::error!("Could not write to stdout: {:#}", e);
+                            std::process::exit(1);
+                        }
+                    }
+                    Some(Err(e)) => {
+                        log::error!("{}: {:#}", path, e);
+
+                        failures.push(path);
+                    }
+                    None => (),
+                }
+            }
+        }
+    });
+
+    if !keep_going && !failures.is_empty() {
+        std::process::exit(1);
+    }
+
+    if !failures.is_empty() {
+        log::error!(
+            "Failed to process {} of {} files:",
+            failures.len(),
+            paths.len()
+        );
+
+        for path in failures {
+            log::error!("  {}", path);
+        }
+
+        std::process::exit(1);
+    }
+}
+
+/// Validates CIFs, archives and directories and prints the reports to stdout. Returns the exit code,
+/// which is nonzero if a file could not be read or has errors.
+fn validate(paths: &[String], format: ReportFormat) -> i32 {
+    let mut reports = Vec::new();
+    let mut is_failed = false;
+
+    for path in paths {
+        let result = if path == STDIN_PATH {
+            cif_modder::validate_reader(STDIN_PATH, std::io::stdin().lock()).map(|r| vec![r])
+        } else {
+            validation_reports(path)
+        };
+
+        match result {
+            Ok(file_reports) => reports.extend(file_reports),
+            Err(e) => {
+                log::error!("{}: {:#}", path, e);
+                is_failed = true;
+            }
+        }
+    }
+
+    match format {
+        ReportFormat::Text => reports.iter().for_each(|report| print!("{}", report)),
+        ReportFormat::Json => match serde_json::to_string_pretty(&reports) {
+            Ok(json) => println!("{}", json),
             Err(e) => {
                 log::error!("{:#}", e);
-                std::process::exit(1);
+                return 1;
             }
+        },
+    }
+
+    match is_failed || reports.iter().any(FileReport::has_errors) {
+        true => 1,
+        false => 0,
+    }
+}
+
+fn validation_reports(path: &str) -> anyhow::Result<Vec<FileReport>> {
+    if std::fs::metadata(path)?.is_dir() {
+        let mut paths: Vec<String> = directory_content_from_path(path)?
+            .into_iter()
+            .filter(|path| is_input_path(path))
+            .collect();
+
+        paths.sort();
+
+        let mut reports = Vec::new();
+
+        for path in paths {
+            reports.extend(validation_reports(&path).with_context(|| path.clone())?);
         }
 
-        log::debug!("Wrote to {}", new_path);
+        return Ok(reports);
+    }
+
+    if ArchiveFormat::from_path(path).is_some() {
+        let mut reports = Vec::new();
+
+        cif_modder::for_each_cif_in_archive(path, |name, reader| {
+            reports.push(cif_modder::validate_reader(
+                &format!("{}/{}", path, name),
+                reader,
+            )?);
+
+            Ok(())
+        })?;
+
+        return Ok(reports);
+    }
+
+    Ok(vec![cif_modder::validate_reader(path, File::open(path)?)?])
+}
+
+/// Reads instructions from a file if `instructions` is a path, otherwise from the string, and applies
+/// the presets, evaluation, safeguard and dictionary given in `args`.
+fn build_instructions(instructions: &str, args: &Args) -> anyhow::Result<Instructions> {
+    let is_instructions_a_file = std::fs::metadata(instructions).is_ok();
+
+    log::debug!("is_instructions_file: {}", is_instructions_a_file);
+
+    let instructions = match is_instructions_a_file {
+        true => Instructions::from_file_with_presets(instructions, &args.preset)?,
+        false => {
+            if !args.preset.is_empty() {
+                anyhow::bail!("Presets can only be used with an instruction file.");
+            }
+
+            Instructions::from_string(instructions)?
+        }
+    };
+
+    let instructions = match args.evaluation {
+        Some(evaluation) => instructions.with_evaluation(evaluation),
+        None => instructions,
+    };
+
+    let instructions = match args.safeguard {
+        Some(safeguard) => instructions.with_safeguard(safeguard),
+        None => instructions,
+    };
+
+    match &args.dictionary {
+        Some(path) => instructions.with_dictionary(std::sync::Arc::new(
+            cif_modder::Dictionary::from_file(path)?,
+        )),
+        None => Ok(instructions),
+    }
+}
+
+/// Computes the geometry of CIFs, archives and directories and prints it to stdout. Returns the exit
+/// code, which is nonzero if a file could not be read.
+fn geometry(
+    paths: &[String],
+    instructions: Option<&Instructions>,
+    max_distance: f64,
+    format: GeometryFormat,
+    seed: u64,
+) -> i32 {
+    let (geometries, is_failed) = read_cifs(paths, seed, |path, reader| {
+        cif_modder::geometry_of_reader(path, reader, max_distance, instructions)
+    });
+
+    match format {
+        GeometryFormat::Text => geometries
+            .iter()
+            .for_each(|geometry| print!("{}", geometry)),
+        GeometryFormat::Json => match serde_json::to_string_pretty(&geometries) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                log::error!("{:#}", e);
+                return 1;
+            }
+        },
+        GeometryFormat::Cif => geometries
+            .iter()
+            .for_each(|geometry| print!("{}", geometry.to_cif())),
+    }
+
+    match is_failed {
+        true => 1,
+        false => 0,
+    }
+}
+
+/// Simulates the powder patterns of CIFs, archives and directories and prints them to stdout.
+/// Returns the exit code, which is nonzero if a file could not be read.
+fn xrd(
+    paths: &[String],
+    instructions: Option<&Instructions>,
+    diffraction: &Diffraction,
+    broadening: Option<&Broadening>,
+    format: DiffractionFormat,
+    seed: u64,
+) -> i32 {
+    let (diffractions, is_failed) = read_cifs(paths, seed, |path, reader| {
+        cif_modder::diffraction_of_reader(path, reader, diffraction, instructions)
+    });
+
+    match format {
+        DiffractionFormat::Text => diffractions
+            .iter()
+            .for_each(|file| print!("{}", file.to_columns(diffraction, broadening))),
+        DiffractionFormat::Peaks => diffractions.iter().for_each(|file| print!("{}", file)),
+        DiffractionFormat::Json => match serde_json::to_string_pretty(&diffractions) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                log::error!("{:#}", e);
+                return 1;
+            }
+        },
+    }
+
+    match is_failed {
+        true => 1,
+        false => 0,
+    }
+}
+
+/// Lists the allowed reflections of CIFs, archives and directories on stdout. Returns the exit
+/// code, which is nonzero if a file could not be read.
+fn reflections(
+    paths: &[String],
+    instructions: Option<&Instructions>,
+    diffraction: &Diffraction,
+    format: ReportFormat,
+    seed: u64,
+) -> i32 {
+    let (reflections, is_failed) = read_cifs(paths, seed, |path, reader| {
+        cif_modder::reflections_of_reader(path, reader, diffraction, instructions)
+    });
+
+    match format {
+        ReportFormat::Text => reflections.iter().for_each(|file| print!("{}", file)),
+        ReportFormat::Json => match serde_json::to_string_pretty(&reflections) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                log::error!("{:#}", e);
+                return 1;
+            }
+        },
+    }
+
+    match is_failed {
+        true => 1,
+        false => 0,
+    }
+}
+
+/// Reads every CIF of the paths, which may be files, archives, directories or `-` for stdin, with
+/// `read`. The random number generator is reseeded from `seed` and the path of every CIF, like when
+/// modifying files. Returns the results and whether a path failed.
+fn read_cifs<T>(
+    paths: &[String],
+    seed: u64,
+    mut read: impl FnMut(&str, &mut dyn Read) -> anyhow::Result<T>,
+) -> (Vec<T>, bool) {
+    let mut results = Vec::new();
+    let mut is_failed = false;
+
+    for path in paths {
+        let result = if path == STDIN_PATH {
+            cif_modder::seed_rng(cif_modder::derive_seed(seed, STDIN_PATH));
+
+            read(STDIN_PATH, &mut std::io::stdin().lock()).map(|result| vec![result])
+        } else {
+            read_path(path, seed, &mut read)
+        };
+
+        match result {
+            Ok(path_results) => results.extend(path_results),
+            Err(e) => {
+                log::error!("{}: {:#}", path, e);
+                is_failed = true;
+            }
+        }
+    }
+
+    (results, is_failed)
+}
+
+fn read_path<T>(
+    path: &str,
+    seed: u64,
+    read: &mut impl FnMut(&str, &mut dyn Read) -> anyhow::Result<T>,
+) -> anyhow::Result<Vec<T>> {
+    if std::fs::metadata(path)?.is_dir() {
+        let mut paths: Vec<String> = directory_content_from_path(path)?
+            .into_iter()
+            .filter(|path| is_input_path(path))
+            .collect();
+
+        paths.sort();
+
+        let mut results = Vec::new();
+
+        for path in paths {
+            results.extend(read_path(&path, seed, read).with_context(|| path.clone())?);
+        }
+
+        return Ok(results);
+    }
+
+    let seed = cif_modder::derive_seed(seed, path);
+
+    if ArchiveFormat::from_path(path).is_some() {
+        let mut results = Vec::new();
+
+        cif_modder::for_each_cif_in_archive(path, |name, reader| {
+            cif_modder::seed_rng(cif_modder::derive_seed(seed, name));
+
+            results.push(read(&format!("{}/{}", path, name), reader)?);
+
+            Ok(())
+        })?;
+
+        return Ok(results);
+    }
+
+    cif_modder::seed_rng(seed);
+
+    Ok(vec![read(path, &mut File::open(path)?)?])
+}
+
+/// Where the modified CIFs of a single input file ended up.
+enum Output {
+    /// Paths of the written modified files or archives, one per configuration.
+    Files(Vec<String>),
+    /// Modified CIFs to be written to stdout as soon as all preceding files are done.
+    Buffer(Vec<u8>),
+}
+
+/// The seed of a configuration of a file. A single configuration uses the seed of the file, so
+/// results do not change with the number of configurations.
+fn configuration_seed(seed: u64, configuration: usize, configurations: usize) -> u64 {
+    match configurations {
+        1 => seed,
+        _ => cif_modder::derive_seed(seed, &configuration.to_string()),
+    }
+}
+
+/// Applies the instructions to a CIF for stdout. With several configurations, the block names get
+/// the number of the configuration, so the blocks written one after another stay distinct.
+fn write_configuration(
+    reader: impl Read,
+    writer: impl Write,
+    instructions: &cif_modder::Instructions,
+    configuration: usize,
+    configurations: usize,
+) -> anyhow::Result<usize> {
+    match configurations {
+        1 => cif_modder::apply_instructions(reader, writer, instructions),
+        _ => cif_modder::apply_instructions_to_configuration(
+            reader,
+            writer,
+            instructions,
+            configuration,
+        ),
+    }
+}
+
+/// Applies the instructions to the CIF from stdin once per configuration and writes the results to
+/// `writer` one after another.
+fn process_stdin(
+    writer: &mut impl Write,
+    instructions: &cif_modder::Instructions,
+    seed: u64,
+    configurations: usize,
+) -> anyhow::Result<()> {
+    let (_, mut reader) = cif_modder::decompress(std::io::stdin().lock())?;
+
+    let mut content = Vec::new();
+    reader.read_to_end(&mut content)?;
+
+    let seed = cif_modder::derive_seed(seed, STDIN_PATH);
+
+    for configuration in 1..=configurations {
+        cif_modder::seed_rng(configuration_seed(seed, configuration, configurations));
+
+        write_configuration(
+            content.as_slice(),
+            &mut *writer,
+            instructions,
+            configuration,
+            configurations,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Applies the instructions to a single file or archive once per configuration and writes the
+/// results next to it or into a buffer for stdout.
+///
+/// The random number generator is reseeded from `seed`, the path and the configuration, so the
+/// result does not depend on which thread processes the file or in which order.
+fn process_file(
+    path: &str,
+    instructions: &cif_modder::Instructions,
+    seed: u64,
+    is_stdout: bool,
+    configurations: usize,
+    is_aborted: &dyn Fn() -> bool,
+) -> anyhow::Result<Output> {
+    let seed = cif_modder::derive_seed(seed, path);
+
+    let mut new_paths = Vec::new();
+    let mut buffer = Vec::new();
+
+    for configuration in 1..=configurations {
+        let seed = configuration_seed(seed, configuration, configurations);
+
+        let new_path = match configurations {
+            1 => modified_path(path),
+            _ => configuration_path(path, configuration),
+        };
+
+        let new_path = match is_stdout {
+            true => None,
+            false => Some(new_path),
+        };
+
+        let target = match &new_path {
+            Some(new_path) => Target::File(new_path),
+            None => Target::Buffer(&mut buffer, configuration, configurations),
+        };
+
+        match ArchiveFormat::from_path(path) {
+            Some(_) => process_archive(path, target, instructions, seed, is_aborted)?,
+            None => {
+                cif_modder::seed_rng(seed);
+                process_cif(path, target, instructions, is_aborted)?
+            }
+        }
+
+        new_paths.extend(new_path);
+    }
+
+    match is_stdout {
+        true => Ok(Output::Buffer(buffer)),
+        false => Ok(Output::Files(new_paths)),
+    }
+}
+
+/// Where the modified CIFs of a configuration are written.
+enum Target<'a> {
+    /// A modified file or archive at the path.
+    File(&'a str),
+    /// The buffer for stdout, with the configuration and the number of configurations.
+    Buffer(&'a mut Vec<u8>, usize, usize),
+}
+
+/// Applies the instructions to a single CIF and writes the result to the target.
+fn process_cif(
+    path: &str,
+    target: Target,
+    instructions: &cif_modder::Instructions,
+    is_aborted: &dyn Fn() -> bool,
+) -> anyhow::Result<()> {
+    let file = File::open(path).context("Could not open CIF file.")?;
+
+    let new_path = match target {
+        Target::File(new_path) => new_path,
+        Target::Buffer(buffer, configuration, configurations) => {
+            let (_, reader) = cif_modder::decompress(file)?;
+
+            write_configuration(reader, buffer, instructions, configuration, configurations)
+                .context("Could not apply instructions to CIF file.")?;
+
+            return Ok(());
+        }
+    };
+
+    cif_modder::write_atomically(new_path, |temporary_path| {
+        let new_file =
+            File::create(temporary_path).context("Could not create modified CIF file.")?;
+
+        cif_modder::apply_instructions_to_compressed(file, BufWriter::new(new_file), instructions)
+            .context("Could not apply instructions to CIF file.")?;
+
+        check_aborted(is_aborted)
+    })?;
+
+    Ok(())
+}
+
+fn process_archive(
+    path: &str,
+    target: Target,
+    instructions: &cif_modder::Instructions,
+    seed: u64,
+    is_aborted: &dyn Fn() -> bool,
+) -> anyhow::Result<()> {
+    let new_path = match target {
+        Target::File(new_path) => new_path,
+        Target::Buffer(buffer, configuration, configurations) => {
+            cif_modder::for_each_cif_in_archive(path, |name, reader| {
+                cif_modder::seed_rng(cif_modder::derive_seed(seed, name));
+
+                let (_, reader) = cif_modder::decompress(reader)?;
+
+                write_configuration(
+                    reader,
+                    &mut *buffer,
+                    instructions,
+                    configuration,
+                    configurations,
+                )?;
+
+                Ok(())
+            })
+            .context("Could not apply instructions to archive.")?;
+
+            return Ok(());
+        }
+    };
+
+    cif_modder::write_atomically(new_path, |temporary_path| {
+        cif_modder::apply_instructions_to_archive(path, temporary_path, instructions, seed)
+            .context("Could not apply instructions to archive.")?;
+
+        check_aborted(is_aborted)
+    })?;
+
+    Ok(())
+}
+
+/// The error of a file whose output is not written because an earlier file failed.
+#[derive(Debug)]
+struct Aborted;
+
+impl std::fmt::Display for Aborted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "An earlier file failed")
+    }
+}
+
+impl std::error::Error for Aborted {}
+
+/// Fails with `Aborted` right before an output is renamed into place if an earlier file failed in
+/// the meantime.
+fn check_aborted(is_aborted: &dyn Fn() -> bool) -> anyhow::Result<()> {
+    match is_aborted() {
+        true => Err(Aborted.into()),
+        false => Ok(()),
     }
 }
@@ -0,0 +1,465 @@
+//! Occupancy constraints of sites shared by several atoms, e.g. Ti and Zr at the same position.
+//!
+//! After occupancies were edited or drawn at random, the atoms of a shared site must not sum to
+//! more than one. [`OccupancyConstraint`] finds the shared sites, rescales their occupancies and
+//! recomputes `_chemical_formula_sum`.
+
+use std::{fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    composition,
+    structure::{self, AtomSites},
+    symmetry, DataBlock, Lattice,
+};
+
+const SUM_OPTION: &str = "sum";
+const TOLERANCE_OPTION: &str = "tolerance";
+
+/// Occupancies closer than this are equal.
+const OCCUPANCY_TOLERANCE: f64 = 1e-6;
+
+/// What the occupancies of the atoms of a shared site add up to.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OccupancySum {
+    /// The shared site is fully occupied.
+    One,
+    /// The sum of the occupancies before the program ran, at most one.
+    #[default]
+    Original,
+}
+
+impl FromStr for OccupancySum {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "one" => Ok(OccupancySum::One),
+            "original" => Ok(OccupancySum::Original),
+            _ => Err(anyhow::anyhow!("{} is neither one nor original", s)),
+        }
+    }
+}
+
+impl Display for OccupancySum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OccupancySum::One => write!(f, "one"),
+            OccupancySum::Original => write!(f, "original"),
+        }
+    }
+}
+
+/// Rescales the occupancies of shared sites to a constrained sum.
+///
+/// Atom sites closer than the tolerance in Å, including their symmetry images, share a site. Sites
+/// whose occupancy was edited keep it and the unedited atoms of the shared site take up the rest.
+/// If every atom was edited or the rest is negative, all occupancies are scaled. A single atom on a
+/// site keeps its occupancy unless it is above one.
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OccupancyConstraint {
+    #[serde(default)]
+    sum: OccupancySum,
+    #[serde(default = "default_tolerance")]
+    tolerance: f64,
+}
+
+fn default_tolerance() -> f64 {
+    symmetry::DEFAULT_TOLERANCE
+}
+
+impl Default for OccupancyConstraint {
+    fn default() -> Self {
+        OccupancyConstraint {
+            sum: OccupancySum::default(),
+            tolerance: symmetry::DEFAULT_TOLERANCE,
+        }
+    }
+}
+
+impl OccupancyConstraint {
+    pub fn new(sum: OccupancySum) -> Self {
+        OccupancyConstraint {
+            sum,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_tolerance(mut self, tolerance: f64) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Applies the constraint to a data block. `original` is the block before the program ran, it
+    /// tells edited from unedited occupancies and gives the original sums and formula. Returns the
+    /// number of modified values.
+    pub(crate) fn apply_to_block(
+        &self,
+        block: &mut DataBlock,
+        original: Option<&DataBlock>,
+    ) -> anyhow::Result<usize> {
+        if block.find_loop(structure::LABEL_TAG).is_none() {
+            return Ok(0);
+        }
+
+        let block_name = block.name().unwrap_or_default().to_string();
+
+        let original_sites = original
+            .filter(|original| original.find_loop(structure::LABEL_TAG).is_some())
+            .map(AtomSites::from_block)
+            .transpose()?;
+
+        let mut sites = AtomSites::from_block(block)?;
+
+        let original_occupancy = |label: &str| -> Option<f64> {
+            let original_sites = original_sites.as_ref()?;
+
+            (0..original_sites.len())
+                .find(|row| original_sites.label(*row) == label)
+                .map(|row| original_sites.occupancy(row))
+        };
+
+        let mut occupancies: Vec<f64> = (0..sites.len()).map(|row| sites.occupancy(row)).collect();
+
+        for group in self.shared_sites(block, &sites)? {
+            let labels: Vec<&str> = group.iter().map(|row| sites.label(*row)).collect();
+            let sum: f64 = group.iter().map(|row| occupancies[*row]).sum();
+
+            if group.len() == 1 {
+                if sum > 1.0 + OCCUPANCY_TOLERANCE {
+                    log::warn!(
+                        "The occupancy {} of {} in data_{} is reduced to 1",
+                        sum,
+                        labels[0],
+                        block_name
+                    );
+                    occupancies[group[0]] = 1.0;
+                }
+                continue;
+            }
+
+            let originals: Vec<Option<f64>> = labels
+                .iter()
+                .map(|label| original_occupancy(label))
+                .collect();
+
+            let target = match self.sum {
+                OccupancySum::One => 1.0,
+                OccupancySum::Original if originals.iter().all(Option::is_none) => sum,
+                OccupancySum::Original => originals.iter().flatten().sum(),
+            };
+
+            if target > 1.0 + OCCUPANCY_TOLERANCE {
+                log::warn!(
+                    "The occupancies of {} in data_{} sum to {}, they are scaled to 1",
+                    labels.join(", "),
+                    block_name,
+                    target
+                );
+            }
+
+            let target = target.min(1.0);
+
+            if (sum - target).abs() <= OCCUPANCY_TOLERANCE || sum <= 0.0 {
+                continue;
+            }
+
+            let is_edited = |index: usize| {
+                originals[index].is_none_or(|original| {
+                    (original - occupancies[group[index]]).abs() > OCCUPANCY_TOLERANCE
+                })
+            };
+
+            let edited_sum: f64 = (0..group.len())
+                .filter(|index| is_edited(*index))
+                .map(|index| occupancies[group[index]])
+                .sum();
+            let unedited_sum = sum - edited_sum;
+
+            let unedited: Vec<usize> = (0..group.len())
+                .filter(|index| !is_edited(*index))
+                .collect();
+
+            let (scaled, factor) = match target - edited_sum {
+                rest if rest >= 0.0 && unedited_sum > 0.0 => (unedited, rest / unedited_sum),
+                _ => ((0..group.len()).collect(), target / sum),
+            };
+
+            for index in &scaled {
+                occupancies[group[*index]] *= factor;
+            }
+
+            log::info!(
+                "Scaled the occupancies of {} so that {} in data_{} sum to {}",
+                scaled
+                    .iter()
+                    .map(|index| labels[*index])
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                labels.join(", "),
+                block_name,
+                structure::format_occupancy(target)
+            );
+        }
+
+        let mut modified_values_counter = 0;
+
+        for (row, occupancy) in occupancies.into_iter().enumerate() {
+            if (occupancy - sites.occupancy(row)).abs() <= OCCUPANCY_TOLERANCE {
+                continue;
+            }
+
+            if sites.column(structure::OCCUPANCY_TAG).is_none() {
+                sites.push_column(structure::OCCUPANCY_TAG, "1");
+            }
+
+            sites.set_value(
+                row,
+                structure::OCCUPANCY_TAG,
+                &structure::format_occupancy(occupancy),
+            );
+            modified_values_counter += 1;
+        }
+
+        if modified_values_counter > 0 {
+            sites.write_to(block);
+        }
+
+        if let Some(original) = original {
+            modified_values_counter += composition::recompute_formula_sum(block, original)?;
+        }
+
+        Ok(modified_values_counter)
+    }
+
+    /// Groups the rows of the atom sites into shared sites. Two rows share a site if one of them is
+    /// closer than the tolerance to a symmetry image of the other.
+    fn shared_sites(
+        &self,
+        block: &DataBlock,
+        sites: &AtomSites,
+    ) -> anyhow::Result<Vec<Vec<usize>>> {
+        let lattice = Lattice::from_block(block)?;
+        let operators = symmetry::symmetry_operators(block)?;
+
+        let positions = (0..sites.len())
+            .map(|row| sites.position(row))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let mut groups: Vec<Vec<usize>> = Vec::new();
+
+        for (row, position) in positions.iter().enumerate() {
+            let images = match operators.is_empty() {
+                true => vec![*position],
+                false => symmetry::images(&lattice, &operators, *position, self.tolerance)
+                    .into_iter()
+                    .map(|(image, _)| image)
+                    .collect(),
+            };
+
+            let is_shared = |other: &usize| {
+                images.iter().any(|image| {
+                    structure::periodic_distance(&lattice, *image, positions[*other])
+                        < self.tolerance
+                })
+            };
+
+            let (shared, mut others): (Vec<Vec<usize>>, Vec<Vec<usize>>) = groups
+                .into_iter()
+                .partition(|group| group.iter().any(is_shared));
+
+            let mut group: Vec<usize> = shared.into_iter().flatten().collect();
+            group.push(row);
+            group.sort_unstable();
+
+            others.push(group);
+            groups = others;
+        }
+
+        groups.sort_unstable_by_key(|group| group[0]);
+
+        Ok(groups)
+    }
+}
+
+impl FromStr for OccupancyConstraint {
+    type Err = anyhow::Error;
+
+    /// Reads the options of the text form, e.g. `sum=one tolerance=0.05`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut constraint = OccupancyConstraint::default();
+
+        for word in s.split_whitespace() {
+            match word.split_once('=') {
+                Some((SUM_OPTION, value)) => constraint.sum = value.parse()?,
+                Some((TOLERANCE_OPTION, value)) => {
+                    constraint.tolerance = value
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|tolerance| *tolerance >= 0.0)
+                        .ok_or_else(|| anyhow::anyhow!("{} is not a valid tolerance", value))?;
+                }
+                _ => return Err(anyhow::anyhow!("Unknown option {}", word)),
+            }
+        }
+
+        Ok(constraint)
+    }
+}
+
+impl Display for OccupancyConstraint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", SUM_OPTION, self.sum)?;
+
+        if self.tolerance != symmetry::DEFAULT_TOLERANCE {
+            write!(f, " {}={}", TOLERANCE_OPTION, self.tolerance)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        composition::{FORMULA_STRUCTURAL_TAG, FORMULA_SUM_TAG},
+        test_utils::block,
+        Doping, Instructions,
+    };
+
+    /// BaTiO3 with a shared site of 90 % Ti1 and 10 % Zr1, the atom sites are Ba1, Ti1, Zr1, O1.
+    fn doped_block() -> DataBlock {
+        let mut block = block("tests/BaTiO3.cif");
+
+        "Ti -> Zr 0.1"
+            .parse::<Doping>()
+            .unwrap()
+            .apply_to_block(&mut block)
+            .unwrap();
+
+        block
+    }
+
+    fn set_occupancy(block: &mut DataBlock, row: usize, occupancy: &str) {
+        let l = block.find_loop_mut(structure::LABEL_TAG).unwrap();
+        let column = l.column(structure::OCCUPANCY_TAG).unwrap();
+        l.set_value(row, column, occupancy);
+    }
+
+    fn occupancies_of(block: &DataBlock) -> Vec<(String, f64)> {
+        let sites = AtomSites::from_block(block).unwrap();
+
+        (0..sites.len())
+            .map(|row| (sites.label(row).to_string(), sites.occupancy(row)))
+            .collect()
+    }
+
+    #[test]
+    fn test_shared_sites() {
+        let block = doped_block();
+
+        let sites = AtomSites::from_block(&block).unwrap();
+        let groups = OccupancyConstraint::default()
+            .shared_sites(&block, &sites)
+            .unwrap();
+
+        assert_eq!(groups, [vec![0], vec![1, 2], vec![3]]);
+    }
+
+    #[test]
+    fn test_sum_to_original() {
+        let mut block = doped_block();
+        let original = block.clone();
+
+        set_occupancy(&mut block, 1, "0.8");
+
+        let constraint: OccupancyConstraint = "sum=original".parse().unwrap();
+        assert_eq!(
+            constraint
+                .apply_to_block(&mut block, Some(&original))
+                .unwrap(),
+            3
+        );
+
+        let occupancies = occupancies_of(&block);
+        assert_eq!(occupancies[1], ("Ti1".to_string(), 0.8));
+        assert_eq!(occupancies[2], ("Zr1".to_string(), 0.2));
+
+        assert_eq!(
+            block.value(FORMULA_SUM_TAG).as_deref(),
+            Some("Ba1 O3 Ti0.8 Zr0.2")
+        );
+        assert_eq!(
+            block.value(FORMULA_STRUCTURAL_TAG).as_deref(),
+            Some("Ba (Ti0.8 Zr0.2 O3)")
+        );
+    }
+
+    #[test]
+    fn test_sum_to_one() {
+        let mut block = doped_block();
+
+        let instructions =
+            Instructions::from_string("_atom_site_occupancy[Zr1] = 0.3\noccupancies sum=one")
+                .unwrap();
+        instructions.apply_to_block(&mut block).unwrap();
+
+        let occupancies = occupancies_of(&block);
+        assert_eq!(occupancies[1], ("Ti1".to_string(), 0.7));
+        assert_eq!(occupancies[2], ("Zr1".to_string(), 0.3));
+
+        // Without the unmodified block, all occupancies of a shared site are scaled.
+        let mut block = doped_block();
+        set_occupancy(&mut block, 2, "0.4");
+        set_occupancy(&mut block, 3, "1.2");
+
+        OccupancyConstraint::new(OccupancySum::One)
+            .apply_to_block(&mut block, None)
+            .unwrap();
+
+        let occupancies = occupancies_of(&block);
+        assert_eq!(occupancies[1].1, 0.6923);
+        assert_eq!(occupancies[2].1, 0.3077);
+        assert_eq!(occupancies[3].1, 1.0);
+    }
+
+    #[test]
+    fn test_formula_after_normalization() {
+        let mut block = block("tests/BaTiO3.cif");
+
+        let instructions = Instructions::from_string(
+            "dope Ti -> Zr 0.1\n_atom_site_occupancy[Zr1] + 0.3\noccupancies",
+        )
+        .unwrap();
+        instructions.apply_to_block(&mut block).unwrap();
+
+        assert_eq!(
+            block.value(FORMULA_SUM_TAG).as_deref(),
+            Some("Ba1 O3 Ti0.6923 Zr0.3077")
+        );
+        assert_eq!(
+            block.value(FORMULA_STRUCTURAL_TAG).as_deref(),
+            Some("Ba (Ti0.6923 Zr0.3077 O3)")
+        );
+    }
+    #[test]
+    fn test_occupancy_constraint_from_str() {
+        let constraint: OccupancyConstraint = "".parse().unwrap();
+        assert_eq!(constraint, OccupancyConstraint::default());
+        assert_eq!(constraint.to_string(), "sum=original");
+
+        let constraint: OccupancyConstraint = "sum=one tolerance=0.05".parse().unwrap();
+        assert_eq!(
+            constraint,
+            OccupancyConstraint::new(OccupancySum::One).with_tolerance(0.05)
+        );
+        assert_eq!(constraint.to_string(), "sum=one tolerance=0.05");
+
+        assert!("sum=two".parse::<OccupancyConstraint>().is_err());
+        assert!("tolerance=-1".parse::<OccupancyConstraint>().is_err());
+    }
+}
@@ -0,0 +1,274 @@
+//! Operations on the whole structure of a data block, e.g. `supercell 2 2 1`, `expand`,
+//! `transform niggli`, `rattle 0.05`, `dope Ti -> Zr 0.1`, `vacancy O 0.1`, `adp to B`,
+//! `occupancies sum=one` or `temperature 300 -> 800 with alpha_V = 3e-5`.
+//!
+//! Unlike instructions, which modify the values of a single tag, operations change the cell, the
+//! symmetry and the atom sites together. In the text form an operation is a line that starts with
+//! its name, in the structured form an entry with an `operation` field.
+
+use std::{fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Adp, BirchMurnaghan, CellTransform, DataBlock, Doping, Interstitial, OccupancyConstraint,
+    Rattle, Substitution, Supercell, SymmetryExpansion, ThermalExpansion, Vacancy,
+};
+
+const SUPERCELL_KEYWORD: &str = "supercell";
+const EXPAND_KEYWORD: &str = "expand";
+const TRANSFORM_KEYWORD: &str = "transform";
+const RATTLE_KEYWORD: &str = "rattle";
+const SUBSTITUTE_KEYWORD: &str = "substitute";
+const DOPE_KEYWORD: &str = "dope";
+const VACANCY_KEYWORD: &str = "vacancy";
+const INTERSTITIAL_KEYWORD: &str = "interstitial";
+const ADP_KEYWORD: &str = "adp";
+const OCCUPANCIES_KEYWORD: &str = "occupancies";
+const TEMPERATURE_KEYWORD: &str = "temperature";
+const PRESSURE_KEYWORD: &str = "pressure";
+
+const KEYWORDS: [&str; 12] = [
+    SUPERCELL_KEYWORD,
+    EXPAND_KEYWORD,
+    TRANSFORM_KEYWORD,
+    RATTLE_KEYWORD,
+    SUBSTITUTE_KEYWORD,
+    DOPE_KEYWORD,
+    VACANCY_KEYWORD,
+    INTERSTITIAL_KEYWORD,
+    ADP_KEYWORD,
+    OCCUPANCIES_KEYWORD,
+    TEMPERATURE_KEYWORD,
+    PRESSURE_KEYWORD,
+];
+
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "operation", rename_all = "lowercase")]
+pub enum Operation {
+    /// Builds a supercell in P1, see [`Supercell`].
+    Supercell(Supercell),
+    /// Expands the asymmetric unit to P1, see [`SymmetryExpansion`].
+    Expand(SymmetryExpansion),
+    /// Changes the basis of the cell, see [`CellTransform`].
+    Transform(CellTransform),
+    /// Displaces the atom sites randomly, see [`Rattle`].
+    Rattle(Rattle),
+    /// Replaces an element by another, see [`Substitution`].
+    Substitute(Substitution),
+    /// Replaces a fraction of an element by another, see [`Doping`].
+    Dope(Doping),
+    /// Removes atom sites, see [`Vacancy`].
+    Vacancy(Vacancy),
+    /// Adds an atom site, see [`Interstitial`].
+    Interstitial(Interstitial),
+    /// Edits the displacement parameters, see [`Adp`].
+    Adp(Adp),
+    /// Constrains the occupancies of shared sites, see [`OccupancyConstraint`].
+    Occupancies(OccupancyConstraint),
+    /// Scales the cell with thermal expansion, see [`ThermalExpansion`].
+    Temperature(ThermalExpansion),
+    /// Scales the cell with an equation of state, see [`BirchMurnaghan`].
+    Pressure(BirchMurnaghan),
+}
+
+impl Operation {
+    /// Returns whether a line of the text form is an operation.
+    pub(crate) fn is_operation(line: &str) -> bool {
+        line.split_whitespace()
+            .next()
+            .is_some_and(|word| KEYWORDS.contains(&word))
+    }
+
+    /// Returns whether the operation draws random numbers.
+    pub(crate) fn is_random(&self) -> bool {
+        match self {
+            Operation::Rattle(_) => true,
+            Operation::Dope(doping) => doping.is_random(),
+            Operation::Vacancy(vacancy) => vacancy.is_random(),
+            _ => false,
+        }
+    }
+
+    /// Applies the operation to a data block. Blocks without a structure are left unchanged.
+    /// Returns the number of modified values.
+    pub(crate) fn apply_to_block(&self, block: &mut DataBlock) -> anyhow::Result<usize> {
+        match self {
+            Operation::Supercell(supercell) => supercell.apply_to_block(block),
+            Operation::Expand(expansion) => expansion.apply_to_block(block),
+            Operation::Transform(transform) => transform.apply_to_block(block),
+            Operation::Rattle(rattle) => rattle.apply_to_block(block),
+            Operation::Substitute(substitution) => substitution.apply_to_block(block),
+            Operation::Dope(doping) => doping.apply_to_block(block),
+            Operation::Vacancy(vacancy) => vacancy.apply_to_block(block),
+            Operation::Interstitial(interstitial) => interstitial.apply_to_block(block),
+            Operation::Adp(adp) => adp.apply_to_block(block),
+            Operation::Occupancies(constraint) => constraint.apply_to_block(block, None),
+            Operation::Temperature(expansion) => expansion.apply_to_block(block),
+            Operation::Pressure(eos) => eos.apply_to_block(block),
+        }
+    }
+}
+
+impl FromStr for Operation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        let (keyword, arguments) = s.split_once(char::is_whitespace).unwrap_or((s, ""));
+
+        match keyword {
+            SUPERCELL_KEYWORD => Ok(Operation::Supercell(arguments.parse()?)),
+            EXPAND_KEYWORD => Ok(Operation::Expand(arguments.parse()?)),
+            TRANSFORM_KEYWORD => Ok(Operation::Transform(arguments.parse()?)),
+            RATTLE_KEYWORD => Ok(Operation::Rattle(arguments.parse()?)),
+            SUBSTITUTE_KEYWORD => Ok(Operation::Substitute(arguments.parse()?)),
+            DOPE_KEYWORD => Ok(Operation::Dope(arguments.parse()?)),
+            VACANCY_KEYWORD => Ok(Operation::Vacancy(arguments.parse()?)),
+            INTERSTITIAL_KEYWORD => Ok(Operation::Interstitial(arguments.parse()?)),
+            ADP_KEYWORD => Ok(Operation::Adp(arguments.parse()?)),
+            OCCUPANCIES_KEYWORD => Ok(Operation::Occupancies(arguments.parse()?)),
+            TEMPERATURE_KEYWORD => Ok(Operation::Temperature(arguments.parse()?)),
+            PRESSURE_KEYWORD => Ok(Operation::Pressure(arguments.parse()?)),
+            _ => Err(anyhow::anyhow!("Unknown operation {}", keyword)),
+        }
+    }
+}
+
+impl Display for Operation {
+    /// Writes the operation in the text form that [`Operation::from_str`] reads.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operation::Supercell(supercell) => write!(f, "{} {}", SUPERCELL_KEYWORD, supercell),
+            Operation::Expand(expansion) => match expansion.to_string().as_str() {
+                "" => write!(f, "{}", EXPAND_KEYWORD),
+                options => write!(f, "{} {}", EXPAND_KEYWORD, options),
+            },
+            Operation::Transform(transform) => write!(f, "{} {}", TRANSFORM_KEYWORD, transform),
+            Operation::Rattle(rattle) => write!(f, "{} {}", RATTLE_KEYWORD, rattle),
+            Operation::Substitute(substitution) => {
+                write!(f, "{} {}", SUBSTITUTE_KEYWORD, substitution)
+            }
+            Operation::Dope(doping) => write!(f, "{} {}", DOPE_KEYWORD, doping),
+            Operation::Vacancy(vacancy) => write!(f, "{} {}", VACANCY_KEYWORD, vacancy),
+            Operation::Interstitial(interstitial) => {
+                write!(f, "{} {}", INTERSTITIAL_KEYWORD, interstitial)
+            }
+            Operation::Adp(adp) => write!(f, "{} {}", ADP_KEYWORD, adp),
+            Operation::Occupancies(constraint) => {
+                write!(f, "{} {}", OCCUPANCIES_KEYWORD, constraint)
+            }
+            Operation::Temperature(expansion) => {
+                write!(f, "{} {}", TEMPERATURE_KEYWORD, expansion)
+            }
+            Operation::Pressure(eos) => write!(f, "{} {}", PRESSURE_KEYWORD, eos),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_operation_from_str() {
+        let operation: Operation = "supercell 2 2 1".parse().unwrap();
+        assert_eq!(
+            operation,
+            Operation::Supercell(Supercell::diagonal(2, 2, 1).unwrap())
+        );
+        assert_eq!(operation.to_string(), "supercell 2 2 1");
+
+        let operation: Operation = "supercell 1 1 0 -1 1 0 0 0 1".parse().unwrap();
+        assert_eq!(operation.to_string(), "supercell 1 1 0 -1 1 0 0 0 1");
+
+        assert!(Operation::is_operation("supercell 2 2 1"));
+        assert!(!Operation::is_operation("a * 2"));
+        assert!("supercell 2 2".parse::<Operation>().is_err());
+        assert!("supercell 2 -2 1".parse::<Operation>().is_err());
+        assert!("supercell 1 1 0 1 1 0 0 0 1".parse::<Operation>().is_err());
+
+        let operation: Operation = "expand".parse().unwrap();
+        assert_eq!(operation, Operation::Expand(SymmetryExpansion::default()));
+        assert_eq!(operation.to_string(), "expand");
+
+        let operation: Operation = "expand tolerance=0.05".parse().unwrap();
+        assert_eq!(operation.to_string(), "expand tolerance=0.05");
+        assert!(Operation::is_operation("expand"));
+        assert!("expand tolerance=-1".parse::<Operation>().is_err());
+        assert!("expand 0.05".parse::<Operation>().is_err());
+    }
+
+    #[test]
+    fn test_operation_serde() {
+        let operation: Operation =
+            serde_json::from_str(r#"{"operation": "expand", "tolerance": 0.05}"#).unwrap();
+        assert_eq!(
+            operation,
+            Operation::Expand(SymmetryExpansion::default().with_tolerance(0.05))
+        );
+
+        let operation: Operation = serde_json::from_str(r#"{"operation": "expand"}"#).unwrap();
+        assert_eq!(operation, Operation::Expand(SymmetryExpansion::default()));
+
+        let operation: Operation =
+            serde_json::from_str(r#"{"operation": "transform", "to": "niggli"}"#).unwrap();
+        assert_eq!(operation.to_string(), "transform niggli");
+
+        let operation: Operation = serde_json::from_str(
+            r#"{"operation": "transform", "matrix": [[0, 1, 0], [0, 0, 1], [1, 0, 0]]}"#,
+        )
+        .unwrap();
+        assert_eq!(operation.to_string(), "transform 0 1 0 0 0 1 1 0 0");
+
+        let operation: Operation = serde_json::from_str(
+            r#"{"operation": "rattle", "amplitude": 0.05, "keep_special": true}"#,
+        )
+        .unwrap();
+        assert_eq!(operation.to_string(), "rattle 0.05 keep special");
+
+        let operation: Operation = serde_json::from_str(
+            r#"{"operation": "dope", "from": "Ti", "to": "Zr", "fraction": 0.1, "ordered": true}"#,
+        )
+        .unwrap();
+        assert_eq!(operation.to_string(), "dope Ti -> Zr 0.1 ordered");
+
+        let operation: Operation =
+            serde_json::from_str(r#"{"operation": "substitute", "from": "Ti", "to": "Zr"}"#)
+                .unwrap();
+        assert_eq!(operation.to_string(), "substitute Ti -> Zr");
+
+        let operation: Operation = serde_json::from_str(
+            r#"{"operation": "interstitial", "species": "Li", "position": [0.5, 0, 0]}"#,
+        )
+        .unwrap();
+        assert_eq!(operation.to_string(), "interstitial Li 0.5 0 0");
+
+        let operation: Operation =
+            serde_json::from_str(r#"{"operation": "adp", "scale": 1.5}"#).unwrap();
+        assert_eq!(operation.to_string(), "adp scale 1.5");
+
+        let operation: Operation =
+            serde_json::from_str(r#"{"operation": "occupancies", "sum": "one"}"#).unwrap();
+        assert_eq!(operation.to_string(), "occupancies sum=one");
+
+        let operation: Operation = serde_json::from_str(
+            r#"{"operation": "temperature", "from": 300, "to": 800, "alpha_V": 3e-5}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            operation.to_string(),
+            "temperature 300 -> 800 with alpha_V = 0.00003"
+        );
+
+        let operation: Operation =
+            serde_json::from_str(r#"{"operation": "pressure", "to": 5, "B0": 160}"#).unwrap();
+        assert_eq!(operation.to_string(), "pressure 5 with B0 = 160");
+
+        assert!(serde_json::from_str::<Operation>(
+            r#"{"operation": "transform", "to": "niggli", "matrix": [[0, 1, 0], [0, 0, 1], [1, 0, 0]]}"#
+        )
+        .is_err());
+    }
+}
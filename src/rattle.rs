@@ -0,0 +1,414 @@
+//! Random displacements of the atom sites, e.g. to augment training sets of machine-learned
+//! potentials.
+
+use std::{fmt::Display, str::FromStr};
+
+use rand_distr::Distribution as _;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    instructions::matches_selector,
+    linalg::{self, Vector3},
+    structure::{self, AtomSites},
+    symmetry::{self, SymmetryOperator},
+    DataBlock, Lattice, RNG,
+};
+
+const SIGMA_OPTION: &str = "sigma";
+const ELEMENTS_OPTION: &str = "elements";
+const LABELS_OPTION: &str = "labels";
+const KEEP_KEYWORD: &str = "keep";
+const KEPT_SPECIAL_POSITIONS: &str = "special";
+const LIST_SEPARATOR: char = ',';
+
+/// The size of the random displacements in Å.
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Displacement {
+    /// Every site moves by this distance in a random direction.
+    Amplitude(f64),
+    /// Every Cartesian component of the displacement is normally distributed with this standard
+    /// deviation.
+    Sigma(f64),
+}
+
+impl Displacement {
+    fn size(&self) -> f64 {
+        match self {
+            Displacement::Amplitude(size) | Displacement::Sigma(size) => *size,
+        }
+    }
+
+    /// Draws a Cartesian displacement from the seeded random number generator.
+    fn sample(&self) -> Vector3 {
+        RNG.with(|rng| {
+            let rng = &mut *rng.borrow_mut();
+
+            match self {
+                Displacement::Amplitude(amplitude) => {
+                    linalg::scale(rand_distr::UnitSphere.sample(rng), *amplitude)
+                }
+                Displacement::Sigma(sigma) => {
+                    let normal = rand_distr::Normal::new(0.0, *sigma)
+                        .expect("The standard deviation is positive and finite");
+
+                    [normal.sample(rng), normal.sample(rng), normal.sample(rng)]
+                }
+            }
+        })
+    }
+}
+
+/// Displaces the atom sites by random Cartesian vectors.
+///
+/// The sites can be restricted by element and by label, where `*` in a label stands for any
+/// sequence of characters. Sites on special positions, i.e. with a symmetry operator other than the
+/// identity that maps them onto themselves, can be kept fixed.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "RattleSpec", into = "RattleSpec")]
+pub struct Rattle {
+    displacement: Displacement,
+    elements: Vec<String>,
+    labels: Vec<String>,
+    keeps_special_positions: bool,
+}
+
+impl Rattle {
+    /// Fails if the size of the displacement is not positive.
+    pub fn new(displacement: Displacement) -> anyhow::Result<Self> {
+        if !(displacement.size() > 0.0 && displacement.size().is_finite()) {
+            return Err(anyhow::anyhow!(
+                "The displacement {} Å has to be positive",
+                displacement.size()
+            ));
+        }
+
+        Ok(Rattle {
+            displacement,
+            elements: Vec::new(),
+            labels: Vec::new(),
+            keeps_special_positions: false,
+        })
+    }
+
+    pub fn with_elements(mut self, elements: Vec<String>) -> Self {
+        self.elements = elements;
+        self
+    }
+
+    pub fn with_labels(mut self, labels: Vec<String>) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    pub fn with_special_positions_kept(mut self) -> Self {
+        self.keeps_special_positions = true;
+        self
+    }
+
+    fn is_selected(&self, sites: &AtomSites, row: usize) -> bool {
+        let is_element_selected = self.elements.is_empty()
+            || sites
+                .element(row)
+                .is_some_and(|element| self.elements.contains(&element));
+
+        let is_label_selected = self.labels.is_empty()
+            || self
+                .labels
+                .iter()
+                .any(|selector| matches_selector(selector, sites.label(row)));
+
+        is_element_selected && is_label_selected
+    }
+
+    /// Displaces the selected atom sites of a block. Returns the number of modified values.
+    pub(crate) fn apply_to_block(&self, block: &mut DataBlock) -> anyhow::Result<usize> {
+        if block.find_loop(structure::LABEL_TAG).is_none() {
+            return Ok(0);
+        }
+
+        let lattice = Lattice::from_block(block)?;
+        let mut sites = AtomSites::from_block(block)?;
+
+        let operators = symmetry::symmetry_operators(block)?;
+
+        let mut modified_values_counter = 0;
+
+        for row in 0..sites.len() {
+            if !self.is_selected(&sites, row) {
+                continue;
+            }
+
+            let position = sites.position(row)?;
+
+            if is_special_position(&lattice, &operators, position) {
+                if self.keeps_special_positions {
+                    log::debug!("{} is on a special position and is kept", sites.label(row));
+                    continue;
+                }
+
+                log::warn!(
+                    "{} in data_{} is on a special position and its symmetry images no longer coincide. Use `keep special` or expand the structure to P1 first.",
+                    sites.label(row),
+                    block.name().unwrap_or_default()
+                );
+            }
+
+            let shift = lattice.to_fractional(self.displacement.sample());
+
+            sites.set_position(row, structure::wrap(linalg::add(position, shift)));
+            modified_values_counter += 3;
+        }
+
+        if modified_values_counter > 0 {
+            sites.write_to(block);
+        }
+
+        Ok(modified_values_counter)
+    }
+}
+
+/// Returns whether an operator other than the identity maps the position onto itself.
+fn is_special_position(
+    lattice: &Lattice,
+    operators: &[SymmetryOperator],
+    position: Vector3,
+) -> bool {
+    operators
+        .iter()
+        .filter(|operator| !operator.is_equivalent(&SymmetryOperator::identity()))
+        .any(|operator| {
+            structure::periodic_distance(lattice, operator.apply(position), position)
+                < symmetry::DEFAULT_TOLERANCE
+        })
+}
+
+impl FromStr for Rattle {
+    type Err = anyhow::Error;
+
+    /// Reads the amplitude or `sigma=` and the options, e.g. `0.05 elements=O,Ti labels=O1*
+    /// keep special`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut displacement = None;
+        let mut elements = Vec::new();
+        let mut labels = Vec::new();
+        let mut keeps_special_positions = false;
+
+        let parse_size = |value: &str| {
+            value
+                .parse::<f64>()
+                .map_err(|_| anyhow::anyhow!("{} is not a number", value))
+        };
+
+        let mut words = s.split_whitespace();
+
+        while let Some(word) = words.next() {
+            match word.split_once('=') {
+                Some((SIGMA_OPTION, value)) => {
+                    displacement = Some(Displacement::Sigma(parse_size(value)?));
+                }
+                Some((ELEMENTS_OPTION, value)) => {
+                    elements = value.split(LIST_SEPARATOR).map(str::to_string).collect();
+                }
+                Some((LABELS_OPTION, value)) => {
+                    labels = value.split(LIST_SEPARATOR).map(str::to_string).collect();
+                }
+                Some(_) => return Err(anyhow::anyhow!("Unknown option {}", word)),
+                None if word == KEEP_KEYWORD => match words.next() {
+                    Some(KEPT_SPECIAL_POSITIONS) => keeps_special_positions = true,
+                    _ => {
+                        return Err(anyhow::anyhow!(
+                            "{} has to be followed by {}",
+                            KEEP_KEYWORD,
+                            KEPT_SPECIAL_POSITIONS
+                        ))
+                    }
+                },
+                None if displacement.is_none() => {
+                    displacement = Some(Displacement::Amplitude(parse_size(word)?));
+                }
+                None => return Err(anyhow::anyhow!("Unexpected {} in {}", word, s)),
+            }
+        }
+
+        let displacement = displacement
+            .ok_or_else(|| anyhow::anyhow!("rattle takes an amplitude in Å or sigma="))?;
+
+        let mut rattle = Rattle::new(displacement)?
+            .with_elements(elements)
+            .with_labels(labels);
+        rattle.keeps_special_positions = keeps_special_positions;
+
+        Ok(rattle)
+    }
+}
+
+impl Display for Rattle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.displacement {
+            Displacement::Amplitude(amplitude) => write!(f, "{}", amplitude)?,
+            Displacement::Sigma(sigma) => write!(f, "{}={}", SIGMA_OPTION, sigma)?,
+        }
+
+        if !self.elements.is_empty() {
+            write!(f, " {}={}", ELEMENTS_OPTION, self.elements.join(","))?;
+        }
+
+        if !self.labels.is_empty() {
+            write!(f, " {}={}", LABELS_OPTION, self.labels.join(","))?;
+        }
+
+        if self.keeps_special_positions {
+            write!(f, " {} {}", KEEP_KEYWORD, KEPT_SPECIAL_POSITIONS)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The structured form of a [`Rattle`], e.g. `{ amplitude = 0.05, elements = ["O"] }`.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RattleSpec {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    amplitude: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sigma: Option<f64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    elements: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    labels: Vec<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    keep_special: bool,
+}
+
+impl TryFrom<RattleSpec> for Rattle {
+    type Error = anyhow::Error;
+
+    fn try_from(spec: RattleSpec) -> Result<Self, Self::Error> {
+        let displacement = match (spec.amplitude, spec.sigma) {
+            (Some(amplitude), None) => Displacement::Amplitude(amplitude),
+            (None, Some(sigma)) => Displacement::Sigma(sigma),
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "rattle takes either an amplitude or a sigma"
+                ))
+            }
+        };
+
+        let mut rattle = Rattle::new(displacement)?
+            .with_elements(spec.elements)
+            .with_labels(spec.labels);
+        rattle.keeps_special_positions = spec.keep_special;
+
+        Ok(rattle)
+    }
+}
+
+impl From<Rattle> for RattleSpec {
+    fn from(rattle: Rattle) -> Self {
+        let (amplitude, sigma) = match rattle.displacement {
+            Displacement::Amplitude(amplitude) => (Some(amplitude), None),
+            Displacement::Sigma(sigma) => (None, Some(sigma)),
+        };
+
+        RattleSpec {
+            amplitude,
+            sigma,
+            elements: rattle.elements,
+            labels: rattle.labels,
+            keep_special: rattle.keeps_special_positions,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::block;
+
+    fn displacements(before: &DataBlock, after: &DataBlock) -> Vec<f64> {
+        let lattice = Lattice::from_block(before).unwrap();
+        let before = AtomSites::from_block(before).unwrap();
+        let after = AtomSites::from_block(after).unwrap();
+
+        (0..before.len())
+            .map(|row| {
+                structure::periodic_distance(
+                    &lattice,
+                    before.position(row).unwrap(),
+                    after.position(row).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_rattle_amplitude() {
+        let original = block("tests/BaTiO3_P1.cif");
+        let mut block = original.clone();
+
+        let rattle: Rattle = "0.1 elements=O,Ti".parse().unwrap();
+        assert_eq!(rattle.apply_to_block(&mut block).unwrap(), 12);
+
+        let distances = displacements(&original, &block);
+        assert_eq!(distances[0], 0.0);
+
+        // The coordinates are rounded to 5 decimal places, i.e. to about 0.0001 Å.
+        for distance in &distances[1..] {
+            assert!((distance - 0.1).abs() < 1e-3, "{}", distance);
+        }
+    }
+
+    #[test]
+    fn test_rattle_sigma_and_labels() {
+        let original = block("tests/BaTiO3_P1.cif");
+        let mut block = original.clone();
+
+        let rattle: Rattle = "sigma=0.05 labels=O1,Ba*".parse().unwrap();
+        assert_eq!(rattle.apply_to_block(&mut block).unwrap(), 6);
+
+        let distances = displacements(&original, &block);
+        assert!(distances[0] > 0.0);
+        assert_eq!(distances[1], 0.0);
+        assert!(distances[2] > 0.0);
+        assert_eq!(distances[3], 0.0);
+    }
+
+    #[test]
+    fn test_rattle_keeps_special_positions() {
+        let original = block("tests/BaTiO3.cif");
+        let mut block = original.clone();
+
+        // Every site of cubic BaTiO3 is on a special position.
+        let rattle: Rattle = "0.1 keep special".parse().unwrap();
+        assert_eq!(rattle.apply_to_block(&mut block).unwrap(), 0);
+        assert_eq!(block.to_string(), original.to_string());
+    }
+
+    #[test]
+    fn test_rattle_from_str() {
+        let rattle: Rattle = "0.05 elements=O labels=O1* keep special".parse().unwrap();
+        assert_eq!(
+            rattle,
+            Rattle::new(Displacement::Amplitude(0.05))
+                .unwrap()
+                .with_elements(vec!["O".to_string()])
+                .with_labels(vec!["O1*".to_string()])
+                .with_special_positions_kept()
+        );
+        assert_eq!(
+            rattle.to_string(),
+            "0.05 elements=O labels=O1* keep special"
+        );
+
+        let rattle: Rattle = serde_json::from_str(r#"{"sigma": 0.02}"#).unwrap();
+        assert_eq!(rattle.to_string(), "sigma=0.02");
+
+        assert!("".parse::<Rattle>().is_err());
+        assert!("-0.1".parse::<Rattle>().is_err());
+        assert!("0.1 keep".parse::<Rattle>().is_err());
+        assert!("0.1 0.2".parse::<Rattle>().is_err());
+        assert!(serde_json::from_str::<Rattle>(r#"{"sigma": 0.02, "amplitude": 0.1}"#).is_err());
+    }
+}
@@ -0,0 +1,202 @@
+//! Physical bounds of modified values.
+//!
+//! Every value an instruction produces has to be finite and lie within the range of its tag in the
+//! [`crate::registry`], e.g. positive cell lengths, cell angles in (0, 180) and occupancies in
+//! [0, 1]. After a program ran on a block, the cell parameters together have to describe a lattice,
+//! i.e. a positive definite metric tensor. What happens to values that violate a bound is set by
+//! [`Safeguard`].
+
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{lattice::CELL_TAGS, parse_number, tags::TagKind, DataBlock, Lattice};
+
+pub(crate) const SAFEGUARD_KEYWORD: &str = "safeguard";
+
+/// How often a random value or a whole program is drawn again before giving up.
+pub(crate) const MAX_RESAMPLES: usize = 100;
+
+/// Margin to an exclusive bound when clamping a value that is written with all digits.
+const FULL_PRECISION_MARGIN: f64 = 1e-9;
+
+/// What to do with a value outside the physical bounds of its tag.
+#[derive(PartialEq, Debug, Clone, Copy, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Safeguard {
+    /// Fail with an error.
+    #[default]
+    Error,
+    /// Move the value to the nearest allowed value.
+    Clamp,
+    /// Draw random values again. Values of instructions that are not random are an error.
+    Resample,
+}
+
+impl FromStr for Safeguard {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Safeguard::Error),
+            "clamp" => Ok(Safeguard::Clamp),
+            "resample" => Ok(Safeguard::Resample),
+            _ => Err(anyhow::anyhow!("Unknown safeguard {}", s)),
+        }
+    }
+}
+
+impl Display for Safeguard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Safeguard::Error => write!(f, "error"),
+            Safeguard::Clamp => write!(f, "clamp"),
+            Safeguard::Resample => write!(f, "resample"),
+        }
+    }
+}
+
+/// Checks that a new value of `tag` is finite and within the range of the tag.
+pub(crate) fn check_value(tag: &str, value: f64) -> anyhow::Result<()> {
+    if !value.is_finite() {
+        return Err(anyhow::anyhow!("{} would become {}", tag, value));
+    }
+
+    match crate::tags::registry().get(tag) {
+        Some(definition) if definition.kind == TagKind::Numeric => {
+            if !definition.is_in_range(value) {
+                return Err(anyhow::anyhow!(
+                    "{} would become {}, which is outside of {}",
+                    tag,
+                    value,
+                    definition.range()
+                ));
+            }
+
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Moves a value into the range of `tag`. Exclusive bounds are kept at a distance of one unit in
+/// the last written digit, given by `digits`. Returns `None` for `NaN` and for infinite values
+/// without a bound to clamp to.
+pub(crate) fn clamp_value(tag: &str, value: f64, digits: Option<usize>) -> Option<f64> {
+    if value.is_nan() {
+        return None;
+    }
+
+    let definition = crate::tags::registry()
+        .get(tag)
+        .filter(|definition| definition.kind == TagKind::Numeric);
+
+    let (minimum, maximum, margin) = match definition {
+        Some(definition) => (
+            definition.minimum,
+            definition.maximum,
+            match (definition.is_exclusive, digits) {
+                (false, _) => 0.0,
+                (true, Some(digits)) => 10f64.powi(-(digits as i32)),
+                (true, None) => FULL_PRECISION_MARGIN,
+            },
+        ),
+        None => (None, None, 0.0),
+    };
+
+    let value = match minimum {
+        Some(minimum) if value < minimum + margin => minimum + margin,
+        _ => value,
+    };
+
+    let value = match maximum {
+        Some(maximum) if value > maximum - margin => maximum - margin,
+        _ => value,
+    };
+
+    value.is_finite().then_some(value)
+}
+
+/// Checks that the cell parameters of a block describe a lattice, i.e. that the metric tensor is
+/// positive definite. Blocks without a complete set of cell parameters are not checked.
+pub fn check_cell(block: &DataBlock) -> anyhow::Result<()> {
+    let parameters: Option<Vec<f64>> = CELL_TAGS
+        .iter()
+        .map(|tag| block.value(tag).as_deref().and_then(parse_number))
+        .collect();
+
+    let Some(parameters) = parameters else {
+        return Ok(());
+    };
+
+    for (tag, value) in CELL_TAGS.iter().zip(&parameters) {
+        check_value(tag, *value)?;
+    }
+
+    Lattice::from_block(block)
+        .with_context(|| format!("Invalid cell in data_{}", block.name().unwrap_or_default()))?;
+
+    Ok(())
+}
+
+/// Returns whether a tag is one of the six cell parameters.
+pub(crate) fn is_cell_tag(tag: &str) -> bool {
+    CELL_TAGS
+        .iter()
+        .any(|cell_tag| crate::tags::registry().is_same_tag(cell_tag, tag))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::block;
+
+    #[test]
+    fn test_check_value() {
+        assert!(check_value("_cell_length_a", 4.0).is_ok());
+        assert!(check_value("_cell_length_a", 0.0).is_err());
+        assert!(check_value("_cell_angle_gamma", 270.0).is_err());
+        assert!(check_value("_cell_angle_gamma", 180.0).is_err());
+        assert!(check_value("_atom_site_occupancy", 1.0).is_ok());
+        assert!(check_value("_atom_site_occupancy", 1.2).is_err());
+        assert!(check_value("_unknown_tag", -1.0).is_ok());
+        assert!(check_value("_unknown_tag", f64::INFINITY).is_err());
+        assert!(check_value("_cell_length_a", f64::NAN).is_err());
+    }
+
+    #[test]
+    fn test_clamp_value() {
+        let clamped = |tag, value, digits| {
+            clamp_value(tag, value, Some(digits)).map(|v| format!("{:.*}", digits, v))
+        };
+
+        assert_eq!(
+            clamped("_cell_length_a", -6.0, 4).as_deref(),
+            Some("0.0001")
+        );
+        assert_eq!(
+            clamped("_cell_angle_gamma", 270.0, 2).as_deref(),
+            Some("179.99")
+        );
+        assert_eq!(
+            clamped("_atom_site_occupancy", 1.2, 2).as_deref(),
+            Some("1.00")
+        );
+        assert_eq!(clamp_value("_cell_length_a", f64::NAN, Some(4)), None);
+        assert_eq!(clamp_value("_cell_length_a", f64::INFINITY, Some(4)), None);
+    }
+
+    #[test]
+    fn test_check_cell() {
+        let mut block = block("tests/BaTiO3.cif");
+
+        assert!(check_cell(&block).is_ok());
+
+        // The angles are valid on their own, but α > β + γ does not form a lattice.
+        block.set_value("_cell_angle_alpha", "170");
+        block.set_value("_cell_angle_beta", "40");
+        block.set_value("_cell_angle_gamma", "40");
+        assert!(check_cell(&block).is_err());
+    }
+}
@@ -0,0 +1,557 @@
+//! The atom sites of a data block as positions that structural operations can modify.
+//!
+//! Operations read the `_atom_site_` loop into [`AtomSites`], change, add or remove rows and write
+//! the rows back. Columns that an operation does not know about are copied unchanged.
+
+use crate::{
+    formula::{element_of, Formula},
+    linalg::{self, Matrix3, Vector3},
+    parse_number, symmetry,
+    utilities::{decimal_places, RemoveUncertaintyDigits},
+    DataBlock, Lattice, RNG,
+};
+
+pub(crate) const LABEL_TAG: &str = "_atom_site_label";
+pub(crate) const TYPE_SYMBOL_TAG: &str = "_atom_site_type_symbol";
+pub(crate) const OCCUPANCY_TAG: &str = "_atom_site_occupancy";
+pub(crate) const POSITION_TAGS: [&str; 3] = [
+    "_atom_site_fract_x",
+    "_atom_site_fract_y",
+    "_atom_site_fract_z",
+];
+pub(crate) const ANISO_LABEL_TAG: &str = "_atom_site_aniso_label";
+
+/// The prefixes of the anisotropic displacement parameters `U_11` ... `U_23` and `B_11` ... `B_23`.
+const ANISO_PREFIXES: [&str; 2] = ["_atom_site_aniso_U_", "_atom_site_aniso_B_"];
+pub(crate) const ANISO_INDICES: [(usize, usize); 6] =
+    [(0, 0), (1, 1), (2, 2), (0, 1), (0, 2), (1, 2)];
+
+/// Transformed values closer than this to the original value keep the original text.
+const ANISO_TOLERANCE: f64 = 1e-10;
+
+/// Fractional coordinates are written with at least this many decimal places.
+const COORDINATE_DIGITS: usize = 5;
+
+/// Occupancies are written with at most this many decimal places.
+const OCCUPANCY_DIGITS: usize = 4;
+
+/// Numbers of sites closer than this to a whole number are exact.
+const COUNT_TOLERANCE: f64 = 1e-6;
+
+/// Fractional coordinates this close to 1 are wrapped to 0.
+const WRAP_TOLERANCE: f64 = 1e-8;
+
+/// The rows of the `_atom_site_` loop of a block.
+#[derive(PartialEq, Debug, Clone)]
+pub(crate) struct AtomSites {
+    tags: Vec<String>,
+    rows: Vec<Vec<String>>,
+    label_column: usize,
+    position_columns: [usize; 3],
+}
+
+impl AtomSites {
+    /// Reads the atom sites of a block. Fails if the block has no `_atom_site_` loop with labels and
+    /// fractional coordinates.
+    pub fn from_block(block: &DataBlock) -> anyhow::Result<Self> {
+        let l = block
+            .find_loop(LABEL_TAG)
+            .ok_or_else(|| anyhow::anyhow!("{} is missing", LABEL_TAG))?;
+
+        let label_column = l.column(LABEL_TAG).expect("The loop contains the tag");
+
+        let mut position_columns = [0; 3];
+
+        for (column, tag) in position_columns.iter_mut().zip(POSITION_TAGS) {
+            *column = l
+                .column(tag)
+                .ok_or_else(|| anyhow::anyhow!("{} is missing", tag))?;
+        }
+
+        Ok(AtomSites {
+            tags: l.tags().to_vec(),
+            rows: l.rows(),
+            label_column,
+            position_columns,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn column(&self, tag: &str) -> Option<usize> {
+        self.tags
+            .iter()
+            .position(|t| crate::tags::registry().is_same_tag(t, tag))
+    }
+
+    pub fn row(&self, row: usize) -> &[String] {
+        &self.rows[row]
+    }
+
+    pub fn label(&self, row: usize) -> &str {
+        &self.rows[row][self.label_column]
+    }
+
+    /// The element of a row from its type symbol or, without one, from its label.
+    pub fn element(&self, row: usize) -> Option<String> {
+        self.value(row, TYPE_SYMBOL_TAG)
+            .and_then(element_of)
+            .or_else(|| element_of(self.label(row)))
+    }
+
+    /// The value of `tag` in a row or `None` if the loop has no such column.
+    pub fn value(&self, row: usize, tag: &str) -> Option<&str> {
+        self.column(tag)
+            .map(|column| self.rows[row][column].as_str())
+    }
+
+    /// Sets the value of `tag` in a row. Returns `false` if the loop has no such column.
+    pub fn set_value(&mut self, row: usize, tag: &str, value: &str) -> bool {
+        match self.column(tag) {
+            Some(column) => {
+                self.rows[row][column] = value.to_string();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_label(&mut self, row: usize, label: &str) {
+        self.rows[row][self.label_column] = label.to_string();
+    }
+
+    pub fn position(&self, row: usize) -> anyhow::Result<Vector3> {
+        let mut position = [0.0; 3];
+
+        for (coordinate, column) in position.iter_mut().zip(self.position_columns) {
+            let value = &self.rows[row][column];
+
+            *coordinate = parse_number(value).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "{} of {} in {} is not a number",
+                    value,
+                    self.tags[column],
+                    self.label(row)
+                )
+            })?;
+        }
+
+        Ok(position)
+    }
+
+    /// Sets the fractional coordinates of a row. Every coordinate keeps its number of decimal
+    /// places, but at least [`COORDINATE_DIGITS`] are written.
+    pub fn set_position(&mut self, row: usize, position: Vector3) {
+        for (coordinate, column) in position.into_iter().zip(self.position_columns) {
+            let value = &mut self.rows[row][column];
+
+            let digits = decimal_places(&value.remove_uncertainty_digits());
+
+            *value = format!("{:.*}", digits.max(COORDINATE_DIGITS), coordinate);
+        }
+    }
+
+    /// A copy with the same columns but without rows.
+    pub fn without_rows(&self) -> Self {
+        AtomSites {
+            rows: Vec::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Appends a column with the same value in every row. Returns its index.
+    pub fn push_column(&mut self, tag: &str, value: &str) -> usize {
+        self.tags.push(tag.to_string());
+
+        for row in &mut self.rows {
+            row.push(value.to_string());
+        }
+
+        self.tags.len() - 1
+    }
+
+    /// The occupancy of a row, 1 if the loop has no occupancies.
+    pub fn occupancy(&self, row: usize) -> f64 {
+        self.value(row, OCCUPANCY_TAG)
+            .and_then(parse_number)
+            .unwrap_or(1.0)
+    }
+
+    /// The number of atoms of every element in the unit cell from occupancy × multiplicity. Without
+    /// `_atom_site_symmetry_multiplicity`, the multiplicities are the numbers of symmetry images.
+    pub fn cell_content(&self, block: &DataBlock) -> anyhow::Result<Formula> {
+        let lattice = Lattice::from_block(block).ok();
+        let operators = symmetry::symmetry_operators(block)?;
+
+        let mut content = Formula::default();
+
+        for row in 0..self.len() {
+            let Some(element) = self.element(row) else {
+                continue;
+            };
+
+            let multiplicity = match (self.value(row, symmetry::MULTIPLICITY_TAG), &lattice) {
+                (Some(multiplicity), _) if parse_number(multiplicity).is_some() => {
+                    parse_number(multiplicity).expect("The multiplicity is a number")
+                }
+                (_, Some(lattice)) if !operators.is_empty() => symmetry::images(
+                    lattice,
+                    &operators,
+                    self.position(row)?,
+                    symmetry::DEFAULT_TOLERANCE,
+                )
+                .len() as f64,
+                _ => 1.0,
+            };
+
+            content.add(&element, multiplicity * self.occupancy(row));
+        }
+
+        Ok(content)
+    }
+
+    /// Appends a row and returns its index.
+    pub fn push_row(&mut self, row: Vec<String>) -> usize {
+        self.rows.push(row);
+        self.rows.len() - 1
+    }
+
+    /// Replaces the rows of the `_atom_site_` loop of the block.
+    pub fn write_to(&self, block: &mut DataBlock) {
+        let l = block
+            .find_loop_mut(LABEL_TAG)
+            .expect("The atom sites were read from the block");
+
+        if l.tags() != self.tags {
+            l.set_tags(self.tags.clone());
+        }
+
+        l.set_rows(self.rows.clone());
+    }
+}
+
+/// The number of sites closest to `fraction` of `total` sites. Warns if the fraction does not
+/// give a whole number of sites, e.g. 0.1 of 8 sites.
+pub(crate) fn fraction_of_sites(fraction: f64, total: usize, description: &str) -> usize {
+    let exact_count = fraction * total as f64;
+    let count = exact_count.round() as usize;
+
+    if (exact_count - count as f64).abs() > COUNT_TOLERANCE {
+        log::warn!(
+            "{} of the {} {} is not a whole number of sites. {} sites are chosen.",
+            fraction,
+            total,
+            description,
+            count
+        );
+    }
+
+    count
+}
+
+/// Writes an occupancy with at most [`OCCUPANCY_DIGITS`] decimal places without trailing zeros.
+pub(crate) fn format_occupancy(occupancy: f64) -> String {
+    let occupancy = format!("{:.*}", OCCUPANCY_DIGITS, occupancy);
+
+    occupancy
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_string()
+}
+
+/// Chooses `count` of `rows` at random with the random number generator of the thread. The chosen
+/// rows keep their order.
+pub(crate) fn sample_rows(rows: &[usize], count: usize) -> Vec<usize> {
+    let mut indices = RNG
+        .with(|rng| rand::seq::index::sample(&mut *rng.borrow_mut(), rows.len(), count).into_vec());
+    indices.sort_unstable();
+
+    indices.into_iter().map(|index| rows[index]).collect()
+}
+
+/// Moves a fractional position into [0, 1).
+pub(crate) fn wrap(position: Vector3) -> Vector3 {
+    position.map(|coordinate| {
+        let coordinate = coordinate - coordinate.floor();
+
+        if 1.0 - coordinate < WRAP_TOLERANCE {
+            0.0
+        } else {
+            coordinate
+        }
+    })
+}
+
+/// The distance in Å between two fractional positions and the closest periodic image of each
+/// other.
+pub(crate) fn periodic_distance(lattice: &Lattice, a: Vector3, b: Vector3) -> f64 {
+    let difference = linalg::sub(a, b).map(|d| d - d.round());
+
+    let mut distance = f64::INFINITY;
+
+    // Rounding gives the closest image for orthogonal cells, the neighbours cover oblique ones.
+    for i in -1..=1 {
+        for j in -1..=1 {
+            for k in -1..=1 {
+                let image = linalg::add(difference, [i as f64, j as f64, k as f64]);
+
+                distance = distance.min(linalg::norm(lattice.to_cartesian(image)));
+            }
+        }
+    }
+
+    distance
+}
+
+/// A new site in the `_atom_site_aniso_` loop that is an image of the site `label`.
+#[derive(PartialEq, Debug, Clone)]
+pub(crate) struct AnisoSite {
+    pub label: String,
+    pub new_label: String,
+    /// The linear part of the map from the original to the new fractional coordinates.
+    pub rotation: Matrix3,
+}
+
+/// Rewrites the `_atom_site_aniso_` loop for new sites in order. The anisotropic displacement
+/// parameters are transformed with the rotation of every site from the axes of `lattice` to the axes
+/// of `new_lattice`. Sites without anisotropic parameters are skipped.
+pub(crate) fn transform_aniso_sites(
+    block: &mut DataBlock,
+    lattice: &Lattice,
+    new_lattice: &Lattice,
+    sites: &[AnisoSite],
+) {
+    let block_name = block.name().unwrap_or_default().to_string();
+
+    let Some(l) = block.find_loop_mut(ANISO_LABEL_TAG) else {
+        return;
+    };
+
+    let column = l
+        .column(ANISO_LABEL_TAG)
+        .expect("The loop contains the tag");
+
+    let parameter_columns: Vec<[Option<usize>; 6]> = ANISO_PREFIXES
+        .iter()
+        .map(|prefix| {
+            ANISO_INDICES.map(|(i, j)| l.column(&format!("{}{}{}", prefix, i + 1, j + 1)))
+        })
+        .collect();
+
+    let rows = l.rows();
+
+    let mut is_incomplete = false;
+
+    let new_rows = sites
+        .iter()
+        .filter_map(|site| {
+            let mut row = rows.iter().find(|row| row[column] == site.label)?.clone();
+            row[column] = site.new_label.clone();
+
+            for columns in &parameter_columns {
+                is_incomplete |=
+                    !transform_aniso_row(&mut row, columns, lattice, new_lattice, site.rotation);
+            }
+
+            Some(row)
+        })
+        .collect();
+
+    l.set_rows(new_rows);
+
+    if is_incomplete {
+        log::warn!(
+            "The anisotropic displacement parameters of data_{} have too few columns to be transformed completely",
+            block_name
+        );
+    }
+}
+
+/// Transforms the anisotropic displacement parameters of every site for a strained cell. The
+/// displacements keep their fractional components, like the positions of the atoms.
+pub(crate) fn strain_aniso_sites(block: &mut DataBlock, lattice: &Lattice, new_lattice: &Lattice) {
+    let Some(l) = block.find_loop(ANISO_LABEL_TAG) else {
+        return;
+    };
+
+    let column = l
+        .column(ANISO_LABEL_TAG)
+        .expect("The loop contains the tag");
+
+    let sites: Vec<AnisoSite> = l
+        .rows()
+        .into_iter()
+        .map(|row| AnisoSite {
+            label: row[column].clone(),
+            new_label: row[column].clone(),
+            rotation: linalg::IDENTITY,
+        })
+        .collect();
+
+    transform_aniso_sites(block, lattice, new_lattice, &sites);
+}
+
+/// Rewrites the `_atom_site_aniso_` loop for relabelled sites without changing the parameters.
+/// `labels` holds the original and the new label of every new site in order.
+pub(crate) fn relabel_aniso_sites(block: &mut DataBlock, labels: &[(String, String)]) {
+    // The parameters do not depend on the cell if the rotation is the identity.
+    let lattice = Lattice::new(1.0, 1.0, 1.0, 90.0, 90.0, 90.0).expect("A cube is a lattice");
+
+    let sites: Vec<AnisoSite> = labels
+        .iter()
+        .map(|(label, new_label)| AnisoSite {
+            label: label.clone(),
+            new_label: new_label.clone(),
+            rotation: linalg::IDENTITY,
+        })
+        .collect();
+
+    transform_aniso_sites(block, &lattice, &lattice, &sites);
+}
+
+/// Transforms one set of parameters in a row by `U' = N'⁻¹ R N U N Rᵀ N'⁻¹` with the reciprocal
+/// lengths `N`. Returns `false` if a changed value has no column.
+fn transform_aniso_row(
+    row: &mut [String],
+    columns: &[Option<usize>; 6],
+    lattice: &Lattice,
+    new_lattice: &Lattice,
+    rotation: Matrix3,
+) -> bool {
+    if columns.iter().all(Option::is_none) {
+        return true;
+    }
+
+    let mut tensor = [[0.0; 3]; 3];
+
+    for ((i, j), column) in ANISO_INDICES.into_iter().zip(columns) {
+        let Some(value) = column.and_then(|column| parse_number(&row[column])) else {
+            continue;
+        };
+
+        tensor[i][j] = value;
+        tensor[j][i] = value;
+    }
+
+    let scale = lattice.reciprocal().lengths();
+    let new_scale = new_lattice.reciprocal().lengths();
+
+    let fractional = scale_tensor(tensor, scale, 1.0);
+    let fractional = linalg::mat_mul(
+        linalg::mat_mul(rotation, fractional),
+        linalg::transpose(rotation),
+    );
+    let new_tensor = scale_tensor(fractional, new_scale, -1.0);
+
+    let mut is_complete = true;
+
+    for ((i, j), column) in ANISO_INDICES.into_iter().zip(columns) {
+        let new_value = new_tensor[i][j];
+
+        match column {
+            Some(column) => {
+                if (new_value - tensor[i][j]).abs() > ANISO_TOLERANCE {
+                    let digits = decimal_places(&row[*column].remove_uncertainty_digits());
+                    let text = format!("{:.*}", digits, new_value);
+
+                    row[*column] = match text.strip_prefix('-') {
+                        Some(abs) if abs.parse::<f64>() == Ok(0.0) => abs.to_string(),
+                        _ => text,
+                    };
+                }
+            }
+            None => is_complete &= new_value.abs() <= ANISO_TOLERANCE,
+        }
+    }
+
+    is_complete
+}
+
+/// Multiplies `tensor[i][j]` by `(scale[i] * scale[j])^exponent`.
+fn scale_tensor(tensor: Matrix3, scale: Vector3, exponent: f64) -> Matrix3 {
+    let mut scaled = tensor;
+
+    for (i, row) in scaled.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value *= (scale[i] * scale[j]).powf(exponent);
+        }
+    }
+
+    scaled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::block;
+
+    #[test]
+    fn test_atom_sites() {
+        let mut block = block("tests/BaTiO3.cif");
+
+        let mut sites = AtomSites::from_block(&block).unwrap();
+        assert_eq!(sites.len(), 3);
+        assert_eq!(sites.label(2), "O1");
+        assert_eq!(sites.row(1)[1], "Ti");
+        assert_eq!(sites.element(1).as_deref(), Some("Ti"));
+        assert_eq!(sites.position(2).unwrap(), [0.5, 0.0, 0.5]);
+
+        sites.set_position(2, [0.25, 0.125, 0.5]);
+        sites.set_label(2, "O2");
+        sites.write_to(&mut block);
+
+        let sites = AtomSites::from_block(&block).unwrap();
+        assert_eq!(sites.label(2), "O2");
+        assert_eq!(sites.row(2)[5], "0.12500");
+        assert_eq!(sites.row(2)[9], "0.005(1)");
+    }
+
+    #[test]
+    fn test_transform_aniso_sites() {
+        let mut block = block("tests/BaTiO3_P1.cif");
+
+        let lattice = Lattice::from_block(&block).unwrap();
+        let new_lattice = Lattice::new(4.0094, 4.0094, 8.0188, 90.0, 90.0, 90.0).unwrap();
+
+        let site = |new_label: &str, rotation| AnisoSite {
+            label: "Ti1".to_string(),
+            new_label: new_label.to_string(),
+            rotation,
+        };
+
+        transform_aniso_sites(
+            &mut block,
+            &lattice,
+            &new_lattice,
+            &[
+                site("Ti1_1", [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 0.5]]),
+                site(
+                    "Ti1_2",
+                    [[0.0, 1.0, 0.0], [-1.0, 0.0, 0.0], [0.0, 0.0, 0.5]],
+                ),
+            ],
+        );
+
+        let l = block.find_loop(ANISO_LABEL_TAG).unwrap();
+        assert_eq!(l.len(), 2);
+        assert_eq!(l.value(1, 0), "Ti1_2");
+        assert_eq!(l.value(0, 3), "0.0087");
+        assert_eq!(l.value(1, 1), "0.0087");
+        assert_eq!(l.value(1, 3), "0.0087");
+    }
+
+    #[test]
+    fn test_periodic_distance() {
+        let lattice = Lattice::new(4.0, 4.0, 4.0, 90.0, 90.0, 90.0).unwrap();
+
+        let distance = periodic_distance(&lattice, [0.95, 0.0, 0.0], [0.05, 0.0, 0.0]);
+        assert!((distance - 0.4).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(wrap([1.25, -0.25, 0.999999999]), [0.25, 0.75, 0.0]);
+    }
+}
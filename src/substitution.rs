@@ -0,0 +1,675 @@
+//! Substitution of one element by another on the atom sites, fully or as random doping.
+//!
+//! Besides the atom sites, the `_atom_type_` loop, the labels of the `_atom_site_aniso_` loop,
+//! `_chemical_formula_sum` and `_chemical_formula_structural` are updated.
+
+use std::{collections::HashSet, fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    composition,
+    formula::{self, element_of, Formula},
+    instructions::matches_selector,
+    structure::{self, AtomSites},
+    symmetry::{self, SymmetryExpansion},
+    DataBlock,
+};
+
+const ARROW: &str = "->";
+const LABELS_OPTION: &str = "labels";
+const ORDERED_KEYWORD: &str = "ordered";
+const LIST_SEPARATOR: char = ',';
+
+/// Counts and fractions closer than this are equal.
+const COUNT_TOLERANCE: f64 = 1e-6;
+
+/// An element with an optional charge, e.g. `Zr` or `Nb5+`.
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Species {
+    element: String,
+    charge: Option<String>,
+}
+
+impl Species {
+    pub fn element(&self) -> &str {
+        &self.element
+    }
+
+    /// The oxidation number of the charge, e.g. 5 for `5+` and -2 for `2-`.
+    pub(crate) fn oxidation_number(&self) -> Option<i64> {
+        let charge = self.charge.as_deref()?;
+        let (magnitude, sign) = charge.split_at(charge.len() - 1);
+
+        let magnitude = match magnitude {
+            "" => 1,
+            magnitude => magnitude.parse::<i64>().ok()?,
+        };
+
+        Some(if sign == "-" { -magnitude } else { magnitude })
+    }
+
+    /// Returns whether an atom site with this type symbol or label is of this species. A species
+    /// without a charge matches every charge.
+    pub(crate) fn matches(&self, sites: &AtomSites, row: usize) -> bool {
+        match &self.charge {
+            None => sites.element(row).as_deref() == Some(&self.element),
+            Some(_) => sites.value(row, structure::TYPE_SYMBOL_TAG) == Some(&self.to_string()),
+        }
+    }
+
+    /// The type symbol of a site that had `old_symbol`. The charge of the old symbol is kept unless
+    /// this species has one, e.g. `Ti4+` becomes `Zr4+`.
+    fn type_symbol(&self, old_symbol: &str) -> String {
+        match (&self.charge, element_of(old_symbol)) {
+            (None, Some(old_element)) => {
+                format!("{}{}", self.element, &old_symbol[old_element.len()..])
+            }
+            _ => self.to_string(),
+        }
+    }
+}
+
+impl FromStr for Species {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let element = element_of(s)
+            .filter(|element| s.starts_with(element.as_str()))
+            .ok_or_else(|| anyhow::anyhow!("{} is not an element", s))?;
+
+        let charge = &s[element.len()..];
+
+        let is_charge = charge
+            .strip_suffix(['+', '-'])
+            .is_some_and(|magnitude| magnitude.chars().all(|c| c.is_ascii_digit()));
+
+        match charge {
+            "" => Ok(Species {
+                element,
+                charge: None,
+            }),
+            charge if is_charge => Ok(Species {
+                element,
+                charge: Some(charge.to_string()),
+            }),
+            _ => Err(anyhow::anyhow!("{} is not an element or an ion", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Species {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Species> for String {
+    fn from(species: Species) -> Self {
+        species.to_string()
+    }
+}
+
+impl Display for Species {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}{}",
+            self.element,
+            self.charge.as_deref().unwrap_or_default()
+        )
+    }
+}
+
+/// Replaces an element by another on all atom sites or on the sites whose labels match `labels`.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Substitution {
+    from: Species,
+    to: Species,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    labels: Vec<String>,
+}
+
+impl Substitution {
+    pub fn new(from: Species, to: Species) -> Self {
+        Substitution {
+            from,
+            to,
+            labels: Vec::new(),
+        }
+    }
+
+    pub fn with_labels(mut self, labels: Vec<String>) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Returns the number of modified values.
+    pub(crate) fn apply_to_block(&self, block: &mut DataBlock) -> anyhow::Result<usize> {
+        if block.find_loop(structure::LABEL_TAG).is_none() {
+            return Ok(0);
+        }
+
+        let sites = AtomSites::from_block(block)?;
+
+        let selection: Vec<(usize, f64)> = selected_rows(&sites, &self.from, &self.labels)
+            .into_iter()
+            .map(|row| (row, 1.0))
+            .collect();
+
+        substitute(block, &self.from, &self.to, &selection)
+    }
+}
+
+impl FromStr for Substitution {
+    type Err = anyhow::Error;
+
+    /// Reads e.g. `Ti -> Zr` or `Ti -> Nb5+ labels=Ti1`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, to, options) = parse_change(s)?;
+
+        let mut substitution = Substitution::new(from, to);
+
+        for option in options {
+            match option.split_once('=') {
+                Some((LABELS_OPTION, value)) => substitution.labels = parse_list(value),
+                _ => return Err(anyhow::anyhow!("Unknown option {}", option)),
+            }
+        }
+
+        Ok(substitution)
+    }
+}
+
+impl Display for Substitution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.from, ARROW, self.to)?;
+        write_labels(f, &self.labels)
+    }
+}
+
+/// Replaces a fraction of an element by another.
+///
+/// Split doping keeps every site and adds a site with the new element and the fraction of its
+/// occupancy at the same position. Ordered doping fully substitutes randomly chosen sites, so the
+/// fraction has to fit the number of sites, e.g. 1 of the 8 Ti sites of a 2×2×2 supercell for
+/// 0.125. Structures with symmetry are expanded to P1 first.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "DopingSpec", into = "DopingSpec")]
+pub struct Doping {
+    from: Species,
+    to: Species,
+    fraction: f64,
+    labels: Vec<String>,
+    is_ordered: bool,
+}
+
+impl Doping {
+    /// Fails if the fraction is not within (0, 1].
+    pub fn new(from: Species, to: Species, fraction: f64) -> anyhow::Result<Self> {
+        if !(fraction > 0.0 && fraction <= 1.0) {
+            return Err(anyhow::anyhow!(
+                "The doping fraction {} has to be within (0, 1]",
+                fraction
+            ));
+        }
+
+        Ok(Doping {
+            from,
+            to,
+            fraction,
+            labels: Vec::new(),
+            is_ordered: false,
+        })
+    }
+
+    pub fn with_labels(mut self, labels: Vec<String>) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    pub fn ordered(mut self) -> Self {
+        self.is_ordered = true;
+        self
+    }
+
+    /// Returns whether the doped sites are chosen at random.
+    pub(crate) fn is_random(&self) -> bool {
+        self.is_ordered
+    }
+
+    /// Returns the number of modified values.
+    pub(crate) fn apply_to_block(&self, block: &mut DataBlock) -> anyhow::Result<usize> {
+        if block.find_loop(structure::LABEL_TAG).is_none() {
+            return Ok(0);
+        }
+
+        let mut modified_values_counter = 0;
+
+        if self.is_ordered && !symmetry::is_p1(block) {
+            log::info!(
+                "Expanding data_{} to P1 before ordered doping",
+                block.name().unwrap_or_default()
+            );
+
+            modified_values_counter += SymmetryExpansion::default().apply_to_block(block)?;
+        }
+
+        let sites = AtomSites::from_block(block)?;
+        let rows = selected_rows(&sites, &self.from, &self.labels);
+
+        let selection: Vec<(usize, f64)> = if self.is_ordered {
+            let description = format!(
+                "{} sites of data_{}",
+                self.from,
+                block.name().unwrap_or_default()
+            );
+            let count = structure::fraction_of_sites(self.fraction, rows.len(), &description);
+
+            structure::sample_rows(&rows, count)
+                .into_iter()
+                .map(|row| (row, 1.0))
+                .collect()
+        } else {
+            rows.into_iter().map(|row| (row, self.fraction)).collect()
+        };
+
+        modified_values_counter += substitute(block, &self.from, &self.to, &selection)?;
+
+        Ok(modified_values_counter)
+    }
+}
+
+impl FromStr for Doping {
+    type Err = anyhow::Error;
+
+    /// Reads e.g. `Ti -> Zr 0.1` or `Ti -> Zr 0.125 ordered labels=Ti1*`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, to, options) = parse_change(s)?;
+
+        let mut fraction = None;
+        let mut labels = Vec::new();
+        let mut is_ordered = false;
+
+        for option in options {
+            match option.split_once('=') {
+                Some((LABELS_OPTION, value)) => labels = parse_list(value),
+                Some(_) => return Err(anyhow::anyhow!("Unknown option {}", option)),
+                None if option == ORDERED_KEYWORD => is_ordered = true,
+                None if fraction.is_none() => {
+                    fraction = Some(
+                        option
+                            .parse::<f64>()
+                            .map_err(|_| anyhow::anyhow!("{} is not a fraction", option))?,
+                    );
+                }
+                None => return Err(anyhow::anyhow!("Unexpected {} in {}", option, s)),
+            }
+        }
+
+        let fraction = fraction
+            .ok_or_else(|| anyhow::anyhow!("dope takes the doped fraction of the sites"))?;
+
+        let mut doping = Doping::new(from, to, fraction)?.with_labels(labels);
+        doping.is_ordered = is_ordered;
+
+        Ok(doping)
+    }
+}
+
+impl Display for Doping {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {} {}", self.from, ARROW, self.to, self.fraction)?;
+
+        if self.is_ordered {
+            write!(f, " {}", ORDERED_KEYWORD)?;
+        }
+
+        write_labels(f, &self.labels)
+    }
+}
+
+/// The structured form of a [`Doping`], e.g. `{ from = "Ti", to = "Zr", fraction = 0.1 }`.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DopingSpec {
+    from: Species,
+    to: Species,
+    fraction: f64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    labels: Vec<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    ordered: bool,
+}
+
+impl TryFrom<DopingSpec> for Doping {
+    type Error = anyhow::Error;
+
+    fn try_from(spec: DopingSpec) -> Result<Self, Self::Error> {
+        let mut doping = Doping::new(spec.from, spec.to, spec.fraction)?.with_labels(spec.labels);
+        doping.is_ordered = spec.ordered;
+
+        Ok(doping)
+    }
+}
+
+impl From<Doping> for DopingSpec {
+    fn from(doping: Doping) -> Self {
+        DopingSpec {
+            from: doping.from,
+            to: doping.to,
+            fraction: doping.fraction,
+            labels: doping.labels,
+            ordered: doping.is_ordered,
+        }
+    }
+}
+
+/// Reads `Ti -> Zr` and returns the species and the remaining words.
+fn parse_change(s: &str) -> anyhow::Result<(Species, Species, Vec<&str>)> {
+    let (from, rest) = s
+        .split_once(ARROW)
+        .ok_or_else(|| anyhow::anyhow!("Expected e.g. Ti {} Zr, but got {}", ARROW, s))?;
+
+    let mut words = rest.split_whitespace();
+
+    let to = words
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("No element after {} in {}", ARROW, s))?;
+
+    Ok((from.trim().parse()?, to.parse()?, words.collect()))
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value.split(LIST_SEPARATOR).map(str::to_string).collect()
+}
+
+fn write_labels(f: &mut std::fmt::Formatter<'_>, labels: &[String]) -> std::fmt::Result {
+    if !labels.is_empty() {
+        write!(f, " {}={}", LABELS_OPTION, labels.join(","))?;
+    }
+
+    Ok(())
+}
+
+/// The rows of the sites of a species whose labels match one of `labels`, or all of them.
+fn selected_rows(sites: &AtomSites, species: &Species, labels: &[String]) -> Vec<usize> {
+    (0..sites.len())
+        .filter(|row| species.matches(sites, *row))
+        .filter(|row| {
+            labels.is_empty()
+                || labels
+                    .iter()
+                    .any(|selector| matches_selector(selector, sites.label(*row)))
+        })
+        .collect()
+}
+
+/// The label of a site of `to` that replaces a site of `from`, e.g. `Zr1` for `Ti1`. Labels that do
+/// not start with the old element get the new element as a prefix.
+fn new_label(label: &str, from: &Species, to: &Species, labels: &mut HashSet<String>) -> String {
+    let rest = label
+        .strip_prefix(from.element())
+        .filter(|rest| !rest.starts_with(|c: char| c.is_ascii_lowercase()));
+
+    let base = match rest {
+        Some(rest) => format!("{}{}", to.element(), rest),
+        None => format!("{}_{}", to.element(), label),
+    };
+
+    composition::unique_label(&base, labels)
+}
+
+/// Replaces `from` by `to` on the selected rows, each with a fraction of its occupancy, and updates
+/// the atom types and formulas. Returns the number of modified values.
+fn substitute(
+    block: &mut DataBlock,
+    from: &Species,
+    to: &Species,
+    selection: &[(usize, f64)],
+) -> anyhow::Result<usize> {
+    let block_name = block.name().unwrap_or_default().to_string();
+
+    if selection.is_empty() {
+        log::warn!("data_{} has no {} sites to substitute", block_name, from);
+        return Ok(0);
+    }
+
+    let mut sites = AtomSites::from_block(block)?;
+    let content = sites.cell_content(block)?;
+
+    let is_split = selection
+        .iter()
+        .any(|(_, fraction)| *fraction < 1.0 - COUNT_TOLERANCE);
+
+    if is_split && sites.column(structure::OCCUPANCY_TAG).is_none() {
+        sites.push_column(structure::OCCUPANCY_TAG, "1");
+    }
+
+    let mut labels: HashSet<String> = (0..sites.len())
+        .map(|row| sites.label(row).to_string())
+        .collect();
+
+    let mut new_sites = sites.without_rows();
+    let mut aniso_labels = Vec::new();
+    let mut type_symbols = Vec::new();
+    let mut modified_values_counter = 0;
+
+    for row in 0..sites.len() {
+        let label = sites.label(row).to_string();
+
+        let Some((_, fraction)) = selection.iter().find(|(selected, _)| *selected == row) else {
+            new_sites.push_row(sites.row(row).to_vec());
+            aniso_labels.push((label.clone(), label));
+            continue;
+        };
+
+        let new_label = new_label(&label, from, to, &mut labels);
+        let occupancy = sites.occupancy(row);
+
+        let is_full = *fraction >= 1.0 - COUNT_TOLERANCE;
+
+        if !is_full {
+            let old_row = new_sites.push_row(sites.row(row).to_vec());
+            new_sites.set_value(
+                old_row,
+                structure::OCCUPANCY_TAG,
+                &structure::format_occupancy(occupancy * (1.0 - fraction)),
+            );
+            aniso_labels.push((label.clone(), label.clone()));
+            modified_values_counter += 1;
+        }
+
+        let new_row = new_sites.push_row(sites.row(row).to_vec());
+        new_sites.set_label(new_row, &new_label);
+
+        if let Some(old_symbol) = sites.value(row, structure::TYPE_SYMBOL_TAG) {
+            let new_symbol = to.type_symbol(old_symbol);
+            new_sites.set_value(new_row, structure::TYPE_SYMBOL_TAG, &new_symbol);
+
+            if !type_symbols.contains(&(old_symbol.to_string(), new_symbol.clone())) {
+                type_symbols.push((old_symbol.to_string(), new_symbol));
+            }
+        }
+
+        if !is_full {
+            new_sites.set_value(
+                new_row,
+                structure::OCCUPANCY_TAG,
+                &structure::format_occupancy(occupancy * fraction),
+            );
+        }
+
+        aniso_labels.push((label, new_label));
+        modified_values_counter += 3;
+    }
+
+    new_sites.write_to(block);
+    structure::relabel_aniso_sites(block, &aniso_labels);
+
+    let oxidation_numbers: Vec<(String, String)> = type_symbols
+        .into_iter()
+        .filter_map(|(old_symbol, new_symbol)| {
+            let oxidation_number = match to.oxidation_number() {
+                Some(oxidation_number) => oxidation_number.to_string(),
+                None => composition::oxidation_number(block, &old_symbol)?,
+            };
+
+            Some((new_symbol, oxidation_number))
+        })
+        .collect();
+
+    modified_values_counter +=
+        composition::update_atom_types(block, &sites, &new_sites, &oxidation_numbers);
+
+    let new_content = new_sites.cell_content(block)?;
+    modified_values_counter += composition::update_formula_sum(block, &content, &new_content)?;
+
+    modified_values_counter += update_formula_structural(block, from, to, &content, &new_content);
+
+    Ok(modified_values_counter)
+}
+
+/// Replaces the element in `_chemical_formula_structural` by the substituted fraction of the cell
+/// content. Returns the number of modified values.
+fn update_formula_structural(
+    block: &mut DataBlock,
+    from: &Species,
+    to: &Species,
+    content: &Formula,
+    new_content: &Formula,
+) -> usize {
+    let Some(structural) = block.value(composition::FORMULA_STRUCTURAL_TAG) else {
+        return 0;
+    };
+
+    let substituted = content.count(from.element()) - new_content.count(from.element());
+    let fraction = substituted / content.count(from.element());
+
+    let replacements: Vec<(&str, f64)> =
+        [(from.element(), 1.0 - fraction), (to.element(), fraction)]
+            .into_iter()
+            .filter(|(_, fraction)| *fraction > COUNT_TOLERANCE)
+            .collect();
+
+    block.set_value(
+        composition::FORMULA_STRUCTURAL_TAG,
+        &formula::substitute_element(&structural, from.element(), &replacements),
+    );
+
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::composition::{FORMULA_STRUCTURAL_TAG, FORMULA_SUM_TAG, TYPE_TAG, Z_TAG};
+    use crate::test_utils::block;
+
+    fn atom_types(block: &DataBlock) -> Vec<Vec<String>> {
+        block.find_loop(TYPE_TAG).unwrap().rows()
+    }
+
+    #[test]
+    fn test_substitution() {
+        let mut block = block("tests/BaTiO3.cif");
+
+        let substitution: Substitution = "Ti -> Zr".parse().unwrap();
+        assert!(substitution.apply_to_block(&mut block).unwrap() > 0);
+
+        let sites = AtomSites::from_block(&block).unwrap();
+        assert_eq!(sites.label(1), "Zr1");
+        assert_eq!(sites.row(1)[1], "Zr");
+
+        assert_eq!(
+            atom_types(&block),
+            [["Ba", "2"], ["O", "-2"], ["Zr", "4"]].map(|row| row.map(str::to_string))
+        );
+        assert_eq!(block.value(FORMULA_SUM_TAG).as_deref(), Some("Ba1 O3 Zr1"));
+        assert_eq!(
+            block.value(FORMULA_STRUCTURAL_TAG).as_deref(),
+            Some("Ba (Zr O3)")
+        );
+
+        let mut block = self::block("tests/BaTiO3.cif");
+        let substitution: Substitution = "Ti -> Nb5+ labels=Ti1".parse().unwrap();
+        substitution.apply_to_block(&mut block).unwrap();
+        assert_eq!(AtomSites::from_block(&block).unwrap().row(1)[1], "Nb5+");
+        assert!(atom_types(&block).contains(&vec!["Nb5+".to_string(), "5".to_string()]));
+    }
+
+    #[test]
+    fn test_split_doping() {
+        let mut block = block("tests/BaTiO3.cif");
+
+        let doping: Doping = "Ti -> Zr 0.1".parse().unwrap();
+        doping.apply_to_block(&mut block).unwrap();
+
+        let sites = AtomSites::from_block(&block).unwrap();
+        assert_eq!(sites.len(), 4);
+        assert_eq!(sites.label(1), "Ti1");
+        assert_eq!(sites.occupancy(1), 0.9);
+        assert_eq!(sites.label(2), "Zr1");
+        assert_eq!(sites.occupancy(2), 0.1);
+        assert_eq!(sites.position(2).unwrap(), [0.5, 0.5, 0.5]);
+
+        assert_eq!(atom_types(&block).len(), 4);
+        assert_eq!(
+            block.value(FORMULA_SUM_TAG).as_deref(),
+            Some("Ba1 O3 Ti0.9 Zr0.1")
+        );
+        assert_eq!(
+            block.value(FORMULA_STRUCTURAL_TAG).as_deref(),
+            Some("Ba (Ti0.9 Zr0.1 O3)")
+        );
+    }
+
+    #[test]
+    fn test_ordered_doping() {
+        let mut block = block("tests/BaTiO3.cif");
+
+        crate::Supercell::diagonal(2, 2, 2)
+            .unwrap()
+            .apply_to_block(&mut block)
+            .unwrap();
+
+        let doping: Doping = "Ti -> Zr 0.25 ordered".parse().unwrap();
+        doping.apply_to_block(&mut block).unwrap();
+
+        let sites = AtomSites::from_block(&block).unwrap();
+        assert_eq!(sites.len(), 40);
+
+        let content = sites.cell_content(&block).unwrap();
+        assert_eq!(content.count("Ti"), 6.0);
+        assert_eq!(content.count("Zr"), 2.0);
+
+        assert_eq!(
+            block.value(FORMULA_SUM_TAG).as_deref(),
+            Some("Ba1 O3 Ti0.75 Zr0.25")
+        );
+        assert_eq!(block.value(Z_TAG).as_deref(), Some("8"));
+    }
+
+    #[test]
+    fn test_substitution_from_str() {
+        let substitution: Substitution = "Ti -> Zr labels=Ti1,Ti2".parse().unwrap();
+        assert_eq!(substitution.to_string(), "Ti -> Zr labels=Ti1,Ti2");
+
+        let doping: Doping = "Ti->Zr 0.125 ordered".parse().unwrap();
+        assert_eq!(doping.to_string(), "Ti -> Zr 0.125 ordered");
+
+        let doping: Doping =
+            serde_json::from_str(r#"{"from": "Ti", "to": "Zr4+", "fraction": 0.1}"#).unwrap();
+        assert_eq!(doping.to_string(), "Ti -> Zr4+ 0.1");
+
+        assert!("Ti Zr".parse::<Substitution>().is_err());
+        assert!("Ti -> zr".parse::<Substitution>().is_err());
+        assert!("Ti -> Zr4".parse::<Substitution>().is_err());
+        assert!("Ti -> Zr 1.5".parse::<Doping>().is_err());
+        assert!("Ti -> Zr".parse::<Doping>().is_err());
+    }
+}
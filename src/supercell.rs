@@ -0,0 +1,302 @@
+//! Supercells whose axes are integer combinations of the axes of the original cell.
+
+use std::{fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    linalg::{self, Matrix3},
+    structure::{self, AnisoSite, AtomSites},
+    symmetry::{self, SymmetryExpansion},
+    transformation, DataBlock, Lattice,
+};
+
+const Z_TAG: &str = "_cell_formula_units_Z";
+
+/// A supercell with the axes `a' = P11 a + P12 b + P13 c`, `b' = P21 a + ...` for the integer matrix
+/// `P`. `2 2 1` is short for the diagonal matrix.
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(try_from = "SupercellSpec", into = "SupercellSpec")]
+pub struct Supercell {
+    matrix: [[i64; 3]; 3],
+}
+
+impl Supercell {
+    /// Fails if the matrix does not have a positive determinant, i.e. if the new axes are not
+    /// independent or not right-handed.
+    pub fn new(matrix: [[i64; 3]; 3]) -> anyhow::Result<Self> {
+        let supercell = Supercell { matrix };
+
+        if supercell.determinant() <= 0 {
+            return Err(anyhow::anyhow!(
+                "The supercell matrix {} has the determinant {}, but it has to be positive",
+                supercell,
+                supercell.determinant()
+            ));
+        }
+
+        Ok(supercell)
+    }
+
+    pub fn diagonal(a: i64, b: i64, c: i64) -> anyhow::Result<Self> {
+        Self::new([[a, 0, 0], [0, b, 0], [0, 0, c]])
+    }
+
+    pub fn matrix(&self) -> [[i64; 3]; 3] {
+        self.matrix
+    }
+
+    fn determinant(&self) -> i64 {
+        let [[a, b, c], [d, e, f], [g, h, i]] = self.matrix;
+
+        a * (e * i - f * h) - b * (d * i - f * g) + c * (d * h - e * g)
+    }
+
+    /// The number of original cells in the supercell.
+    pub fn size(&self) -> usize {
+        self.determinant() as usize
+    }
+
+    fn is_diagonal(&self) -> bool {
+        (0..3).all(|i| (0..3).all(|j| i == j || self.matrix[i][j] == 0))
+    }
+
+    fn float_matrix(&self) -> Matrix3 {
+        self.matrix.map(|row| row.map(|value| value as f64))
+    }
+
+    /// Builds the supercell of a block in P1. Structures with other symmetry are expanded to P1 with
+    /// the default [`SymmetryExpansion`] first.
+    ///
+    /// The cell parameters, volume and Z are updated and every atom site is repeated once per
+    /// original cell with the labels `Ti1_1`, `Ti1_2`, ... Returns the number of modified values.
+    pub(crate) fn apply_to_block(&self, block: &mut DataBlock) -> anyhow::Result<usize> {
+        let lattice = match Lattice::from_block(block) {
+            Ok(lattice) => lattice,
+            Err(_) if block.find_loop(structure::LABEL_TAG).is_none() => return Ok(0),
+            Err(e) => return Err(e),
+        };
+
+        let mut modified_values_counter = 0;
+
+        if !symmetry::is_p1(block) {
+            log::info!(
+                "Expanding data_{} to P1 before building the supercell",
+                block.name().unwrap_or_default()
+            );
+
+            modified_values_counter += SymmetryExpansion::default().apply_to_block(block)?;
+        }
+
+        let sites = AtomSites::from_block(block)?;
+
+        let inverse = linalg::inverse(self.float_matrix()).expect("The determinant is positive");
+
+        let lattice_points = transformation::lattice_points(self.float_matrix());
+
+        debug_assert_eq!(lattice_points.len(), self.size());
+
+        let mut new_sites = sites.without_rows();
+        let mut aniso_sites = Vec::new();
+
+        for row in 0..sites.len() {
+            let position = linalg::vec_mat(structure::wrap(sites.position(row)?), inverse);
+
+            for (index, lattice_point) in lattice_points.iter().enumerate() {
+                let label = format!("{}_{}", sites.label(row), index + 1);
+
+                let new_row = new_sites.push_row(sites.row(row).to_vec());
+                new_sites.set_label(new_row, &label);
+                new_sites.set_position(
+                    new_row,
+                    structure::wrap(linalg::add(position, *lattice_point)),
+                );
+
+                aniso_sites.push(AnisoSite {
+                    label: sites.label(row).to_string(),
+                    new_label: label,
+                    rotation: linalg::transpose(inverse),
+                });
+            }
+        }
+
+        symmetry::set_p1(block, &mut new_sites);
+        new_sites.write_to(block);
+
+        let new_lattice =
+            Lattice::from_matrix(linalg::mat_mul(self.float_matrix(), lattice.matrix()))?;
+
+        structure::transform_aniso_sites(block, &lattice, &new_lattice, &aniso_sites);
+
+        modified_values_counter += aniso_sites.len() + new_lattice.write_to(block);
+
+        if let Some(z) = block.value(Z_TAG) {
+            let new_z = z
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| anyhow::anyhow!("{} of {} is not an integer", z, Z_TAG))?
+                * self.size();
+
+            block.set_value(Z_TAG, &new_z.to_string());
+            modified_values_counter += 1;
+        }
+
+        Ok(modified_values_counter)
+    }
+}
+
+impl FromStr for Supercell {
+    type Err = anyhow::Error;
+
+    /// Reads three integers of a diagonal matrix, e.g. `2 2 1`, or nine integers of a full matrix
+    /// row by row.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split_whitespace()
+            .map(|value| {
+                value
+                    .parse::<i64>()
+                    .map_err(|_| anyhow::anyhow!("{} in {} is not an integer", value, s))
+            })
+            .collect::<anyhow::Result<Vec<i64>>>()?;
+
+        match values.as_slice() {
+            [a, b, c] => Self::diagonal(*a, *b, *c),
+            [a, b, c, d, e, f, g, h, i] => Self::new([[*a, *b, *c], [*d, *e, *f], [*g, *h, *i]]),
+            _ => Err(anyhow::anyhow!(
+                "A supercell takes 3 or 9 integers, but {} were given",
+                values.len()
+            )),
+        }
+    }
+}
+
+impl Display for Supercell {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let values: Vec<String> = if self.is_diagonal() {
+            (0..3).map(|i| self.matrix[i][i].to_string()).collect()
+        } else {
+            self.matrix.iter().flatten().map(i64::to_string).collect()
+        };
+
+        write!(f, "{}", values.join(" "))
+    }
+}
+
+/// The structured form of a [`Supercell`].
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SupercellSpec {
+    matrix: MatrixSpec,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum MatrixSpec {
+    Diagonal([i64; 3]),
+    Full([[i64; 3]; 3]),
+}
+
+impl TryFrom<SupercellSpec> for Supercell {
+    type Error = anyhow::Error;
+
+    fn try_from(spec: SupercellSpec) -> Result<Self, Self::Error> {
+        match spec.matrix {
+            MatrixSpec::Diagonal([a, b, c]) => Self::diagonal(a, b, c),
+            MatrixSpec::Full(matrix) => Self::new(matrix),
+        }
+    }
+}
+
+impl From<Supercell> for SupercellSpec {
+    fn from(supercell: Supercell) -> Self {
+        let matrix = supercell.matrix;
+
+        SupercellSpec {
+            matrix: if supercell.is_diagonal() {
+                MatrixSpec::Diagonal([matrix[0][0], matrix[1][1], matrix[2][2]])
+            } else {
+                MatrixSpec::Full(matrix)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::block;
+
+    #[test]
+    fn test_diagonal_supercell() {
+        let mut block = block("tests/BaTiO3_P1.cif");
+
+        let supercell = Supercell::diagonal(2, 2, 1).unwrap();
+        assert_eq!(supercell.size(), 4);
+        assert!(supercell.apply_to_block(&mut block).unwrap() > 0);
+
+        assert_eq!(block.value("_cell_length_a").as_deref(), Some("8.0188"));
+        assert_eq!(block.value("_cell_length_c").as_deref(), Some("4.0094"));
+        assert_eq!(block.value("_cell_angle_gamma").as_deref(), Some("90"));
+        assert_eq!(block.value("_cell_volume").as_deref(), Some("257.81"));
+        assert_eq!(block.value(Z_TAG).as_deref(), Some("4"));
+
+        let sites = AtomSites::from_block(&block).unwrap();
+        assert_eq!(sites.len(), 20);
+        assert_eq!(sites.label(1), "Ba1_2");
+        assert_eq!(sites.position(1).unwrap(), [0.0, 0.5, 0.0]);
+        assert_eq!(sites.label(7), "Ti1_4");
+        assert_eq!(sites.position(7).unwrap(), [0.75, 0.75, 0.5]);
+
+        let aniso = block.find_loop(structure::ANISO_LABEL_TAG).unwrap();
+        assert_eq!(aniso.len(), 4);
+        assert_eq!(aniso.value(3, 0), "Ti1_4");
+    }
+
+    #[test]
+    fn test_general_supercell() {
+        let mut block = block("tests/BaTiO3_P1.cif");
+
+        let supercell: Supercell = "1 1 0 -1 1 0 0 0 1".parse().unwrap();
+        assert_eq!(supercell.size(), 2);
+        supercell.apply_to_block(&mut block).unwrap();
+
+        assert_eq!(block.value("_cell_length_a").as_deref(), Some("5.6701"));
+        assert_eq!(block.value("_cell_length_b").as_deref(), Some("5.6701"));
+        assert_eq!(block.value("_cell_angle_gamma").as_deref(), Some("90"));
+
+        let sites = AtomSites::from_block(&block).unwrap();
+        assert_eq!(sites.len(), 10);
+
+        // Every original position appears once in each of the two original cells.
+        let lattice = Lattice::from_block(&block).unwrap();
+        for row in (0..sites.len()).step_by(2) {
+            let a = lattice.to_cartesian(sites.position(row).unwrap());
+            let b = lattice.to_cartesian(sites.position(row + 1).unwrap());
+            assert!((linalg::norm(linalg::sub(a, b)) - 4.0094).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_supercell_expands_to_p1() {
+        let cif = crate::Cif::parse(&std::fs::read_to_string("tests/BaTiO3.cif").unwrap()).unwrap();
+
+        let mut block = cif.blocks()[0].clone();
+        assert_eq!(
+            Supercell::diagonal(2, 1, 1)
+                .unwrap()
+                .apply_to_block(&mut block)
+                .unwrap(),
+            0
+        );
+
+        let mut block = cif.blocks()[1].clone();
+        Supercell::diagonal(2, 1, 1)
+            .unwrap()
+            .apply_to_block(&mut block)
+            .unwrap();
+
+        assert!(symmetry::is_p1(&block));
+        assert_eq!(AtomSites::from_block(&block).unwrap().len(), 10);
+    }
+}
@@ -0,0 +1,623 @@
+//! Space group symmetry of a data block.
+//!
+//! Symmetry operators are read from `_symmetry_equiv_pos_as_xyz` or
+//! `_space_group_symop_operation_xyz`, e.g. `-y, x-y, z+1/3`, and act on fractional coordinates.
+
+use std::{fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    linalg::{self, Matrix3, Vector3},
+    structure::{self, AnisoSite, AtomSites},
+    DataBlock, Item, Lattice, Loop,
+};
+
+pub(crate) const OPERATOR_TAG: &str = "_symmetry_equiv_pos_as_xyz";
+const NAME_TAGS: [&str; 2] = [
+    "_symmetry_space_group_name_H-M",
+    "_symmetry_space_group_name_Hall",
+];
+const NUMBER_TAG: &str = "_symmetry_Int_Tables_number";
+const CELL_SETTING_TAG: &str = "_symmetry_cell_setting";
+pub(crate) const MULTIPLICITY_TAG: &str = "_atom_site_symmetry_multiplicity";
+pub(crate) const WYCKOFF_TAG: &str = "_atom_site_Wyckoff_symbol";
+
+const IDENTITY: &str = "x, y, z";
+const P1: &str = "P 1";
+
+const VARIABLES: [char; 3] = ['x', 'y', 'z'];
+
+/// Denominators tried when writing numbers as fractions.
+const DENOMINATORS: [i64; 7] = [1, 2, 3, 4, 6, 8, 12];
+
+/// Translations of equivalent operators differ by integers within this tolerance.
+const OPERATOR_TOLERANCE: f64 = 1e-6;
+
+const TOLERANCE_OPTION: &str = "tolerance";
+
+/// Images of an atom site closer than this in Å are the same atom.
+pub(crate) const DEFAULT_TOLERANCE: f64 = 0.01;
+
+/// An affine symmetry operator `x' = R x + t` on fractional coordinates.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct SymmetryOperator {
+    rotation: Matrix3,
+    translation: Vector3,
+}
+
+impl SymmetryOperator {
+    pub fn new(rotation: Matrix3, translation: Vector3) -> Self {
+        SymmetryOperator {
+            rotation,
+            translation,
+        }
+    }
+
+    pub fn rotation(&self) -> Matrix3 {
+        self.rotation
+    }
+
+    pub fn translation(&self) -> Vector3 {
+        self.translation
+    }
+
+    pub fn identity() -> Self {
+        SymmetryOperator::new(linalg::IDENTITY, [0.0; 3])
+    }
+
+    pub fn apply(&self, position: Vector3) -> Vector3 {
+        linalg::add(linalg::mat_vec(self.rotation, position), self.translation)
+    }
+
+    /// Returns whether the operator is a translation by a fraction of the cell, e.g. the centring
+    /// `x+1/2, y+1/2, z+1/2`.
+    pub fn is_centring(&self) -> bool {
+        self.rotation == linalg::IDENTITY
+            && self
+                .translation
+                .iter()
+                .any(|t| (t - t.round()).abs() > OPERATOR_TOLERANCE)
+    }
+
+    /// Returns whether both operators have the same rotation and translations that differ by a
+    /// lattice vector.
+    pub fn is_equivalent(&self, other: &SymmetryOperator) -> bool {
+        let is_close = |a: f64, b: f64| (a - b).abs() < OPERATOR_TOLERANCE;
+
+        (0..3).all(|i| (0..3).all(|j| is_close(self.rotation[i][j], other.rotation[i][j])))
+            && (0..3).all(|i| {
+                let difference = self.translation[i] - other.translation[i];
+                is_close(difference, difference.round())
+            })
+    }
+}
+
+impl FromStr for SymmetryOperator {
+    type Err = anyhow::Error;
+
+    /// Reads an operator in the xyz form, e.g. `-x+1/2, y, z` or `x-y, x, z+0.25`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let components: Vec<&str> = s.split(',').collect();
+
+        let [x, y, z] = components.as_slice() else {
+            return Err(anyhow::anyhow!(
+                "The symmetry operator {} does not have three components",
+                s
+            ));
+        };
+
+        let mut rotation = [[0.0; 3]; 3];
+        let mut translation = [0.0; 3];
+
+        for (i, component) in [x, y, z].into_iter().enumerate() {
+            (rotation[i], translation[i]) = parse_component(component)
+                .map_err(|e| e.context(format!("Cannot read the symmetry operator {}", s)))?;
+        }
+
+        Ok(SymmetryOperator::new(rotation, translation))
+    }
+}
+
+/// Reads one component of an operator, e.g. `-x+1/2`, as a row of the rotation and a translation.
+fn parse_component(component: &str) -> anyhow::Result<(Vector3, f64)> {
+    let component: String = component
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase();
+
+    let mut row = [0.0; 3];
+    let mut translation = 0.0;
+
+    let mut chars = component.chars().peekable();
+
+    while chars.peek().is_some() {
+        let mut sign = 1.0;
+
+        while let Some(c) = chars.next_if(|c| *c == '+' || *c == '-') {
+            if c == '-' {
+                sign = -sign;
+            }
+        }
+
+        let mut number = String::new();
+
+        while let Some(c) = chars.next_if(|c| c.is_ascii_digit() || *c == '.' || *c == '/') {
+            number.push(c);
+        }
+
+        chars.next_if_eq(&'*');
+
+        let variable = chars
+            .next_if(|c| VARIABLES.contains(c))
+            .and_then(|c| VARIABLES.iter().position(|v| *v == c));
+
+        let value = match (number.as_str(), variable) {
+            ("", None) => return Err(anyhow::anyhow!("Unexpected character in {}", component)),
+            ("", Some(_)) => 1.0,
+            (number, _) => parse_fraction(number)?,
+        };
+
+        match variable {
+            Some(index) => row[index] += sign * value,
+            None => translation += sign * value,
+        }
+    }
+
+    if row == [0.0; 3] {
+        return Err(anyhow::anyhow!("{} has no x, y or z", component));
+    }
+
+    Ok((row, translation))
+}
+
+/// Reads a number that may be a fraction, e.g. `-1/2` or `0.5`.
+pub(crate) fn parse_fraction(s: &str) -> anyhow::Result<f64> {
+    let value = match s.split_once('/') {
+        Some((numerator, denominator)) => numerator
+            .parse::<f64>()
+            .ok()
+            .zip(denominator.parse::<f64>().ok())
+            .map(|(n, d)| n / d),
+        None => s.parse::<f64>().ok(),
+    };
+
+    value
+        .filter(|value| value.is_finite())
+        .ok_or_else(|| anyhow::anyhow!("{} is not a number", s))
+}
+
+impl Display for SymmetryOperator {
+    /// Writes the operator in the xyz form, e.g. `-x+1/2, y, z`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let components: Vec<String> = (0..3)
+            .map(|i| {
+                let mut component = String::new();
+
+                for (coefficient, variable) in self.rotation[i].iter().zip(VARIABLES) {
+                    match *coefficient {
+                        0.0 => continue,
+                        1.0 => component.push('+'),
+                        -1.0 => component.push('-'),
+                        c => component.push_str(&format!("{:+}*", c)),
+                    }
+
+                    component.push(variable);
+                }
+
+                let translation = self.translation[i];
+
+                if translation != 0.0 {
+                    component.push_str(&match fraction(translation) {
+                        Some((numerator, 1)) => format!("{:+}", numerator),
+                        Some((numerator, denominator)) => {
+                            format!("{:+}/{}", numerator, denominator)
+                        }
+                        None => format!("{:+}", translation),
+                    });
+                }
+
+                component
+                    .strip_prefix('+')
+                    .unwrap_or(&component)
+                    .to_string()
+            })
+            .collect();
+
+        write!(f, "{}", components.join(", "))
+    }
+}
+
+/// The numerator and denominator of a number that is a simple fraction, e.g. `(-1, 3)` for -0.3333.
+pub(crate) fn fraction(value: f64) -> Option<(i64, i64)> {
+    DENOMINATORS.iter().find_map(|denominator| {
+        let numerator = value * *denominator as f64;
+
+        ((numerator - numerator.round()).abs() < OPERATOR_TOLERANCE)
+            .then_some((numerator.round() as i64, *denominator))
+    })
+}
+
+/// The symmetry operators of the block as written, e.g. `-x, y, z`.
+pub(crate) fn operators(block: &DataBlock) -> Vec<String> {
+    let Some(l) = block.find_loop(OPERATOR_TAG) else {
+        return block.value(OPERATOR_TAG).into_iter().collect();
+    };
+
+    let column = l.column(OPERATOR_TAG).expect("The loop contains the tag");
+
+    (0..l.len()).map(|row| l.value(row, column)).collect()
+}
+
+/// Reads the symmetry operators of the block.
+pub(crate) fn symmetry_operators(block: &DataBlock) -> anyhow::Result<Vec<SymmetryOperator>> {
+    operators(block)
+        .iter()
+        .map(|operator| operator.parse())
+        .collect()
+}
+
+/// The distinct images of a position within the cell and the operators that generate them. Images
+/// closer than `tolerance` in Å to an earlier image are skipped.
+pub(crate) fn images<'a>(
+    lattice: &Lattice,
+    operators: &'a [SymmetryOperator],
+    position: Vector3,
+    tolerance: f64,
+) -> Vec<(Vector3, &'a SymmetryOperator)> {
+    let mut images: Vec<(Vector3, &SymmetryOperator)> = Vec::new();
+
+    for operator in operators {
+        let image = structure::wrap(operator.apply(position));
+
+        let is_duplicate = images
+            .iter()
+            .any(|(other, _)| structure::periodic_distance(lattice, image, *other) < tolerance);
+
+        if !is_duplicate {
+            images.push((image, operator));
+        }
+    }
+
+    images
+}
+
+/// Returns whether the block has no symmetry besides the identity. Blocks without operators are P1
+/// unless they name another space group.
+pub(crate) fn is_p1(block: &DataBlock) -> bool {
+    let operators = operators(block);
+
+    if !operators.is_empty() {
+        return operators.iter().all(|operator| is_identity(operator));
+    }
+
+    match block.value(NUMBER_TAG) {
+        Some(number) => number.trim() == "1",
+        None => block
+            .value(NAME_TAGS[0])
+            .is_none_or(|name| name.replace(' ', "").eq_ignore_ascii_case("P1")),
+    }
+}
+
+fn is_identity(operator: &str) -> bool {
+    let operator: String = operator.chars().filter(|c| !c.is_whitespace()).collect();
+
+    operator.eq_ignore_ascii_case("x,y,z") || operator.eq_ignore_ascii_case("+x,+y,+z")
+}
+
+/// The lattice centring of the Hermann-Mauguin symbol of the block, e.g. `I` for `I m -3 m`.
+pub(crate) fn centring_letter(block: &DataBlock) -> Option<char> {
+    block
+        .value(NAME_TAGS[0])
+        .and_then(|name| name.trim().chars().next())
+        .map(|letter| letter.to_ascii_uppercase())
+}
+
+/// Replaces the symmetry operators of the block. Other columns of the operator loop are numbered
+/// from 1. Blocks without operators get a new loop unless the identity is the only operator.
+pub(crate) fn set_operators(block: &mut DataBlock, operators: &[SymmetryOperator]) {
+    let texts: Vec<String> = operators.iter().map(SymmetryOperator::to_string).collect();
+
+    if let Some(l) = block.find_loop_mut(OPERATOR_TAG) {
+        let column = l.column(OPERATOR_TAG).expect("The loop contains the tag");
+
+        let rows = texts
+            .iter()
+            .enumerate()
+            .map(|(index, text)| {
+                (0..l.tags().len())
+                    .map(|c| match c == column {
+                        true => text.clone(),
+                        false => (index + 1).to_string(),
+                    })
+                    .collect()
+            })
+            .collect();
+
+        l.set_rows(rows);
+        return;
+    }
+
+    let has_operator = block.value(OPERATOR_TAG).is_some();
+
+    match texts.as_slice() {
+        [text] if has_operator => {
+            block.set_value(OPERATOR_TAG, text);
+        }
+        [_] if operators[0] == SymmetryOperator::identity() => {}
+        _ => {
+            let position = block
+                .items()
+                .iter()
+                .position(|item| matches!(item, Item::Value(value) if value.is(OPERATOR_TAG)));
+
+            let l = Loop::new(
+                vec![OPERATOR_TAG.to_string()],
+                texts.into_iter().map(|text| vec![text]).collect(),
+            );
+
+            match position {
+                Some(position) => block.items_mut()[position] = Item::Loop(l),
+                None => block.push_item(Item::Loop(l)),
+            }
+        }
+    }
+}
+
+/// Marks the space group symbols and number as unknown, e.g. when the symmetry operators are
+/// given in a setting that the symbols do not describe. Returns the number of modified values.
+pub(crate) fn clear_space_group(block: &mut DataBlock) -> usize {
+    NAME_TAGS
+        .into_iter()
+        .chain([NUMBER_TAG])
+        .filter(|tag| block.set_value(tag, "?"))
+        .count()
+}
+
+/// Sets the space group of the block to P1 with the identity as the only operator. All atom sites
+/// get multiplicity 1 and Wyckoff letter a.
+pub(crate) fn set_p1(block: &mut DataBlock, sites: &mut AtomSites) {
+    for tag in NAME_TAGS {
+        block.set_value(tag, P1);
+    }
+
+    block.set_value(NUMBER_TAG, "1");
+    block.set_value(CELL_SETTING_TAG, "triclinic");
+
+    if let Some(l) = block.find_loop_mut(OPERATOR_TAG) {
+        let column = l.column(OPERATOR_TAG).expect("The loop contains the tag");
+
+        let row = (0..l.tags().len())
+            .map(|c| if c == column { IDENTITY } else { "1" }.to_string())
+            .collect();
+
+        l.set_rows(vec![row]);
+    } else {
+        block.set_value(OPERATOR_TAG, IDENTITY);
+    }
+
+    for row in 0..sites.len() {
+        sites.set_value(row, MULTIPLICITY_TAG, "1");
+        sites.set_value(row, WYCKOFF_TAG, "a");
+    }
+}
+
+/// Expands the asymmetric unit to all atoms of the cell and sets the space group to P1.
+///
+/// Every symmetry operator is applied to every atom site. Images of a site that are closer than
+/// `tolerance` in Å to an earlier image, e.g. on special positions, are merged. Sites with more than
+/// one image get the labels `O1_1`, `O1_2`, ...
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SymmetryExpansion {
+    #[serde(default = "default_tolerance")]
+    tolerance: f64,
+}
+
+fn default_tolerance() -> f64 {
+    DEFAULT_TOLERANCE
+}
+
+impl Default for SymmetryExpansion {
+    fn default() -> Self {
+        SymmetryExpansion {
+            tolerance: DEFAULT_TOLERANCE,
+        }
+    }
+}
+
+impl SymmetryExpansion {
+    pub fn with_tolerance(mut self, tolerance: f64) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Returns the number of modified values. Blocks without atom sites or already in P1 are left
+    /// unchanged.
+    pub(crate) fn apply_to_block(&self, block: &mut DataBlock) -> anyhow::Result<usize> {
+        if block.find_loop(structure::LABEL_TAG).is_none() || is_p1(block) {
+            return Ok(0);
+        }
+
+        let operators = symmetry_operators(block)?;
+
+        if operators.is_empty() {
+            return Err(anyhow::anyhow!(
+                "data_{} has no symmetry operators to expand",
+                block.name().unwrap_or_default()
+            ));
+        }
+
+        let lattice = Lattice::from_block(block)?;
+        let sites = AtomSites::from_block(block)?;
+
+        let mut new_sites = sites.without_rows();
+        let mut aniso_sites = Vec::new();
+
+        for row in 0..sites.len() {
+            let position = sites.position(row)?;
+
+            let images = images(&lattice, &operators, position, self.tolerance);
+
+            if let Some(multiplicity) = sites
+                .value(row, MULTIPLICITY_TAG)
+                .and_then(|multiplicity| multiplicity.parse::<usize>().ok())
+                .filter(|multiplicity| *multiplicity != images.len())
+            {
+                log::warn!(
+                    "{} has {} images but the multiplicity {} in data_{}. Check the tolerance of {} Å.",
+                    sites.label(row),
+                    images.len(),
+                    multiplicity,
+                    block.name().unwrap_or_default(),
+                    self.tolerance
+                );
+            }
+
+            for (index, (image, operator)) in images.iter().enumerate() {
+                let label = match images.len() {
+                    1 => sites.label(row).to_string(),
+                    _ => format!("{}_{}", sites.label(row), index + 1),
+                };
+
+                let new_row = new_sites.push_row(sites.row(row).to_vec());
+                new_sites.set_label(new_row, &label);
+                new_sites.set_position(new_row, *image);
+
+                aniso_sites.push(AnisoSite {
+                    label: sites.label(row).to_string(),
+                    new_label: label,
+                    rotation: operator.rotation(),
+                });
+            }
+        }
+
+        let modified_values_counter = new_sites.len();
+
+        set_p1(block, &mut new_sites);
+        new_sites.write_to(block);
+
+        structure::transform_aniso_sites(block, &lattice, &lattice, &aniso_sites);
+
+        Ok(modified_values_counter)
+    }
+}
+
+impl FromStr for SymmetryExpansion {
+    type Err = anyhow::Error;
+
+    /// Reads the options of the text form, e.g. `tolerance=0.05`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut expansion = SymmetryExpansion::default();
+
+        for word in s.split_whitespace() {
+            match word.split_once('=') {
+                Some((TOLERANCE_OPTION, value)) => {
+                    expansion.tolerance = value
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|tolerance| *tolerance >= 0.0)
+                        .ok_or_else(|| anyhow::anyhow!("{} is not a valid tolerance", value))?;
+                }
+                _ => return Err(anyhow::anyhow!("Unknown option {}", word)),
+            }
+        }
+
+        Ok(expansion)
+    }
+}
+
+impl Display for SymmetryExpansion {
+    /// Writes the options that differ from the default.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.tolerance != DEFAULT_TOLERANCE {
+            write!(f, "{}={}", TOLERANCE_OPTION, self.tolerance)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::block;
+
+    #[test]
+    fn test_set_p1() {
+        let mut block = block("tests/BaTiO3.cif");
+
+        assert_eq!(operators(&block).len(), 48);
+        assert!(!is_p1(&block));
+
+        let mut sites = AtomSites::from_block(&block).unwrap();
+        set_p1(&mut block, &mut sites);
+        sites.write_to(&mut block);
+
+        assert_eq!(operators(&block), vec![IDENTITY.to_string()]);
+        assert!(is_p1(&block));
+        assert_eq!(
+            block.value("_space_group_name_H-M_alt").as_deref(),
+            Some(P1)
+        );
+        assert_eq!(AtomSites::from_block(&block).unwrap().row(2)[3], "a");
+    }
+
+    #[test]
+    fn test_symmetry_operator() {
+        let operator: SymmetryOperator = "-x+1/2, x-y, 0.25+z".parse().unwrap();
+        assert_eq!(
+            operator.rotation(),
+            [[-1.0, 0.0, 0.0], [1.0, -1.0, 0.0], [0.0, 0.0, 1.0]]
+        );
+        assert_eq!(operator.translation(), [0.5, 0.0, 0.25]);
+        assert_eq!(operator.apply([0.1, 0.3, 0.5]), [0.4, 0.1 - 0.3, 0.75]);
+        assert_eq!(operator.to_string(), "-x+1/2, x-y, z+1/4");
+
+        let operator: SymmetryOperator = "Z, Y, -X".parse().unwrap();
+        assert_eq!(operator.to_string(), "z, y, -x");
+
+        let operator: SymmetryOperator = "-y+2/3, x-y+1/3, z+1/3".parse().unwrap();
+        assert_eq!(operator.to_string(), "-y+2/3, x-y+1/3, z+1/3");
+
+        assert!("x, y".parse::<SymmetryOperator>().is_err());
+        assert!("x, y, 1/2".parse::<SymmetryOperator>().is_err());
+        assert!("x, y, z+a".parse::<SymmetryOperator>().is_err());
+        assert!("x, y, z+1/0".parse::<SymmetryOperator>().is_err());
+    }
+
+    #[test]
+    fn test_symmetry_expansion() {
+        let mut block = block("tests/BaTiO3.cif");
+
+        assert_eq!(symmetry_operators(&block).unwrap().len(), 48);
+        assert_eq!(
+            SymmetryExpansion::default()
+                .apply_to_block(&mut block)
+                .unwrap(),
+            5
+        );
+
+        assert!(is_p1(&block));
+
+        let sites = AtomSites::from_block(&block).unwrap();
+        let labels: Vec<&str> = (0..sites.len()).map(|row| sites.label(row)).collect();
+        assert_eq!(labels, ["Ba1", "Ti1", "O1_1", "O1_2", "O1_3"]);
+
+        let mut positions: Vec<Vector3> = (2..5).map(|row| sites.position(row).unwrap()).collect();
+        positions.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            positions,
+            [[0.0, 0.5, 0.5], [0.5, 0.0, 0.5], [0.5, 0.5, 0.0]]
+        );
+
+        // A structure in P1 is left unchanged.
+        assert_eq!(
+            SymmetryExpansion::default()
+                .apply_to_block(&mut block)
+                .unwrap(),
+            0
+        );
+    }
+}
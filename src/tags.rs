@@ -0,0 +1,547 @@
+//! Registry of known CIF tags.
+//!
+//! Every tag is known under its DDL1 name (`_cell_length_a`), which is used as the canonical name,
+//! and under its DDLm name (`_cell.length_a`, `_chemical_formula.sum`). Some tags have additional
+//! aliases from renamed categories, e.g. `_space_group_name_H-M_alt` and the DDL2 name
+//! `_symmetry.space_group_name_H-M` of `_symmetry_space_group_name_H-M`, and a short name for
+//! instructions, e.g. `a`.
+
+use std::{collections::HashMap, sync::OnceLock};
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum TagKind {
+    Numeric,
+    Char,
+}
+
+#[derive(Debug, Clone)]
+pub struct TagDefinition {
+    /// The DDL1 name.
+    pub name: String,
+    /// All other names, i.e. the DDLm name and additional aliases.
+    pub aliases: Vec<String>,
+    pub short_name: Option<&'static str>,
+    pub kind: TagKind,
+    pub units: Option<&'static str>,
+    /// Inclusive lower bound of numeric values.
+    pub minimum: Option<f64>,
+    /// Inclusive upper bound of numeric values.
+    pub maximum: Option<f64>,
+    /// Whether the values must not be equal to the bounds, e.g. a cell length of 0.
+    pub is_exclusive: bool,
+    /// The DDLm category, e.g. `chemical_formula`.
+    pub category: &'static str,
+    /// Whether the category is a loop category, i.e. the tag usually appears inside a loop.
+    pub is_looped: bool,
+}
+
+impl TagDefinition {
+    /// The canonical name followed by all aliases.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.name.as_str()).chain(self.aliases.iter().map(String::as_str))
+    }
+
+    /// Returns whether a numeric value lies within the allowed range.
+    pub fn is_in_range(&self, value: f64) -> bool {
+        match self.is_exclusive {
+            true => {
+                self.minimum.is_none_or(|minimum| value > minimum)
+                    && self.maximum.is_none_or(|maximum| value < maximum)
+            }
+            false => {
+                self.minimum.is_none_or(|minimum| value >= minimum)
+                    && self.maximum.is_none_or(|maximum| value <= maximum)
+            }
+        }
+    }
+
+    /// The allowed range as `minimum:maximum`, e.g. `0:180`, or `(0:180)` if the bounds are
+    /// exclusive.
+    pub fn range(&self) -> String {
+        let bound = |bound: Option<f64>| bound.map(|b| b.to_string()).unwrap_or_default();
+
+        let range = format!("{}:{}", bound(self.minimum), bound(self.maximum));
+
+        match self.is_exclusive {
+            true => format!("({})", range),
+            false => range,
+        }
+    }
+}
+
+impl std::fmt::Display for TagDefinition {
+    /// One line with the name, short name, type, units, range and aliases.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+
+        if let Some(short_name) = self.short_name {
+            write!(f, " ({})", short_name)?;
+        }
+
+        match self.kind {
+            TagKind::Numeric => write!(f, " numeric")?,
+            TagKind::Char => write!(f, " char")?,
+        }
+
+        if let Some(units) = self.units {
+            write!(f, " [{}]", units)?;
+        }
+
+        if self.minimum.is_some() || self.maximum.is_some() {
+            write!(f, " {}", self.range())?;
+        }
+
+        if self.is_looped {
+            write!(f, " loop")?;
+        }
+
+        write!(f, " aliases: {}", self.aliases.join(", "))
+    }
+}
+
+pub struct TagRegistry {
+    definitions: Vec<TagDefinition>,
+    by_name: HashMap<String, usize>,
+    by_short_name: HashMap<&'static str, usize>,
+}
+
+impl TagRegistry {
+    fn new() -> Self {
+        let mut registry = TagRegistry {
+            definitions: Vec::new(),
+            by_name: HashMap::new(),
+            by_short_name: HashMap::new(),
+        };
+
+        for entry in ENTRIES {
+            let index = registry.definitions.len();
+
+            let definition = entry.definition();
+
+            for name in definition.names() {
+                registry.by_name.insert(name.to_lowercase(), index);
+            }
+
+            if let Some(short_name) = definition.short_name {
+                registry.by_short_name.insert(short_name, index);
+            }
+
+            registry.definitions.push(definition);
+        }
+
+        registry
+    }
+
+    pub fn definitions(&self) -> &[TagDefinition] {
+        &self.definitions
+    }
+
+    /// Looks up a tag by any of its names. Tags are case-insensitive.
+    pub fn get(&self, tag: &str) -> Option<&TagDefinition> {
+        self.by_name
+            .get(&tag.to_lowercase())
+            .map(|&index| &self.definitions[index])
+    }
+
+    /// Looks up a tag by its short name, e.g. `a` for `_cell_length_a`.
+    pub fn get_short(&self, short_name: &str) -> Option<&TagDefinition> {
+        self.by_short_name
+            .get(short_name)
+            .map(|&index| &self.definitions[index])
+    }
+
+    /// Returns whether two names refer to the same tag.
+    pub fn is_same_tag(&self, a: &str, b: &str) -> bool {
+        if a.eq_ignore_ascii_case(b) {
+            return true;
+        }
+
+        match (
+            self.by_name.get(&a.to_lowercase()),
+            self.by_name.get(&b.to_lowercase()),
+        ) {
+            (Some(a), Some(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    /// Returns all names and short names that start with `prefix`, sorted.
+    pub fn completions(&self, prefix: &str) -> Vec<&str> {
+        let prefix = prefix.to_lowercase();
+
+        let mut completions: Vec<&str> = self
+            .definitions
+            .iter()
+            .flat_map(|definition| definition.names().chain(definition.short_name))
+            .filter(|name| name.to_lowercase().starts_with(&prefix))
+            .collect();
+
+        completions.sort_unstable();
+
+        completions
+    }
+}
+
+/// The registry of all known tags.
+pub fn registry() -> &'static TagRegistry {
+    static REGISTRY: OnceLock<TagRegistry> = OnceLock::new();
+
+    REGISTRY.get_or_init(TagRegistry::new)
+}
+
+const LOOP_CATEGORIES: [&str; 9] = [
+    "atom_site",
+    "atom_site_aniso",
+    "atom_type",
+    "citation",
+    "geom_angle",
+    "geom_bond",
+    "publ_author",
+    "space_group_symop",
+    "refln",
+];
+
+const ANGSTROMS: Option<&str> = Some("angstroms");
+const ANGSTROMS_SQUARED: Option<&str> = Some("angstrom_squared");
+const CUBIC_ANGSTROMS: Option<&str> = Some("cubic_angstroms");
+const DEGREES: Option<&str> = Some("degrees");
+const KELVINS: Option<&str> = Some("kelvins");
+const KILOPASCALS: Option<&str> = Some("kilopascals");
+const DENSITY: Option<&str> = Some("megagrams_per_metre_cubed");
+const RECIPROCAL_MILLIMETRES: Option<&str> = Some("reciprocal_millimetres");
+const MILLIMETRES: Option<&str> = Some("millimetres");
+const DALTONS: Option<&str> = Some("daltons");
+
+/// A row of the tag table. `category` and `object` are the DDLm names, so the DDLm name is
+/// `_<category>.<object>`. The DDL1 name is `_<category>_<object>` unless it is given explicitly,
+/// e.g. `_symmetry_Int_Tables_number` for `_space_group.IT_number`.
+struct Entry {
+    category: &'static str,
+    object: &'static str,
+    ddl1_name: Option<&'static str>,
+    kind: TagKind,
+    units: Option<&'static str>,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+    short_name: Option<&'static str>,
+    is_exclusive: bool,
+    aliases: &'static [&'static str],
+}
+
+impl Entry {
+    const fn numeric(
+        category: &'static str,
+        object: &'static str,
+        units: Option<&'static str>,
+        minimum: Option<f64>,
+        maximum: Option<f64>,
+    ) -> Self {
+        Entry {
+            category,
+            object,
+            ddl1_name: None,
+            kind: TagKind::Numeric,
+            units,
+            minimum,
+            maximum,
+            short_name: None,
+            is_exclusive: false,
+            aliases: &[],
+        }
+    }
+
+    const fn char(category: &'static str, object: &'static str) -> Self {
+        Entry {
+            category,
+            object,
+            ddl1_name: None,
+            kind: TagKind::Char,
+            units: None,
+            minimum: None,
+            maximum: None,
+            short_name: None,
+            is_exclusive: false,
+            aliases: &[],
+        }
+    }
+
+    const fn short(mut self, short_name: &'static str) -> Self {
+        self.short_name = Some(short_name);
+        self
+    }
+
+    const fn ddl1(mut self, name: &'static str) -> Self {
+        self.ddl1_name = Some(name);
+        self
+    }
+
+    const fn exclusive(mut self) -> Self {
+        self.is_exclusive = true;
+        self
+    }
+
+    const fn aliases(mut self, aliases: &'static [&'static str]) -> Self {
+        self.aliases = aliases;
+        self
+    }
+
+    fn definition(&self) -> TagDefinition {
+        let ddlm_name = format!("_{}.{}", self.category, self.object);
+
+        TagDefinition {
+            name: match self.ddl1_name {
+                Some(name) => name.to_string(),
+                None => format!("_{}_{}", self.category, self.object),
+            },
+            aliases: std::iter::once(ddlm_name)
+                .chain(self.aliases.iter().map(|alias| alias.to_string()))
+                .collect(),
+            short_name: self.short_name,
+            kind: self.kind,
+            units: self.units,
+            minimum: self.minimum,
+            maximum: self.maximum,
+            is_exclusive: self.is_exclusive,
+            category: self.category,
+            is_looped: LOOP_CATEGORIES.contains(&self.category),
+        }
+    }
+}
+
+const POSITIVE: Option<f64> = Some(0.0);
+
+#[rustfmt::skip]
+const ENTRIES: &[Entry] = &[
+    // cell
+    Entry::numeric("cell", "length_a", ANGSTROMS, POSITIVE, None).short("a").exclusive(),
+    Entry::numeric("cell", "length_b", ANGSTROMS, POSITIVE, None).short("b").exclusive(),
+    Entry::numeric("cell", "length_c", ANGSTROMS, POSITIVE, None).short("c").exclusive(),
+    Entry::numeric("cell", "angle_alpha", DEGREES, POSITIVE, Some(180.0)).short("alpha").exclusive(),
+    Entry::numeric("cell", "angle_beta", DEGREES, POSITIVE, Some(180.0)).short("beta").exclusive(),
+    Entry::numeric("cell", "angle_gamma", DEGREES, POSITIVE, Some(180.0)).short("gamma").exclusive(),
+    Entry::numeric("cell", "volume", CUBIC_ANGSTROMS, POSITIVE, None).short("volume").exclusive(),
+    Entry::numeric("cell", "formula_units_Z", None, Some(1.0), None).short("Z"),
+    Entry::numeric("cell_measurement", "temperature", KELVINS, POSITIVE, None),
+    Entry::numeric("cell_measurement", "pressure", KILOPASCALS, POSITIVE, None),
+    Entry::numeric("cell_measurement", "reflns_used", None, POSITIVE, None),
+    Entry::numeric("cell_measurement", "theta_min", DEGREES, POSITIVE, Some(90.0)),
+    Entry::numeric("cell_measurement", "theta_max", DEGREES, POSITIVE, Some(90.0)),
+    // chemical
+    Entry::char("chemical_name", "systematic"),
+    Entry::char("chemical_name", "common"),
+    Entry::char("chemical_formula", "sum"),
+    Entry::char("chemical_formula", "structural"),
+    Entry::numeric("chemical_formula", "weight", DALTONS, Some(1.0), None),
+    // symmetry and space group
+    Entry::char("space_group", "name_H-M_alt")
+        .ddl1("_symmetry_space_group_name_H-M")
+        .aliases(&["_space_group_name_H-M_alt", "_symmetry.space_group_name_H-M"]),
+    Entry::char("space_group", "name_Hall")
+        .ddl1("_symmetry_space_group_name_Hall")
+        .aliases(&["_space_group_name_Hall", "_symmetry.space_group_name_Hall"]),
+    Entry::numeric("space_group", "IT_number", None, Some(1.0), Some(230.0))
+        .ddl1("_symmetry_Int_Tables_number")
+        .aliases(&["_space_group_IT_number", "_symmetry.Int_Tables_number"]),
+    Entry::char("space_group", "crystal_system")
+        .ddl1("_symmetry_cell_setting")
+        .aliases(&["_space_group_crystal_system", "_symmetry.cell_setting"]),
+    Entry::char("space_group_symop", "id")
+        .ddl1("_symmetry_equiv_pos_site_id")
+        .aliases(&["_space_group_symop_id", "_symmetry_equiv.pos_site_id"]),
+    Entry::char("space_group_symop", "operation_xyz")
+        .ddl1("_symmetry_equiv_pos_as_xyz")
+        .aliases(&["_space_group_symop_operation_xyz", "_symmetry_equiv.pos_as_xyz"]),
+    // diffrn
+    Entry::numeric("diffrn", "ambient_temperature", KELVINS, POSITIVE, None),
+    Entry::numeric("diffrn", "ambient_pressure", KILOPASCALS, POSITIVE, None),
+    Entry::char("diffrn_radiation", "type"),
+    Entry::numeric("diffrn_radiation_wavelength", "value", ANGSTROMS, POSITIVE, None)
+        .ddl1("_diffrn_radiation_wavelength")
+        .aliases(&["_diffrn_radiation_wavelength.wavelength"]),
+    Entry::numeric("diffrn_reflns", "number", None, POSITIVE, None),
+    Entry::numeric("diffrn_reflns", "av_R_equivalents", None, POSITIVE, None),
+    Entry::numeric("diffrn_reflns", "theta_min", DEGREES, POSITIVE, Some(90.0)),
+    Entry::numeric("diffrn_reflns", "theta_max", DEGREES, POSITIVE, Some(90.0)),
+    Entry::numeric("diffrn_measured_fraction", "theta_max", None, POSITIVE, Some(1.0)),
+    // exptl
+    Entry::numeric("exptl_crystal", "density_diffrn", DENSITY, POSITIVE, None),
+    Entry::numeric("exptl_crystal", "density_meas", DENSITY, POSITIVE, None),
+    Entry::numeric("exptl_crystal", "F_000", None, POSITIVE, None),
+    Entry::numeric("exptl_crystal", "size_max", MILLIMETRES, POSITIVE, None),
+    Entry::numeric("exptl_crystal", "size_mid", MILLIMETRES, POSITIVE, None),
+    Entry::numeric("exptl_crystal", "size_min", MILLIMETRES, POSITIVE, None),
+    Entry::numeric("exptl_absorpt", "coefficient_mu", RECIPROCAL_MILLIMETRES, POSITIVE, None),
+    // refine
+    Entry::numeric("refine_ls", "R_factor_all", None, POSITIVE, None),
+    Entry::numeric("refine_ls", "R_factor_gt", None, POSITIVE, None),
+    Entry::numeric("refine_ls", "wR_factor_ref", None, POSITIVE, None),
+    Entry::numeric("refine_ls", "goodness_of_fit_ref", None, POSITIVE, None),
+    Entry::numeric("refine_ls", "number_reflns", None, POSITIVE, None),
+    Entry::numeric("refine_ls", "number_parameters", None, POSITIVE, None),
+    Entry::numeric("refine_ls", "number_restraints", None, POSITIVE, None),
+    Entry::numeric("refine_ls", "shift_over_su_max", None, POSITIVE, None)
+        .ddl1("_refine_ls_shift/su_max")
+        .aliases(&["_refine_ls_shift_over_su_max"]),
+    Entry::numeric("refine_ls", "extinction_coef", None, None, None),
+    // atom_type
+    Entry::char("atom_type", "symbol"),
+    Entry::numeric("atom_type", "oxidation_number", None, Some(-8.0), Some(8.0)),
+    // atom_site
+    Entry::char("atom_site", "label"),
+    Entry::char("atom_site", "type_symbol"),
+    Entry::numeric("atom_site", "fract_x", None, None, None).short("x"),
+    Entry::numeric("atom_site", "fract_y", None, None, None).short("y"),
+    Entry::numeric("atom_site", "fract_z", None, None, None).short("z"),
+    Entry::numeric("atom_site", "Cartn_x", ANGSTROMS, None, None),
+    Entry::numeric("atom_site", "Cartn_y", ANGSTROMS, None, None),
+    Entry::numeric("atom_site", "Cartn_z", ANGSTROMS, None, None),
+    Entry::numeric("atom_site", "occupancy", None, POSITIVE, Some(1.0)).short("occupancy"),
+    Entry::numeric("atom_site", "U_iso_or_equiv", ANGSTROMS_SQUARED, POSITIVE, Some(10.0))
+        .short("Uiso"),
+    Entry::numeric("atom_site", "B_iso_or_equiv", ANGSTROMS_SQUARED, POSITIVE, Some(800.0))
+        .short("Biso"),
+    Entry::char("atom_site", "adp_type"),
+    Entry::numeric("atom_site", "site_symmetry_multiplicity", None, Some(1.0), Some(192.0))
+        .ddl1("_atom_site_symmetry_multiplicity")
+        .aliases(&["_atom_site_site_symmetry_multiplicity"]),
+    Entry::char("atom_site", "Wyckoff_symbol"),
+    Entry::numeric("atom_site", "attached_hydrogens", None, POSITIVE, Some(8.0)),
+    Entry::char("atom_site", "calc_flag"),
+    Entry::char("atom_site", "refinement_flags"),
+    Entry::char("atom_site", "disorder_assembly"),
+    Entry::char("atom_site", "disorder_group"),
+    // atom_site_aniso
+    Entry::char("atom_site_aniso", "label"),
+    Entry::char("atom_site_aniso", "type_symbol"),
+    Entry::numeric("atom_site_aniso", "U_11", ANGSTROMS_SQUARED, None, None),
+    Entry::numeric("atom_site_aniso", "U_22", ANGSTROMS_SQUARED, None, None),
+    Entry::numeric("atom_site_aniso", "U_33", ANGSTROMS_SQUARED, None, None),
+    Entry::numeric("atom_site_aniso", "U_12", ANGSTROMS_SQUARED, None, None),
+    Entry::numeric("atom_site_aniso", "U_13", ANGSTROMS_SQUARED, None, None),
+    Entry::numeric("atom_site_aniso", "U_23", ANGSTROMS_SQUARED, None, None),
+    Entry::numeric("atom_site_aniso", "B_11", ANGSTROMS_SQUARED, None, None),
+    Entry::numeric("atom_site_aniso", "B_22", ANGSTROMS_SQUARED, None, None),
+    Entry::numeric("atom_site_aniso", "B_33", ANGSTROMS_SQUARED, None, None),
+    Entry::numeric("atom_site_aniso", "B_12", ANGSTROMS_SQUARED, None, None),
+    Entry::numeric("atom_site_aniso", "B_13", ANGSTROMS_SQUARED, None, None),
+    Entry::numeric("atom_site_aniso", "B_23", ANGSTROMS_SQUARED, None, None),
+    // geom
+    Entry::char("geom_bond", "atom_site_label_1"),
+    Entry::char("geom_bond", "atom_site_label_2"),
+    Entry::numeric("geom_bond", "distance", ANGSTROMS, POSITIVE, None),
+    Entry::char("geom_bond", "site_symmetry_1"),
+    Entry::char("geom_bond", "site_symmetry_2"),
+    Entry::char("geom_bond", "publ_flag"),
+    Entry::char("geom_angle", "atom_site_label_1"),
+    Entry::char("geom_angle", "atom_site_label_2"),
+    Entry::char("geom_angle", "atom_site_label_3"),
+    Entry::numeric("geom_angle", "value", DEGREES, POSITIVE, Some(180.0)),
+    Entry::char("geom_angle", "site_symmetry_1"),
+    Entry::char("geom_angle", "site_symmetry_2"),
+    Entry::char("geom_angle", "site_symmetry_3"),
+    Entry::char("geom_angle", "publ_flag"),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_by_alias() {
+        let registry = registry();
+
+        let definition = registry.get("_cell.length_a").unwrap();
+        assert_eq!(definition.name, "_cell_length_a");
+        assert_eq!(definition.short_name, Some("a"));
+        assert_eq!(definition.kind, TagKind::Numeric);
+        assert!(!definition.is_looped);
+
+        let definition = registry.get("_space_group.name_h-m_alt").unwrap();
+        assert_eq!(definition.name, "_symmetry_space_group_name_H-M");
+        assert_eq!(definition.kind, TagKind::Char);
+
+        assert_eq!(
+            registry.get_short("alpha").unwrap().name,
+            "_cell_angle_alpha"
+        );
+        assert!(registry.get("_unknown_tag").is_none());
+    }
+
+    #[test]
+    fn test_ddlm_names_match_dictionary() {
+        let registry = registry();
+        let dictionary = crate::Dictionary::from_file("tests/cif_core_ddlm.dic").unwrap();
+
+        let mut count = 0;
+
+        for definition in dictionary.definitions() {
+            let Some(tag) = definition
+                .aliases
+                .iter()
+                .find_map(|alias| registry.get(alias))
+            else {
+                continue;
+            };
+
+            assert!(
+                registry.is_same_tag(&definition.name, &tag.name),
+                "{} is not an alias of {}",
+                definition.name,
+                tag.name
+            );
+            assert_eq!(tag.aliases[0], definition.name);
+
+            count += 1;
+        }
+
+        assert_eq!(count, 17);
+
+        let definition = registry.get("_chemical_formula.sum").unwrap();
+        assert_eq!(definition.name, "_chemical_formula_sum");
+        assert_eq!(definition.category, "chemical_formula");
+    }
+
+    #[test]
+    fn test_is_same_tag() {
+        let registry = registry();
+
+        assert!(registry.is_same_tag(
+            "_symmetry_equiv_pos_as_xyz",
+            "_space_group_symop_operation_xyz"
+        ));
+        assert!(registry.is_same_tag("_CELL_LENGTH_A", "_cell_length_a"));
+        assert!(!registry.is_same_tag("_cell_length_a", "_cell_length_b"));
+        assert!(registry.is_same_tag("_unknown", "_Unknown"));
+    }
+
+    #[test]
+    fn test_ranges_and_loops() {
+        let registry = registry();
+
+        let occupancy = registry.get("_atom_site_occupancy").unwrap();
+        assert!(occupancy.is_looped);
+        assert!(occupancy.is_in_range(1.0));
+        assert!(!occupancy.is_in_range(1.1));
+
+        assert!(!registry
+            .get("_cell_angle_gamma")
+            .unwrap()
+            .is_in_range(270.0));
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            registry().get("_cell_angle_beta").unwrap().to_string(),
+            "_cell_angle_beta (beta) numeric [degrees] (0:180) aliases: _cell.angle_beta"
+        );
+    }
+
+    #[test]
+    fn test_completions() {
+        assert_eq!(
+            registry().completions("_cell_angle"),
+            vec!["_cell_angle_alpha", "_cell_angle_beta", "_cell_angle_gamma"]
+        );
+    }
+}
@@ -0,0 +1,14 @@
+//! Fixtures shared by the unit tests.
+
+use crate::{Cif, DataBlock};
+
+/// Reads the data block of a test CIF, i.e. the block after the content before the first `data_`
+/// header.
+pub(crate) fn block(path: &str) -> DataBlock {
+    let cif = Cif::parse(&std::fs::read_to_string(path).unwrap()).unwrap();
+    cif.blocks()[1].clone()
+}
+
+pub(crate) fn assert_close(a: f64, b: f64, tolerance: f64) {
+    assert!((a - b).abs() < tolerance, "{} != {}", a, b);
+}
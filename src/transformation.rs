@@ -0,0 +1,1089 @@
+//! Changes of the basis of the unit cell: a given matrix, the primitive and the conventional
+//! setting and the Niggli and Delaunay reduced cells.
+//!
+//! A transformation matrix `P` gives the new axes as combinations of the old ones row by row, as for
+//! [`crate::Supercell`]. Fractional coordinates and symmetry operators are transformed with it, so
+//! the structure itself does not change.
+
+use std::{fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    formula::element_of,
+    linalg::{self, Matrix3, Vector3},
+    structure::{self, AnisoSite, AtomSites},
+    symmetry::{self, SymmetryOperator},
+    DataBlock, Lattice,
+};
+
+const Z_TAG: &str = "_cell_formula_units_Z";
+
+/// Lattice points closer than this to a face of the new cell belong to the face at 0.
+const LATTICE_POINT_TOLERANCE: f64 = 1e-8;
+
+/// Transformed rotations and translations are rounded to integers and simple fractions within this
+/// tolerance.
+const ROUNDING_TOLERANCE: f64 = 1e-6;
+
+/// The relative tolerance of the reductions with respect to the squared cell lengths.
+const REDUCTION_TOLERANCE: f64 = 1e-5;
+
+const MAX_REDUCTION_STEPS: usize = 1000;
+
+/// The primitive cells of the centred cells as in the International Tables, Vol. A.
+const CENTRINGS: [(char, Matrix3); 6] = [
+    ('A', [[1.0, 0.0, 0.0], [0.0, 0.5, 0.5], [0.0, -0.5, 0.5]]),
+    ('B', [[0.5, 0.0, 0.5], [0.0, 1.0, 0.0], [-0.5, 0.0, 0.5]]),
+    ('C', [[0.5, 0.5, 0.0], [-0.5, 0.5, 0.0], [0.0, 0.0, 1.0]]),
+    ('I', [[-0.5, 0.5, 0.5], [0.5, -0.5, 0.5], [0.5, 0.5, -0.5]]),
+    ('F', [[0.0, 0.5, 0.5], [0.5, 0.0, 0.5], [0.5, 0.5, 0.0]]),
+    (
+        'R',
+        [
+            [2.0 / 3.0, 1.0 / 3.0, 1.0 / 3.0],
+            [-1.0 / 3.0, 1.0 / 3.0, 1.0 / 3.0],
+            [-1.0 / 3.0, -2.0 / 3.0, 1.0 / 3.0],
+        ],
+    ),
+];
+
+/// A setting that the cell is transformed to.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Setting {
+    /// The Niggli reduced cell.
+    Niggli,
+    /// The Delaunay (Selling) reduced cell.
+    Delaunay,
+    /// A primitive cell without centring translations.
+    Primitive,
+    /// The centred cell of the Hermann-Mauguin symbol, e.g. `F` in `F m -3 m`.
+    Conventional,
+}
+
+impl FromStr for Setting {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "niggli" => Ok(Setting::Niggli),
+            "delaunay" => Ok(Setting::Delaunay),
+            "primitive" => Ok(Setting::Primitive),
+            "conventional" => Ok(Setting::Conventional),
+            _ => Err(anyhow::anyhow!(
+                "Unknown setting {}. Expected niggli, delaunay, primitive or conventional",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for Setting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Setting::Niggli => "niggli",
+            Setting::Delaunay => "delaunay",
+            Setting::Primitive => "primitive",
+            Setting::Conventional => "conventional",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// A change of the basis of the unit cell, either by a matrix or to a [`Setting`].
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(try_from = "CellTransformSpec", into = "CellTransformSpec")]
+pub enum CellTransform {
+    Matrix(Matrix3),
+    Setting(Setting),
+}
+
+impl CellTransform {
+    /// Fails if the matrix does not have a positive determinant.
+    pub fn matrix(matrix: Matrix3) -> anyhow::Result<Self> {
+        let determinant = linalg::determinant(matrix);
+
+        if determinant < ROUNDING_TOLERANCE {
+            return Err(anyhow::anyhow!(
+                "The transformation matrix {} has the determinant {}, but it has to be positive",
+                CellTransform::Matrix(matrix),
+                determinant
+            ));
+        }
+
+        Ok(CellTransform::Matrix(matrix))
+    }
+
+    /// Transforms the cell, the atom sites and the symmetry operators of a block. Returns the number
+    /// of modified values.
+    pub(crate) fn apply_to_block(&self, block: &mut DataBlock) -> anyhow::Result<usize> {
+        let lattice = match Lattice::from_block(block) {
+            Ok(lattice) => lattice,
+            Err(_) if block.find_loop(structure::LABEL_TAG).is_none() => return Ok(0),
+            Err(e) => return Err(e),
+        };
+
+        let matrix = match self {
+            CellTransform::Matrix(matrix) => Some(*matrix),
+            CellTransform::Setting(Setting::Niggli) => Some(niggli_matrix(&lattice)?),
+            CellTransform::Setting(Setting::Delaunay) => Some(delaunay_matrix(&lattice)?),
+            CellTransform::Setting(Setting::Primitive) => primitive_matrix(block, &lattice)?,
+            CellTransform::Setting(Setting::Conventional) => conventional_matrix(block)?,
+        };
+
+        // The conventional cell is the setting that the Hermann-Mauguin symbol describes.
+        let keeps_space_group = matches!(self, CellTransform::Setting(Setting::Conventional));
+
+        match matrix {
+            Some(matrix) if !is_identity(matrix) => {
+                change_basis(block, &lattice, matrix, keeps_space_group)
+            }
+            _ => {
+                log::info!(
+                    "data_{} is already in the {} cell",
+                    block.name().unwrap_or_default(),
+                    self
+                );
+                Ok(0)
+            }
+        }
+    }
+}
+
+fn is_integer(matrix: Matrix3) -> bool {
+    matrix
+        .iter()
+        .flatten()
+        .all(|x| (x - x.round()).abs() < ROUNDING_TOLERANCE)
+}
+
+fn is_identity(matrix: Matrix3) -> bool {
+    (0..3)
+        .all(|i| (0..3).all(|j| (matrix[i][j] - linalg::IDENTITY[i][j]).abs() < ROUNDING_TOLERANCE))
+}
+
+/// Rounds a number to an integer or a simple fraction if it is close to one.
+fn snap(value: f64) -> f64 {
+    match symmetry::fraction(value) {
+        Some((numerator, denominator)) => numerator as f64 / denominator as f64,
+        None => value,
+    }
+}
+
+/// The lattice points of the original cell within the cell with the axes `matrix` in fractional
+/// coordinates of the new cell.
+pub(crate) fn lattice_points(matrix: Matrix3) -> Vec<Vector3> {
+    let inverse = linalg::inverse(matrix).expect("The determinant is positive");
+
+    let mut minimum = [0i64; 3];
+    let mut maximum = [0i64; 3];
+
+    // The corners of the new cell are sums of subsets of its axes.
+    for corner in 0..8 {
+        let mut point = [0.0; 3];
+
+        for (axis, row) in matrix.iter().enumerate() {
+            if corner & (1 << axis) != 0 {
+                point = linalg::add(point, *row);
+            }
+        }
+
+        for i in 0..3 {
+            minimum[i] = minimum[i].min(point[i].floor() as i64);
+            maximum[i] = maximum[i].max(point[i].ceil() as i64);
+        }
+    }
+
+    let mut points = Vec::new();
+
+    for i in minimum[0]..=maximum[0] {
+        for j in minimum[1]..=maximum[1] {
+            for k in minimum[2]..=maximum[2] {
+                let point = linalg::vec_mat([i as f64, j as f64, k as f64], inverse);
+
+                if point
+                    .iter()
+                    .all(|p| (-LATTICE_POINT_TOLERANCE..1.0 - LATTICE_POINT_TOLERANCE).contains(p))
+                {
+                    points.push(structure::wrap(point.map(snap)));
+                }
+            }
+        }
+    }
+
+    points
+}
+
+/// The translations of the structure by fractions of the cell, from the centring operators or, in
+/// P1, from the atom sites that every atom site has a copy of.
+fn centring_translations(
+    block: &DataBlock,
+    lattice: &Lattice,
+    operators: &[SymmetryOperator],
+) -> anyhow::Result<Vec<Vector3>> {
+    if !symmetry::is_p1(block) {
+        return Ok(operators
+            .iter()
+            .filter(|operator| operator.is_centring())
+            .map(|operator| structure::wrap(operator.translation()))
+            .collect());
+    }
+
+    let sites = AtomSites::from_block(block)?;
+
+    if sites.len() == 0 {
+        return Ok(Vec::new());
+    }
+
+    let keys: Vec<(String, String)> = (0..sites.len()).map(|row| site_key(&sites, row)).collect();
+
+    let positions = (0..sites.len())
+        .map(|row| sites.position(row))
+        .collect::<anyhow::Result<Vec<Vector3>>>()?;
+
+    let tolerance = symmetry::DEFAULT_TOLERANCE;
+
+    let translations = (1..sites.len())
+        .filter(|row| keys[*row] == keys[0])
+        .map(|row| structure::wrap(linalg::sub(positions[row], positions[0])))
+        .filter(|translation| {
+            structure::periodic_distance(lattice, *translation, [0.0; 3]) > tolerance
+        })
+        .filter(|translation| {
+            (0..sites.len()).all(|i| {
+                let image = linalg::add(positions[i], *translation);
+
+                (0..sites.len()).any(|j| {
+                    keys[i] == keys[j]
+                        && structure::periodic_distance(lattice, image, positions[j]) < tolerance
+                })
+            })
+        })
+        .collect();
+
+    Ok(translations)
+}
+
+/// Atom sites with the same key can be images of each other.
+fn site_key(sites: &AtomSites, row: usize) -> (String, String) {
+    let symbol = sites
+        .value(row, structure::TYPE_SYMBOL_TAG)
+        .map(str::to_string)
+        .or_else(|| element_of(sites.label(row)))
+        .unwrap_or_default();
+
+    let occupancy = sites
+        .value(row, structure::OCCUPANCY_TAG)
+        .unwrap_or("1")
+        .to_string();
+
+    (symbol, occupancy)
+}
+
+/// The matrix to a primitive cell or `None` if the cell has no centring translations.
+///
+/// Centrings of the International Tables get their standard primitive cell, others the primitive
+/// cell with the shortest axes.
+fn primitive_matrix(block: &DataBlock, lattice: &Lattice) -> anyhow::Result<Option<Matrix3>> {
+    let operators = block_operators(block)?;
+    let translations = centring_translations(block, lattice, &operators)?;
+
+    if translations.is_empty() {
+        return Ok(None);
+    }
+
+    let is_lattice_of = |matrix: &Matrix3| {
+        (linalg::determinant(*matrix) * (translations.len() + 1) as f64 - 1.0).abs()
+            < ROUNDING_TOLERANCE
+            && matrix
+                .iter()
+                .all(|axis| is_lattice_vector(*axis, &translations))
+    };
+
+    if let Some((_, matrix)) = CENTRINGS.iter().find(|(_, matrix)| is_lattice_of(matrix)) {
+        return Ok(Some(*matrix));
+    }
+
+    let mut candidates: Vec<Vector3> = linalg::IDENTITY.to_vec();
+    candidates.extend(translations.iter().copied());
+    candidates.extend(
+        translations
+            .iter()
+            .map(|t| linalg::sub(*t, [1.0; 3]).map(snap)),
+    );
+
+    let mut best: Option<(f64, Matrix3)> = None;
+
+    for (i, a) in candidates.iter().enumerate() {
+        for (j, b) in candidates.iter().enumerate().skip(i + 1) {
+            for c in candidates.iter().skip(j + 1) {
+                let mut matrix = [*a, *b, *c];
+
+                if linalg::determinant(matrix) < 0.0 {
+                    matrix = matrix.map(|axis| linalg::scale(axis, -1.0));
+                }
+
+                let determinant = linalg::determinant(matrix);
+
+                if determinant < ROUNDING_TOLERANCE || !is_lattice_of(&matrix) {
+                    continue;
+                }
+
+                let length: f64 = matrix
+                    .iter()
+                    .map(|axis| linalg::norm(lattice.to_cartesian(*axis)))
+                    .sum();
+
+                if best.is_none_or(|(best_length, _)| length < best_length - ROUNDING_TOLERANCE) {
+                    best = Some((length, matrix));
+                }
+            }
+        }
+    }
+
+    best.map(|(_, matrix)| Some(matrix)).ok_or_else(|| {
+        anyhow::anyhow!(
+            "Cannot find a primitive cell for the centring translations of data_{}",
+            block.name().unwrap_or_default()
+        )
+    })
+}
+
+/// The matrix from the primitive cell to the centred cell of the Hermann-Mauguin symbol or `None` if
+/// the symbol is primitive or the cell is already centred.
+fn conventional_matrix(block: &DataBlock) -> anyhow::Result<Option<Matrix3>> {
+    let Some(letter) = symmetry::centring_letter(block) else {
+        return Ok(None);
+    };
+
+    let Some((_, primitive)) = CENTRINGS.iter().find(|(l, _)| *l == letter) else {
+        return Ok(None);
+    };
+
+    if block_operators(block)?
+        .iter()
+        .any(SymmetryOperator::is_centring)
+    {
+        return Ok(None);
+    }
+
+    Ok(Some(
+        linalg::inverse(*primitive)
+            .expect("The primitive cells are not singular")
+            .map(|row| row.map(snap)),
+    ))
+}
+
+/// The symmetry operators of the block or the identity if it has none.
+fn block_operators(block: &DataBlock) -> anyhow::Result<Vec<SymmetryOperator>> {
+    let operators = symmetry::symmetry_operators(block)?;
+
+    Ok(match operators.is_empty() {
+        true => vec![SymmetryOperator::identity()],
+        false => operators,
+    })
+}
+
+/// Returns whether a vector in fractional coordinates is a lattice translation.
+fn is_lattice_vector(vector: Vector3, translations: &[Vector3]) -> bool {
+    let is_integer = |v: Vector3| v.iter().all(|x| (x - x.round()).abs() < ROUNDING_TOLERANCE);
+
+    is_integer(vector)
+        || translations
+            .iter()
+            .any(|t| is_integer(linalg::sub(vector, *t)))
+}
+
+/// The Niggli reduced cell by the algorithm of Křivý and Gruber (1976) with the tolerances of
+/// Grosse-Kunstleve et al. (2004).
+fn niggli_matrix(lattice: &Lattice) -> anyhow::Result<Matrix3> {
+    let mut basis = lattice.matrix();
+
+    let epsilon = REDUCTION_TOLERANCE * lattice.volume().powf(2.0 / 3.0);
+
+    let sign = |value: f64| match value {
+        v if v > epsilon => 1,
+        v if v < -epsilon => -1,
+        _ => 0,
+    };
+
+    for _ in 0..MAX_REDUCTION_STEPS {
+        let [a, b, c] = basis;
+        let (aa, bb, cc) = (linalg::dot(a, a), linalg::dot(b, b), linalg::dot(c, c));
+        let (xi, eta, zeta) = (
+            2.0 * linalg::dot(b, c),
+            2.0 * linalg::dot(a, c),
+            2.0 * linalg::dot(a, b),
+        );
+
+        // N1: A <= B
+        if aa > bb + epsilon || ((aa - bb).abs() < epsilon && xi.abs() > eta.abs() + epsilon) {
+            basis = [
+                linalg::scale(b, -1.0),
+                linalg::scale(a, -1.0),
+                linalg::scale(c, -1.0),
+            ];
+            continue;
+        }
+
+        // N2: B <= C
+        if bb > cc + epsilon || ((bb - cc).abs() < epsilon && eta.abs() > zeta.abs() + epsilon) {
+            basis = [
+                linalg::scale(a, -1.0),
+                linalg::scale(c, -1.0),
+                linalg::scale(b, -1.0),
+            ];
+            continue;
+        }
+
+        // N3, N4: the angles are all acute or all non-acute.
+        let (l, m, n) = (sign(xi), sign(eta), sign(zeta));
+
+        let signs = if l * m * n == 1 {
+            [l, m, n]
+        } else {
+            let mut signs = [1, 1, 1];
+            let mut zero = None;
+
+            for (index, s) in [l, m, n].into_iter().enumerate() {
+                match s {
+                    1 => signs[index] = -1,
+                    0 => zero = Some(index),
+                    _ => {}
+                }
+            }
+
+            if signs.iter().product::<i32>() < 0 {
+                if let Some(zero) = zero {
+                    signs[zero] = -1;
+                }
+            }
+
+            signs
+        };
+
+        basis = [
+            linalg::scale(a, signs[0] as f64),
+            linalg::scale(b, signs[1] as f64),
+            linalg::scale(c, signs[2] as f64),
+        ];
+
+        let [a, b, c] = basis;
+        let (xi, eta, zeta) = (
+            2.0 * linalg::dot(b, c),
+            2.0 * linalg::dot(a, c),
+            2.0 * linalg::dot(a, b),
+        );
+
+        // N5: |ξ| <= B
+        if xi.abs() > bb + epsilon
+            || ((xi - bb).abs() < epsilon && 2.0 * eta < zeta - epsilon)
+            || ((xi + bb).abs() < epsilon && zeta < -epsilon)
+        {
+            basis[2] = linalg::sub(c, linalg::scale(b, xi.signum()));
+            continue;
+        }
+
+        // N6: |η| <= A
+        if eta.abs() > aa + epsilon
+            || ((eta - aa).abs() < epsilon && 2.0 * xi < zeta - epsilon)
+            || ((eta + aa).abs() < epsilon && zeta < -epsilon)
+        {
+            basis[2] = linalg::sub(c, linalg::scale(a, eta.signum()));
+            continue;
+        }
+
+        // N7: |ζ| <= A
+        if zeta.abs() > aa + epsilon
+            || ((zeta - aa).abs() < epsilon && 2.0 * xi < eta - epsilon)
+            || ((zeta + aa).abs() < epsilon && eta < -epsilon)
+        {
+            basis[1] = linalg::sub(b, linalg::scale(a, zeta.signum()));
+            continue;
+        }
+
+        // N8: C <= A + B + C + ξ + η + ζ
+        let sum = xi + eta + zeta + aa + bb;
+
+        if sum < -epsilon || (sum.abs() < epsilon && 2.0 * (aa + eta) + zeta > epsilon) {
+            basis[2] = linalg::add(c, linalg::add(a, b));
+            continue;
+        }
+
+        return Ok(integer_matrix(basis, lattice));
+    }
+
+    Err(anyhow::anyhow!(
+        "The Niggli reduction did not converge after {} steps",
+        MAX_REDUCTION_STEPS
+    ))
+}
+
+/// The Delaunay reduced cell by Selling reduction: the three shortest of `a`, `b`, `c`,
+/// `d = -(a + b + c)` and their pairwise sums once all their scalar products are non-positive.
+fn delaunay_matrix(lattice: &Lattice) -> anyhow::Result<Matrix3> {
+    let [a, b, c] = lattice.matrix();
+    let mut vectors = [
+        a,
+        b,
+        c,
+        linalg::scale(linalg::add(a, linalg::add(b, c)), -1.0),
+    ];
+
+    let epsilon = REDUCTION_TOLERANCE * lattice.volume().powf(2.0 / 3.0);
+
+    let mut is_reduced = false;
+
+    for _ in 0..MAX_REDUCTION_STEPS {
+        let pair = (0..4)
+            .flat_map(|i| (i + 1..4).map(move |j| (i, j)))
+            .find(|(i, j)| linalg::dot(vectors[*i], vectors[*j]) > epsilon);
+
+        let Some((i, j)) = pair else {
+            is_reduced = true;
+            break;
+        };
+
+        for k in (0..4).filter(|k| *k != i && *k != j) {
+            vectors[k] = linalg::add(vectors[k], vectors[i]);
+        }
+
+        vectors[i] = linalg::scale(vectors[i], -1.0);
+    }
+
+    if !is_reduced {
+        return Err(anyhow::anyhow!(
+            "The Delaunay reduction did not converge after {} steps",
+            MAX_REDUCTION_STEPS
+        ));
+    }
+
+    let [a, b, c, d] = vectors;
+    let mut candidates = [
+        a,
+        b,
+        c,
+        d,
+        linalg::add(a, b),
+        linalg::add(b, c),
+        linalg::add(c, a),
+    ];
+    candidates.sort_by(|u, v| linalg::norm(*u).total_cmp(&linalg::norm(*v)));
+
+    let mut basis: Vec<Vector3> = Vec::new();
+
+    for candidate in candidates {
+        let is_independent = match basis.as_slice() {
+            [] => true,
+            [u] => linalg::norm(linalg::cross(*u, candidate)) > epsilon,
+            [u, v] => linalg::determinant([*u, *v, candidate]).abs() > epsilon,
+            _ => false,
+        };
+
+        if is_independent {
+            basis.push(candidate);
+        }
+    }
+
+    let mut basis: Matrix3 = [basis[0], basis[1], basis[2]];
+
+    if linalg::determinant(basis) < 0.0 {
+        basis = basis.map(|axis| linalg::scale(axis, -1.0));
+    }
+
+    Ok(integer_matrix(basis, lattice))
+}
+
+/// The integer matrix from the axes of the lattice to the new axes.
+fn integer_matrix(basis: Matrix3, lattice: &Lattice) -> Matrix3 {
+    let inverse = linalg::inverse(lattice.matrix()).expect("A lattice is never singular");
+
+    linalg::mat_mul(basis, inverse).map(|row| row.map(f64::round))
+}
+
+/// Transforms the cell, the atom sites and the symmetry operators of a block to the axes `matrix`.
+///
+/// Atom sites that become equivalent in the new cell, e.g. in a primitive cell, are removed. New
+/// lattice translations within a larger cell are added to the symmetry operators. Operators that do
+/// not map the new lattice onto itself are removed, and the images of a site that only they related
+/// become sites of their own.
+///
+/// The site multiplicities are recomputed in the new cell. Unless `keeps_space_group` is set, the
+/// space group symbols and number and the Wyckoff symbols are set to `?` because they refer to the
+/// original setting. Only P1 keeps them in cells of the same volume.
+fn change_basis(
+    block: &mut DataBlock,
+    lattice: &Lattice,
+    matrix: Matrix3,
+    keeps_space_group: bool,
+) -> anyhow::Result<usize> {
+    let block_name = block.name().unwrap_or_default().to_string();
+
+    let operators = block_operators(block)?;
+
+    let has_fractional_axes = matrix.iter().any(|axis| {
+        axis.iter()
+            .any(|x| (x - x.round()).abs() > ROUNDING_TOLERANCE)
+    });
+
+    if has_fractional_axes {
+        let translations = centring_translations(block, lattice, &operators)?;
+
+        if let Some(axis) = matrix
+            .iter()
+            .find(|axis| !is_lattice_vector(**axis, &translations))
+        {
+            return Err(anyhow::anyhow!(
+                "The new axis {:?} is not a lattice translation of data_{}",
+                axis,
+                block_name
+            ));
+        }
+    }
+
+    // In column form the coordinates transform as x' = Q x with Q = P⁻ᵀ.
+    let transform =
+        linalg::transpose(linalg::inverse(matrix).expect("The determinant is positive"));
+    let transform_inverse = linalg::transpose(matrix);
+
+    let mut new_operators: Vec<SymmetryOperator> = Vec::new();
+    let mut removed_operators = 0;
+
+    for operator in &operators {
+        let rotation = linalg::mat_mul(
+            linalg::mat_mul(transform, operator.rotation()),
+            transform_inverse,
+        );
+
+        // The rotation does not map the new lattice onto itself, e.g. a 4-fold axis along a in a
+        // cell with the axes a+b, -a+b, c.
+        if !is_integer(rotation) {
+            removed_operators += 1;
+            continue;
+        }
+
+        let rotation = rotation.map(|row| row.map(|x| x.round()));
+
+        let translation = linalg::mat_vec(transform, operator.translation());
+
+        for point in lattice_points(matrix) {
+            let new_operator = SymmetryOperator::new(
+                rotation,
+                structure::wrap(linalg::add(translation, point).map(snap)),
+            );
+
+            if !new_operators.iter().any(|o| o.is_equivalent(&new_operator)) {
+                new_operators.push(new_operator);
+            }
+        }
+    }
+
+    let new_lattice = Lattice::from_matrix(linalg::mat_mul(matrix, lattice.matrix()))?;
+
+    let is_p1 = symmetry::is_p1(block) && new_operators.len() == 1;
+    let keeps_setting = keeps_space_group || is_p1;
+
+    let sites = AtomSites::from_block(block)?;
+
+    let mut new_sites = sites.without_rows();
+    let mut aniso_sites = Vec::new();
+    let mut kept: Vec<(Vector3, (String, String))> = Vec::new();
+
+    if removed_operators > 0 {
+        log::info!(
+            "{} symmetry operators of data_{} do not map the new cell onto itself and are removed",
+            removed_operators,
+            block_name
+        );
+    }
+
+    for row in 0..sites.len() {
+        let position = sites.position(row)?;
+        let key = site_key(&sites, row);
+
+        // Without the removed operators, the images of a site that they relate to it are sites of
+        // their own.
+        let images = match removed_operators {
+            0 => Vec::new(),
+            _ => symmetry::images(lattice, &operators, position, symmetry::DEFAULT_TOLERANCE),
+        };
+
+        let mut new_positions: Vec<(Vector3, Matrix3)> = Vec::new();
+
+        for (image, rotation) in std::iter::once((position, linalg::IDENTITY)).chain(
+            images
+                .iter()
+                .map(|(image, operator)| (*image, operator.rotation())),
+        ) {
+            let new_position = structure::wrap(linalg::mat_vec(transform, image));
+
+            let is_duplicate = new_operators.iter().any(|operator| {
+                let image = operator.apply(new_position);
+
+                kept.iter().any(|(other, other_key)| {
+                    *other_key == key
+                        && structure::periodic_distance(&new_lattice, image, *other)
+                            < symmetry::DEFAULT_TOLERANCE
+                })
+            });
+
+            if !is_duplicate {
+                kept.push((new_position, key.clone()));
+                new_positions.push((new_position, rotation));
+            }
+        }
+
+        if new_positions.is_empty() {
+            log::info!(
+                "{} is equivalent to another atom site in the new cell of data_{} and is removed",
+                sites.label(row),
+                block_name
+            );
+            continue;
+        }
+
+        for (index, (position, rotation)) in new_positions.iter().enumerate() {
+            let label = match new_positions.len() {
+                1 => sites.label(row).to_string(),
+                _ => format!("{}_{}", sites.label(row), index + 1),
+            };
+
+            let new_row = new_sites.push_row(sites.row(row).to_vec());
+            new_sites.set_label(new_row, &label);
+            new_sites.set_position(new_row, *position);
+
+            let multiplicity = symmetry::images(
+                &new_lattice,
+                &new_operators,
+                *position,
+                symmetry::DEFAULT_TOLERANCE,
+            )
+            .len();
+
+            new_sites.set_value(
+                new_row,
+                symmetry::MULTIPLICITY_TAG,
+                &multiplicity.to_string(),
+            );
+
+            if !keeps_setting {
+                new_sites.set_value(new_row, symmetry::WYCKOFF_TAG, "?");
+            }
+
+            aniso_sites.push(AnisoSite {
+                label: sites.label(row).to_string(),
+                new_label: label,
+                rotation: linalg::mat_mul(transform, *rotation),
+            });
+        }
+    }
+
+    new_sites.write_to(block);
+    structure::transform_aniso_sites(block, lattice, &new_lattice, &aniso_sites);
+
+    symmetry::set_operators(block, &new_operators);
+
+    let mut modified_values_counter =
+        new_sites.len() + new_operators.len() + new_lattice.write_to(block);
+
+    if !keeps_setting {
+        let cleared = symmetry::clear_space_group(block);
+
+        if cleared > 0 {
+            log::info!(
+                "The space group symbols and number of data_{} refer to the original setting and \
+                 are set to ?",
+                block_name
+            );
+        }
+
+        modified_values_counter += cleared;
+    }
+
+    if let Some(z) = block.value(Z_TAG) {
+        let new_z = z
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| anyhow::anyhow!("{} of {} is not a number", z, Z_TAG))?
+            * linalg::determinant(matrix);
+
+        if (new_z - new_z.round()).abs() > ROUNDING_TOLERANCE {
+            log::warn!(
+                "{} of data_{} is {} in the new cell, which is not an integer",
+                Z_TAG,
+                block_name,
+                new_z
+            );
+        }
+
+        block.set_value(Z_TAG, &format!("{}", snap(new_z)));
+        modified_values_counter += 1;
+    }
+
+    Ok(modified_values_counter)
+}
+
+impl FromStr for CellTransform {
+    type Err = anyhow::Error;
+
+    /// Reads a setting, e.g. `niggli`, or the nine numbers of a matrix row by row, e.g.
+    /// `0 1/2 1/2 1/2 0 1/2 1/2 1/2 0`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+
+        match words.as_slice() {
+            [setting] => Ok(CellTransform::Setting(setting.parse()?)),
+            [_, _, _, _, _, _, _, _, _] => {
+                let values = words
+                    .iter()
+                    .map(|word| symmetry::parse_fraction(word))
+                    .collect::<anyhow::Result<Vec<f64>>>()?;
+
+                Self::matrix([
+                    [values[0], values[1], values[2]],
+                    [values[3], values[4], values[5]],
+                    [values[6], values[7], values[8]],
+                ])
+            }
+            _ => Err(anyhow::anyhow!(
+                "A transformation takes a setting or 9 numbers, but {} was given",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for CellTransform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CellTransform::Setting(setting) => write!(f, "{}", setting),
+            CellTransform::Matrix(matrix) => {
+                let values: Vec<String> = matrix
+                    .iter()
+                    .flatten()
+                    .map(|value| match symmetry::fraction(*value) {
+                        Some((numerator, 1)) => numerator.to_string(),
+                        Some((numerator, denominator)) => format!("{}/{}", numerator, denominator),
+                        None => value.to_string(),
+                    })
+                    .collect();
+
+                write!(f, "{}", values.join(" "))
+            }
+        }
+    }
+}
+
+/// The structured form of a [`CellTransform`], e.g. `{ to = "niggli" }` or
+/// `{ matrix = [[0, 0.5, 0.5], [0.5, 0, 0.5], [0.5, 0.5, 0]] }`.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CellTransformSpec {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    matrix: Option<Matrix3>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    to: Option<Setting>,
+}
+
+impl TryFrom<CellTransformSpec> for CellTransform {
+    type Error = anyhow::Error;
+
+    fn try_from(spec: CellTransformSpec) -> Result<Self, Self::Error> {
+        match (spec.matrix, spec.to) {
+            (Some(matrix), None) => Self::matrix(matrix),
+            (None, Some(setting)) => Ok(CellTransform::Setting(setting)),
+            _ => Err(anyhow::anyhow!(
+                "A transformation takes either a matrix or a setting"
+            )),
+        }
+    }
+}
+
+impl From<CellTransform> for CellTransformSpec {
+    fn from(transform: CellTransform) -> Self {
+        match transform {
+            CellTransform::Matrix(matrix) => CellTransformSpec {
+                matrix: Some(matrix),
+                to: None,
+            },
+            CellTransform::Setting(setting) => CellTransformSpec {
+                matrix: None,
+                to: Some(setting),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::block;
+    use crate::Supercell;
+
+    fn is_close(value: Option<String>, expected: f64) -> bool {
+        value
+            .and_then(|value| value.parse::<f64>().ok())
+            .is_some_and(|value| (value - expected).abs() < 1e-3)
+    }
+
+    #[test]
+    fn test_niggli_reduction() {
+        // A cell of the cubic lattice with a = 4 and long oblique axes.
+        let lattice =
+            Lattice::from_matrix([[4.0, 0.0, 0.0], [4.0, 4.0, 0.0], [8.0, 4.0, 4.0]]).unwrap();
+
+        let matrix = niggli_matrix(&lattice).unwrap();
+        let reduced = Lattice::from_matrix(linalg::mat_mul(matrix, lattice.matrix())).unwrap();
+
+        for (value, expected) in reduced
+            .parameters()
+            .iter()
+            .zip([4.0, 4.0, 4.0, 90.0, 90.0, 90.0])
+        {
+            assert!((value - expected).abs() < 1e-9);
+        }
+        assert_eq!(linalg::determinant(matrix), 1.0);
+
+        let matrix = delaunay_matrix(&lattice).unwrap();
+        let reduced = Lattice::from_matrix(linalg::mat_mul(matrix, lattice.matrix())).unwrap();
+        assert!((reduced.volume() - 64.0).abs() < 1e-9);
+        assert!(reduced
+            .lengths()
+            .iter()
+            .all(|length| (length - 4.0).abs() < 1e-9));
+    }
+
+    #[test]
+    fn test_niggli_of_centred_cell() {
+        // The primitive cell of an fcc lattice with a = 4 has a = b = c = 2.8284 and 60° angles.
+        let lattice = Lattice::new(4.0, 4.0, 4.0, 90.0, 90.0, 90.0).unwrap();
+        let primitive =
+            Lattice::from_matrix(linalg::mat_mul(CENTRINGS[4].1, lattice.matrix())).unwrap();
+
+        let matrix = niggli_matrix(&primitive).unwrap();
+        let reduced = Lattice::from_matrix(linalg::mat_mul(matrix, primitive.matrix())).unwrap();
+
+        for (value, expected) in reduced
+            .parameters()
+            .iter()
+            .zip([2.828427, 2.828427, 2.828427, 60.0, 60.0, 60.0])
+        {
+            assert!((value - expected).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_change_basis() {
+        let mut block = block("tests/BaTiO3.cif");
+
+        let transform: CellTransform = "0 1 0 0 0 1 1 0 0".parse().unwrap();
+        assert!(transform.apply_to_block(&mut block).unwrap() > 0);
+
+        let sites = AtomSites::from_block(&block).unwrap();
+        assert_eq!(sites.len(), 3);
+        assert_eq!(sites.position(2).unwrap(), [0.0, 0.5, 0.5]);
+        assert_eq!(symmetry::operators(&block).len(), 48);
+        assert_eq!(block.value(Z_TAG).as_deref(), Some("1"));
+
+        let mut block = self::block("tests/BaTiO3.cif");
+        assert!("1 1 0 -1 1 0 0 0 1"
+            .parse::<CellTransform>()
+            .unwrap()
+            .apply_to_block(&mut block)
+            .is_ok());
+        // Only the operators of 4/mmm along c map the cell onto itself, so the oxygen atoms on
+        // the faces normal to c are a site of their own.
+        assert_eq!(symmetry::operators(&block).len(), 32);
+        assert_eq!(block.value(Z_TAG).as_deref(), Some("2"));
+        let sites = AtomSites::from_block(&block).unwrap();
+        assert_eq!(
+            (0..sites.len())
+                .map(|row| sites.label(row))
+                .collect::<Vec<_>>(),
+            ["Ba1", "Ti1", "O1_1", "O1_2"]
+        );
+        // The multiplicities count the images in the new cell, the Wyckoff letters of Pm-3m do not
+        // apply to it.
+        for (row, multiplicity) in ["2", "2", "4", "2"].into_iter().enumerate() {
+            assert_eq!(
+                sites.value(row, symmetry::MULTIPLICITY_TAG),
+                Some(multiplicity)
+            );
+            assert_eq!(sites.value(row, symmetry::WYCKOFF_TAG), Some("?"));
+        }
+        assert_eq!(
+            block.value("_symmetry_Int_Tables_number").as_deref(),
+            Some("?")
+        );
+        assert_eq!(crate::validate_block(&block), Vec::new());
+
+        // Half of a primitive cell is not a cell of the structure.
+        let mut block = self::block("tests/BaTiO3.cif");
+        assert!("1/2 0 0 0 1 0 0 0 1"
+            .parse::<CellTransform>()
+            .unwrap()
+            .apply_to_block(&mut block)
+            .is_err());
+    }
+
+    #[test]
+    fn test_primitive_and_conventional() {
+        let mut block = block("tests/BaTiO3_P1.cif");
+
+        // A body-centred copy of the structure in P1: every site has an image at +(½, ½, ½).
+        Supercell::new([[1, 1, 0], [0, 1, 1], [1, 0, 1]])
+            .unwrap()
+            .apply_to_block(&mut block)
+            .unwrap();
+        assert_eq!(AtomSites::from_block(&block).unwrap().len(), 10);
+
+        let primitive = CellTransform::Setting(Setting::Primitive);
+        primitive.apply_to_block(&mut block).unwrap();
+        assert_eq!(AtomSites::from_block(&block).unwrap().len(), 5);
+        assert!(is_close(block.value("_cell_volume"), 64.45));
+        assert_eq!(primitive.apply_to_block(&mut block).unwrap(), 0);
+
+        let mut block = self::block("tests/BaTiO3.cif");
+        block.set_value("_symmetry_space_group_name_H-M", "I m -3 m");
+        CellTransform::Setting(Setting::Conventional)
+            .apply_to_block(&mut block)
+            .unwrap();
+        assert!(is_close(block.value("_cell_length_a"), 5.6701));
+        assert!(is_close(block.value("_cell_angle_alpha"), 60.0));
+        assert_eq!(symmetry::operators(&block).len(), 96);
+        assert_eq!(block.value(Z_TAG).as_deref(), Some("2"));
+        assert_eq!(
+            block.value("_symmetry_space_group_name_H-M").as_deref(),
+            Some("I m -3 m")
+        );
+        assert_eq!(
+            AtomSites::from_block(&block)
+                .unwrap()
+                .value(0, symmetry::WYCKOFF_TAG),
+            Some("a")
+        );
+
+        primitive.apply_to_block(&mut block).unwrap();
+        assert!(is_close(block.value("_cell_length_a"), 4.0094));
+        assert!(is_close(block.value("_cell_angle_alpha"), 90.0));
+        assert_eq!(symmetry::operators(&block).len(), 48);
+        assert_eq!(block.value(Z_TAG).as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn test_cell_transform_from_str() {
+        let transform: CellTransform = "0 1/2 1/2 1/2 0 1/2 1/2 1/2 0".parse().unwrap();
+        assert_eq!(transform, CellTransform::Matrix(CENTRINGS[4].1));
+        assert_eq!(transform.to_string(), "0 1/2 1/2 1/2 0 1/2 1/2 1/2 0");
+
+        let transform: CellTransform = "niggli".parse().unwrap();
+        assert_eq!(transform, CellTransform::Setting(Setting::Niggli));
+        assert_eq!(transform.to_string(), "niggli");
+
+        assert!("reduced".parse::<CellTransform>().is_err());
+        assert!("1 0 0 0 1 0 0 0 -1".parse::<CellTransform>().is_err());
+        assert!("1 0 0 0 1 0".parse::<CellTransform>().is_err());
+    }
+}
@@ -1,3 +1,5 @@
+use anyhow::Context;
+
 pub fn remove_uncertainty_digits(value: &str) -> String {
     let mut value = value.to_string();
     if value.contains('(') {
@@ -21,7 +23,7 @@ impl RemoveUncertaintyDigits for str {
 }
 
 pub fn precision_of_value(value: &str) -> usize {
-    let value_precision = match value.split('.').last() {
+    let value_precision = match value.split('.').next_back() {
         Some(precision) => precision.len(),
         None => 0,
     };
@@ -29,6 +31,15 @@ pub fn precision_of_value(value: &str) -> usize {
     value_precision
 }
 
+/// The number of decimal places of a number as written. Unlike [`precision_of_value`], integers have
+/// none, so recomputed values like cell angles of 90 stay integers.
+pub fn decimal_places(value: &str) -> usize {
+    value
+        .split_once('.')
+        .map(|(_, decimals)| decimals.len())
+        .unwrap_or_default()
+}
+
 pub fn whitespace_between_two_values(line: &str) -> Option<usize> {
     let mut words = line.split_whitespace();
     let key = words.next()?;
@@ -37,11 +48,103 @@ pub fn whitespace_between_two_values(line: &str) -> Option<usize> {
     Some(whitespace)
 }
 
+/// Derives a seed for a single file from a base seed and the path of the file.
+///
+/// Uses FNV-1a so that the derived seed is stable across platforms and compiler versions.
+pub fn derive_seed(seed: u64, path: &str) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+
+    let mut hash = FNV_OFFSET;
+
+    for byte in seed.to_le_bytes().iter().chain(path.as_bytes()) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+
+    hash
+}
+
+const MODIFIED_SUFFIX: &str = "_modified";
+
+const KNOWN_EXTENSIONS: [&str; 10] = [
+    ".cif.gz", ".cif.xz", ".cif", ".tar.gz", ".tar.xz", ".tgz", ".txz", ".tar", ".zip", "",
+];
+
+/// Returns the path the modified version of the file at `path` is written to.
+///
+/// `_modified` is inserted before the extension, e.g. `a.cif.gz` becomes `a_modified.cif.gz`.
+pub fn modified_path(path: &str) -> String {
+    let extension = KNOWN_EXTENSIONS
+        .iter()
+        .find(|extension| path.ends_with(*extension))
+        .expect("The empty extension matches every path. Should not happen.");
+
+    let stem = &path[..path.len() - extension.len()];
+
+    format!("{}{}{}", stem, MODIFIED_SUFFIX, extension)
+}
+
+/// Returns the path configuration `configuration` of several modified versions of the file at
+/// `path` is written to, e.g. `a_modified_2.cif` for `a.cif` and 2.
+pub fn configuration_path(path: &str, configuration: usize) -> String {
+    let extension = KNOWN_EXTENSIONS
+        .iter()
+        .find(|extension| path.ends_with(*extension))
+        .expect("The empty extension matches every path. Should not happen.");
+
+    let stem = &path[..path.len() - extension.len()];
+
+    format!("{}{}_{}{}", stem, MODIFIED_SUFFIX, configuration, extension)
+}
+
+/// Calls `write` with a temporary path next to `path` and renames the temporary file to `path` only
+/// if `write` succeeds, so a failure leaves neither a partial file nor the temporary one behind.
+pub fn write_atomically<T>(
+    path: &str,
+    write: impl FnOnce(&str) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let temporary_path = format!("{}.{}.tmp", path, std::process::id());
+
+    let result = write(&temporary_path).and_then(|value| {
+        std::fs::rename(&temporary_path, path)
+            .with_context(|| format!("Could not rename {} to {}", temporary_path, path))?;
+
+        Ok(value)
+    });
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temporary_path);
+    }
+
+    result
+}
+
+/// Returns whether the file at `path` has a known extension and is not itself the output of a
+/// previous run.
+pub fn is_input_path(path: &str) -> bool {
+    KNOWN_EXTENSIONS
+        .iter()
+        .filter(|extension| !extension.is_empty())
+        .find(|extension| path.ends_with(*extension))
+        .is_some_and(|extension| {
+            let stem = &path[..path.len() - extension.len()];
+
+            let stem = stem
+                .trim_end_matches(|c: char| c.is_ascii_digit())
+                .strip_suffix('_')
+                .filter(|_| stem.ends_with(|c: char| c.is_ascii_digit()))
+                .unwrap_or(stem);
+
+            !stem.ends_with(MODIFIED_SUFFIX)
+        })
+}
+
 pub fn directory_content_from_path(path: &str) -> anyhow::Result<Vec<String>> {
-    let paths = match std::fs::metadata(&path) {
+    let paths = match std::fs::metadata(path) {
         Ok(metadata) => {
             if metadata.is_dir() {
-                let paths: Vec<Result<String, anyhow::Error>> = std::fs::read_dir(&path)?
+                let paths: Vec<Result<String, anyhow::Error>> = std::fs::read_dir(path)?
                     .map(|entry| {
                         entry.map(|e| {
                             e.path()
@@ -115,6 +218,79 @@ mod tests {
         assert_eq!(result, 4);
     }
 
+    #[test]
+    fn test_decimal_places() {
+        assert_eq!(super::decimal_places("4.0094"), 4);
+        assert_eq!(super::decimal_places("90"), 0);
+        assert_eq!(super::decimal_places("1."), 0);
+    }
+
+    #[test]
+    fn test_derive_seed() {
+        let seed_a = super::derive_seed(42, "a.cif");
+        let seed_b = super::derive_seed(42, "b.cif");
+        assert_eq!(seed_a, super::derive_seed(42, "a.cif"));
+        assert_ne!(seed_a, seed_b);
+        assert_ne!(seed_a, super::derive_seed(43, "a.cif"));
+    }
+
+    #[test]
+    fn test_modified_path() {
+        assert_eq!(super::modified_path("dir/a.cif"), "dir/a_modified.cif");
+        assert_eq!(super::modified_path("a.cif.gz"), "a_modified.cif.gz");
+        assert_eq!(super::modified_path("a.tar.xz"), "a_modified.tar.xz");
+        assert_eq!(super::modified_path("a.zip"), "a_modified.zip");
+    }
+
+    #[test]
+    fn test_configuration_path() {
+        assert_eq!(
+            super::configuration_path("dir/a.cif", 2),
+            "dir/a_modified_2.cif"
+        );
+        assert_eq!(
+            super::configuration_path("a.tar.gz", 10),
+            "a_modified_10.tar.gz"
+        );
+    }
+
+    #[test]
+    fn test_write_atomically() {
+        let directory = std::env::temp_dir().join(format!("cif_modder_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let path = directory.join("a_modified.cif").display().to_string();
+
+        let result = super::write_atomically(&path, |temporary_path| {
+            std::fs::write(temporary_path, "data_a\n")?;
+            Err::<(), _>(anyhow::anyhow!("Failed halfway"))
+        });
+        assert!(result.is_err());
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 0);
+
+        super::write_atomically(&path, |temporary_path| {
+            Ok(std::fs::write(temporary_path, "data_a\n")?)
+        })
+        .unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "data_a\n");
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_is_input_path() {
+        assert!(super::is_input_path("a.cif"));
+        assert!(super::is_input_path("a.cif.xz"));
+        assert!(super::is_input_path("a.tgz"));
+        assert!(!super::is_input_path("a_modified.cif"));
+        assert!(!super::is_input_path("a_modified.tar.gz"));
+        assert!(!super::is_input_path("a_modified_3.cif"));
+        assert!(super::is_input_path("a_3.cif"));
+        assert!(super::is_input_path("modified_3.cif"));
+        assert!(!super::is_input_path("a.txt"));
+    }
+
     #[test]
     fn test_whitespace_between_two_values() {
         let line = "_cell_length_a     4.0094(2)";
@@ -0,0 +1,509 @@
+//! Consistency checks of CIF data blocks in the style of checkCIF.
+//!
+//! - The stated cell volume matches the volume computed from the cell parameters.
+//! - `_cell_formula_units_Z` matches the number of formula units implied by the atom sites, i.e.
+//!   the sum of occupancy × multiplicity divided by the counts of `_chemical_formula_sum`.
+//! - `_chemical_formula_sum` matches the composition of the atom sites.
+//! - Atom site labels are unique.
+//! - Mandatory tags are present.
+//! - The number of symmetry operators matches the order of the space group.
+
+use std::{collections::HashSet, fmt::Display, io::Read};
+
+use serde::Serialize;
+
+use crate::{
+    decompress,
+    formula::{element_of, Formula},
+    parse_number, Cif, DataBlock, Lattice,
+};
+
+/// Relative deviation of the stated from the computed cell volume that is reported.
+const VOLUME_TOLERANCE: f64 = 1e-3;
+
+/// Absolute deviation of element counts that is reported.
+const COUNT_TOLERANCE: f64 = 1e-2;
+
+const MANDATORY_TAGS: [&str; 10] = [
+    "_cell_length_a",
+    "_cell_length_b",
+    "_cell_length_c",
+    "_cell_angle_alpha",
+    "_cell_angle_beta",
+    "_cell_angle_gamma",
+    "_atom_site_label",
+    "_atom_site_fract_x",
+    "_atom_site_fract_y",
+    "_atom_site_fract_z",
+];
+
+#[derive(PartialEq, Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub struct Issue {
+    pub severity: Severity,
+    /// Short name of the check, e.g. `cell_volume`.
+    pub check: &'static str,
+    pub message: String,
+}
+
+impl Issue {
+    fn error(check: &'static str, message: String) -> Self {
+        Issue {
+            severity: Severity::Error,
+            check,
+            message,
+        }
+    }
+
+    fn warning(check: &'static str, message: String) -> Self {
+        Issue {
+            severity: Severity::Warning,
+            check,
+            message,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub struct BlockReport {
+    pub block: String,
+    pub issues: Vec<Issue>,
+}
+
+impl BlockReport {
+    pub fn has_errors(&self) -> bool {
+        self.issues
+            .iter()
+            .any(|issue| issue.severity == Severity::Error)
+    }
+}
+
+impl Display for BlockReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.issues.is_empty() {
+            return writeln!(f, "  data_{}: ok", self.block);
+        }
+
+        writeln!(f, "  data_{}:", self.block)?;
+
+        for issue in &self.issues {
+            let severity = match issue.severity {
+                Severity::Warning => "warning",
+                Severity::Error => "error",
+            };
+
+            writeln!(f, "    {:<8}{}: {}", severity, issue.check, issue.message)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub struct FileReport {
+    pub path: String,
+    pub blocks: Vec<BlockReport>,
+}
+
+impl FileReport {
+    pub fn has_errors(&self) -> bool {
+        self.blocks.iter().any(BlockReport::has_errors)
+    }
+}
+
+impl Display for FileReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.path)?;
+
+        for block in &self.blocks {
+            write!(f, "{}", block)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads a CIF, which may be gzip or xz compressed, and validates it.
+pub fn validate_reader(path: &str, reader: impl Read) -> anyhow::Result<FileReport> {
+    let (_, mut reader) = decompress(reader)?;
+
+    let mut content = String::new();
+    reader.read_to_string(&mut content)?;
+
+    Ok(FileReport {
+        path: path.to_string(),
+        blocks: validate_cif(&Cif::parse(&content)?),
+    })
+}
+
+/// Validates every named data block of a CIF.
+pub fn validate_cif(cif: &Cif) -> Vec<BlockReport> {
+    cif.blocks()
+        .iter()
+        .filter(|block| block.name().is_some())
+        .map(|block| BlockReport {
+            block: block.name().unwrap_or_default().to_string(),
+            issues: validate_block(block),
+        })
+        .collect()
+}
+
+pub fn validate_block(block: &DataBlock) -> Vec<Issue> {
+    let mut issues = Vec::new();
+
+    check_mandatory_tags(block, &mut issues);
+    check_cell_volume(block, &mut issues);
+    check_labels(block, &mut issues);
+    check_composition(block, &mut issues);
+    check_symmetry_operators(block, &mut issues);
+
+    issues
+}
+
+fn number(block: &DataBlock, tag: &str) -> Option<f64> {
+    block.value(tag).as_deref().and_then(parse_number)
+}
+
+fn has_tag(block: &DataBlock, tag: &str) -> bool {
+    block.find_value(tag).is_some() || block.find_loop(tag).is_some()
+}
+
+fn check_mandatory_tags(block: &DataBlock, issues: &mut Vec<Issue>) {
+    for tag in MANDATORY_TAGS {
+        if !has_tag(block, tag) {
+            issues.push(Issue::error(
+                "mandatory_tags",
+                format!("{} is missing", tag),
+            ));
+        }
+    }
+
+    if !has_tag(block, "_symmetry_space_group_name_H-M")
+        && !has_tag(block, "_symmetry_equiv_pos_as_xyz")
+    {
+        issues.push(Issue::error(
+            "mandatory_tags",
+            "Neither the space group name nor symmetry operators are given".to_string(),
+        ));
+    }
+}
+
+fn check_cell_volume(block: &DataBlock, issues: &mut Vec<Issue>) {
+    let (Some(stated), Ok(lattice)) = (number(block, "_cell_volume"), Lattice::from_block(block))
+    else {
+        return;
+    };
+
+    let computed = lattice.volume();
+
+    let deviation = (stated - computed).abs() / computed;
+
+    if deviation > VOLUME_TOLERANCE {
+        issues.push(Issue::error(
+            "cell_volume",
+            format!(
+                "Stated volume {} differs from the volume {:.4} computed from the cell parameters by {:.2} %",
+                stated,
+                computed,
+                deviation * 100.0
+            ),
+        ));
+    }
+}
+
+fn check_labels(block: &DataBlock, issues: &mut Vec<Issue>) {
+    let Some(atom_sites) = block.find_loop("_atom_site_label") else {
+        return;
+    };
+
+    let column = atom_sites
+        .column("_atom_site_label")
+        .expect("The loop contains the tag");
+
+    let mut labels = HashSet::new();
+
+    for row in 0..atom_sites.len() {
+        let label = atom_sites.value(row, column);
+
+        if !labels.insert(label.clone()) {
+            issues.push(Issue::error(
+                "duplicate_labels",
+                format!("Atom site label {} is used more than once", label),
+            ));
+        }
+    }
+}
+
+/// The composition of the unit cell from occupancy × multiplicity of every atom site.
+fn cell_content(block: &DataBlock) -> Option<Formula> {
+    let atom_sites = block.find_loop("_atom_site_label")?;
+
+    let multiplicity_column = atom_sites.column("_atom_site_symmetry_multiplicity")?;
+    let type_column = atom_sites
+        .column("_atom_site_type_symbol")
+        .unwrap_or(atom_sites.label_column());
+    let occupancy_column = atom_sites.column("_atom_site_occupancy");
+
+    let mut content = Formula::default();
+
+    for row in 0..atom_sites.len() {
+        let element = element_of(&atom_sites.value(row, type_column))?;
+        let multiplicity = parse_number(&atom_sites.value(row, multiplicity_column))?;
+        let occupancy = match occupancy_column {
+            Some(column) => parse_number(&atom_sites.value(row, column)).unwrap_or(1.0),
+            None => 1.0,
+        };
+
+        content.add(&element, multiplicity * occupancy);
+    }
+
+    Some(content)
+}
+
+fn check_composition(block: &DataBlock, issues: &mut Vec<Issue>) {
+    let Some(formula) = block.value("_chemical_formula_sum") else {
+        return;
+    };
+
+    let formula = match Formula::parse(&formula) {
+        Ok(formula) => formula,
+        Err(e) => {
+            issues.push(Issue::error("formula_sum", format!("{:#}", e)));
+            return;
+        }
+    };
+
+    let Some(content) = cell_content(block) else {
+        if has_tag(block, "_atom_site_label") {
+            issues.push(Issue::warning(
+                "formula_sum",
+                "Site multiplicities are missing. The composition was not checked.".to_string(),
+            ));
+        }
+        return;
+    };
+
+    // Every element gives an estimate of Z. If they agree, only Z can be wrong.
+    let ratios: Vec<f64> = formula
+        .elements()
+        .map(|(element, count)| content.count(element) / count)
+        .collect();
+
+    let implied_z = ratios.first().copied().unwrap_or_default();
+
+    let is_consistent = ratios
+        .iter()
+        .all(|ratio| (ratio - implied_z).abs() < COUNT_TOLERANCE)
+        && content
+            .elements()
+            .all(|(element, _)| formula.count(element) > 0.0);
+
+    let z = number(block, "_cell_formula_units_Z");
+
+    match (is_consistent, z) {
+        (true, Some(z)) if (z - implied_z).abs() > COUNT_TOLERANCE => issues.push(Issue::error(
+            "formula_units_z",
+            format!(
+                "_cell_formula_units_Z is {}, but the atom sites contain {} formula units",
+                z,
+                format_count(implied_z)
+            ),
+        )),
+        (false, Some(z)) => issues.push(Issue::error(
+            "formula_sum",
+            format!(
+                "_chemical_formula_sum {} with Z = {} does not match the atom sites, which contain {} per formula unit",
+                formula,
+                z,
+                content.scaled(1.0 / z)
+            ),
+        )),
+        (false, None) => issues.push(Issue::error(
+            "formula_sum",
+            format!(
+                "_chemical_formula_sum {} does not match the atom sites, which contain {} per cell",
+                formula, content
+            ),
+        )),
+        _ => (),
+    }
+}
+
+fn format_count(count: f64) -> String {
+    let count = format!("{:.2}", count);
+
+    count
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_string()
+}
+
+fn check_symmetry_operators(block: &DataBlock, issues: &mut Vec<Issue>) {
+    let Some(operators) = block.find_loop("_symmetry_equiv_pos_as_xyz") else {
+        return;
+    };
+
+    let Some(number) = number(block, "_symmetry_Int_Tables_number") else {
+        return;
+    };
+
+    let Some(point_group_order) = point_group_order(number as usize) else {
+        issues.push(Issue::error(
+            "symmetry_operators",
+            format!("{} is not a space group number", number),
+        ));
+        return;
+    };
+
+    let centring = block
+        .value("_symmetry_space_group_name_H-M")
+        .map(|name| centring_multiplicity(&name, block))
+        .unwrap_or(1);
+
+    let expected = point_group_order * centring;
+
+    if operators.len() != expected {
+        issues.push(Issue::error(
+            "symmetry_operators",
+            format!(
+                "Space group {} has {} symmetry operators, but {} are given",
+                number,
+                expected,
+                operators.len()
+            ),
+        ));
+    }
+}
+
+/// The order of the point group of a space group, from the ranges of space group numbers of each
+/// point group in the International Tables.
+fn point_group_order(number: usize) -> Option<usize> {
+    let order = match number {
+        1 => 1,
+        2 => 2,
+        3..=9 => 2,
+        10..=15 => 4,
+        16..=46 => 4,
+        47..=74 => 8,
+        75..=82 => 4,
+        83..=122 => 8,
+        123..=142 => 16,
+        143..=146 => 3,
+        147..=148 => 6,
+        149..=161 => 6,
+        162..=167 => 12,
+        168..=174 => 6,
+        175..=190 => 12,
+        191..=194 => 24,
+        195..=199 => 12,
+        200..=220 => 24,
+        221..=230 => 48,
+        _ => return None,
+    };
+
+    Some(order)
+}
+
+/// The number of lattice points per cell from the first letter of the Hermann-Mauguin symbol.
+/// Rhombohedral groups have three lattice points in the hexagonal setting and one in the
+/// rhombohedral setting.
+fn centring_multiplicity(name: &str, block: &DataBlock) -> usize {
+    match name.trim().chars().next().map(|c| c.to_ascii_uppercase()) {
+        Some('A' | 'B' | 'C' | 'I') => 2,
+        Some('F') => 4,
+        Some('R') => {
+            let is_hexagonal_setting = number(block, "_cell_angle_gamma")
+                .is_some_and(|gamma| (gamma - 120.0).abs() < 1e-3);
+
+            if is_hexagonal_setting {
+                3
+            } else {
+                1
+            }
+        }
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::block;
+
+    #[test]
+    fn test_valid_block() {
+        assert_eq!(validate_block(&block("tests/BaTiO3.cif")), Vec::new());
+    }
+
+    #[test]
+    fn test_cell_volume() {
+        let mut block = block("tests/BaTiO3.cif");
+        block.set_value("_cell_length_a", "5.0094");
+
+        let issues = validate_block(&block);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].check, "cell_volume");
+    }
+
+    #[test]
+    fn test_composition() {
+        let mut block = block("tests/BaTiO3.cif");
+        block.set_value("_cell_formula_units_Z", "2");
+
+        let issues = validate_block(&block);
+        assert_eq!(issues[0].check, "formula_units_z");
+
+        let mut block = self::block("tests/BaTiO3.cif");
+        block.set_value("_chemical_formula_sum", "Ba1 O2 Ti1");
+
+        let issues = validate_block(&block);
+        assert_eq!(issues[0].check, "formula_sum");
+        assert!(issues[0].message.contains("Ba1 O3 Ti1 per formula unit"));
+    }
+
+    #[test]
+    fn test_labels_and_symmetry_operators() {
+        let mut block = block("tests/BaTiO3.cif");
+
+        let atom_sites = block.find_loop_mut("_atom_site_label").unwrap();
+        atom_sites.set_value(2, 0, "Ti1");
+
+        block.set_value("_symmetry_Int_Tables_number", "200");
+
+        let checks: Vec<&str> = validate_block(&block).iter().map(|i| i.check).collect();
+        assert!(checks.contains(&"duplicate_labels"));
+        assert!(checks.contains(&"symmetry_operators"));
+    }
+
+    #[test]
+    fn test_file_report() {
+        let file = std::fs::File::open("tests/BaTiO3.cif").unwrap();
+        let report = validate_reader("tests/BaTiO3.cif", file).unwrap();
+
+        assert!(!report.has_errors());
+        assert_eq!(
+            report.to_string(),
+            "tests/BaTiO3.cif\n  data_95437-ICSD: ok\n"
+        );
+        assert_eq!(
+            serde_json::to_string(&report).unwrap(),
+            r#"{"path":"tests/BaTiO3.cif","blocks":[{"block":"95437-ICSD","issues":[]}]}"#
+        );
+    }
+
+    #[test]
+    fn test_point_group_order() {
+        assert_eq!(point_group_order(1), Some(1));
+        assert_eq!(point_group_order(14), Some(4));
+        assert_eq!(point_group_order(62), Some(8));
+        assert_eq!(point_group_order(166), Some(12));
+        assert_eq!(point_group_order(194), Some(24));
+        assert_eq!(point_group_order(231), None);
+    }
+}